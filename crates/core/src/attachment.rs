@@ -0,0 +1,89 @@
+//! Attachment validation (`Patient.photo`)
+//!
+//! A photo is either inline base64 `data` or a `url` referencing a Binary
+//! resource, never both, and always declares an image `contentType`.
+
+use serde_json::Value;
+
+/// Problems with the photo at `index`, described for an OperationOutcome;
+/// empty if it is acceptable
+pub fn check_photo(photo: &Value, index: usize, max_bytes: usize) -> Vec<String> {
+    let mut problems = Vec::new();
+    let at = format!("Patient.photo[{}]", index);
+    let Some(photo) = photo.as_object() else {
+        return vec![format!("{} must be an Attachment object", at)];
+    };
+
+    match photo.get("contentType").and_then(Value::as_str) {
+        Some(content_type) if is_image(content_type) => {}
+        Some(content_type) => problems.push(format!(
+            "{}.contentType '{}' is not an image type",
+            at, content_type
+        )),
+        None => problems.push(format!("{}.contentType is required", at)),
+    }
+
+    match (photo.get("data"), photo.get("url")) {
+        (Some(_), Some(_)) => problems.push(format!("{} must not have both data and url", at)),
+        (None, None) => problems.push(format!("{} must have data or a Binary url", at)),
+        (Some(data), None) => match data.as_str().and_then(decoded_len) {
+            None => problems.push(format!("{}.data is not valid base64", at)),
+            Some(len) if len > max_bytes => problems.push(format!(
+                "{}.data is {} bytes, more than the {} allowed",
+                at, len, max_bytes
+            )),
+            Some(len) => {
+                let size = photo.get("size").and_then(Value::as_u64);
+                if size.is_some_and(|size| size != len as u64) {
+                    problems.push(format!(
+                        "{}.size does not match its data ({} bytes)",
+                        at, len
+                    ));
+                }
+            }
+        },
+        (None, Some(url)) => {
+            if !url.as_str().is_some_and(is_binary_reference) {
+                problems.push(format!("{}.url must reference a Binary resource", at));
+            }
+        }
+    }
+    problems
+}
+
+/// Whether a MIME type (parameters ignored) is an image type
+fn is_image(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.len() > "image/".len()
+        && mime
+            .get(.."image/".len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("image/"))
+}
+
+/// `Binary/{id}`, optionally behind a base URL
+fn is_binary_reference(url: &str) -> bool {
+    let mut segments = url.rsplit('/');
+    let id = segments.next().unwrap_or("");
+    let valid_id = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    valid_id && segments.next() == Some("Binary")
+}
+
+/// Length of the bytes encoded by standard, padded base64 (whitespace
+/// allowed), or None if it is not valid base64
+fn decoded_len(data: &str) -> Option<usize> {
+    let chars: Vec<u8> = data.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if chars.len() % 4 != 0 {
+        return None;
+    }
+    let padding = chars.iter().rev().take_while(|&&b| b == b'=').count();
+    let body = &chars[..chars.len() - padding];
+    let valid = padding <= 2
+        && body
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/');
+    valid.then(|| chars.len() / 4 * 3 - padding)
+}
@@ -2,6 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::outcome::OperationOutcome;
+
 /// FHIR Bundle types
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
@@ -59,6 +61,33 @@ impl Bundle {
         }
     }
 
+    /// Create a new collection bundle (e.g. an export snapshot)
+    pub fn collection(entries: Vec<BundleEntry>) -> Self {
+        Self {
+            resource_type: "Bundle".to_string(),
+            bundle_type: BundleType::Collection,
+            total: None,
+            link: Vec::new(),
+            entry: entries,
+        }
+    }
+
+    /// Append an OperationOutcome entry (`search.mode = outcome`) describing
+    /// how the search was processed; does nothing if there are no issues
+    pub fn add_outcome(&mut self, outcome: OperationOutcome) {
+        if outcome.issue.is_empty() {
+            return;
+        }
+        let resource = serde_json::to_value(&outcome).expect("OperationOutcome always serializes");
+        self.entry.push(BundleEntry {
+            search: Some(BundleEntrySearch {
+                mode: SearchEntryMode::Outcome,
+                score: None,
+            }),
+            ..BundleEntry::new(None, resource)
+        });
+    }
+
     /// Add a pagination link
     pub fn add_link(&mut self, relation: &str, url: &str) {
         self.link.push(BundleLink {
@@ -66,6 +95,10 @@ impl Bundle {
             url: url.to_string(),
         });
     }
+    /// Serialize as FHIR XML
+    pub fn to_xml(&self) -> String {
+        crate::xml::to_xml(&serde_json::to_value(self).expect("Bundle always serializes"))
+    }
 }
 
 /// Bundle link for pagination
@@ -84,6 +117,15 @@ pub struct BundleEntry {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search: Option<BundleEntrySearch>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<BundleEntryRequest>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<BundleEntryResponse>,
 }
 
 impl BundleEntry {
@@ -92,6 +134,121 @@ impl BundleEntry {
         Self {
             full_url,
             resource: Some(resource),
+            search: None,
+            request: None,
+            response: None,
         }
     }
+
+    /// A search match ranked by `score`
+    pub fn scored(full_url: Option<String>, resource: serde_json::Value, score: f64) -> Self {
+        Self {
+            search: Some(BundleEntrySearch {
+                mode: SearchEntryMode::Match,
+                score: Some(score),
+            }),
+            ..Self::new(full_url, resource)
+        }
+    }
+
+    /// A history entry for one version of a resource
+    ///
+    /// `method` is the request that wrote the version: a create (`POST`,
+    /// `201`), an update (`PUT`, `200`) or a deletion (`DELETE`, `204`, no
+    /// resource). `url` is the resource's `Type/id`, `last_modified` when the
+    /// version was written.
+    pub fn history(
+        base: &str,
+        url: &str,
+        version: i32,
+        method: HttpVerb,
+        resource: Option<serde_json::Value>,
+        last_modified: Option<String>,
+    ) -> Self {
+        let (request_url, status) = match method {
+            HttpVerb::Post => (url.split('/').next().unwrap_or(url), "201 Created"),
+            HttpVerb::Delete => (url, "204 No Content"),
+            _ => (url, "200 OK"),
+        };
+        let resource = resource.filter(|_| method != HttpVerb::Delete);
+        Self {
+            full_url: Some(format!("{}/{}/_history/{}", base, url, version)),
+            resource,
+            search: None,
+            request: Some(BundleEntryRequest {
+                method,
+                url: request_url.to_string(),
+            }),
+            response: Some(BundleEntryResponse {
+                status: status.to_string(),
+                etag: Some(format!("W/\"{}\"", version)),
+                last_modified,
+            }),
+        }
+    }
+}
+
+/// HTTP method of a Bundle entry request
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpVerb {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+}
+
+impl HttpVerb {
+    /// Parse a method name (`GET`, `POST`, ...)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "GET" => Some(HttpVerb::Get),
+            "HEAD" => Some(HttpVerb::Head),
+            "POST" => Some(HttpVerb::Post),
+            "PUT" => Some(HttpVerb::Put),
+            "DELETE" => Some(HttpVerb::Delete),
+            "PATCH" => Some(HttpVerb::Patch),
+            _ => None,
+        }
+    }
+}
+
+/// The request that produced an entry (history) or is to be performed
+/// (transaction / batch)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntryRequest {
+    pub method: HttpVerb,
+    pub url: String,
+}
+
+/// Outcome of an entry's request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleEntryResponse {
+    /// Status code and optional phrase, e.g. `201 Created`
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+/// Why an entry is in a search result
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchEntryMode {
+    Match,
+    Include,
+    Outcome,
+}
+
+/// Search information for a bundle entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntrySearch {
+    pub mode: SearchEntryMode,
+    /// How well the entry matches the search, from 0 to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
 }
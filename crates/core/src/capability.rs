@@ -2,6 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::package::PackageRegistry;
+
 /// FHIR CapabilityStatement resource (simplified)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -12,6 +14,12 @@ pub struct CapabilityStatement {
     pub kind: String,
     pub fhir_version: String,
     pub format: Vec<String>,
+    /// CapabilityStatements (from loaded IGs) this server claims to implement
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instantiates: Vec<String>,
+    /// Implementation guides loaded by the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub implementation_guide: Vec<String>,
     pub rest: Vec<CapabilityRest>,
 }
 
@@ -25,9 +33,85 @@ impl CapabilityStatement {
             kind: "instance".to_string(),
             fhir_version: "4.3.0".to_string(), // R4B
             format: vec!["json".to_string()],
+            instantiates: Vec::new(),
+            implementation_guide: Vec::new(),
             rest: vec![CapabilityRest::default()],
         }
     }
+
+    /// Capability statement advertising the profiles, search parameters and
+    /// implementation guides of the loaded packages
+    pub fn with_packages(packages: &PackageRegistry) -> Self {
+        let mut statement = Self::new();
+        statement.implementation_guide = packages
+            .packages()
+            .iter()
+            .filter_map(|p| p.guide_url())
+            .collect();
+        statement.instantiates = packages
+            .capability_statements()
+            .iter()
+            .filter(|cs| cs.get("kind").and_then(|k| k.as_str()) != Some("instance"))
+            .filter_map(|cs| cs.get("url").and_then(|u| u.as_str()))
+            .map(str::to_string)
+            .collect();
+
+        for resource in statement
+            .rest
+            .iter_mut()
+            .flat_map(|r| r.resource.iter_mut())
+        {
+            let mut profiles: Vec<String> = packages
+                .profiles()
+                .profiles()
+                .filter(|p| p.base_type == resource.resource_type)
+                .map(|p| p.url.clone())
+                .collect();
+            profiles.sort();
+            resource.supported_profile = profiles;
+
+            for param in packages.search_params() {
+                let supported = param.base.contains(&resource.resource_type)
+                    && param.element_path(&resource.resource_type).is_some()
+                    && !resource.search_param.iter().any(|p| p.name == param.code);
+                if supported {
+                    resource.search_param.push(CapabilitySearchParam {
+                        name: param.code.clone(),
+                        definition: Some(param.url.clone()),
+                        param_type: param.param_type.clone(),
+                    });
+                }
+            }
+        }
+        statement
+    }
+
+    /// Advertise the operation `name` (without `$`) on `resource_type`, or
+    /// server-wide when `None`
+    pub fn add_operation(&mut self, resource_type: Option<&str>, name: &str) {
+        let operation = CapabilityOperation::new(resource_type, name);
+        for rest in self.rest.iter_mut() {
+            match resource_type {
+                Some(resource_type) => rest
+                    .resource
+                    .iter_mut()
+                    .filter(|r| r.resource_type == resource_type)
+                    .for_each(|r| r.operation.push(operation.clone())),
+                None => rest.operation.push(operation.clone()),
+            }
+        }
+    }
+
+    /// Stop advertising an interaction (e.g. `history-type` on every
+    /// resource, or the system-level `transaction`)
+    pub fn remove_interaction(&mut self, code: &str) {
+        for rest in self.rest.iter_mut() {
+            rest.interaction.retain(|i| i.code != code);
+            for resource in rest.resource.iter_mut() {
+                resource.interaction.retain(|i| i.code != code);
+            }
+        }
+    }
 }
 
 impl Default for CapabilityStatement {
@@ -41,6 +125,12 @@ impl Default for CapabilityStatement {
 pub struct CapabilityRest {
     pub mode: String,
     pub resource: Vec<CapabilityResource>,
+    /// System-level interactions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interaction: Vec<CapabilityInteraction>,
+    /// System-level operations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operation: Vec<CapabilityOperation>,
 }
 
 impl Default for CapabilityRest {
@@ -48,6 +138,11 @@ impl Default for CapabilityRest {
         Self {
             mode: "server".to_string(),
             resource: vec![CapabilityResource::patient()],
+            interaction: vec![
+                CapabilityInteraction::new("transaction"),
+                CapabilityInteraction::new("batch"),
+            ],
+            operation: Vec::new(),
         }
     }
 }
@@ -61,7 +156,13 @@ pub struct CapabilityResource {
     pub interaction: Vec<CapabilityInteraction>,
     pub versioning: String,
     pub read_history: bool,
+    /// Profiles (from loaded IGs) that instances of this type may claim
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub supported_profile: Vec<String>,
     pub search_param: Vec<CapabilitySearchParam>,
+    /// Type- and instance-level operations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operation: Vec<CapabilityOperation>,
 }
 
 impl CapabilityResource {
@@ -73,18 +174,34 @@ impl CapabilityResource {
                 CapabilityInteraction::new("read"),
                 CapabilityInteraction::new("vread"),
                 CapabilityInteraction::new("update"),
+                CapabilityInteraction::new("patch"),
                 CapabilityInteraction::new("delete"),
                 CapabilityInteraction::new("history-instance"),
+                CapabilityInteraction::new("history-type"),
                 CapabilityInteraction::new("create"),
                 CapabilityInteraction::new("search-type"),
             ],
             versioning: "versioned".to_string(),
             read_history: true,
+            supported_profile: Vec::new(),
             search_param: vec![
                 CapabilitySearchParam::new("name", "string"),
+                CapabilitySearchParam::new("family", "string"),
+                CapabilitySearchParam::new("given", "string"),
                 CapabilitySearchParam::new("gender", "token"),
                 CapabilitySearchParam::new("birthdate", "date"),
+                CapabilitySearchParam::new("identifier", "token"),
+                CapabilitySearchParam::new("telecom", "token"),
+                CapabilitySearchParam::new("phone", "token"),
+                CapabilitySearchParam::new("email", "token"),
+                CapabilitySearchParam::new("address", "string"),
+                CapabilitySearchParam::new("address-city", "string"),
+                CapabilitySearchParam::new("address-state", "string"),
+                CapabilitySearchParam::new("address-postalcode", "string"),
+                CapabilitySearchParam::new("address-country", "string"),
+                CapabilitySearchParam::new("active", "token"),
             ],
+            operation: Vec::new(),
         }
     }
 }
@@ -103,10 +220,35 @@ impl CapabilityInteraction {
     }
 }
 
+/// Supported operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapabilityOperation {
+    /// Operation name, without the leading `$`
+    pub name: String,
+    /// Reference to the OperationDefinition
+    pub definition: String,
+}
+
+impl CapabilityOperation {
+    pub fn new(resource_type: Option<&str>, name: &str) -> Self {
+        let id = match resource_type {
+            Some(resource_type) => format!("{}-{}", resource_type, name),
+            None => name.to_string(),
+        };
+        Self {
+            name: name.to_string(),
+            definition: format!("OperationDefinition/{}", id),
+        }
+    }
+}
+
 /// Search parameter definition
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CapabilitySearchParam {
     pub name: String,
+    /// Canonical URL of the SearchParameter, for parameters defined by IGs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub definition: Option<String>,
     #[serde(rename = "type")]
     pub param_type: String,
 }
@@ -115,6 +257,7 @@ impl CapabilitySearchParam {
     pub fn new(name: &str, param_type: &str) -> Self {
         Self {
             name: name.to_string(),
+            definition: None,
             param_type: param_type.to_string(),
         }
     }
@@ -0,0 +1,205 @@
+//! Choice elements (`deceased[x]`, `multipleBirth[x]`, `value[x]`)
+//!
+//! A choice element is written with its type appended to the name
+//! (`deceasedBoolean`, `deceasedDateTime`), and a resource carries at most
+//! one of those forms. Validation and search go through this module so
+//! every variant is recognized wherever the element is used.
+
+use serde_json::Value;
+
+/// A choice element of a resource type and the types it may take
+#[derive(Debug, Clone, Copy)]
+pub struct ChoiceElement {
+    pub resource_type: &'static str,
+    /// Element name without `[x]`, e.g. `deceased`
+    pub name: &'static str,
+    /// Allowed types as written in the JSON key suffix (`Boolean`,
+    /// `DateTime`); empty if any type is allowed
+    pub types: &'static [&'static str],
+}
+
+/// Top-level choice elements known to the server
+pub const CHOICE_ELEMENTS: &[ChoiceElement] = &[
+    ChoiceElement {
+        resource_type: "Patient",
+        name: "deceased",
+        types: &["Boolean", "DateTime"],
+    },
+    ChoiceElement {
+        resource_type: "Patient",
+        name: "multipleBirth",
+        types: &["Boolean", "Integer"],
+    },
+    ChoiceElement {
+        resource_type: "Observation",
+        name: "value",
+        types: &[
+            "Quantity",
+            "CodeableConcept",
+            "String",
+            "Boolean",
+            "Integer",
+            "Range",
+            "Ratio",
+            "SampledData",
+            "Time",
+            "DateTime",
+            "Period",
+        ],
+    },
+    ChoiceElement {
+        resource_type: "Observation",
+        name: "effective",
+        types: &["DateTime", "Period", "Timing", "Instant"],
+    },
+    ChoiceElement {
+        resource_type: "Extension",
+        name: "value",
+        types: &[
+            "Base64Binary",
+            "Boolean",
+            "Canonical",
+            "Code",
+            "Date",
+            "DateTime",
+            "Decimal",
+            "Id",
+            "Instant",
+            "Integer",
+            "Integer64",
+            "Markdown",
+            "Oid",
+            "PositiveInt",
+            "String",
+            "Time",
+            "UnsignedInt",
+            "Uri",
+            "Url",
+            "Uuid",
+            "Address",
+            "Age",
+            "Annotation",
+            "Attachment",
+            "Availability",
+            "CodeableConcept",
+            "CodeableReference",
+            "Coding",
+            "ContactDetail",
+            "ContactPoint",
+            "Contributor",
+            "Count",
+            "DataRequirement",
+            "Distance",
+            "Dosage",
+            "Duration",
+            "Expression",
+            "ExtendedContactDetail",
+            "HumanName",
+            "Identifier",
+            "Meta",
+            "Money",
+            "ParameterDefinition",
+            "Period",
+            "Quantity",
+            "Range",
+            "Ratio",
+            "RatioRange",
+            "Reference",
+            "RelatedArtifact",
+            "SampledData",
+            "Signature",
+            "Timing",
+            "TriggerDefinition",
+            "UsageContext",
+        ],
+    },
+];
+
+/// The choice element `name` of `resource_type`, if it is one
+pub fn element(resource_type: &str, name: &str) -> Option<&'static ChoiceElement> {
+    CHOICE_ELEMENTS
+        .iter()
+        .find(|e| e.resource_type == resource_type && e.name == name)
+}
+
+/// The type suffix of `key` if it is a form of choice element `name`
+/// (`deceasedDateTime` gives `DateTime`)
+pub fn variant_type<'a>(key: &'a str, name: &str) -> Option<&'a str> {
+    key.strip_prefix(name).filter(|suffix| {
+        suffix
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_uppercase())
+    })
+}
+
+/// The form of choice element `name` present in `object`, as
+/// `(type suffix, value)`; the first if there are several
+pub fn get<'a>(object: &'a Value, name: &str) -> Option<(&'a str, &'a Value)> {
+    variants(object, name).into_iter().next()
+}
+
+/// Every form of choice element `name` present in `object`
+pub fn variants<'a>(object: &'a Value, name: &str) -> Vec<(&'a str, &'a Value)> {
+    object
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((variant_type(key, name)?, value)))
+        .collect()
+}
+
+/// Problems with the choice elements of a resource, described for an
+/// OperationOutcome; empty if every choice element has at most one form of
+/// an allowed type
+pub fn check(resource_type: &str, resource: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+    for choice in CHOICE_ELEMENTS
+        .iter()
+        .filter(|e| e.resource_type == resource_type)
+    {
+        let found = variants(resource, choice.name);
+        if found.len() > 1 {
+            let keys: Vec<String> = found
+                .iter()
+                .map(|(suffix, _)| format!("{}{}", choice.name, suffix))
+                .collect();
+            problems.push(format!(
+                "{}.{}[x] must have a single value, found {}",
+                resource_type,
+                choice.name,
+                keys.join(", ")
+            ));
+        }
+        if choice.types.is_empty() {
+            continue;
+        }
+        for (suffix, _) in found {
+            if !choice.types.contains(&suffix) {
+                problems.push(format!(
+                    "{}.{}{} is not allowed; {}[x] must be one of {}",
+                    resource_type,
+                    choice.name,
+                    suffix,
+                    choice.name,
+                    choice.types.join(", ")
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// A dotted element path with its leading choice element marked `[x]`
+/// (`deceased` becomes `deceased[x]`), so searches match every form
+pub fn search_path(resource_type: &str, path: &str) -> String {
+    let (first, rest) = match path.split_once('.') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+    match (element(resource_type, first), rest) {
+        (Some(_), Some(rest)) => format!("{}[x].{}", first, rest),
+        (Some(_), None) => format!("{}[x]", first),
+        (None, _) => path.to_string(),
+    }
+}
@@ -0,0 +1,192 @@
+//! Coding, CodeableConcept and token search values
+//!
+//! Two codings are the same concept when both system and code are equal;
+//! display text never takes part. CodeableConcepts without any coding fall
+//! back to comparing their text. [`TokenParam`] gives the `system|code`
+//! search syntax the same semantics as the token search in the extension.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A code from a code system
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Coding {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+/// Trimmed, non-empty string field of a JSON object
+fn str_field(value: &Value, key: &str) -> Option<String> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+impl Coding {
+    pub fn new(system: Option<&str>, code: &str) -> Self {
+        Self {
+            system: system.map(str::to_string),
+            code: Some(code.to_string()),
+            display: None,
+        }
+    }
+
+    /// Read a Coding, ignoring anything but system, code and display
+    pub fn from_json(value: &Value) -> Option<Self> {
+        value.as_object()?;
+        Some(Self {
+            system: str_field(value, "system"),
+            code: str_field(value, "code"),
+            display: str_field(value, "display"),
+        })
+    }
+
+    /// Whether both codings name the same concept: equal code and system
+    /// (two codings without a system are only equal if neither has one)
+    pub fn same_concept(&self, other: &Coding) -> bool {
+        self.code.is_some() && self.code == other.code && self.system == other.system
+    }
+}
+
+/// A concept as one or more codings plus free text
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeableConcept {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub coding: Vec<Coding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl CodeableConcept {
+    /// Read a CodeableConcept; a bare Coding is taken as a concept with that
+    /// one coding
+    pub fn from_json(value: &Value) -> Option<Self> {
+        value.as_object()?;
+        let coding = match value.get("coding").and_then(Value::as_array) {
+            Some(codings) => codings.iter().filter_map(Coding::from_json).collect(),
+            None => Coding::from_json(value)
+                .filter(|c| c.code.is_some())
+                .into_iter()
+                .collect(),
+        };
+        Some(Self {
+            coding,
+            text: str_field(value, "text"),
+        })
+    }
+
+    /// Whether both concepts share a coding or, when neither has codings,
+    /// have the same text ignoring case
+    pub fn matches(&self, other: &CodeableConcept) -> bool {
+        if self.coding.is_empty() && other.coding.is_empty() {
+            return match (&self.text, &other.text) {
+                (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+                _ => false,
+            };
+        }
+        self.coding
+            .iter()
+            .any(|a| other.coding.iter().any(|b| a.same_concept(b)))
+    }
+
+    /// Whether any coding names the given concept
+    pub fn has_coding(&self, system: Option<&str>, code: &str) -> bool {
+        let wanted = Coding::new(system, code);
+        self.coding.iter().any(|c| c.same_concept(&wanted))
+    }
+
+    /// Text for display: the concept text, else the first coding display
+    pub fn display(&self) -> Option<&str> {
+        self.text
+            .as_deref()
+            .or_else(|| self.coding.iter().find_map(|c| c.display.as_deref()))
+    }
+}
+
+/// System part of a token search value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenSystem {
+    /// `code`: any system, or none
+    Any,
+    /// `|code`: no system
+    None,
+    /// `system|code` or `system|`
+    Is(String),
+}
+
+/// A parsed token search value (`code`, `system|code`, `|code`, `system|`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenParam {
+    pub system: TokenSystem,
+    /// None for `system|`, which matches every code in the system
+    pub code: Option<String>,
+}
+
+impl TokenParam {
+    /// Parse a token search value; None if it names neither a system nor a
+    /// code
+    pub fn parse(value: &str) -> Option<Self> {
+        let non_empty = |s: &str| Some(s.trim()).filter(|s| !s.is_empty()).map(str::to_string);
+        let (system, code) = match value.split_once('|') {
+            Some((system, code)) => (
+                non_empty(system).map_or(TokenSystem::None, TokenSystem::Is),
+                non_empty(code),
+            ),
+            None => (TokenSystem::Any, non_empty(value)),
+        };
+        match (&system, &code) {
+            (TokenSystem::Is(_), _) | (_, Some(_)) => Some(Self { system, code }),
+            _ => None,
+        }
+    }
+
+    /// Whether a system and code satisfy the token
+    fn accepts(&self, system: Option<&str>, code: Option<&str>) -> bool {
+        let system_ok = match &self.system {
+            TokenSystem::Any => true,
+            TokenSystem::None => system.is_none(),
+            TokenSystem::Is(wanted) => system == Some(wanted.as_str()),
+        };
+        let code_ok = match &self.code {
+            Some(wanted) => code == Some(wanted.as_str()),
+            None => code.is_some(),
+        };
+        system_ok && code_ok
+    }
+
+    pub fn matches_coding(&self, coding: &Coding) -> bool {
+        self.accepts(coding.system.as_deref(), coding.code.as_deref())
+    }
+
+    /// Whether an element value matches: a primitive code, a Coding, an
+    /// Identifier (`system`/`value`) or a CodeableConcept
+    pub fn matches(&self, value: &Value) -> bool {
+        match value {
+            Value::String(code) => self.accepts(None, Some(code)),
+            Value::Object(obj) if obj.contains_key("value") => {
+                self.accepts(str_field(value, "system").as_deref(), obj["value"].as_str())
+            }
+            Value::Object(_) => CodeableConcept::from_json(value)
+                .is_some_and(|concept| concept.coding.iter().any(|c| self.matches_coding(c))),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for TokenParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = self.code.as_deref().unwrap_or_default();
+        match &self.system {
+            TokenSystem::Any => f.write_str(code),
+            TokenSystem::None => write!(f, "|{}", code),
+            TokenSystem::Is(system) => write!(f, "{}|{}", system, code),
+        }
+    }
+}
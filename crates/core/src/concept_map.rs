@@ -0,0 +1,145 @@
+//! ConceptMap translation (`$translate`)
+//!
+//! Maps a code from one system to another using the `group` / `element` /
+//! `target` structure of a ConceptMap, including the group's `unmapped`
+//! fallback. Both R4 (`equivalence`) and R5 (`relationship`) maps are read;
+//! results use the R4 `equivalence` codes.
+
+use serde_json::{Value, json};
+
+use crate::coding::Coding;
+
+/// Equivalences that mean the source code has no counterpart
+const NO_MATCH: &[&str] = &["unmatched", "disjoint", "not-related-to"];
+
+/// One candidate translation of a code
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translation {
+    /// How the target relates to the source (`equivalent`, `wider`, ...)
+    pub equivalence: String,
+    pub concept: Coding,
+    /// Canonical URL of the ConceptMap that produced the match
+    pub source: Option<String>,
+}
+
+impl Translation {
+    /// Whether the match is usable (not `unmatched` / `disjoint`)
+    pub fn is_match(&self) -> bool {
+        !NO_MATCH.contains(&self.equivalence.as_str())
+    }
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(Value::as_str)
+}
+
+/// Canonical URL of a ConceptMap
+pub fn url(map: &Value) -> Option<&str> {
+    str_field(map, "url")
+}
+
+/// Translate `coding` with `map`, optionally only into `target_system`
+///
+/// Groups whose source system differs from the coding's are skipped (a
+/// coding without a system matches any group). When no element of a matching
+/// group lists the code, the group's `unmapped` mode applies: `provided`
+/// returns the code itself in the target system, `fixed` returns the given
+/// code.
+pub fn translate(map: &Value, coding: &Coding, target_system: Option<&str>) -> Vec<Translation> {
+    let Some(code) = coding.code.as_deref() else {
+        return Vec::new();
+    };
+    let source = url(map).map(str::to_string);
+    let groups = map
+        .get("group")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+
+    let mut translations = Vec::new();
+    for group in groups {
+        let group_target = str_field(group, "target");
+        let source_matches = match (coding.system.as_deref(), str_field(group, "source")) {
+            (Some(system), Some(group_source)) => system == group_source,
+            _ => true,
+        };
+        let target_matches = target_system.is_none_or(|t| group_target.is_none_or(|g| g == t));
+        if !source_matches || !target_matches {
+            continue;
+        }
+
+        let element = group
+            .get("element")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .find(|e| str_field(e, "code") == Some(code));
+
+        match element {
+            Some(element) => {
+                let targets = element.get("target").and_then(Value::as_array);
+                for target in targets.into_iter().flatten() {
+                    let equivalence = str_field(target, "equivalence")
+                        .or_else(|| str_field(target, "relationship"))
+                        .unwrap_or("equivalent");
+                    let concept = Coding {
+                        system: group_target.map(str::to_string),
+                        code: str_field(target, "code").map(str::to_string),
+                        display: str_field(target, "display").map(str::to_string),
+                    };
+                    translations.push(Translation {
+                        equivalence: equivalence.to_string(),
+                        concept,
+                        source: source.clone(),
+                    });
+                }
+            }
+            None => {
+                let Some(unmapped) = group.get("unmapped") else {
+                    continue;
+                };
+                let concept = match str_field(unmapped, "mode") {
+                    Some("provided") => Coding::new(group_target, code),
+                    Some("fixed") => match str_field(unmapped, "code") {
+                        Some(fixed) => {
+                            let mut concept = Coding::new(group_target, fixed);
+                            concept.display = str_field(unmapped, "display").map(str::to_string);
+                            concept
+                        }
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                translations.push(Translation {
+                    equivalence: "equivalent".to_string(),
+                    concept,
+                    source: source.clone(),
+                });
+            }
+        }
+    }
+    translations
+}
+
+/// The `$translate` output `Parameters` for a set of candidate translations
+pub fn to_parameters(translations: &[Translation]) -> Value {
+    let result = translations.iter().any(Translation::is_match);
+    let mut parameter = vec![json!({"name": "result", "valueBoolean": result})];
+    if !result {
+        parameter.push(json!({
+            "name": "message",
+            "valueString": "No mapping found for the given code",
+        }));
+    }
+    for t in translations {
+        let mut part = vec![
+            json!({"name": "equivalence", "valueCode": t.equivalence}),
+            json!({"name": "concept", "valueCoding": t.concept}),
+        ];
+        if let Some(ref source) = t.source {
+            part.push(json!({"name": "source", "valueUri": source}));
+        }
+        parameter.push(json!({"name": "match", "part": part}));
+    }
+    json!({"resourceType": "Parameters", "parameter": parameter})
+}
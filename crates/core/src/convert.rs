@@ -0,0 +1,75 @@
+//! Cross-version transforms between R4B and R5
+//!
+//! Only the Patient elements whose shape differs between the versions are
+//! mapped; everything else is carried over unchanged.
+
+use serde_json::Value;
+
+use crate::version::FhirVersion;
+
+/// `Attachment` elements that only exist in R5
+const R5_ONLY_ATTACHMENT_FIELDS: &[&str] = &["height", "width", "frames", "duration", "pages"];
+
+/// `Patient.contact` elements that only exist in R5
+const R5_ONLY_CONTACT_FIELDS: &[&str] = &["additionalName", "additionalAddress"];
+
+/// Convert a Patient between FHIR versions and retag it with the target version
+pub fn convert_patient(mut resource: Value, from: FhirVersion, to: FhirVersion) -> Value {
+    if from != to {
+        match (from, to) {
+            (FhirVersion::R4B, FhirVersion::R5) => r4b_to_r5(&mut resource),
+            (FhirVersion::R5, FhirVersion::R4B) => r5_to_r4b(&mut resource),
+            _ => {}
+        }
+    }
+    to.tag(&mut resource);
+    resource
+}
+
+/// Convert a stored Patient to `to`, reading its source version from its tag
+pub fn patient_to(resource: Value, to: FhirVersion) -> Value {
+    let from = FhirVersion::of_resource(&resource);
+    convert_patient(resource, from, to)
+}
+
+fn r4b_to_r5(resource: &mut Value) {
+    // Attachment.size is unsignedInt in R4B but integer64 (a JSON string) in R5
+    for photo in array_mut(resource, "photo") {
+        if let Some(size) = photo.get("size").and_then(|s| s.as_u64()) {
+            photo["size"] = Value::String(size.to_string());
+        }
+    }
+}
+
+fn r5_to_r4b(resource: &mut Value) {
+    for photo in array_mut(resource, "photo") {
+        if let Some(obj) = photo.as_object_mut() {
+            for field in R5_ONLY_ATTACHMENT_FIELDS {
+                obj.remove(*field);
+            }
+            if let Some(size) = obj.remove("size") {
+                // Sizes beyond unsignedInt cannot be represented in R4B
+                if let Some(size) = size.as_str().and_then(|s| s.parse::<u32>().ok()) {
+                    obj.insert("size".to_string(), Value::from(size));
+                }
+            }
+        }
+    }
+
+    for contact in array_mut(resource, "contact") {
+        if let Some(obj) = contact.as_object_mut() {
+            for field in R5_ONLY_CONTACT_FIELDS {
+                obj.remove(*field);
+            }
+        }
+    }
+}
+
+/// Mutable iterator over the elements of an array field (empty if absent)
+fn array_mut<'a>(resource: &'a mut Value, field: &str) -> impl Iterator<Item = &'a mut Value> {
+    resource
+        .get_mut(field)
+        .and_then(|v| v.as_array_mut())
+        .into_iter()
+        .flatten()
+}
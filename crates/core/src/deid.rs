@@ -0,0 +1,140 @@
+//! De-identification of resources for research extracts
+//!
+//! A [`DeidProfile`] decides which elements are removed and which are
+//! generalized. Both profiles drop identifiers, names, contact details and
+//! free text, and replace the resource id with a caller-supplied pseudonym so
+//! records stay linkable within one extract but not back to the source.
+
+use serde_json::{Map, Value};
+
+/// Elements removed from every resource by both profiles
+const DIRECT_IDENTIFIERS: &[&str] = &[
+    "identifier",
+    "name",
+    "telecom",
+    "photo",
+    "contact",
+    "text",
+    "link",
+    "generalPractitioner",
+    "managingOrganization",
+    // Extensions may carry anything, including identifying data
+    "extension",
+    "modifierExtension",
+];
+
+/// Elements holding dates that Safe Harbor reduces to the year
+const DATE_ELEMENTS: &[&str] = &["birthDate", "deceasedDateTime"];
+
+/// Code system of the `PSEUDED` / `REDACTED` security labels
+const OBSERVATION_VALUE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-ObservationValue";
+
+/// Address parts a profile may keep
+const ADDRESS_PARTS: &[&str] = &["city", "district", "state", "postalCode", "country"];
+
+/// De-identification rule set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeidProfile {
+    /// HIPAA Safe Harbor: dates reduced to the year, addresses to state,
+    /// country and a three-digit postal prefix
+    SafeHarbor,
+    /// HIPAA limited data set: dates and address city / state / postal code
+    /// are kept
+    Limited,
+}
+
+impl DeidProfile {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "safe-harbor" | "safeharbor" => Some(Self::SafeHarbor),
+            "limited" => Some(Self::Limited),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::SafeHarbor => "safe-harbor",
+            Self::Limited => "limited",
+        }
+    }
+
+    /// De-identified copy of `resource` with `pseudonym` as its id
+    pub fn apply(self, resource: &Value, pseudonym: &str) -> Value {
+        let Some(obj) = resource.as_object() else {
+            return resource.clone();
+        };
+        let mut out: Map<String, Value> = obj
+            .iter()
+            .filter(|(key, _)| !DIRECT_IDENTIFIERS.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        out.insert("id".to_string(), Value::String(pseudonym.to_string()));
+        // Version and timestamps would let records be matched to the source,
+        // so meta only carries the labels of what was done
+        out.insert(
+            "meta".to_string(),
+            serde_json::json!({"security": [
+                {"system": OBSERVATION_VALUE_SYSTEM, "code": "PSEUDED"},
+                {"system": OBSERVATION_VALUE_SYSTEM, "code": "REDACTED"},
+            ]}),
+        );
+
+        if let Some(address) = out.remove("address") {
+            let kept = self.address(&address);
+            if !kept.as_array().is_some_and(Vec::is_empty) {
+                out.insert("address".to_string(), kept);
+            }
+        }
+
+        if self == Self::SafeHarbor {
+            for key in DATE_ELEMENTS {
+                if let Some(date) = out.get_mut(*key) {
+                    generalize_date(date);
+                }
+            }
+        }
+
+        Value::Object(out)
+    }
+
+    /// Addresses reduced to the parts the profile keeps
+    fn address(self, address: &Value) -> Value {
+        let reduce = |address: &Value| -> Option<Value> {
+            let obj = address.as_object()?;
+            let mut kept: Map<String, Value> = obj
+                .iter()
+                .filter(|(key, _)| ADDRESS_PARTS.contains(&key.as_str()))
+                .filter(|(key, _)| {
+                    self == Self::Limited || !matches!(key.as_str(), "city" | "district")
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            let postal = kept
+                .get_mut("postalCode")
+                .filter(|_| self == Self::SafeHarbor);
+            if let Some(postal) = postal {
+                let prefix: String = postal
+                    .as_str()
+                    .unwrap_or_default()
+                    .chars()
+                    .take(3)
+                    .collect();
+                *postal = Value::String(prefix);
+            }
+            (!kept.is_empty()).then_some(Value::Object(kept))
+        };
+        match address {
+            Value::Array(addresses) => Value::Array(addresses.iter().filter_map(reduce).collect()),
+            address => reduce(address).unwrap_or(Value::Array(Vec::new())),
+        }
+    }
+}
+
+/// Reduce a date or dateTime string to its year
+fn generalize_date(date: &mut Value) {
+    if let Some(year) = date.as_str().and_then(|d| d.get(..4)).map(str::to_string) {
+        *date = Value::String(year);
+    }
+}
@@ -0,0 +1,161 @@
+//! Structural checks of extensions and modifier extensions
+//!
+//! Extensions are stored as written, including ones the server knows
+//! nothing about; only their shape is checked. Each needs a `url` and either
+//! one `value[x]` of a type an extension can take or nested extensions.
+//! A `modifierExtension` changes the meaning of the element it sits on, so
+//! one the server does not recognize is an error rather than something to
+//! carry along.
+
+use serde_json::Value;
+
+use crate::choice;
+
+/// `value[x]` types whose JSON form is a string
+const STRING_TYPES: &[&str] = &[
+    "Base64Binary",
+    "Canonical",
+    "Code",
+    "Date",
+    "DateTime",
+    "Id",
+    "Instant",
+    "Integer64",
+    "Markdown",
+    "Oid",
+    "String",
+    "Time",
+    "Uri",
+    "Url",
+    "Uuid",
+];
+
+/// Problems with the extensions anywhere in a resource, described for an
+/// OperationOutcome; empty if they are all well-formed
+///
+/// `recognized` tells whether a modifier extension URL is understood.
+pub fn check(resource: &Value, recognized: impl Fn(&str) -> bool) -> Vec<String> {
+    let root = resource
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .unwrap_or("Resource");
+    let mut problems = Vec::new();
+    walk(resource, root, false, &recognized, &mut problems);
+    problems
+}
+
+/// Check the extensions of `value` and everything below it
+fn walk(
+    value: &Value,
+    location: &str,
+    in_extension: bool,
+    recognized: &dyn Fn(&str) -> bool,
+    problems: &mut Vec<String>,
+) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                let at = format!("{}.{}", location, key);
+                let modifier = key == "modifierExtension";
+                if key == "extension" || modifier {
+                    let Some(extensions) = child.as_array() else {
+                        problems.push(format!("{} must be an array", at));
+                        continue;
+                    };
+                    for (i, extension) in extensions.iter().enumerate() {
+                        let at = format!("{}[{}]", at, i);
+                        check_extension(
+                            extension,
+                            &at,
+                            in_extension,
+                            modifier,
+                            recognized,
+                            problems,
+                        );
+                        walk(extension, &at, true, recognized, problems);
+                    }
+                } else {
+                    walk(child, &at, false, recognized, problems);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                walk(
+                    item,
+                    &format!("{}[{}]", location, i),
+                    in_extension,
+                    recognized,
+                    problems,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Check one extension; `nested` extensions (parts of a complex extension)
+/// may use a relative url
+fn check_extension(
+    extension: &Value,
+    at: &str,
+    nested: bool,
+    modifier: bool,
+    recognized: &dyn Fn(&str) -> bool,
+    problems: &mut Vec<String>,
+) {
+    if !extension.is_object() {
+        problems.push(format!("{} must be an Extension object", at));
+        return;
+    }
+
+    match extension.get("url").and_then(Value::as_str) {
+        None | Some("") => problems.push(format!("{}.url is required", at)),
+        Some(url) if !nested && !url.contains(':') => {
+            problems.push(format!("{}.url '{}' must be an absolute URL", at, url))
+        }
+        Some(url) if modifier && !recognized(url) => problems.push(format!(
+            "{} '{}' is a modifier extension the server does not understand",
+            at, url
+        )),
+        Some(_) => {}
+    }
+
+    let values = choice::variants(extension, "value");
+    let has_children = extension
+        .get("extension")
+        .and_then(Value::as_array)
+        .is_some_and(|children| !children.is_empty());
+    match (values.as_slice(), has_children) {
+        ([], false) => problems.push(format!("{} must have a value[x] or nested extensions", at)),
+        ([_, ..], true) => problems.push(format!(
+            "{} must not have both a value[x] and nested extensions",
+            at
+        )),
+        ([_, _, ..], false) => problems.push(format!("{} must have a single value[x]", at)),
+        _ => {}
+    }
+
+    let allowed = choice::element("Extension", "value").map_or(&[][..], |e| e.types);
+    for (suffix, value) in values {
+        if !allowed.contains(&suffix) {
+            problems.push(format!(
+                "{}.value{} is not a type an extension can take",
+                at, suffix
+            ));
+        } else if !json_kind_matches(suffix, value) {
+            problems.push(format!("{}.value{} has the wrong JSON type", at, suffix));
+        }
+    }
+}
+
+/// Whether `value` has the JSON form of the FHIR type `suffix`
+fn json_kind_matches(suffix: &str, value: &Value) -> bool {
+    match suffix {
+        "Boolean" => value.is_boolean(),
+        "Integer" | "PositiveInt" | "UnsignedInt" => value.is_i64() || value.is_u64(),
+        "Decimal" => value.is_number(),
+        _ if STRING_TYPES.contains(&suffix) => value.is_string(),
+        _ => value.is_object(),
+    }
+}
@@ -0,0 +1,331 @@
+//! Identifier with use, type, period and assigner
+//!
+//! Resources often carry several identifiers (MRN, national id, old numbers
+//! from a merged record). [`primary`] picks the one to key on, and
+//! [`ResourceIdentifier::to_v2_cx`] renders an identifier as an HL7 v2 `CX`
+//! field.
+//!
+//! [`ResourceIdentifier`] works on identifiers in raw resource JSON; it is
+//! named apart from `fhir_core::Identifier`, the fhir-sdk model type.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::coding::{CodeableConcept, TokenParam};
+
+/// Code system of identifier type codes (`MR`, `SS`, `DL`, …)
+pub const IDENTIFIER_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v2-0203";
+
+/// Purpose of an identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentifierUse {
+    Usual,
+    Official,
+    Temp,
+    Secondary,
+    Old,
+}
+
+/// Time range as FHIR dateTime strings (either end may be open)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Period {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+}
+
+impl Period {
+    /// Whether `instant` (an RFC 3339 string) falls in the period
+    ///
+    /// Partial dates cover their whole span: an end of `2026` includes every
+    /// instant in 2026.
+    pub fn contains(&self, instant: &str) -> bool {
+        // Compare at the precision of the bound, ignoring time zones
+        let at = |bound: &str| {
+            let bound = bound.get(..19).unwrap_or(bound);
+            (bound, instant.get(..bound.len()).unwrap_or(instant))
+        };
+        let started = self.start.as_deref().is_none_or(|start| {
+            let (start, now) = at(start);
+            now >= start
+        });
+        let ended = self.end.as_deref().is_some_and(|end| {
+            let (end, now) = at(end);
+            now > end
+        });
+        started && !ended
+    }
+}
+
+/// Reference to the organization that issued an identifier
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reference {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    /// Identifier of the referenced organization (its system is used as the
+    /// assigning authority)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<Box<ResourceIdentifier>>,
+}
+
+/// An identifier for a resource
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceIdentifier {
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<IdentifierUse>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<CodeableConcept>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<Period>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assigner: Option<Reference>,
+}
+
+impl ResourceIdentifier {
+    pub fn new(system: &str, value: &str) -> Self {
+        Self {
+            system: Some(system.to_string()),
+            value: Some(value.to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// Read an identifier; None unless it is an object with a value
+    pub fn from_json(value: &Value) -> Option<Self> {
+        value.get("value")?.as_str()?;
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Identifiers of a resource's `identifier` array that can be read
+    pub fn all_from_resource(resource: &Value) -> Vec<Self> {
+        resource
+            .get("identifier")
+            .and_then(Value::as_array)
+            .map(|ids| ids.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    /// v2-0203 type code (`MR`, `SS`, …), if the type is coded that way
+    pub fn type_code(&self) -> Option<&str> {
+        self.type_.as_ref()?.coding.iter().find_map(|c| {
+            (c.system.as_deref() == Some(IDENTIFIER_TYPE_SYSTEM))
+                .then_some(c.code.as_deref())
+                .flatten()
+        })
+    }
+
+    /// `system|value` as used in token searches and conditional references
+    pub fn key(&self) -> Option<String> {
+        let value = self.value.as_deref()?;
+        Some(match self.system.as_deref() {
+            Some(system) => format!("{}|{}", system, value),
+            None => value.to_string(),
+        })
+    }
+
+    /// Whether the identifier is in use at `instant` (RFC 3339): not `old`
+    /// and within its period
+    pub fn is_active(&self, instant: &str) -> bool {
+        self.use_ != Some(IdentifierUse::Old)
+            && self.period.as_ref().is_none_or(|p| p.contains(instant))
+    }
+
+    /// Whether the identifier satisfies a token search value
+    pub fn matches(&self, token: &TokenParam) -> bool {
+        token.matches(&serde_json::json!({"system": self.system, "value": self.value}))
+    }
+
+    /// Assigning authority: the assigner's identifier system, else its
+    /// display name
+    pub fn assigning_authority(&self) -> Option<&str> {
+        let assigner = self.assigner.as_ref()?;
+        assigner
+            .identifier
+            .as_ref()
+            .and_then(|id| id.system.as_deref())
+            .or(assigner.display.as_deref())
+    }
+
+    /// HL7 v2 `CX` rendering: `value^^^namespace&system&URI^typeCode`
+    pub fn to_v2_cx(&self) -> Option<String> {
+        let value = v2_escape(self.value.as_deref()?);
+        let namespace = self
+            .assigner
+            .as_ref()
+            .and_then(|a| a.display.as_deref())
+            .map(v2_escape)
+            .unwrap_or_default();
+        let authority = match self.system.as_deref() {
+            Some(system) => format!("{}&{}&URI", namespace, v2_escape(system)),
+            None => namespace,
+        };
+        let type_code = self.type_code().map(v2_escape).unwrap_or_default();
+        let cx = format!("{}^^^{}^{}", value, authority, type_code);
+        Some(cx.trim_end_matches(['^', '&']).to_string())
+    }
+
+    /// Rank for [`primary`]: lower is preferred
+    fn rank(&self) -> (u8, u8) {
+        let by_use = match self.use_ {
+            Some(IdentifierUse::Official) => 0,
+            Some(IdentifierUse::Usual) => 1,
+            None => 2,
+            Some(IdentifierUse::Secondary) => 3,
+            Some(IdentifierUse::Temp) => 4,
+            Some(IdentifierUse::Old) => 5,
+        };
+        let by_type = match self.type_code() {
+            Some("MR") => 0,
+            Some(_) => 1,
+            None => 2,
+        };
+        (by_use, by_type)
+    }
+}
+
+/// HL7 v2 escaping of the default delimiters `|^~\&`
+fn v2_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\E\\"),
+            '|' => out.push_str("\\F\\"),
+            '^' => out.push_str("\\S\\"),
+            '&' => out.push_str("\\T\\"),
+            '~' => out.push_str("\\R\\"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The identifier to key a resource on at `instant` (RFC 3339)
+///
+/// With `systems`, the first active identifier in the first listed system
+/// wins. Otherwise active identifiers are ranked by use (official, usual,
+/// unspecified, secondary, temp), then by type (MR first), then by position.
+pub fn primary<'a>(
+    identifiers: &'a [ResourceIdentifier],
+    systems: &[&str],
+    instant: &str,
+) -> Option<&'a ResourceIdentifier> {
+    let active = || {
+        identifiers
+            .iter()
+            .filter(|id| id.value.is_some() && id.is_active(instant))
+    };
+    if let Some(id) = systems
+        .iter()
+        .find_map(|system| active().find(|id| id.system.as_deref() == Some(*system)))
+    {
+        return Some(id);
+    }
+    active().min_by_key(|id| id.rank())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const MRN: &str = "http://hospital.example/mrn";
+
+    fn patient() -> Value {
+        json!({
+            "resourceType": "Patient",
+            "identifier": [
+                {"use": "old", "system": MRN, "value": "OLD-1"},
+                {"system": "http://example.org/ssn", "value": "123-45-6789"},
+                {
+                    "use": "official",
+                    "type": {"coding": [{"system": IDENTIFIER_TYPE_SYSTEM, "code": "MR"}]},
+                    "system": MRN,
+                    "value": "MRN-2",
+                    "period": {"start": "2020-01-01"},
+                    "assigner": {"display": "General Hospital"}
+                },
+                {"system": MRN},
+                "not an identifier"
+            ]
+        })
+    }
+
+    #[test]
+    fn test_all_from_resource_skips_unreadable() {
+        let ids = ResourceIdentifier::all_from_resource(&patient());
+        let keys: Vec<_> = ids.iter().filter_map(ResourceIdentifier::key).collect();
+        assert_eq!(
+            keys,
+            [
+                format!("{}|OLD-1", MRN),
+                "http://example.org/ssn|123-45-6789".to_string(),
+                format!("{}|MRN-2", MRN),
+            ]
+        );
+        assert!(ResourceIdentifier::all_from_resource(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_primary_prefers_official_mrn() {
+        let ids = ResourceIdentifier::all_from_resource(&patient());
+        let now = "2026-10-16T12:00:00Z";
+        let primary_id = primary(&ids, &[], now).unwrap();
+        assert_eq!(primary_id.value.as_deref(), Some("MRN-2"));
+        assert_eq!(primary_id.type_code(), Some("MR"));
+
+        let ssn = primary(&ids, &["http://example.org/ssn"], now).unwrap();
+        assert_eq!(ssn.value.as_deref(), Some("123-45-6789"));
+
+        // Before the official MRN's period the SSN is the only active one
+        let before = primary(&ids, &[], "2019-06-01T00:00:00Z").unwrap();
+        assert_eq!(before.value.as_deref(), Some("123-45-6789"));
+    }
+
+    #[test]
+    fn test_period_contains_partial_dates() {
+        let period = Period {
+            start: Some("2026-01".to_string()),
+            end: Some("2026".to_string()),
+        };
+        assert!(period.contains("2026-01-01T00:00:00Z"));
+        assert!(period.contains("2026-12-31T23:59:59Z"));
+        assert!(!period.contains("2025-12-31T23:59:59Z"));
+        assert!(!period.contains("2027-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_matches_token() {
+        let id = ResourceIdentifier::new(MRN, "MRN-2");
+        assert!(id.matches(&TokenParam::parse(&format!("{}|MRN-2", MRN)).unwrap()));
+        assert!(id.matches(&TokenParam::parse("MRN-2").unwrap()));
+        assert!(!id.matches(&TokenParam::parse("|MRN-2").unwrap()));
+        assert!(!id.matches(&TokenParam::parse("http://other.example|MRN-2").unwrap()));
+    }
+
+    #[test]
+    fn test_to_v2_cx() {
+        let ids = ResourceIdentifier::all_from_resource(&patient());
+        assert_eq!(
+            ids[2].to_v2_cx().as_deref(),
+            Some("MRN-2^^^General Hospital&http://hospital.example/mrn&URI^MR")
+        );
+        assert_eq!(
+            ResourceIdentifier {
+                value: Some("A|B^C".to_string()),
+                ..ResourceIdentifier::default()
+            }
+            .to_v2_cx()
+            .as_deref(),
+            Some("A\\F\\B\\S\\C")
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! JSON Patch (RFC 6902)
+//!
+//! A patch is an array of `add`, `remove`, `replace`, `move`, `copy` and
+//! `test` operations addressed by JSON Pointers (RFC 6901). [`apply`] is
+//! all-or-nothing: it works on a copy, so a failing operation leaves the
+//! original document untouched. Malformed or inapplicable operations are
+//! [`FhirError::Invalid`]; a failed `test` is [`FhirError::Conflict`], since
+//! the document no longer is what the client expected.
+
+use serde_json::Value;
+
+use crate::error::FhirError;
+
+/// Apply `patch` to `doc`, returning the patched copy
+pub fn apply(doc: &Value, patch: &Value) -> Result<Value, FhirError> {
+    let operations = patch.as_array().ok_or_else(|| {
+        FhirError::Invalid("A JSON Patch must be an array of operations".to_string())
+    })?;
+
+    let mut doc = doc.clone();
+    for (index, operation) in operations.iter().enumerate() {
+        apply_operation(&mut doc, operation).map_err(|err| match err {
+            FhirError::Invalid(msg) => {
+                FhirError::Invalid(format!("JSON Patch operation {}: {}", index, msg))
+            }
+            other => other,
+        })?;
+    }
+    Ok(doc)
+}
+
+fn apply_operation(doc: &mut Value, operation: &Value) -> Result<(), FhirError> {
+    let member = |name: &str| {
+        operation
+            .get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| FhirError::Invalid(format!("\"{}\" must be a string", name)))
+    };
+    let value = || {
+        operation
+            .get("value")
+            .cloned()
+            .ok_or_else(|| FhirError::Invalid("\"value\" is required".to_string()))
+    };
+    let path = member("path")?;
+
+    match member("op")? {
+        "add" => add(doc, path, value()?),
+        "remove" => remove(doc, path).map(drop),
+        "replace" => {
+            let target = doc.pointer_mut(path).ok_or_else(|| missing(path))?;
+            *target = value()?;
+            Ok(())
+        }
+        "move" => {
+            let from = member("from")?;
+            if path.starts_with(from) && path[from.len()..].starts_with('/') {
+                return Err(FhirError::Invalid(format!(
+                    "cannot move {} into its own child {}",
+                    from, path
+                )));
+            }
+            let moved = remove(doc, from)?;
+            add(doc, path, moved)
+        }
+        "copy" => {
+            let from = member("from")?;
+            let copied = doc.pointer(from).cloned().ok_or_else(|| missing(from))?;
+            add(doc, path, copied)
+        }
+        "test" => match doc.pointer(path) {
+            Some(actual) if *actual == value()? => Ok(()),
+            _ => Err(FhirError::Conflict(format!(
+                "JSON Patch test failed: {} does not have the expected value",
+                path
+            ))),
+        },
+        other => Err(FhirError::Invalid(format!("unknown op \"{}\"", other))),
+    }
+}
+
+fn missing(path: &str) -> FhirError {
+    FhirError::Invalid(format!("no value at {}", path))
+}
+
+/// The parent pointer and unescaped last token of a non-root pointer
+fn split_pointer(path: &str) -> Result<(&str, String), FhirError> {
+    match path.rsplit_once('/') {
+        Some((parent, last)) if path.starts_with('/') => {
+            Ok((parent, last.replace("~1", "/").replace("~0", "~")))
+        }
+        _ => Err(FhirError::Invalid(format!(
+            "\"{}\" is not a JSON Pointer to a member",
+            path
+        ))),
+    }
+}
+
+/// Array position from a pointer token: digits without leading zeros, at
+/// most `len`
+fn array_index(token: &str, len: usize) -> Result<usize, FhirError> {
+    let valid = !token.is_empty()
+        && token.chars().all(|c| c.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    token
+        .parse()
+        .ok()
+        .filter(|i| valid && *i <= len)
+        .ok_or_else(|| FhirError::Invalid(format!("array index {} is out of bounds", token)))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), FhirError> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, last) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(last, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = match last.as_str() {
+                "-" => items.len(),
+                token => array_index(token, items.len())?,
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        Some(_) => Err(FhirError::Invalid(format!(
+            "{} is not an object or array",
+            parent
+        ))),
+        None => Err(missing(parent)),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, FhirError> {
+    let (parent, last) = split_pointer(path)?;
+    let removed = match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(&last),
+        Some(Value::Array(items)) => match array_index(&last, items.len())? {
+            index if index < items.len() => Some(items.remove(index)),
+            _ => None,
+        },
+        _ => None,
+    };
+    removed.ok_or_else(|| missing(path))
+}
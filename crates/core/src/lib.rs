@@ -3,17 +3,48 @@
 //! This crate provides common types used across the FHIR server,
 //! including Patient, Bundle, OperationOutcome, and CapabilityStatement.
 
+pub mod attachment;
 pub mod bundle;
 pub mod capability;
+pub mod choice;
+pub mod coding;
+pub mod concept_map;
+pub mod convert;
+pub mod deid;
 pub mod error;
+pub mod extension;
+pub mod identifier;
+pub mod json_patch;
+pub mod name;
+pub mod naming_system;
+pub mod narrative;
 pub mod outcome;
+pub mod package;
+pub mod profile;
+pub mod quantity;
+pub mod rdf;
+pub mod resource_type;
+pub mod snapshot;
+pub mod version;
+pub mod xml;
 
 // Re-export fhir-sdk types
 pub use fhir_sdk::r4b::resources::Patient;
 pub use fhir_sdk::r4b::types::{HumanName, Identifier};
 
 // Re-export our types
-pub use bundle::{Bundle, BundleEntry, BundleLink, BundleType};
+pub use bundle::{
+    Bundle, BundleEntry, BundleEntryRequest, BundleEntryResponse, BundleEntrySearch, BundleLink,
+    BundleType, HttpVerb, SearchEntryMode,
+};
 pub use capability::CapabilityStatement;
+pub use coding::{CodeableConcept, Coding, TokenParam, TokenSystem};
+pub use deid::DeidProfile;
 pub use error::FhirError;
+pub use identifier::{IdentifierUse, ResourceIdentifier};
+pub use name::NormalizedName;
 pub use outcome::{IssueSeverity, IssueType, OperationOutcome, OperationOutcomeIssue};
+pub use package::{PackageInfo, PackageRegistry, SearchParameterDef};
+pub use profile::{Profile, ProfileRegistry};
+pub use quantity::{Quantity, QuantityParam, UcumUnit};
+pub use version::FhirVersion;
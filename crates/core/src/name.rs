@@ -0,0 +1,297 @@
+//! HumanName normalization and matching
+//!
+//! One definition of "the same name" for duplicate detection, `$match` and
+//! fuzzy search: names are case-folded, stripped of diacritics and
+//! punctuation, given names match through a nickname table (`Bill` ~
+//! `William`) or an initial (`J` ~ `John`), and the remaining differences are
+//! scored by edit distance.
+
+use serde_json::Value;
+
+/// Groups of given names that refer to the same person; the first entry is
+/// the canonical form
+const NICKNAMES: &[&[&str]] = &[
+    &["abigail", "abby", "abbie", "gail"],
+    &["albert", "al", "bert", "bertie"],
+    &["alexander", "alex", "alec", "sandy", "xander"],
+    &["alexandra", "alex", "sandra", "sasha", "lexi"],
+    &["andrew", "andy", "drew"],
+    &["anthony", "tony"],
+    &["barbara", "barb", "babs"],
+    &["benjamin", "ben", "benny", "benji"],
+    &[
+        "catherine",
+        "katherine",
+        "kathryn",
+        "kate",
+        "katie",
+        "kathy",
+        "cathy",
+        "kat",
+        "kay",
+    ],
+    &["charles", "charlie", "chuck", "chas"],
+    &["christopher", "chris", "kit"],
+    &["christine", "christina", "chris", "chrissy", "tina"],
+    &["daniel", "dan", "danny"],
+    &["david", "dave", "davey"],
+    &["deborah", "debra", "deb", "debbie"],
+    &["donald", "don", "donnie"],
+    &["dorothy", "dot", "dottie", "dolly"],
+    &["edward", "ed", "eddie", "ted", "ned"],
+    &["eleanor", "ellie", "nora", "nell"],
+    &[
+        "elizabeth",
+        "liz",
+        "lizzie",
+        "beth",
+        "betty",
+        "betsy",
+        "eliza",
+        "libby",
+    ],
+    &["frances", "fran", "frannie"],
+    &["francis", "frank", "frankie"],
+    &["gerald", "gerry", "jerry"],
+    &["gregory", "greg"],
+    &["harold", "harry", "hal"],
+    &["henry", "hank", "harry"],
+    &["james", "jim", "jimmy", "jamie"],
+    &["jennifer", "jen", "jenny"],
+    &["john", "jack", "johnny", "jon"],
+    &["jonathan", "jon", "jonny"],
+    &["joseph", "joe", "joey"],
+    &["josephine", "jo", "josie"],
+    &["kenneth", "ken", "kenny"],
+    &["lawrence", "laurence", "larry"],
+    &["margaret", "maggie", "meg", "peggy", "marge", "greta"],
+    &["matthew", "matt"],
+    &["michael", "mike", "mikey", "mick"],
+    &["nicholas", "nick", "nicky"],
+    &["patricia", "pat", "patty", "trish"],
+    &["patrick", "pat", "paddy"],
+    &["peter", "pete"],
+    &["raymond", "ray"],
+    &["rebecca", "becky", "becca"],
+    &["richard", "dick", "rick", "rich", "ricky"],
+    &["robert", "bob", "bobby", "rob", "robbie", "bert"],
+    &["ronald", "ron", "ronnie"],
+    &["samantha", "sam", "sammy"],
+    &["samuel", "sam", "sammy"],
+    &["stephen", "steven", "steve"],
+    &["susan", "sue", "susie"],
+    &["theodore", "theo", "ted", "teddy"],
+    &["thomas", "tom", "tommy"],
+    &["timothy", "tim", "timmy"],
+    &["victoria", "vicky", "tori"],
+    &["william", "bill", "billy", "will", "willy", "liam"],
+];
+
+/// ASCII replacement for a letter with diacritics or a ligature
+fn fold_char(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    })
+}
+
+/// Case-fold, strip diacritics and turn punctuation into word breaks:
+/// `"  Zoë O'Brien-Núñez "` → `"zoe obrien nunez"`
+///
+/// Apostrophes join (`O'Brien` → `obrien`); hyphens, dots and spaces
+/// separate words.
+pub fn normalize(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars().flat_map(char::to_lowercase) {
+        if let Some(folded) = fold_char(c) {
+            out.push_str(folded);
+        } else if c.is_alphanumeric() {
+            out.push(c);
+        } else if matches!(c, '\'' | '’' | '`') {
+            continue;
+        } else if !out.ends_with(' ') && !out.is_empty() {
+            out.push(' ');
+        }
+    }
+    out.trim_end().to_string()
+}
+
+/// Canonical form of a normalized given name (`bill` → `william`); names
+/// without a nickname entry are returned as they are
+///
+/// Names in several groups (`sam`, `pat`) resolve to the first group.
+pub fn canonical_given(name: &str) -> &str {
+    NICKNAMES
+        .iter()
+        .find(|group| group.contains(&name))
+        .map_or(name, |group| group[0])
+}
+
+/// How two given names relate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GivenMatch {
+    Different,
+    /// One is the other's initial
+    Initial,
+    /// Nicknames of the same name
+    Nickname,
+    Exact,
+}
+
+/// Compare two normalized given names
+pub fn compare_given(a: &str, b: &str) -> GivenMatch {
+    if a.is_empty() || b.is_empty() {
+        return GivenMatch::Different;
+    }
+    if a == b {
+        return GivenMatch::Exact;
+    }
+    let shares_group = NICKNAMES
+        .iter()
+        .any(|group| group.contains(&a) && group.contains(&b));
+    if shares_group {
+        return GivenMatch::Nickname;
+    }
+    let is_initial_of =
+        |initial: &str, name: &str| initial.chars().count() == 1 && name.starts_with(initial);
+    if is_initial_of(a, b) || is_initial_of(b, a) {
+        return GivenMatch::Initial;
+    }
+    GivenMatch::Different
+}
+
+/// Similarity of two strings from 0 (nothing in common) to 1 (equal), by
+/// Levenshtein distance relative to the longer string
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+/// A HumanName reduced to normalized family and given name words
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NormalizedName {
+    /// Family name words (`Smith-Jones` → `smith jones`)
+    pub family: String,
+    /// Given names in order, one word each
+    pub given: Vec<String>,
+}
+
+impl NormalizedName {
+    pub fn new(family: &str, given: &[&str]) -> Self {
+        Self {
+            family: normalize(family),
+            given: given
+                .iter()
+                .flat_map(|g| {
+                    normalize(g)
+                        .split(' ')
+                        .map(str::to_string)
+                        .collect::<Vec<_>>()
+                })
+                .filter(|g| !g.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Read a HumanName; `text` (`"Given Middle Family"`) is used when family
+    /// and given are absent
+    pub fn from_json(name: &Value) -> Option<Self> {
+        let family = name.get("family").and_then(Value::as_str);
+        let given: Vec<&str> = name
+            .get("given")
+            .and_then(Value::as_array)
+            .map(|g| g.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        if family.is_some() || !given.is_empty() {
+            return Some(Self::new(family.unwrap_or_default(), &given));
+        }
+
+        let text = normalize(name.get("text")?.as_str()?);
+        let mut words: Vec<&str> = text.split(' ').filter(|w| !w.is_empty()).collect();
+        let family = words.pop()?;
+        Some(Self::new(family, &words))
+    }
+
+    /// Names of a resource's `name` array that can be read
+    pub fn all_from_resource(resource: &Value) -> Vec<Self> {
+        resource
+            .get("name")
+            .and_then(Value::as_array)
+            .map(|names| names.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    /// Key that is equal for names that are very likely the same: family
+    /// name without spaces plus the canonical first given name
+    pub fn blocking_key(&self) -> String {
+        let given = self.given.first().map_or("", |g| canonical_given(g));
+        format!("{}|{}", self.family.replace(' ', ""), given)
+    }
+
+    /// Match score from 0 to 1: family name similarity weighted 0.6, first
+    /// given name 0.4 (exact or nickname 1, initial 0.7, otherwise edit
+    /// similarity)
+    pub fn score(&self, other: &NormalizedName) -> f64 {
+        let family = similarity(
+            &self.family.replace(' ', ""),
+            &other.family.replace(' ', ""),
+        );
+        let given = match (self.given.first(), other.given.first()) {
+            (Some(a), Some(b)) => match compare_given(a, b) {
+                GivenMatch::Exact | GivenMatch::Nickname => 1.0,
+                GivenMatch::Initial => 0.7,
+                GivenMatch::Different => similarity(canonical_given(a), canonical_given(b)),
+            },
+            // A missing given name neither confirms nor contradicts
+            _ => 0.5,
+        };
+        0.6 * family + 0.4 * given
+    }
+
+    /// Whether the names are the same person's name: the same family name
+    /// and first given names that are equal, nicknames or an initial
+    pub fn same_name(&self, other: &NormalizedName) -> bool {
+        self.family.replace(' ', "") == other.family.replace(' ', "")
+            && match (self.given.first(), other.given.first()) {
+                (Some(a), Some(b)) => compare_given(a, b) != GivenMatch::Different,
+                _ => true,
+            }
+    }
+}
@@ -0,0 +1,97 @@
+//! NamingSystem resolution (OID ↔ URI)
+//!
+//! A NamingSystem lists the `uniqueId`s one identifier or code system is
+//! known by, e.g. an OID and a URI. Resolution finds the NamingSystem that
+//! has a given id and returns its preferred id of the wanted type, so an
+//! importer receiving `2.16.840.1.113883.4.1` can emit
+//! `http://hl7.org/fhir/sid/us-ssn` as `identifier.system`.
+
+use serde_json::Value;
+
+/// Prefix of an OID written as a URI
+const OID_URN: &str = "urn:oid:";
+
+/// One `uniqueId` of a NamingSystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UniqueId {
+    /// `oid`, `uuid`, `uri` or `other`
+    pub kind: String,
+    pub value: String,
+    pub preferred: bool,
+}
+
+/// Whether `value` is a bare OID (`1.2.840...`)
+pub fn is_oid(value: &str) -> bool {
+    !value.is_empty()
+        && value.split('.').count() > 1
+        && value
+            .split('.')
+            .all(|arc| !arc.is_empty() && arc.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// `value` with a `urn:oid:` prefix removed, for comparing OIDs
+fn normalize(value: &str) -> &str {
+    value.strip_prefix(OID_URN).unwrap_or(value)
+}
+
+/// The unique ids of a NamingSystem
+pub fn unique_ids(naming_system: &Value) -> Vec<UniqueId> {
+    naming_system
+        .get("uniqueId")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|id| {
+            Some(UniqueId {
+                kind: id.get("type")?.as_str()?.to_string(),
+                value: id.get("value")?.as_str()?.to_string(),
+                preferred: id.get("preferred").and_then(Value::as_bool) == Some(true),
+            })
+        })
+        .collect()
+}
+
+/// Whether the NamingSystem is known by `value` (OIDs match with or without
+/// `urn:oid:`)
+pub fn identifies(naming_system: &Value, value: &str) -> bool {
+    let value = normalize(value);
+    unique_ids(naming_system)
+        .iter()
+        .any(|id| normalize(&id.value) == value)
+}
+
+/// The NamingSystem's id of type `kind`, preferring one marked `preferred`
+pub fn preferred_id(naming_system: &Value, kind: &str) -> Option<String> {
+    let ids = unique_ids(naming_system);
+    let mut of_kind = ids.iter().filter(|id| id.kind == kind);
+    let first = of_kind.clone().next();
+    of_kind
+        .find(|id| id.preferred)
+        .or(first)
+        .map(|id| id.value.clone())
+}
+
+/// The `kind` id of the system known by `value`, among `naming_systems`
+///
+/// Retired NamingSystems are skipped.
+pub fn resolve(naming_systems: &[Value], value: &str, kind: &str) -> Option<String> {
+    naming_systems
+        .iter()
+        .filter(|ns| ns.get("status").and_then(Value::as_str) != Some("retired"))
+        .find(|ns| identifies(ns, value))
+        .and_then(|ns| preferred_id(ns, kind))
+}
+
+/// The URI to use as `system` for a system known by `value`
+///
+/// Uses the registered URI when there is one; otherwise a bare OID becomes
+/// `urn:oid:<oid>` and anything else is returned as given.
+pub fn system_uri(naming_systems: &[Value], value: &str) -> String {
+    if let Some(uri) = resolve(naming_systems, value, "uri") {
+        return uri;
+    }
+    match is_oid(value) {
+        true => format!("{}{}", OID_URN, value),
+        false => value.to_string(),
+    }
+}
@@ -0,0 +1,435 @@
+//! XHTML narrative (`text.div`) validation and sanitization
+//!
+//! Narratives are restricted to the FHIR XHTML subset: basic formatting,
+//! lists, tables, links and images, with no scripts, forms, event handlers or
+//! externally loaded content.
+
+/// Elements allowed in FHIR narrative XHTML
+const ALLOWED_ELEMENTS: &[&str] = &[
+    "a",
+    "abbr",
+    "acronym",
+    "b",
+    "big",
+    "blockquote",
+    "br",
+    "caption",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "dd",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "samp",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "tt",
+    "ul",
+    "var",
+];
+
+/// Disallowed elements whose content is dropped along with the tags
+const DROP_CONTENT_ELEMENTS: &[&str] = &[
+    "script", "style", "iframe", "object", "embed", "applet", "form", "head", "title", "noscript",
+    "template", "svg", "math",
+];
+
+/// Attributes allowed on any element
+const ALLOWED_ATTRIBUTES: &[&str] = &[
+    "xmlns",
+    "id",
+    "class",
+    "style",
+    "title",
+    "lang",
+    "xml:lang",
+    "dir",
+    "href",
+    "name",
+    "src",
+    "alt",
+    "width",
+    "height",
+    "colspan",
+    "rowspan",
+    "span",
+    "align",
+    "valign",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "summary",
+    "scope",
+    "headers",
+    "abbr",
+    "axis",
+    "char",
+    "charoff",
+    "start",
+    "type",
+    "value",
+    "cite",
+];
+
+/// URL schemes links may use; scheme-less (relative) references are allowed
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Result of checking a narrative
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarrativeCheck {
+    /// The narrative with every violation removed
+    pub sanitized: String,
+    /// Human-readable description of each violation found
+    pub violations: Vec<String>,
+}
+
+impl NarrativeCheck {
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Check a `text.div` value against the FHIR XHTML subset
+pub fn check_div(div: &str) -> NarrativeCheck {
+    let mut out = String::with_capacity(div.len());
+    let mut violations = Vec::new();
+    let mut rest = div;
+
+    let trimmed = div.trim_start();
+    if !trimmed.starts_with("<div") {
+        violations.push("Narrative must be a single <div> element".to_string());
+    }
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // Comments are dropped; doctypes and processing instructions are violations
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map(|end| &after[end + 3..]).unwrap_or("");
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            violations.push("Declarations and processing instructions are not allowed".into());
+            rest = rest.find('>').map(|end| &rest[end + 1..]).unwrap_or("");
+            continue;
+        }
+
+        let Some(end) = find_tag_end(rest) else {
+            violations.push("Unterminated tag".to_string());
+            rest = "";
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let body = tag.trim_start_matches('/').trim_end_matches('/');
+        let self_closing = tag.ends_with('/');
+        let name_len = body.find(|c: char| c.is_whitespace()).unwrap_or(body.len());
+        let name = body[..name_len].to_ascii_lowercase();
+
+        if !ALLOWED_ELEMENTS.contains(&name.as_str()) {
+            violations.push(format!("Element <{}> is not allowed", name));
+            if !closing && !self_closing && DROP_CONTENT_ELEMENTS.contains(&name.as_str()) {
+                rest = skip_element(rest, &name);
+            }
+            continue;
+        }
+
+        if closing {
+            out.push_str(&format!("</{}>", name));
+            continue;
+        }
+
+        out.push('<');
+        out.push_str(&name);
+        for (attr, value) in parse_attributes(&body[name_len..]) {
+            match attribute_violation(&name, &attr, value.as_deref()) {
+                Some(violation) => violations.push(violation),
+                None => {
+                    out.push(' ');
+                    out.push_str(&attr);
+                    if let Some(value) = value {
+                        out.push_str(&format!("=\"{}\"", escape_attr(&value)));
+                    }
+                }
+            }
+        }
+        out.push_str(if self_closing { "/>" } else { ">" });
+    }
+    out.push_str(rest);
+
+    NarrativeCheck {
+        sanitized: out,
+        violations,
+    }
+}
+
+/// Describe why an attribute is not allowed, or `None` if it is fine
+///
+/// URLs and styles are checked as a browser would read them: with character
+/// references decoded and whitespace and control characters removed, so
+/// `jav&#x09;ascript:` or `&#106;avascript:` cannot slip through.
+fn attribute_violation(element: &str, attr: &str, value: Option<&str>) -> Option<String> {
+    let value = value.unwrap_or("");
+
+    if attr.starts_with("on") {
+        return Some(format!("Event handler attribute '{}' is not allowed", attr));
+    }
+    if !ALLOWED_ATTRIBUTES.contains(&attr) {
+        return Some(format!(
+            "Attribute '{}' is not allowed on <{}>",
+            attr, element
+        ));
+    }
+    if attr == "href" || attr == "src" {
+        let url = normalize(&decode_entities(value));
+        if let Some(scheme) = url_scheme(&url) {
+            let data_image = attr == "src" && url.starts_with("data:image/");
+            if !ALLOWED_SCHEMES.contains(&scheme) && !data_image {
+                return Some(format!(
+                    "URL scheme '{}:' in '{}' is not allowed",
+                    scheme, attr
+                ));
+            }
+        }
+        // Images may only reference contained content, never external URLs
+        if attr == "src" && !(url.starts_with('#') || url.starts_with("data:image/")) {
+            return Some(format!("External image source '{}' is not allowed", value));
+        }
+    }
+    if attr == "style" {
+        let style = normalize(&strip_css_comments(&decode_css_escapes(&decode_entities(
+            value,
+        ))));
+        if style.contains("url(") || style.contains("expression(") || style.contains("javascript:")
+        {
+            return Some("Style attribute must not load external content".to_string());
+        }
+    }
+    None
+}
+
+/// The lowercase scheme of a normalized URL, or `None` for a relative
+/// reference (including `#fragment`)
+fn url_scheme(url: &str) -> Option<&str> {
+    let colon = url.find(':')?;
+    // A colon after the path, query or fragment has started is not a scheme
+    if url[..colon].contains(['/', '?', '#']) {
+        return None;
+    }
+    Some(&url[..colon])
+}
+
+/// Lowercase `s` without whitespace and control characters, which browsers
+/// ignore inside URL schemes and CSS keywords
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Decode XML/HTML character references (`&#106;`, `&#x6A;`, `&colon;`, ...)
+///
+/// As in browsers, numeric references need not end with `;`. Unknown named
+/// references are kept as written.
+fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp + 1..];
+
+        if let Some(number) = rest.strip_prefix('#') {
+            let (digits, radix) = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16),
+                None => (number, 10),
+            };
+            let len = digits
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(digits.len());
+            if len > 0 {
+                let decoded = u32::from_str_radix(&digits[..len], radix)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                out.push(decoded);
+                rest = &digits[len..];
+                rest = rest.strip_prefix(';').unwrap_or(rest);
+                continue;
+            }
+        } else if let Some(semi) = rest.find(';').filter(|&semi| semi <= 8) {
+            let decoded = match &rest[..semi] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "colon" => Some(':'),
+                "sol" => Some('/'),
+                "lpar" => Some('('),
+                "rpar" => Some(')'),
+                "bsol" => Some('\\'),
+                "Tab" => Some('\t'),
+                "NewLine" => Some('\n'),
+                _ => None,
+            };
+            if let Some(decoded) = decoded {
+                out.push(decoded);
+                rest = &rest[semi + 1..];
+                continue;
+            }
+        }
+        out.push('&');
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decode CSS escapes (`\75`, `\000075 `, `\u`)
+fn decode_css_escapes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let mut hex = String::new();
+        while hex.len() < 6 && chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            hex.extend(chars.next());
+        }
+        if hex.is_empty() {
+            out.extend(chars.next());
+            continue;
+        }
+        // One whitespace character may terminate a hex escape
+        if chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        out.push(
+            u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER),
+        );
+    }
+    out
+}
+
+/// Remove CSS comments, which may split a keyword (`ur/**/l(`)
+fn strip_css_comments(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = rest[start + 2..]
+            .find("*/")
+            .map(|end| &rest[start + 2 + end + 2..])
+            .unwrap_or("");
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Find the `>` closing a tag, ignoring any inside quoted attribute values
+fn find_tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Skip past the closing tag of `name` (or to the end if it is never closed)
+fn skip_element<'a>(s: &'a str, name: &str) -> &'a str {
+    let closing = format!("</{}", name);
+    let lowered = s.to_ascii_lowercase();
+    match lowered.find(&closing) {
+        Some(pos) => {
+            let after = &s[pos..];
+            after.find('>').map(|end| &after[end + 1..]).unwrap_or("")
+        }
+        None => "",
+    }
+}
+
+/// Parse `name="value"`, `name='value'` and bare `name` attributes
+fn parse_attributes(s: &str) -> Vec<(String, Option<String>)> {
+    let mut attrs = Vec::new();
+    let mut rest = s.trim_start();
+
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let value = if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            match after_eq.chars().next() {
+                Some(q @ ('"' | '\'')) => {
+                    let inner = &after_eq[1..];
+                    let end = inner.find(q).unwrap_or(inner.len());
+                    rest = inner.get(end + 1..).unwrap_or("");
+                    Some(inner[..end].to_string())
+                }
+                _ => {
+                    let end = after_eq.find(char::is_whitespace).unwrap_or(after_eq.len());
+                    rest = &after_eq[end..];
+                    Some(after_eq[..end].to_string())
+                }
+            }
+        } else {
+            None
+        };
+
+        if !name.is_empty() {
+            attrs.push((name, value));
+        }
+        rest = rest.trim_start();
+    }
+
+    attrs
+}
+
+/// Escape an attribute value for double quotes (values are already XML-escaped)
+fn escape_attr(s: &str) -> String {
+    s.replace('"', "&quot;").replace('<', "&lt;")
+}
@@ -86,6 +86,26 @@ impl OperationOutcome {
         Self::error(IssueType::Conflict, message)
     }
 
+    /// Create an outcome from a list of issues
+    pub fn from_issues(issue: Vec<OperationOutcomeIssue>) -> Self {
+        Self {
+            resource_type: "OperationOutcome".to_string(),
+            issue,
+        }
+    }
+
+    /// Serialize as FHIR XML
+    pub fn to_xml(&self) -> String {
+        crate::xml::to_xml(&serde_json::to_value(self).expect("OperationOutcome always serializes"))
+    }
+
+    /// Whether any issue is an error or fatal
+    pub fn has_errors(&self) -> bool {
+        self.issue
+            .iter()
+            .any(|i| matches!(i.severity, IssueSeverity::Error | IssueSeverity::Fatal))
+    }
+
     /// Create a successful validation outcome
     pub fn success(message: &str) -> Self {
         Self {
@@ -113,3 +133,25 @@ pub struct OperationOutcomeIssue {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub location: Vec<String>,
 }
+
+impl OperationOutcomeIssue {
+    /// Create an error issue
+    pub fn error(code: IssueType, diagnostics: &str) -> Self {
+        Self {
+            severity: IssueSeverity::Error,
+            code,
+            diagnostics: Some(diagnostics.to_string()),
+            location: Vec::new(),
+        }
+    }
+
+    /// Create a warning issue
+    pub fn warning(code: IssueType, diagnostics: &str) -> Self {
+        Self {
+            severity: IssueSeverity::Warning,
+            code,
+            diagnostics: Some(diagnostics.to_string()),
+            location: Vec::new(),
+        }
+    }
+}
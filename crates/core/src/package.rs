@@ -0,0 +1,378 @@
+//! Implementation guide (FHIR NPM) packages
+//!
+//! A package contributes StructureDefinitions and ValueSets (profile
+//! validation), SearchParameters (extra search parameters) and
+//! CapabilityStatements (conformance claims). Packages are read from `.tgz`
+//! tarballs or unpacked directories; the US Core Patient package and the
+//! Patient and Extension base definitions ship with the crate.
+
+use std::io::Read;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::error::FhirError;
+use crate::profile::ProfileRegistry;
+use crate::snapshot;
+
+/// Bundled US Core resources (`package/` contents)
+const US_CORE: &[(&str, &str)] = &[
+    (
+        "package.json",
+        include_str!("../packages/hl7.fhir.us.core/package.json"),
+    ),
+    (
+        "StructureDefinition-us-core-patient.json",
+        include_str!("../packages/hl7.fhir.us.core/StructureDefinition-us-core-patient.json"),
+    ),
+    (
+        "StructureDefinition-us-core-race.json",
+        include_str!("../packages/hl7.fhir.us.core/StructureDefinition-us-core-race.json"),
+    ),
+    (
+        "StructureDefinition-us-core-ethnicity.json",
+        include_str!("../packages/hl7.fhir.us.core/StructureDefinition-us-core-ethnicity.json"),
+    ),
+    (
+        "StructureDefinition-us-core-birthsex.json",
+        include_str!("../packages/hl7.fhir.us.core/StructureDefinition-us-core-birthsex.json"),
+    ),
+    (
+        "ValueSet-omb-race-category.json",
+        include_str!("../packages/hl7.fhir.us.core/ValueSet-omb-race-category.json"),
+    ),
+    (
+        "ValueSet-omb-ethnicity-category.json",
+        include_str!("../packages/hl7.fhir.us.core/ValueSet-omb-ethnicity-category.json"),
+    ),
+    (
+        "ValueSet-birthsex.json",
+        include_str!("../packages/hl7.fhir.us.core/ValueSet-birthsex.json"),
+    ),
+];
+
+/// Bundled base definitions the US Core profiles are built on
+const R4_CORE: &[(&str, &str)] = &[
+    (
+        "StructureDefinition-Patient.json",
+        include_str!("../packages/hl7.fhir.r4.core/StructureDefinition-Patient.json"),
+    ),
+    (
+        "StructureDefinition-Extension.json",
+        include_str!("../packages/hl7.fhir.r4.core/StructureDefinition-Extension.json"),
+    ),
+];
+
+/// Canonical URL prefix of the core resource and data type definitions
+const CORE_DEFINITION_BASE: &str = "http://hl7.org/fhir/StructureDefinition/";
+
+/// Longest chain of differential-only base definitions resolved for a snapshot
+const MAX_BASE_DEPTH: usize = 8;
+
+/// Search parameter types that can be evaluated over a plain element path
+const PATH_SEARCH_TYPES: &[&str] = &["string", "token", "date"];
+
+/// Summary of a loaded package
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    /// Canonical base URL of the implementation guide
+    pub canonical: Option<String>,
+    /// Conformance resources added from the package
+    pub resources: usize,
+}
+
+impl PackageInfo {
+    /// Canonical URL of the package's ImplementationGuide resource
+    pub fn guide_url(&self) -> Option<String> {
+        self.canonical
+            .as_ref()
+            .map(|c| format!("{}/ImplementationGuide/{}", c, self.name))
+    }
+}
+
+/// A SearchParameter definition from a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchParameterDef {
+    pub url: String,
+    pub code: String,
+    pub base: Vec<String>,
+    pub param_type: String,
+    pub expression: Option<String>,
+}
+
+impl SearchParameterDef {
+    fn parse(resource: &Value) -> Option<Self> {
+        let field = |key: &str| {
+            resource
+                .get(key)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        Some(Self {
+            url: field("url")?,
+            code: field("code")?,
+            base: resource
+                .get("base")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|b| b.as_str().map(str::to_string))
+                .collect(),
+            param_type: field("type")?,
+            expression: field("expression"),
+        })
+    }
+
+    /// The dotted element path searched on `resource_type`, if the
+    /// expression is a plain path (e.g. `Patient.address.city`) of a
+    /// supported type; choice elements are marked `[x]` (`deceased[x]`)
+    pub fn element_path(&self, resource_type: &str) -> Option<String> {
+        if !PATH_SEARCH_TYPES.contains(&self.param_type.as_str()) {
+            return None;
+        }
+        let prefix = format!("{}.", resource_type);
+        self.expression
+            .as_deref()?
+            .split('|')
+            .map(str::trim)
+            .find_map(|e| e.strip_prefix(&prefix))
+            .filter(|path| {
+                path.split('.').all(|segment| {
+                    segment
+                        .chars()
+                        .next()
+                        .is_some_and(|c| c.is_ascii_alphabetic())
+                        && segment.chars().all(|c| c.is_ascii_alphanumeric())
+                })
+            })
+            .map(|path| crate::choice::search_path(resource_type, path))
+    }
+}
+
+/// Conformance resources from every loaded package
+#[derive(Debug, Clone, Default)]
+pub struct PackageRegistry {
+    profiles: ProfileRegistry,
+    structure_definitions: Vec<Value>,
+    search_params: Vec<SearchParameterDef>,
+    capability_statements: Vec<Value>,
+    packages: Vec<PackageInfo>,
+}
+
+impl PackageRegistry {
+    /// A registry holding the bundled US Core package and the base
+    /// definitions it constrains
+    pub fn with_us_core() -> Self {
+        let mut registry = Self::default();
+        for (name, contents) in R4_CORE {
+            registry
+                .add_file(name, contents, &mut PackageInfo::default())
+                .expect("bundled base definitions are valid");
+        }
+        let mut info = PackageInfo::default();
+        for (name, contents) in US_CORE {
+            registry
+                .add_file(name, contents, &mut info)
+                .expect("bundled US Core package is valid");
+        }
+        registry.packages.push(info);
+        registry
+    }
+
+    pub fn profiles(&self) -> &ProfileRegistry {
+        &self.profiles
+    }
+
+    pub fn search_params(&self) -> &[SearchParameterDef] {
+        &self.search_params
+    }
+
+    pub fn capability_statements(&self) -> &[Value] {
+        &self.capability_statements
+    }
+
+    pub fn packages(&self) -> &[PackageInfo] {
+        &self.packages
+    }
+
+    /// Every loaded StructureDefinition, including base definitions
+    pub fn structure_definitions(&self) -> &[Value] {
+        &self.structure_definitions
+    }
+
+    /// Look up a StructureDefinition by canonical URL (`url|version` is also
+    /// accepted); without a version the most recently loaded one is returned
+    pub fn structure_definition(&self, url: &str, version: Option<&str>) -> Option<&Value> {
+        let (url, version) = match url.split_once('|') {
+            Some((url, version)) => (url, Some(version)),
+            None => (url, version),
+        };
+        self.structure_definitions.iter().rev().find(|sd| {
+            sd.get("url").and_then(Value::as_str) == Some(url)
+                && version.is_none_or(|v| sd.get("version").and_then(Value::as_str) == Some(v))
+        })
+    }
+
+    /// `sd` with its snapshot generated from the differential and the
+    /// snapshot of its base definition
+    pub fn snapshot(&self, sd: &Value) -> Result<Value, FhirError> {
+        let mut sd = sd.clone();
+        let elements = self.snapshot_elements(&sd, 0)?;
+        sd["snapshot"] = serde_json::json!({ "element": elements });
+        Ok(sd)
+    }
+
+    fn snapshot_elements(&self, sd: &Value, depth: usize) -> Result<Vec<Value>, FhirError> {
+        if depth > MAX_BASE_DEPTH {
+            return Err(FhirError::Invalid(
+                "Base definition chain is too deep or circular".to_string(),
+            ));
+        }
+        let base_url = sd
+            .get("baseDefinition")
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                FhirError::Invalid("StructureDefinition has no baseDefinition".to_string())
+            })?;
+        let base = self.structure_definition(base_url, None).ok_or_else(|| {
+            FhirError::NotFound(format!("Base definition {} is not loaded", base_url))
+        })?;
+        let base_elements = match base.pointer("/snapshot/element").and_then(Value::as_array) {
+            Some(elements) => elements.clone(),
+            None => self.snapshot_elements(base, depth + 1)?,
+        };
+
+        let types = |code: &str| {
+            self.structure_definition(&format!("{}{}", CORE_DEFINITION_BASE, code), None)
+                .and_then(|sd| sd.pointer("/snapshot/element"))
+                .and_then(Value::as_array)
+                .cloned()
+        };
+        snapshot::generate(sd, &base_elements, &types)
+    }
+
+    /// Add a conformance resource; unsupported resource types are ignored.
+    ///
+    /// Returns whether the resource was added.
+    pub fn add_resource(&mut self, resource: &Value) -> Result<bool, FhirError> {
+        match resource.get("resourceType").and_then(Value::as_str) {
+            Some("SearchParameter") => {
+                let param = SearchParameterDef::parse(resource).ok_or_else(|| {
+                    FhirError::Invalid("SearchParameter requires url, code and type".to_string())
+                })?;
+                self.search_params.retain(|p| p.url != param.url);
+                self.search_params.push(param);
+                Ok(true)
+            }
+            Some("CapabilityStatement") => {
+                self.capability_statements.push(resource.clone());
+                Ok(true)
+            }
+            Some("StructureDefinition") => {
+                self.profiles.add_resource(resource)?;
+                let (url, version) = (resource.get("url"), resource.get("version"));
+                self.structure_definitions
+                    .retain(|sd| sd.get("url") != url || sd.get("version") != version);
+                self.structure_definitions.push(resource.clone());
+                Ok(true)
+            }
+            _ => self.profiles.add_resource(resource),
+        }
+    }
+
+    /// Load a package from a `.tgz` file or an unpacked directory
+    pub fn load_path(&mut self, path: &Path) -> Result<PackageInfo, FhirError> {
+        let read_error = |e: std::io::Error| {
+            FhirError::Internal(format!("Failed to read {}: {}", path.display(), e))
+        };
+        let mut info = PackageInfo::default();
+
+        if path.is_dir() {
+            // Accept both the package root and its `package/` folder
+            let dir = if path.join("package").is_dir() {
+                path.join("package")
+            } else {
+                path.to_path_buf()
+            };
+            let mut files: Vec<_> = std::fs::read_dir(&dir)
+                .map_err(read_error)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            files.sort();
+            for file in files {
+                let contents = std::fs::read_to_string(&file).map_err(read_error)?;
+                let name = file.file_name().unwrap_or_default().to_string_lossy();
+                self.add_file(&name, &contents, &mut info)?;
+            }
+        } else {
+            let file = std::fs::File::open(path).map_err(read_error)?;
+            info = self.load_tgz(file).map_err(|e| match e {
+                FhirError::Internal(msg) => {
+                    FhirError::Internal(format!("{}: {}", path.display(), msg))
+                }
+                other => other,
+            })?;
+        }
+
+        if info.name.is_empty() {
+            return Err(FhirError::Invalid(format!(
+                "{} is not a FHIR package (no package.json)",
+                path.display()
+            )));
+        }
+        self.packages.push(info.clone());
+        Ok(info)
+    }
+
+    /// Read the top-level files of a gzipped package tarball
+    fn load_tgz(&mut self, reader: impl Read) -> Result<PackageInfo, FhirError> {
+        let read_error = |e: std::io::Error| FhirError::Internal(e.to_string());
+        let mut info = PackageInfo::default();
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+        for entry in archive.entries().map_err(read_error)? {
+            let mut entry = entry.map_err(read_error)?;
+            let entry_path = entry.path().map_err(read_error)?;
+            // Examples and docs live in subfolders of `package/`
+            let Some(name) = entry_path
+                .to_str()
+                .and_then(|p| p.strip_prefix("package/"))
+                .filter(|n| !n.contains('/') && n.ends_with(".json"))
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(read_error)?;
+            self.add_file(&name, &contents, &mut info)?;
+        }
+        Ok(info)
+    }
+
+    fn add_file(
+        &mut self,
+        name: &str,
+        contents: &str,
+        info: &mut PackageInfo,
+    ) -> Result<(), FhirError> {
+        // `.index.json` and similar tooling files
+        if name.starts_with('.') {
+            return Ok(());
+        }
+        let json: Value = serde_json::from_str(contents)
+            .map_err(|e| FhirError::Invalid(format!("{}: {}", name, e)))?;
+
+        if name == "package.json" {
+            let field = |key: &str| json.get(key).and_then(Value::as_str).map(str::to_string);
+            info.name = field("name").unwrap_or_default();
+            info.version = field("version").unwrap_or_default();
+            info.canonical = field("canonical");
+        } else if self.add_resource(&json)? {
+            info.resources += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,710 @@
+//! Profile (StructureDefinition) validation
+//!
+//! Profiles are read from the `differential` (or `snapshot`) of a
+//! StructureDefinition and checked element by element: cardinality,
+//! must-support, fixed and pattern values, quantity limits (in any
+//! commensurable UCUM unit), choice types, required bindings to known value
+//! sets, slicing with `value`/`pattern` discriminators, and the
+//! profiles of extension slices. FHIRPath invariants are not evaluated.
+//!
+//! Profiles usually arrive through implementation guide packages
+//! (see [`crate::package`]).
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::coding::{CodeableConcept, Coding};
+use crate::error::FhirError;
+use crate::outcome::{IssueSeverity, IssueType, OperationOutcomeIssue};
+use crate::quantity::Quantity;
+
+/// One step of an element path: the element name and an optional slice
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    name: String,
+    slice: Option<String>,
+}
+
+impl Segment {
+    fn plain(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            slice: None,
+        }
+    }
+}
+
+/// How the instances of a repeating element are divided into slices
+#[derive(Debug, Clone)]
+struct Slicing {
+    /// `(type, path)` of each discriminator
+    discriminators: Vec<(String, String)>,
+    /// Whether instances outside every slice are an error
+    closed: bool,
+}
+
+/// Constraints on one element (or slice) of a profile
+#[derive(Debug, Clone)]
+struct ElementRule {
+    id: String,
+    /// Path below the profiled type, e.g. `extension:race`, `value[x]`
+    path: Vec<Segment>,
+    min: usize,
+    max: Option<usize>,
+    must_support: bool,
+    types: Vec<String>,
+    profiles: Vec<String>,
+    fixed: Option<Value>,
+    pattern: Option<Value>,
+    /// `minValueQuantity` / `maxValueQuantity`
+    min_quantity: Option<Quantity>,
+    max_quantity: Option<Quantity>,
+    slicing: Option<Slicing>,
+    /// Value set of a required binding
+    value_set: Option<String>,
+}
+
+impl ElementRule {
+    fn parse(element: &Value, root: &str) -> Option<Self> {
+        let id = match element.get("id").and_then(Value::as_str) {
+            Some(id) => id.to_string(),
+            None => {
+                let path = element.get("path")?.as_str()?;
+                match element.get("sliceName").and_then(Value::as_str) {
+                    Some(slice) => format!("{}:{}", path, slice),
+                    None => path.to_string(),
+                }
+            }
+        };
+
+        let mut parts = id.split('.');
+        if parts.next()? != root {
+            return None;
+        }
+        let path: Vec<Segment> = parts
+            .map(|part| match part.split_once(':') {
+                Some((name, slice)) => Segment {
+                    name: name.to_string(),
+                    slice: Some(slice.to_string()),
+                },
+                None => Segment::plain(part),
+            })
+            .collect();
+        if path.is_empty() {
+            return None;
+        }
+
+        let types: Vec<&Value> = element
+            .get("type")
+            .and_then(Value::as_array)
+            .map(|t| t.iter().collect())
+            .unwrap_or_default();
+        let prefixed = |prefix: &str| {
+            element.as_object().and_then(|obj| {
+                obj.iter()
+                    .find(|(key, _)| key.starts_with(prefix))
+                    .map(|(_, value)| value.clone())
+            })
+        };
+
+        let slicing = element.get("slicing").map(|slicing| Slicing {
+            discriminators: slicing
+                .get("discriminator")
+                .and_then(Value::as_array)
+                .map(|d| {
+                    d.iter()
+                        .filter_map(|d| {
+                            Some((
+                                d.get("type")?.as_str()?.to_string(),
+                                d.get("path")?.as_str()?.to_string(),
+                            ))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            closed: slicing.get("rules").and_then(Value::as_str) == Some("closed"),
+        });
+
+        let binding = element.get("binding");
+        let value_set = binding
+            .filter(|b| b.get("strength").and_then(Value::as_str) == Some("required"))
+            .and_then(|b| b.get("valueSet"))
+            .and_then(Value::as_str)
+            .map(|vs| canonical_url(vs).to_string());
+
+        Some(Self {
+            id,
+            path,
+            min: element.get("min").and_then(Value::as_u64).unwrap_or(0) as usize,
+            max: element
+                .get("max")
+                .and_then(Value::as_str)
+                .and_then(|m| m.parse().ok()),
+            must_support: element.get("mustSupport").and_then(Value::as_bool) == Some(true),
+            types: types
+                .iter()
+                .filter_map(|t| t.get("code")?.as_str().map(str::to_string))
+                .collect(),
+            profiles: types
+                .iter()
+                .filter_map(|t| t.get("profile")?.as_array())
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(|p| canonical_url(p).to_string())
+                .collect(),
+            fixed: prefixed("fixed"),
+            pattern: prefixed("pattern"),
+            min_quantity: element
+                .get("minValueQuantity")
+                .and_then(Quantity::from_json),
+            max_quantity: element
+                .get("maxValueQuantity")
+                .and_then(Quantity::from_json),
+            slicing,
+            value_set,
+        })
+    }
+}
+
+/// A constraint profile parsed from a StructureDefinition
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub url: String,
+    pub id: String,
+    pub name: String,
+    /// Resource or data type the profile constrains
+    pub base_type: String,
+    elements: Vec<ElementRule>,
+}
+
+impl Profile {
+    pub fn from_structure_definition(sd: &Value) -> Result<Self, FhirError> {
+        let field = |name: &str| {
+            sd.get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let url = field("url");
+        let base_type = field("type");
+        if url.is_empty() || base_type.is_empty() {
+            return Err(FhirError::Invalid(
+                "StructureDefinition requires url and type".to_string(),
+            ));
+        }
+
+        let elements = sd
+            .pointer("/differential/element")
+            .or_else(|| sd.pointer("/snapshot/element"))
+            .and_then(Value::as_array)
+            .ok_or_else(|| {
+                FhirError::Invalid(format!("StructureDefinition {} has no elements", url))
+            })?
+            .iter()
+            .filter_map(|e| ElementRule::parse(e, &base_type))
+            .collect();
+
+        Ok(Self {
+            id: field("id"),
+            name: field("name"),
+            url,
+            base_type,
+            elements,
+        })
+    }
+
+    fn rule_at(&self, path: &[Segment]) -> Option<&ElementRule> {
+        self.elements.iter().find(|r| r.path == path)
+    }
+
+    /// Nodes reached by following `path` from `root`, with their locations
+    fn select<'a>(&self, root: &'a Value, location: &str, path: &[Segment]) -> Vec<Node<'a>> {
+        let mut nodes = vec![Node {
+            key: String::new(),
+            value: root,
+            location: location.to_string(),
+        }];
+        for (i, segment) in path.iter().enumerate() {
+            nodes = nodes
+                .iter()
+                .flat_map(|node| self.children(node, &path[..i], segment))
+                .collect();
+        }
+        nodes
+    }
+
+    /// Children of `node` matching `segment` (below the element at `prefix`)
+    fn children<'a>(
+        &self,
+        node: &Node<'a>,
+        prefix: &[Segment],
+        segment: &Segment,
+    ) -> Vec<Node<'a>> {
+        let Some(obj) = node.value.as_object() else {
+            return Vec::new();
+        };
+
+        let mut children = Vec::new();
+        for (key, value) in obj
+            .iter()
+            .filter(|(key, _)| key_matches(key, &segment.name))
+        {
+            match value {
+                Value::Array(items) => {
+                    children.extend(items.iter().enumerate().map(|(i, item)| Node {
+                        key: key.clone(),
+                        value: item,
+                        location: format!("{}.{}[{}]", node.location, key, i),
+                    }))
+                }
+                value => children.push(Node {
+                    key: key.clone(),
+                    value,
+                    location: format!("{}.{}", node.location, key),
+                }),
+            }
+        }
+
+        if let Some(ref slice) = segment.slice {
+            children.retain(|child| self.in_slice(prefix, &segment.name, slice, child.value));
+        }
+        children
+    }
+
+    /// Whether an instance belongs to slice `slice` of element `prefix.name`
+    fn in_slice(&self, prefix: &[Segment], name: &str, slice: &str, item: &Value) -> bool {
+        let mut base = prefix.to_vec();
+        base.push(Segment::plain(name));
+
+        // Extensions are sliced by url unless the profile says otherwise
+        let discriminators = match self.rule_at(&base).and_then(|r| r.slicing.as_ref()) {
+            Some(slicing) => slicing.discriminators.clone(),
+            None if name == "extension" || name == "modifierExtension" => {
+                vec![("value".to_string(), "url".to_string())]
+            }
+            None => return false,
+        };
+
+        let mut slice_path = base;
+        if let Some(last) = slice_path.last_mut() {
+            last.slice = Some(slice.to_string());
+        }
+
+        discriminators
+            .iter()
+            .all(|(kind, path)| match kind.as_str() {
+                "value" | "pattern" => {
+                    let Some((expected, exact)) = self.slice_value(&slice_path, path) else {
+                        return false;
+                    };
+                    let actual = if path == "$this" {
+                        Some(item)
+                    } else {
+                        path.split('.').try_fold(item, |node, key| node.get(key))
+                    };
+                    actual.is_some_and(|actual| {
+                        if exact {
+                            actual == &expected
+                        } else {
+                            matches_pattern(actual, &expected)
+                        }
+                    })
+                }
+                // type, profile and exists discriminators are not evaluated
+                _ => true,
+            })
+    }
+
+    /// The fixed (exact) or pattern value a slice requires at `path`
+    fn slice_value(&self, slice_path: &[Segment], path: &str) -> Option<(Value, bool)> {
+        let mut target = slice_path.to_vec();
+        if path != "$this" {
+            target.extend(path.split('.').map(Segment::plain));
+        }
+
+        let rule = self.rule_at(&target);
+        if let Some(fixed) = rule.and_then(|r| r.fixed.clone()) {
+            return Some((fixed, true));
+        }
+        if let Some(pattern) = rule.and_then(|r| r.pattern.clone()) {
+            return Some((pattern, false));
+        }
+
+        // An extension slice is identified by the url of its profile
+        (path == "url")
+            .then(|| self.rule_at(slice_path))
+            .flatten()
+            .and_then(|r| r.profiles.first())
+            .map(|url| (Value::String(url.clone()), true))
+    }
+
+    /// Slices declared for the (unsliced) element of `rule`
+    fn slices_of<'a>(&'a self, rule: &'a ElementRule) -> impl Iterator<Item = &'a ElementRule> {
+        let (last, prefix) = rule.path.split_last().expect("rule paths are never empty");
+        self.elements.iter().filter(move |r| {
+            r.path.len() == rule.path.len()
+                && r.path.starts_with(prefix)
+                && r.path
+                    .last()
+                    .is_some_and(|s| s.name == last.name && s.slice.is_some())
+        })
+    }
+}
+
+/// An element of the instance being validated
+struct Node<'a> {
+    /// JSON key the value was found under (tells choice types apart)
+    key: String,
+    value: &'a Value,
+    location: String,
+}
+
+/// Profiles and value sets available for validation, keyed by canonical URL
+#[derive(Debug, Clone, Default)]
+pub struct ProfileRegistry {
+    profiles: HashMap<String, Profile>,
+    value_sets: HashMap<String, Vec<Coding>>,
+}
+
+impl ProfileRegistry {
+    /// Look up a profile by canonical URL (optionally `|version`), id or name
+    pub fn get(&self, reference: &str) -> Option<&Profile> {
+        self.profiles.get(canonical_url(reference)).or_else(|| {
+            self.profiles
+                .values()
+                .find(|p| p.id == reference || p.name == reference)
+        })
+    }
+
+    pub fn profiles(&self) -> impl Iterator<Item = &Profile> {
+        self.profiles.values()
+    }
+
+    /// Add a StructureDefinition or ValueSet; other resources are ignored.
+    ///
+    /// Returns whether the resource was added.
+    pub fn add_resource(&mut self, resource: &Value) -> Result<bool, FhirError> {
+        match resource.get("resourceType").and_then(Value::as_str) {
+            // Base definitions of resources and types are not profiles
+            Some("StructureDefinition")
+                if resource.get("derivation").and_then(Value::as_str) == Some("specialization") =>
+            {
+                Ok(false)
+            }
+            Some("StructureDefinition") => {
+                let profile = Profile::from_structure_definition(resource)?;
+                self.profiles.insert(profile.url.clone(), profile);
+                Ok(true)
+            }
+            Some("ValueSet") => {
+                let Some(url) = resource.get("url").and_then(Value::as_str) else {
+                    return Ok(false);
+                };
+                self.value_sets
+                    .insert(url.to_string(), value_set_codes(resource));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Validate a resource against a profile, returning every issue found
+    pub fn validate(&self, profile: &Profile, resource: &Value) -> Vec<OperationOutcomeIssue> {
+        let mut issues = Vec::new();
+        let resource_type = resource.get("resourceType").and_then(Value::as_str);
+        if resource_type != Some(profile.base_type.as_str()) {
+            issues.push(issue(
+                IssueSeverity::Error,
+                IssueType::Structure,
+                format!(
+                    "Profile {} applies to {} resources",
+                    profile.url, profile.base_type
+                ),
+                &profile.base_type,
+            ));
+            return issues;
+        }
+        self.check(profile, resource, &profile.base_type, &mut issues);
+        issues
+    }
+
+    fn check(
+        &self,
+        profile: &Profile,
+        root: &Value,
+        location: &str,
+        issues: &mut Vec<OperationOutcomeIssue>,
+    ) {
+        for rule in &profile.elements {
+            let (last, parent_path) = rule.path.split_last().expect("rule paths are never empty");
+            for parent in profile.select(root, location, parent_path) {
+                let children = profile.children(&parent, parent_path, last);
+                self.check_rule(profile, rule, &parent, &children, issues);
+            }
+        }
+    }
+
+    fn check_rule(
+        &self,
+        profile: &Profile,
+        rule: &ElementRule,
+        parent: &Node,
+        children: &[Node],
+        issues: &mut Vec<OperationOutcomeIssue>,
+    ) {
+        let location = format!(
+            "{}.{}",
+            parent.location,
+            rule.path
+                .last()
+                .map(|s| s.name.as_str())
+                .unwrap_or_default()
+        );
+        let count = children.len();
+
+        if count < rule.min {
+            issues.push(issue(
+                IssueSeverity::Error,
+                IssueType::Required,
+                format!(
+                    "{} requires at least {} (found {})",
+                    rule.id, rule.min, count
+                ),
+                &location,
+            ));
+        } else if count == 0 && rule.must_support {
+            issues.push(issue(
+                IssueSeverity::Warning,
+                IssueType::Incomplete,
+                format!("Must-support element {} is absent", rule.id),
+                &location,
+            ));
+        }
+        match rule.max {
+            Some(max) if count > max => issues.push(issue(
+                IssueSeverity::Error,
+                IssueType::Structure,
+                format!("{} allows at most {} (found {})", rule.id, max, count),
+                &location,
+            )),
+            _ => {}
+        }
+
+        for child in children {
+            self.check_value(rule, child, issues);
+
+            // Extension slices (and other profiled types) carry their own rules
+            for url in &rule.profiles {
+                if let Some(nested) = self.profiles.get(url) {
+                    self.check(nested, child.value, &child.location, issues);
+                }
+            }
+        }
+
+        let closed = rule.slicing.as_ref().is_some_and(|s| s.closed);
+        if closed && rule.path.last().is_some_and(|s| s.slice.is_none()) {
+            let (last, prefix) = rule.path.split_last().expect("rule paths are never empty");
+            for child in children {
+                let sliced = profile.slices_of(rule).any(|slice| {
+                    let name = slice.path.last().and_then(|s| s.slice.as_deref());
+                    name.is_some_and(|name| profile.in_slice(prefix, &last.name, name, child.value))
+                });
+                if !sliced {
+                    issues.push(issue(
+                        IssueSeverity::Error,
+                        IssueType::Structure,
+                        format!("Element does not match any slice of {}", rule.id),
+                        &child.location,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Check fixed and pattern values, choice types and required bindings
+    fn check_value(
+        &self,
+        rule: &ElementRule,
+        child: &Node,
+        issues: &mut Vec<OperationOutcomeIssue>,
+    ) {
+        if let Some(fixed) = rule.fixed.as_ref().filter(|f| child.value != *f) {
+            issues.push(issue(
+                IssueSeverity::Error,
+                IssueType::Value,
+                format!("{} must be {}", rule.id, fixed),
+                &child.location,
+            ));
+        }
+        let pattern = rule.pattern.as_ref();
+        if let Some(pattern) = pattern.filter(|p| !matches_pattern(child.value, p)) {
+            issues.push(issue(
+                IssueSeverity::Error,
+                IssueType::Value,
+                format!("{} must match {}", rule.id, pattern),
+                &child.location,
+            ));
+        }
+
+        // Limits in other units are converted; incomparable units are skipped
+        if let Some(quantity) = Quantity::from_json(child.value) {
+            let below = rule
+                .min_quantity
+                .as_ref()
+                .filter(|min| quantity.compare(min) == Some(std::cmp::Ordering::Less));
+            let above = rule
+                .max_quantity
+                .as_ref()
+                .filter(|max| quantity.compare(max) == Some(std::cmp::Ordering::Greater));
+            for (limit, word) in [(below, "at least"), (above, "at most")] {
+                if let Some(limit) = limit {
+                    issues.push(issue(
+                        IssueSeverity::Error,
+                        IssueType::Value,
+                        format!(
+                            "{} must be {} {} {}",
+                            rule.id,
+                            word,
+                            limit.value.unwrap_or_default(),
+                            limit
+                                .code
+                                .as_deref()
+                                .or(limit.unit.as_deref())
+                                .unwrap_or("")
+                        ),
+                        &child.location,
+                    ));
+                }
+            }
+        }
+
+        let choice = rule.path.last().and_then(|s| s.name.strip_suffix("[x]"));
+        if let Some(prefix) = choice.filter(|_| !rule.types.is_empty()) {
+            let actual = crate::choice::variant_type(&child.key, prefix).unwrap_or_default();
+            if !rule.types.iter().any(|t| t.eq_ignore_ascii_case(actual)) {
+                issues.push(issue(
+                    IssueSeverity::Error,
+                    IssueType::Structure,
+                    format!("{} must be of type {}", rule.id, rule.types.join(" or ")),
+                    &child.location,
+                ));
+            }
+        }
+
+        // Bindings to value sets that were not loaded are not checked
+        let unbound = rule.value_set.as_ref().filter(|url| {
+            self.value_sets
+                .get(*url)
+                .is_some_and(|codes| !in_value_set(child.value, codes))
+        });
+        if let Some(url) = unbound {
+            issues.push(issue(
+                IssueSeverity::Error,
+                IssueType::CodeInvalid,
+                format!("{} must be a code from {}", rule.id, url),
+                &child.location,
+            ));
+        }
+    }
+}
+
+fn issue(
+    severity: IssueSeverity,
+    code: IssueType,
+    diagnostics: String,
+    location: &str,
+) -> OperationOutcomeIssue {
+    OperationOutcomeIssue {
+        severity,
+        code,
+        diagnostics: Some(diagnostics),
+        location: vec![location.to_string()],
+    }
+}
+
+/// Strip a `|version` suffix from a canonical reference
+fn canonical_url(reference: &str) -> &str {
+    reference.split('|').next().unwrap_or(reference)
+}
+
+/// Whether a JSON key holds element `name` (`value[x]` matches `valueString`)
+fn key_matches(key: &str, name: &str) -> bool {
+    match name.strip_suffix("[x]") {
+        Some(prefix) => crate::choice::variant_type(key, prefix).is_some(),
+        None => key == name,
+    }
+}
+
+/// FHIR pattern matching: every property of the pattern is present in the
+/// value, and every pattern array item matches some value array item
+fn matches_pattern(value: &Value, pattern: &Value) -> bool {
+    // UCUM quantities match in any commensurable unit (`5 mg` ~ `0.005 g`)
+    let ucum = |v: &Value| Quantity::from_json(v).filter(|q| q.ucum_unit().is_some());
+    if let (Some(a), Some(b)) = (ucum(value), ucum(pattern)) {
+        return a.equivalent(&b);
+    }
+    match (value, pattern) {
+        (Value::Object(value), Value::Object(pattern)) => pattern
+            .iter()
+            .all(|(key, p)| value.get(key).is_some_and(|v| matches_pattern(v, p))),
+        (Value::Array(values), Value::Array(patterns)) => patterns
+            .iter()
+            .all(|p| values.iter().any(|v| matches_pattern(v, p))),
+        (value, pattern) => value == pattern,
+    }
+}
+
+/// Codes enumerated by a ValueSet's compose or expansion
+fn value_set_codes(value_set: &Value) -> Vec<Coding> {
+    let mut codes: Vec<Coding> = Vec::new();
+    let mut add = |coding: Coding| {
+        if coding.code.is_some() && !codes.iter().any(|c| c.same_concept(&coding)) {
+            codes.push(coding);
+        }
+    };
+
+    let includes = value_set
+        .pointer("/compose/include")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    for include in includes {
+        let system = include.get("system").and_then(Value::as_str);
+        let concepts = include
+            .get("concept")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten();
+        for concept in concepts.filter_map(Coding::from_json) {
+            add(Coding {
+                system: system.map(str::to_string),
+                ..concept
+            });
+        }
+    }
+
+    let contains = value_set
+        .pointer("/expansion/contains")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    for item in contains.filter_map(Coding::from_json) {
+        add(item);
+    }
+    codes
+}
+
+/// Whether a code, Coding or CodeableConcept is drawn from `codes`
+fn in_value_set(value: &Value, codes: &[Coding]) -> bool {
+    match value {
+        // A bare code carries no system, so any system will do
+        Value::String(code) => codes.iter().any(|c| c.code.as_deref() == Some(code)),
+        _ => CodeableConcept::from_json(value).is_some_and(|concept| {
+            concept
+                .coding
+                .iter()
+                .any(|coding| codes.iter().any(|c| c.same_concept(coding)))
+        }),
+    }
+}
@@ -0,0 +1,538 @@
+//! Quantity with UCUM unit conversion
+//!
+//! UCUM codes are parsed into a factor and a vector of base dimensions, so
+//! quantities in commensurable units compare by value: `5 mg` equals
+//! `0.005 g`, and `mmol/L` converts to `umol/mL`. The common clinical subset
+//! of UCUM is covered: metric prefixes, SI base and derived units, volume,
+//! time, customary mass and length, `%`, `10*n` factors, exponents,
+//! parentheses and `{annotations}`. Arbitrary units (`[IU]`) and non-ratio
+//! scales (`Cel`, `[degF]`) are only comparable in the same unit.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Code system URL for UCUM units
+pub const UCUM_SYSTEM: &str = "http://unitsofmeasure.org";
+
+/// Base dimensions: mass, length, time, amount, temperature, current,
+/// luminous intensity
+const DIMENSIONS: [&str; 7] = ["g", "m", "s", "mol", "K", "A", "cd"];
+
+/// A unit as a multiple of a product of base units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UcumUnit {
+    /// Multiplier from this unit to the canonical unit
+    pub factor: f64,
+    /// Exponent of each base dimension (see [`UcumUnit::canonical_code`])
+    pub dims: [i8; 7],
+}
+
+/// `(symbol, factor, dims, takes metric prefixes)`
+const ATOMS: &[(&str, f64, [i8; 7], bool)] = &[
+    ("g", 1.0, [1, 0, 0, 0, 0, 0, 0], true),
+    ("m", 1.0, [0, 1, 0, 0, 0, 0, 0], true),
+    ("s", 1.0, [0, 0, 1, 0, 0, 0, 0], true),
+    ("mol", 1.0, [0, 0, 0, 1, 0, 0, 0], true),
+    ("eq", 1.0, [0, 0, 0, 1, 0, 0, 0], true),
+    ("K", 1.0, [0, 0, 0, 0, 1, 0, 0], true),
+    ("A", 1.0, [0, 0, 0, 0, 0, 1, 0], true),
+    ("cd", 1.0, [0, 0, 0, 0, 0, 0, 1], true),
+    ("L", 1e-3, [0, 3, 0, 0, 0, 0, 0], true),
+    ("l", 1e-3, [0, 3, 0, 0, 0, 0, 0], true),
+    ("Hz", 1.0, [0, 0, -1, 0, 0, 0, 0], true),
+    ("N", 1e3, [1, 1, -2, 0, 0, 0, 0], true),
+    ("Pa", 1e3, [1, -1, -2, 0, 0, 0, 0], true),
+    ("J", 1e3, [1, 2, -2, 0, 0, 0, 0], true),
+    ("W", 1e3, [1, 2, -3, 0, 0, 0, 0], true),
+    ("V", 1e3, [1, 2, -3, 0, 0, -1, 0], true),
+    ("bar", 1e8, [1, -1, -2, 0, 0, 0, 0], true),
+    ("cal", 4184.0, [1, 2, -2, 0, 0, 0, 0], true),
+    ("m[Hg]", 133_322_387.415, [1, -1, -2, 0, 0, 0, 0], true),
+    ("m[H2O]", 9_806_650.0, [1, -1, -2, 0, 0, 0, 0], true),
+    ("min", 60.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("h", 3600.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("d", 86_400.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("wk", 604_800.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("mo", 2_629_800.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("a", 31_557_600.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("%", 0.01, [0; 7], false),
+    ("[lb_av]", 453.592_37, [1, 0, 0, 0, 0, 0, 0], false),
+    ("[oz_av]", 28.349_523_125, [1, 0, 0, 0, 0, 0, 0], false),
+    ("[in_i]", 0.0254, [0, 1, 0, 0, 0, 0, 0], false),
+    ("[ft_i]", 0.3048, [0, 1, 0, 0, 0, 0, 0], false),
+    ("[mi_i]", 1609.344, [0, 1, 0, 0, 0, 0, 0], false),
+    ("[gal_us]", 3.785_411_784e-3, [0, 3, 0, 0, 0, 0, 0], false),
+    (
+        "[foz_us]",
+        2.957_352_956_25e-5,
+        [0, 3, 0, 0, 0, 0, 0],
+        false,
+    ),
+];
+
+/// Metric prefixes, longest first so `da` wins over `d`
+const PREFIXES: &[(&str, f64)] = &[
+    ("da", 1e1),
+    ("Y", 1e24),
+    ("Z", 1e21),
+    ("E", 1e18),
+    ("P", 1e15),
+    ("T", 1e12),
+    ("G", 1e9),
+    ("M", 1e6),
+    ("k", 1e3),
+    ("h", 1e2),
+    ("d", 1e-1),
+    ("c", 1e-2),
+    ("m", 1e-3),
+    ("u", 1e-6),
+    ("n", 1e-9),
+    ("p", 1e-12),
+    ("f", 1e-15),
+    ("a", 1e-18),
+];
+
+impl UcumUnit {
+    const ONE: UcumUnit = UcumUnit {
+        factor: 1.0,
+        dims: [0; 7],
+    };
+
+    /// Parse a UCUM code; None for syntax errors and unsupported units
+    pub fn parse(code: &str) -> Option<Self> {
+        let code = code.trim();
+        if code.is_empty() {
+            return Some(Self::ONE);
+        }
+        let mut parser = Parser {
+            input: code.as_bytes(),
+            pos: 0,
+        };
+        let unit = parser.term()?;
+        (parser.pos == parser.input.len()).then_some(unit)
+    }
+
+    /// Whether values in both units can be converted into each other
+    pub fn is_commensurable(&self, other: &UcumUnit) -> bool {
+        self.dims == other.dims
+    }
+
+    /// Code of the canonical unit, e.g. `g`, `mol.m-3` or `1` for
+    /// dimensionless units
+    pub fn canonical_code(&self) -> String {
+        let parts: Vec<String> = DIMENSIONS
+            .iter()
+            .zip(self.dims)
+            .filter(|(_, exp)| *exp != 0)
+            .map(|(dim, exp)| match exp {
+                1 => dim.to_string(),
+                _ => format!("{}{}", dim, exp),
+            })
+            .collect();
+        if parts.is_empty() {
+            "1".to_string()
+        } else {
+            parts.join(".")
+        }
+    }
+
+    /// Product of two units; None if an exponent or the factor overflows
+    fn mul(self, other: UcumUnit) -> Option<Self> {
+        let mut dims = self.dims;
+        for (d, o) in dims.iter_mut().zip(other.dims) {
+            *d = d.checked_add(o)?;
+        }
+        Self {
+            factor: self.factor * other.factor,
+            dims,
+        }
+        .finite()
+    }
+
+    /// Power of a unit; None if an exponent or the factor overflows
+    fn pow(self, exp: i32) -> Option<Self> {
+        let exp = i8::try_from(exp).ok()?;
+        let mut dims = self.dims;
+        for d in dims.iter_mut() {
+            *d = d.checked_mul(exp)?;
+        }
+        Self {
+            factor: self.factor.powi(exp.into()),
+            dims,
+        }
+        .finite()
+    }
+
+    /// None for factors that overflowed to infinity or underflowed to zero
+    fn finite(self) -> Option<Self> {
+        (self.factor.is_finite() && self.factor != 0.0).then_some(self)
+    }
+
+    /// Look up a unit symbol without exponent, with an optional prefix
+    fn atom(symbol: &str) -> Option<Self> {
+        let unit = |(_, factor, dims, _): &(&str, f64, [i8; 7], bool)| UcumUnit {
+            factor: *factor,
+            dims: *dims,
+        };
+        if let Some(atom) = ATOMS.iter().find(|a| a.0 == symbol) {
+            return Some(unit(atom));
+        }
+        PREFIXES.iter().find_map(|(prefix, scale)| {
+            let rest = symbol.strip_prefix(prefix)?;
+            let atom = ATOMS.iter().find(|a| a.0 == rest && a.3)?;
+            let atom = unit(atom);
+            Some(UcumUnit {
+                factor: atom.factor * scale,
+                ..atom
+            })
+        })
+    }
+}
+
+/// Recursive-descent parser over the UCUM grammar:
+/// `term := ['/'] component (('.' | '/') component)*`
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn term(&mut self) -> Option<UcumUnit> {
+        let mut unit = if self.peek() == Some(b'/') {
+            self.pos += 1;
+            self.component()?.pow(-1)?
+        } else {
+            self.component()?
+        };
+        while let Some(op) = self.peek().filter(|c| *c == b'.' || *c == b'/') {
+            self.pos += 1;
+            let next = self.component()?;
+            unit = unit.mul(if op == b'/' { next.pow(-1)? } else { next })?;
+        }
+        Some(unit)
+    }
+
+    /// A parenthesized term, an annotation, an integer factor or a unit
+    /// symbol with an optional exponent; trailing annotations are ignored
+    fn component(&mut self) -> Option<UcumUnit> {
+        let unit = match self.peek()? {
+            b'(' => {
+                self.pos += 1;
+                let unit = self.term()?;
+                (self.peek() == Some(b')')).then_some(())?;
+                self.pos += 1;
+                unit
+            }
+            b'{' => {
+                self.annotation()?;
+                return Some(UcumUnit::ONE);
+            }
+            _ => self.symbol()?,
+        };
+        if self.peek() == Some(b'{') {
+            self.annotation()?;
+        }
+        Some(unit)
+    }
+
+    fn annotation(&mut self) -> Option<()> {
+        let end = self.input[self.pos..].iter().position(|c| *c == b'}')?;
+        self.pos += end + 1;
+        Some(())
+    }
+
+    fn symbol(&mut self) -> Option<UcumUnit> {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                b'.' | b'/' | b'(' | b')' | b'{' if depth == 0 => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.input[start..self.pos]).ok()?;
+
+        // `10*3`, `10^-6`
+        if let Some(exp) = text
+            .strip_prefix("10*")
+            .or_else(|| text.strip_prefix("10^"))
+        {
+            let exp: i32 = exp.parse().ok()?;
+            return UcumUnit {
+                factor: 10f64.powi(exp),
+                dims: [0; 7],
+            }
+            .finite();
+        }
+        if text.bytes().all(|c| c.is_ascii_digit()) {
+            return Some(UcumUnit {
+                factor: text.parse().ok()?,
+                dims: [0; 7],
+            });
+        }
+
+        // Trailing exponent: `m2`, `s-1`
+        let digits = text.len() - text.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let (mut base, mut exp) = (text, 1);
+        if digits > 0 {
+            let mut split = text.len() - digits;
+            if text[..split].ends_with(['+', '-']) {
+                split -= 1;
+            }
+            if split > 0 {
+                base = &text[..split];
+                exp = text[split..].parse().ok()?;
+            }
+        }
+        UcumUnit::atom(base)?.pow(exp)
+    }
+}
+
+/// Comparator of a Quantity value (`<5 mg`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuantityComparator {
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = ">")]
+    Gt,
+}
+
+/// A measured amount
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Quantity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comparator: Option<QuantityComparator>,
+    /// Human-readable unit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// Coded unit (a UCUM code when `system` is UCUM)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+impl Quantity {
+    /// A UCUM quantity
+    pub fn ucum(value: f64, code: &str) -> Self {
+        Self {
+            value: Some(value),
+            comparator: None,
+            unit: Some(code.to_string()),
+            system: Some(UCUM_SYSTEM.to_string()),
+            code: Some(code.to_string()),
+        }
+    }
+
+    /// Read a Quantity; None unless the value is an object with a number
+    pub fn from_json(value: &Value) -> Option<Self> {
+        value.get("value")?.as_f64()?;
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// The parsed UCUM unit, if the quantity is coded in UCUM
+    pub fn ucum_unit(&self) -> Option<UcumUnit> {
+        match self.system.as_deref() {
+            Some(UCUM_SYSTEM) => UcumUnit::parse(self.code.as_deref()?),
+            _ => None,
+        }
+    }
+
+    /// The quantity in canonical UCUM units (`5 mg` → `0.005 g`)
+    pub fn canonical(&self) -> Option<Quantity> {
+        let unit = self.ucum_unit()?;
+        let code = unit.canonical_code();
+        Some(Quantity {
+            value: Some(self.value? * unit.factor),
+            comparator: self.comparator,
+            unit: Some(code.clone()),
+            system: Some(UCUM_SYSTEM.to_string()),
+            code: Some(code),
+        })
+    }
+
+    /// The value converted to another UCUM unit, if commensurable
+    pub fn convert_to(&self, code: &str) -> Option<f64> {
+        let from = self.ucum_unit()?;
+        let to = UcumUnit::parse(code)?;
+        from.is_commensurable(&to)
+            .then(|| self.value.map(|v| v * from.factor / to.factor))?
+    }
+
+    /// Order two quantities by value: by canonical value for commensurable
+    /// UCUM units, otherwise only if system and code are the same
+    pub fn compare(&self, other: &Quantity) -> Option<Ordering> {
+        let (a, b) = self.comparable_values(other)?;
+        a.partial_cmp(&b)
+    }
+
+    /// Both values in a common unit
+    fn comparable_values(&self, other: &Quantity) -> Option<(f64, f64)> {
+        if let (Some(a), Some(b)) = (self.ucum_unit(), other.ucum_unit()) {
+            return a
+                .is_commensurable(&b)
+                .then(|| Some((self.value? * a.factor, other.value? * b.factor)))?;
+        }
+        (self.system == other.system && self.code == other.code)
+            .then(|| Some((self.value?, other.value?)))?
+    }
+
+    /// Whether both quantities are the same amount, allowing for floating
+    /// point error in the conversion
+    pub fn equivalent(&self, other: &Quantity) -> bool {
+        self.comparable_values(other)
+            .is_some_and(|(a, b)| (a - b).abs() <= 1e-9 * a.abs().max(b.abs()))
+    }
+}
+
+/// Search comparison prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchPrefix {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    /// Approximately (within 10%)
+    Ap,
+}
+
+/// A parsed quantity search value: `[prefix]number[|system|code]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantityParam {
+    pub prefix: SearchPrefix,
+    pub quantity: Quantity,
+    /// Half a unit in the last digit given, for `eq` and `ne`
+    precision: f64,
+}
+
+impl QuantityParam {
+    /// Parse `5.4|http://unitsofmeasure.org|mg`, `ge5||mg` or `5.4`; a unit
+    /// without a system is taken as UCUM if it parses as a UCUM code
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(3, '|');
+        let number = parts.next()?.trim();
+        let system = parts.next().map(str::trim).filter(|s| !s.is_empty());
+        let code = parts.next().map(str::trim).filter(|s| !s.is_empty());
+
+        let prefixes = [
+            ("eq", SearchPrefix::Eq),
+            ("ne", SearchPrefix::Ne),
+            ("gt", SearchPrefix::Gt),
+            ("lt", SearchPrefix::Lt),
+            ("ge", SearchPrefix::Ge),
+            ("le", SearchPrefix::Le),
+            ("ap", SearchPrefix::Ap),
+        ];
+        let (prefix, number) = prefixes
+            .iter()
+            .find_map(|(p, prefix)| number.strip_prefix(p).map(|n| (*prefix, n)))
+            .unwrap_or((SearchPrefix::Eq, number));
+
+        let amount: f64 = number.parse().ok()?;
+        let decimals = number
+            .split_once('.')
+            .map_or(0, |(_, fraction)| fraction.len() as i32);
+        Some(Self {
+            prefix,
+            precision: 0.5 * 10f64.powi(-decimals),
+            quantity: Quantity {
+                value: Some(amount),
+                comparator: None,
+                unit: code.map(str::to_string),
+                // A bare code is tried as UCUM first
+                system: system
+                    .or(code
+                        .filter(|c| UcumUnit::parse(c).is_some())
+                        .map(|_| UCUM_SYSTEM))
+                    .map(str::to_string),
+                code: code.map(str::to_string),
+            },
+        })
+    }
+
+    /// Whether a stored Quantity satisfies the search value
+    pub fn matches(&self, quantity: &Quantity) -> bool {
+        // Without a unit, only the number is compared
+        let wanted = &self.quantity;
+        let (value, target, scale) = if wanted.code.is_none() {
+            match quantity.value {
+                Some(v) => (v, wanted.value.unwrap_or_default(), 1.0),
+                None => return false,
+            }
+        } else {
+            let scale = wanted.ucum_unit().map_or(1.0, |u| u.factor);
+            match quantity.comparable_values(wanted) {
+                Some((v, t)) => (v, t, scale),
+                None => return false,
+            }
+        };
+
+        let precision = self.precision * scale;
+        match self.prefix {
+            SearchPrefix::Eq => (value - target).abs() <= precision,
+            SearchPrefix::Ne => (value - target).abs() > precision,
+            SearchPrefix::Gt => value > target,
+            SearchPrefix::Lt => value < target,
+            SearchPrefix::Ge => value >= target,
+            SearchPrefix::Le => value <= target,
+            SearchPrefix::Ap => (value - target).abs() <= (target * 0.1).abs().max(precision),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_exponents() {
+        let unit = UcumUnit::parse("mol.m-3").unwrap();
+        assert_eq!(unit.canonical_code(), "m-3.mol");
+        assert_eq!(UcumUnit::parse("/s").unwrap().canonical_code(), "s-1");
+        assert_eq!(UcumUnit::parse("m127").unwrap().dims[1], 127);
+        assert_eq!(UcumUnit::parse("m-128").unwrap().dims[1], -128);
+    }
+
+    #[test]
+    fn test_exponent_overflow() {
+        // Exponents beyond the i8 range are rejected instead of wrapping
+        assert_eq!(UcumUnit::parse("m128"), None);
+        assert_eq!(UcumUnit::parse("m-129"), None);
+        assert_eq!(UcumUnit::parse("m64.m64"), None);
+        assert_eq!(UcumUnit::parse("m100.m100"), None);
+        assert_eq!(UcumUnit::parse("/m-128"), None);
+        assert_eq!(UcumUnit::parse("m99999999999"), None);
+    }
+
+    #[test]
+    fn test_factor_overflow() {
+        assert_eq!(UcumUnit::parse("10*400"), None);
+        assert_eq!(UcumUnit::parse("10*-400"), None);
+        assert_eq!(UcumUnit::parse("10*200.10*200"), None);
+        assert!(UcumUnit::parse("10*300").is_some());
+    }
+
+    #[test]
+    fn test_overflowing_units_do_not_compare() {
+        let overflowing = Quantity::ucum(1.0, "m128");
+        assert_eq!(overflowing.canonical(), None);
+        assert_eq!(overflowing.compare(&Quantity::ucum(1.0, "m")), None);
+    }
+}
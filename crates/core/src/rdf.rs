@@ -0,0 +1,186 @@
+//! FHIR RDF (Turtle) serialization
+//!
+//! Follows the FHIR RDF mapping: each element becomes `fhir:<name>`,
+//! primitives are wrapped as `[ fhir:v <literal> ]`, repeating elements become
+//! RDF lists, and resources are typed with `a fhir:<resourceType>`.
+
+use std::fmt::Write;
+
+use serde_json::{Map, Value};
+
+/// Prefix declarations emitted at the top of every document
+const PREFIXES: &str = "@prefix fhir: <http://hl7.org/fhir/> .\n\
+@prefix owl: <http://www.w3.org/2002/07/owl#> .\n\
+@prefix rdf: <http://www.w3.org/1999/02/22-rdf-syntax-ns#> .\n\
+@prefix xsd: <http://www.w3.org/2001/XMLSchema#> .\n";
+
+/// Serialize a resource as a Turtle document rooted at `iri`
+///
+/// `base` is prepended to relative references to produce `fhir:link` IRIs
+/// (e.g. `/fhir` turns `Patient/123` into `</fhir/Patient/123>`).
+pub fn to_turtle(resource: &Value, iri: &str, base: &str) -> String {
+    let mut out = String::from(PREFIXES);
+    out.push('\n');
+
+    let Some(obj) = resource.as_object() else {
+        return out;
+    };
+
+    let mut statements = node_statements(obj, base, 1);
+    statements.insert(
+        1.min(statements.len()),
+        "fhir:nodeRole fhir:treeRoot".to_string(),
+    );
+    let _ = writeln!(
+        out,
+        "<{}> {} .\n",
+        escape_iri(iri),
+        statements.join(&format!(" ;\n{}", indent(1)))
+    );
+
+    // Ontology header linking the document to the FHIR ontology
+    let _ = writeln!(
+        out,
+        "<{}.ttl> a owl:Ontology ;\n  owl:imports fhir:fhir.ttl .",
+        escape_iri(iri)
+    );
+
+    out
+}
+
+/// Predicate/object pairs describing a resource or complex element
+fn node_statements(obj: &Map<String, Value>, base: &str, depth: usize) -> Vec<String> {
+    let mut statements = Vec::new();
+
+    if let Some(rt) = obj.get("resourceType").and_then(|v| v.as_str()) {
+        statements.push(format!("a fhir:{}", rt));
+    }
+
+    // Relative references also get a `fhir:link` to the target
+    if let Some(reference) = obj.get("reference").and_then(|v| v.as_str()) {
+        if !reference.starts_with('#') {
+            let target = if reference.contains("://") {
+                reference.to_string()
+            } else {
+                format!("{}/{}", base.trim_end_matches('/'), reference)
+            };
+            statements.push(format!("fhir:link <{}>", escape_iri(&target)));
+        }
+    }
+
+    for (name, value) in obj {
+        // `resourceType` is expressed as `a fhir:<type>`; `_name` primitive
+        // extensions are not mapped
+        if name == "resourceType" || name.starts_with('_') {
+            continue;
+        }
+        statements.push(format!(
+            "fhir:{} {}",
+            name,
+            object_value(name, value, base, depth)
+        ));
+    }
+
+    statements
+}
+
+/// Render an element value: lists for repeating elements, blank nodes otherwise
+fn object_value(name: &str, value: &Value, base: &str, depth: usize) -> String {
+    match value {
+        Value::Array(items) => {
+            let items: Vec<String> = items
+                .iter()
+                .map(|item| object_value(name, item, base, depth))
+                .collect();
+            format!("( {} )", items.join(" "))
+        }
+        Value::Object(obj) => {
+            let statements = node_statements(obj, base, depth + 1);
+            if statements.is_empty() {
+                "[ ]".to_string()
+            } else {
+                format!(
+                    "[\n{inner}{}\n{outer}]",
+                    statements.join(&format!(" ;\n{}", indent(depth + 1))),
+                    inner = indent(depth + 1),
+                    outer = indent(depth),
+                )
+            }
+        }
+        Value::Null => "[ ]".to_string(),
+        _ => format!("[ fhir:v {} ]", literal(name, value)),
+    }
+}
+
+/// Render a primitive as a Turtle literal
+fn literal(name: &str, value: &Value) -> String {
+    match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) if n.is_i64() || n.is_u64() => n.to_string(),
+        Value::Number(n) => format!("\"{}\"^^xsd:decimal", n),
+        Value::String(s) => {
+            let quoted = format!("\"{}\"", escape_string(s));
+            match xsd_type(name, s) {
+                Some(ty) => format!("{}^^xsd:{}", quoted, ty),
+                None => quoted,
+            }
+        }
+        _ => "\"\"".to_string(),
+    }
+}
+
+/// Infer the XSD datatype of date-like string primitives
+fn xsd_type(name: &str, s: &str) -> Option<&'static str> {
+    let digits = |range: std::ops::Range<usize>| {
+        s.get(range)
+            .is_some_and(|p| p.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let bytes = s.as_bytes();
+    let is_date = bytes.len() >= 10
+        && digits(0..4)
+        && bytes[4] == b'-'
+        && digits(5..7)
+        && bytes[7] == b'-'
+        && digits(8..10);
+
+    if name == "id" || !is_date {
+        None
+    } else if s.len() == 10 {
+        Some("date")
+    } else if bytes[10] == b'T' {
+        Some("dateTime")
+    } else {
+        None
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode characters that are not allowed inside `<...>` IRIs
+fn escape_iri(iri: &str) -> String {
+    iri.chars()
+        .map(|c| match c {
+            '<' | '>' | '"' | ' ' | '{' | '}' | '|' | '\\' | '^' | '`' => {
+                format!("%{:02X}", c as u32)
+            }
+            _ => c.to_string(),
+        })
+        .collect()
+}
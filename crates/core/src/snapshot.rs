@@ -0,0 +1,230 @@
+//! StructureDefinition snapshot generation
+//!
+//! A snapshot is the base definition's snapshot with the differential
+//! applied: matching elements are merged, slices are inserted after the
+//! element they slice, and the children of complex types are expanded from
+//! the type's definition when one is loaded. Differential elements the base
+//! cannot describe (children of data types without a loaded definition) are
+//! taken as written.
+
+use serde_json::{Map, Value};
+
+use crate::error::FhirError;
+
+/// Element id, falling back to the path for ids omitted in the differential
+fn element_id(element: &Value) -> Option<&str> {
+    element
+        .get("id")
+        .or_else(|| element.get("path"))
+        .and_then(Value::as_str)
+}
+
+/// Id with slice names removed, i.e. the element path
+fn unsliced(id: &str) -> String {
+    id.split('.')
+        .map(|segment| segment.split(':').next().unwrap_or(segment))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn parent_id(id: &str) -> Option<&str> {
+    id.rsplit_once('.').map(|(parent, _)| parent)
+}
+
+/// Id of the element a slice slices (`Patient.identifier:mrn` →
+/// `Patient.identifier`), or None if the last segment is not a slice
+fn sliced_id(id: &str) -> Option<&str> {
+    let last = id.rsplit('.').next().unwrap_or(id);
+    last.rfind(':').map(|i| &id[..id.len() - last.len() + i])
+}
+
+/// Merge differential properties into a snapshot element; constraints are
+/// added to the inherited ones, everything else replaces them
+fn merge(target: &mut Value, diff: &Value) {
+    let (Some(target), Some(diff)) = (target.as_object_mut(), diff.as_object()) else {
+        return;
+    };
+    for (key, value) in diff {
+        match (key.as_str(), target.get_mut(key)) {
+            ("constraint", Some(Value::Array(existing))) => {
+                existing.extend(value.as_array().into_iter().flatten().cloned())
+            }
+            _ => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Snapshot elements under construction
+struct Snapshot {
+    elements: Vec<Value>,
+}
+
+impl Snapshot {
+    fn position(&self, id: &str) -> Option<usize> {
+        self.elements.iter().position(|e| element_id(e) == Some(id))
+    }
+
+    fn has_children(&self, id: &str) -> bool {
+        let prefix = format!("{}.", id);
+        self.elements
+            .iter()
+            .filter_map(element_id)
+            .any(|e| e.starts_with(&prefix))
+    }
+
+    /// Index after the last element at or below `anchor`; slices of the
+    /// anchor count as below it when `with_slices` is set
+    fn insertion_point(&self, anchor: &str, with_slices: bool) -> Option<usize> {
+        let children = format!("{}.", anchor);
+        let slices = format!("{}:", anchor);
+        self.elements
+            .iter()
+            .rposition(|e| {
+                element_id(e).is_some_and(|id| {
+                    id == anchor
+                        || id.starts_with(&children)
+                        || (with_slices && id.starts_with(&slices))
+                })
+            })
+            .map(|i| i + 1)
+    }
+
+    fn insert(&mut self, anchor: Option<&str>, with_slices: bool, element: Value) {
+        // Fall back to the nearest ancestor that exists, then the end
+        let mut anchor = anchor;
+        while let Some(id) = anchor {
+            if let Some(index) = self.insertion_point(id, with_slices) {
+                self.elements.insert(index, element);
+                return;
+            }
+            anchor = parent_id(id);
+        }
+        self.elements.push(element);
+    }
+
+    /// Expand the children of the element at `index` from its type's
+    /// definition; returns whether anything was added
+    fn expand(&mut self, index: usize, types: &dyn Fn(&str) -> Option<Vec<Value>>) -> bool {
+        let parent = &self.elements[index];
+        let (Some(parent_id), Some(parent_path)) = (
+            element_id(parent).map(str::to_string),
+            parent
+                .get("path")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        ) else {
+            return false;
+        };
+        let type_code = match parent.get("type").and_then(Value::as_array) {
+            Some(codes) if codes.len() == 1 => codes[0].get("code").and_then(Value::as_str),
+            _ => None,
+        };
+        let Some(type_elements) = type_code.and_then(types) else {
+            return false;
+        };
+
+        let children: Vec<Value> = type_elements
+            .iter()
+            .skip(1)
+            .filter_map(|element| {
+                let id = element_id(element)?;
+                let (_, rest) = id.split_once('.')?;
+                let mut child = element.clone();
+                child["id"] = format!("{}.{}", parent_id, rest).into();
+                child["path"] = format!("{}.{}", parent_path, unsliced(rest)).into();
+                Some(child)
+            })
+            .collect();
+        let added = !children.is_empty();
+        self.elements.splice(index + 1..index + 1, children);
+        added
+    }
+
+    fn apply(
+        &mut self,
+        diff: &Value,
+        types: &dyn Fn(&str) -> Option<Vec<Value>>,
+    ) -> Result<(), FhirError> {
+        let id = element_id(diff)
+            .ok_or_else(|| {
+                FhirError::Invalid("Differential element has no id or path".to_string())
+            })?
+            .to_string();
+
+        if let Some(index) = self.position(&id) {
+            merge(&mut self.elements[index], diff);
+            return Ok(());
+        }
+
+        // A new slice starts as a copy of the element it slices
+        if let Some(sliced) = sliced_id(&id) {
+            let mut element = self
+                .position(sliced)
+                .map(|i| self.elements[i].clone())
+                .unwrap_or_else(|| Value::Object(Map::new()));
+            if let Some(obj) = element.as_object_mut() {
+                obj.remove("slicing");
+            }
+            element["id"] = id.clone().into();
+            merge(&mut element, diff);
+            self.insert(Some(sliced), true, element);
+            return Ok(());
+        }
+
+        // A child of a complex type: expand the type, then merge
+        let parent = parent_id(&id);
+        let expandable = parent
+            .filter(|p| !self.has_children(p))
+            .and_then(|p| self.position(p));
+        if expandable.is_some_and(|index| self.expand(index, types)) {
+            return self.apply(diff, types);
+        }
+
+        // Otherwise inherit from the unsliced element, if any
+        let mut element = self
+            .position(&unsliced(&id))
+            .map(|i| self.elements[i].clone())
+            .unwrap_or_else(|| Value::Object(Map::new()));
+        element["id"] = id.clone().into();
+        merge(&mut element, diff);
+        self.insert(parent, false, element);
+        Ok(())
+    }
+}
+
+/// Apply the differential of `sd` to the snapshot elements of its base
+/// definition
+///
+/// `types` returns the snapshot elements of a data type's definition by type
+/// code, for expanding the children of complex types.
+pub fn generate(
+    sd: &Value,
+    base: &[Value],
+    types: &dyn Fn(&str) -> Option<Vec<Value>>,
+) -> Result<Vec<Value>, FhirError> {
+    let differential = sd
+        .pointer("/differential/element")
+        .and_then(Value::as_array)
+        .ok_or_else(|| FhirError::Invalid("StructureDefinition has no differential".to_string()))?;
+
+    let mut snapshot = Snapshot {
+        elements: base.to_vec(),
+    };
+    for diff in differential {
+        snapshot.apply(diff, types)?;
+    }
+
+    // Every element records where it came from in the base
+    for element in &mut snapshot.elements {
+        if element.get("base").is_none() {
+            element["base"] = serde_json::json!({
+                "path": element["path"],
+                "min": element.get("min").cloned().unwrap_or_else(|| 0.into()),
+                "max": element.get("max").cloned().unwrap_or_else(|| "*".into()),
+            });
+        }
+    }
+    Ok(snapshot.elements)
+}
@@ -0,0 +1,114 @@
+//! FHIR version selection and version tagging of stored resources
+//!
+//! All versions share one store; each resource carries a `meta.tag` with the
+//! FHIR version it was written in so readers can tell them apart.
+
+use serde_json::{Value, json};
+
+/// Tag system used to record the FHIR version of a stored resource
+pub const VERSION_TAG_SYSTEM: &str = "http://hl7.org/fhir/FHIR-version";
+
+/// FHIR versions understood by the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FhirVersion {
+    #[default]
+    R4B,
+    R5,
+}
+
+impl FhirVersion {
+    /// Short version code used in MIME parameters and tags (e.g. `4.3`)
+    pub fn code(&self) -> &'static str {
+        match self {
+            FhirVersion::R4B => "4.3",
+            FhirVersion::R5 => "5.0",
+        }
+    }
+
+    /// Full semantic version (e.g. `4.3.0`)
+    pub fn full(&self) -> &'static str {
+        match self {
+            FhirVersion::R4B => "4.3.0",
+            FhirVersion::R5 => "5.0.0",
+        }
+    }
+
+    /// Parse a version code such as `5.0` or `5.0.0`
+    pub fn from_code(code: &str) -> Option<Self> {
+        let mut parts = code.trim().split('.');
+        match (parts.next(), parts.next()) {
+            (Some("4"), Some("3")) => Some(FhirVersion::R4B),
+            (Some("5"), Some("0")) => Some(FhirVersion::R5),
+            _ => None,
+        }
+    }
+
+    /// Extract the `fhirVersion` parameter from a MIME type, e.g.
+    /// `application/fhir+json; fhirVersion=5.0`
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        mime.split(';').skip(1).find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("fhirVersion") {
+                Self::from_code(value.trim().trim_matches('"'))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Whether this build can handle the version (R5 needs the `r5` feature)
+    pub fn is_supported(&self) -> bool {
+        match self {
+            FhirVersion::R4B => true,
+            FhirVersion::R5 => cfg!(feature = "r5"),
+        }
+    }
+
+    /// Validate a Patient by deserializing it into this version's model
+    pub fn validate_patient(&self, resource: Value) -> Result<(), String> {
+        match self {
+            FhirVersion::R4B => {
+                serde_json::from_value::<fhir_sdk::r4b::resources::Patient>(resource)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "r5")]
+            FhirVersion::R5 => serde_json::from_value::<fhir_sdk::r5::resources::Patient>(resource)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            #[cfg(not(feature = "r5"))]
+            FhirVersion::R5 => Err("FHIR R5 support is not enabled in this build".to_string()),
+        }
+    }
+
+    /// Record this version in the resource's `meta.tag`, replacing any
+    /// existing version tag
+    pub fn tag(&self, resource: &mut Value) {
+        let Some(obj) = resource.as_object_mut() else {
+            return;
+        };
+        let meta = obj.entry("meta").or_insert_with(|| json!({}));
+        let Some(meta) = meta.as_object_mut() else {
+            return;
+        };
+        let tags = meta.entry("tag").or_insert_with(|| json!([]));
+        if let Some(tags) = tags.as_array_mut() {
+            tags.retain(|t| t.get("system").and_then(|s| s.as_str()) != Some(VERSION_TAG_SYSTEM));
+            tags.push(json!({"system": VERSION_TAG_SYSTEM, "code": self.code()}));
+        }
+    }
+
+    /// Read the version tag of a stored resource (untagged resources are R4B)
+    pub fn of_resource(resource: &Value) -> Self {
+        resource
+            .get("meta")
+            .and_then(|m| m.get("tag"))
+            .and_then(|t| t.as_array())
+            .into_iter()
+            .flatten()
+            .find(|t| t.get("system").and_then(|s| s.as_str()) == Some(VERSION_TAG_SYSTEM))
+            .and_then(|t| t.get("code").and_then(|c| c.as_str()))
+            .and_then(Self::from_code)
+            .unwrap_or(FhirVersion::R4B)
+    }
+}
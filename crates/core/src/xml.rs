@@ -0,0 +1,537 @@
+//! FHIR XML serialization
+//!
+//! Converts between the JSON and XML representations: primitives become
+//! `<name value="..."/>` (with `_name` ids and extensions folded in), `id` and
+//! `url` of datatypes and extensions are attributes, repeating elements are
+//! repeated, nested resources are wrapped in an element named after their
+//! type, and `text.div` is carried as literal XHTML. Element order follows
+//! the JSON member order.
+//!
+//! XML does not say which elements repeat or which primitives are booleans
+//! or numbers, so parsing uses the tables below; they cover Patient, Bundle,
+//! OperationOutcome and the datatypes they use. An element that occurs more
+//! than once always becomes an array.
+
+use serde_json::{Map, Value};
+
+use crate::error::FhirError;
+
+/// Namespace of FHIR XML documents
+const FHIR_NAMESPACE: &str = "http://hl7.org/fhir";
+
+/// Deepest element nesting parsed; deeper documents are rejected rather than
+/// exhausting the stack
+const MAX_DEPTH: usize = 100;
+
+/// Elements that are arrays in JSON even when they occur once
+const REPEATING: &[&str] = &[
+    "address",
+    "coding",
+    "communication",
+    "contact",
+    "contained",
+    "entry",
+    "expression",
+    "extension",
+    "generalPractitioner",
+    "given",
+    "identifier",
+    "issue",
+    "line",
+    "link",
+    "location",
+    "modifierExtension",
+    "name",
+    "parameter",
+    "part",
+    "photo",
+    "prefix",
+    "profile",
+    "relationship",
+    "security",
+    "suffix",
+    "tag",
+    "telecom",
+];
+
+/// Boolean primitives not named `*Boolean`
+const BOOLEANS: &[&str] = &["active", "preferred", "userSelected"];
+
+/// Numeric primitives not named `*Integer`, `*Decimal`, `*UnsignedInt` or
+/// `*PositiveInt`
+const NUMBERS: &[&str] = &["rank", "score", "total"];
+
+/// Serialize a resource as a FHIR XML document
+pub fn to_xml(resource: &Value) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    if let Some(obj) = resource.as_object() {
+        write_resource(&mut out, obj, 0);
+    }
+    out
+}
+
+/// Parse a FHIR XML document into its JSON representation
+pub fn from_xml(xml: &str) -> Result<Value, FhirError> {
+    let mut parser = Parser { xml, pos: 0 };
+    parser.skip_misc()?;
+    let root = parser.element(0)?;
+    parser.skip_misc()?;
+    if parser.pos < xml.len() {
+        return Err(parser.error("content after the root element"));
+    }
+    resource_json(&root, xml)
+}
+
+fn indent(out: &mut String, depth: usize) {
+    out.extend(std::iter::repeat_n("  ", depth));
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "&#10;")
+}
+
+fn write_resource(out: &mut String, obj: &Map<String, Value>, depth: usize) {
+    let resource_type = obj
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .unwrap_or("Resource");
+    indent(out, depth);
+    out.push_str(&format!(
+        "<{} xmlns=\"{}\">\n",
+        resource_type, FHIR_NAMESPACE
+    ));
+    write_members(out, obj, depth + 1, true);
+    indent(out, depth);
+    out.push_str(&format!("</{}>\n", resource_type));
+}
+
+fn write_members(out: &mut String, obj: &Map<String, Value>, depth: usize, is_resource: bool) {
+    for (name, value) in obj {
+        let is_attribute = !is_resource && (name == "id" || name == "url") && value.is_string();
+        if name == "resourceType" || name.starts_with('_') || is_attribute {
+            continue;
+        }
+        let extension = obj.get(&format!("_{}", name));
+        match value {
+            Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let extension = extension.and_then(|e| e.get(i));
+                    write_element(out, name, item, extension, depth);
+                }
+            }
+            value => write_element(out, name, value, extension, depth),
+        }
+    }
+
+    // Primitives that only have an id or extensions
+    for (name, extension) in obj {
+        let Some(base) = name.strip_prefix('_').filter(|b| !obj.contains_key(*b)) else {
+            continue;
+        };
+        match extension {
+            Value::Array(items) => {
+                for item in items {
+                    write_element(out, base, &Value::Null, Some(item), depth);
+                }
+            }
+            extension => write_element(out, base, &Value::Null, Some(extension), depth),
+        }
+    }
+}
+
+/// `id` and `url` attributes of a datatype or extension
+fn attributes(obj: &Map<String, Value>) -> String {
+    ["id", "url"]
+        .iter()
+        .filter_map(|key| {
+            let value = obj.get(*key)?.as_str()?;
+            Some(format!(" {}=\"{}\"", key, escape(value)))
+        })
+        .collect()
+}
+
+fn write_element(
+    out: &mut String,
+    name: &str,
+    value: &Value,
+    extension: Option<&Value>,
+    depth: usize,
+) {
+    match value {
+        Value::Object(obj) if obj.contains_key("resourceType") => {
+            indent(out, depth);
+            out.push_str(&format!("<{}>\n", name));
+            write_resource(out, obj, depth + 1);
+            indent(out, depth);
+            out.push_str(&format!("</{}>\n", name));
+        }
+        Value::Object(obj) => {
+            let attributes = attributes(obj);
+            let has_children = obj
+                .iter()
+                .any(|(k, v)| !((k == "id" || k == "url") && v.is_string()));
+            indent(out, depth);
+            if has_children {
+                out.push_str(&format!("<{}{}>\n", name, attributes));
+                write_members(out, obj, depth + 1, false);
+                indent(out, depth);
+                out.push_str(&format!("</{}>\n", name));
+            } else {
+                out.push_str(&format!("<{}{}/>\n", name, attributes));
+            }
+        }
+        Value::String(div) if name == "div" => {
+            indent(out, depth);
+            out.push_str(div);
+            out.push('\n');
+        }
+        primitive => {
+            let value = match primitive {
+                Value::Null => String::new(),
+                Value::String(s) => format!(" value=\"{}\"", escape(s)),
+                other => format!(" value=\"{}\"", other),
+            };
+            let id = extension
+                .and_then(|e| e.get("id"))
+                .and_then(Value::as_str)
+                .map(|id| format!(" id=\"{}\"", escape(id)))
+                .unwrap_or_default();
+            let extensions = extension
+                .and_then(|e| e.get("extension"))
+                .and_then(Value::as_array)
+                .filter(|e| !e.is_empty());
+            indent(out, depth);
+            match extensions {
+                Some(extensions) => {
+                    out.push_str(&format!("<{}{}{}>\n", name, id, value));
+                    for item in extensions {
+                        write_element(out, "extension", item, None, depth + 1);
+                    }
+                    indent(out, depth);
+                    out.push_str(&format!("</{}>\n", name));
+                }
+                None => out.push_str(&format!("<{}{}{}/>\n", name, id, value)),
+            }
+        }
+    }
+}
+
+/// A parsed element; `start..end` is its span in the document
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    start: usize,
+    end: usize,
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Just enough of an XML parser for FHIR documents: elements, attributes,
+/// comments, processing instructions and character references. CDATA
+/// sections are rejected, since FHIR XML carries no text content outside the
+/// narrative and the narrative is XHTML without them.
+struct Parser<'a> {
+    xml: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> FhirError {
+        FhirError::Invalid(format!("Invalid FHIR XML at byte {}: {}", self.pos, msg))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.xml[self.pos..]
+    }
+
+    fn skip_past(&mut self, terminator: &str) -> Result<(), FhirError> {
+        match self.rest().find(terminator) {
+            Some(i) => {
+                self.pos += i + terminator.len();
+                Ok(())
+            }
+            None => Err(self.error(&format!("missing {}", terminator))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.xml.len() - trimmed.len();
+    }
+
+    /// Whitespace, comments, processing instructions and a doctype
+    fn skip_misc(&mut self) -> Result<(), FhirError> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!DOCTYPE") {
+                return Err(self.error("document type declarations are not allowed"));
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, FhirError> {
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        let name = &self.rest()[..len];
+        self.pos += len;
+        // Namespace prefixes are not significant here
+        Ok(name.rsplit(':').next().unwrap_or(name).to_string())
+    }
+
+    /// The element at the current position, `depth` levels below the root
+    fn element(&mut self, depth: usize) -> Result<Element, FhirError> {
+        let start = self.pos;
+        if !self.rest().starts_with('<') {
+            return Err(self.error("expected an element"));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(self.error(&format!(
+                "elements are nested more than {} levels deep",
+                MAX_DEPTH
+            )));
+        }
+        self.pos += 1;
+        let name = self.name()?;
+        let mut attributes = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                    start,
+                    end: self.pos,
+                });
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(self.error("expected '=' after an attribute name"));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(q @ ('"' | '\'')) => q,
+                _ => return Err(self.error("expected a quoted attribute value")),
+            };
+            self.pos += 1;
+            let Some(len) = self.rest().find(quote) else {
+                return Err(self.error("unterminated attribute value"));
+            };
+            let value = decode(&self.rest()[..len]).map_err(|msg| self.error(&msg))?;
+            self.pos += len + 1;
+            attributes.push((attribute, value));
+        }
+
+        let mut children = Vec::new();
+        loop {
+            let text = self.rest().find('<').unwrap_or(self.rest().len());
+            self.pos += text;
+            if self.rest().is_empty() {
+                return Err(self.error(&format!("unclosed element <{}>", name)));
+            }
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let closing = self.name()?;
+                if closing != name {
+                    return Err(self.error(&format!("</{}> does not close <{}>", closing, name)));
+                }
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return Err(self.error("expected '>'"));
+                }
+                self.pos += 1;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children,
+                    start,
+                    end: self.pos,
+                });
+            }
+            if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<![CDATA[") {
+                return Err(self.error("CDATA sections are not allowed"));
+            } else if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else {
+                children.push(self.element(depth + 1)?);
+            }
+        }
+    }
+}
+
+/// Replace entity and character references
+fn decode(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let end = rest
+            .find(';')
+            .ok_or_else(|| "unterminated entity reference".to_string())?;
+        let entity = &rest[..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity
+                    .strip_prefix('#')
+                    .and_then(|dec| dec.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        };
+        out.push(c.ok_or_else(|| format!("unknown entity &{};", entity))?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn resource_json(element: &Element, xml: &str) -> Result<Value, FhirError> {
+    if !element.name.starts_with(|c: char| c.is_ascii_uppercase()) {
+        return Err(FhirError::Invalid(format!(
+            "<{}> is not a resource",
+            element.name
+        )));
+    }
+    let mut obj = Map::new();
+    obj.insert(
+        "resourceType".to_string(),
+        Value::String(element.name.clone()),
+    );
+    members(&mut obj, element, xml)?;
+    Ok(Value::Object(obj))
+}
+
+/// Add the JSON members for an element's children to `obj`
+fn members(obj: &mut Map<String, Value>, element: &Element, xml: &str) -> Result<(), FhirError> {
+    let mut names: Vec<&str> = Vec::new();
+    for child in &element.children {
+        if !names.contains(&child.name.as_str()) {
+            names.push(&child.name);
+        }
+    }
+
+    for name in names {
+        let items = element
+            .children
+            .iter()
+            .filter(|c| c.name == name)
+            .map(|c| element_json(c, xml))
+            .collect::<Result<Vec<_>, _>>()?;
+        let repeating = items.len() > 1 || REPEATING.contains(&name);
+        let (values, extensions): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+
+        for (key, values) in [
+            (name.to_string(), values),
+            (format!("_{}", name), extensions),
+        ] {
+            if values.iter().all(Option::is_none) {
+                continue;
+            }
+            let value = match repeating {
+                true => Value::Array(
+                    values
+                        .into_iter()
+                        .map(|v| v.unwrap_or(Value::Null))
+                        .collect(),
+                ),
+                false => values.into_iter().flatten().next().unwrap_or(Value::Null),
+            };
+            obj.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// JSON value and `_name` primitive extension of one element
+fn element_json(element: &Element, xml: &str) -> Result<(Option<Value>, Option<Value>), FhirError> {
+    if element.name == "div" {
+        let div = xml[element.start..element.end].to_string();
+        return Ok((Some(Value::String(div)), None));
+    }
+
+    // A resource wrapped in an element such as `contained` or `resource`
+    if let [inner] = element.children.as_slice() {
+        let is_resource = inner.name.starts_with(|c: char| c.is_ascii_uppercase());
+        if is_resource && element.attribute("value").is_none() {
+            return Ok((Some(resource_json(inner, xml)?), None));
+        }
+    }
+
+    let value = element.attribute("value");
+    let only_extensions = element.children.iter().all(|c| c.name == "extension");
+    if value.is_some() || (only_extensions && element.attribute("url").is_none()) {
+        let mut extension = Map::new();
+        if let Some(id) = element.attribute("id") {
+            extension.insert("id".to_string(), Value::String(id.to_string()));
+        }
+        members(&mut extension, element, xml)?;
+        let extension = Some(Value::Object(extension)).filter(|e| e != &Value::Object(Map::new()));
+        return Ok((value.map(|v| primitive(&element.name, v)), extension));
+    }
+
+    let mut obj = Map::new();
+    for key in ["id", "url"] {
+        if let Some(value) = element.attribute(key) {
+            obj.insert(key.to_string(), Value::String(value.to_string()));
+        }
+    }
+    members(&mut obj, element, xml)?;
+    Ok((Some(Value::Object(obj)), None))
+}
+
+/// A primitive's JSON value: booleans and numbers by element name, strings
+/// otherwise
+fn primitive(name: &str, value: &str) -> Value {
+    let is_boolean = BOOLEANS.contains(&name) || name.ends_with("Boolean");
+    let is_number = NUMBERS.contains(&name)
+        || ["Integer", "Decimal", "UnsignedInt", "PositiveInt"]
+            .iter()
+            .any(|suffix| name.ends_with(suffix));
+    match (value.parse::<bool>(), value.parse::<serde_json::Number>()) {
+        (Ok(b), _) if is_boolean => Value::Bool(b),
+        (_, Ok(n)) if is_number => Value::Number(n),
+        _ => Value::String(value.to_string()),
+    }
+}
@@ -0,0 +1,296 @@
+-- Upgrade fhir_pg_ext from 0.1.0 to 0.2.0
+--
+-- Applied by: ALTER EXTENSION fhir_pg_ext UPDATE TO '0.2.0';
+
+-- History: deletions are DELETE rows without data
+ALTER TABLE fhir_history
+    ADD COLUMN IF NOT EXISTS method TEXT NOT NULL DEFAULT 'PUT'
+        CHECK (method IN ('POST', 'PUT', 'DELETE'));
+ALTER TABLE fhir_history ALTER COLUMN data DROP NOT NULL;
+
+UPDATE fhir_history SET method = 'POST' WHERE version = 1;
+UPDATE fhir_history SET method = 'DELETE', data = NULL
+ WHERE data = '{"deleted": true}'::jsonb;
+
+ALTER TABLE fhir_history
+    ADD CHECK ((method = 'DELETE') = (data IS NULL));
+
+-- History: optional merge-patch storage
+ALTER TABLE fhir_history
+    ADD COLUMN IF NOT EXISTS delta BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Search index bookkeeping; existing resources read as stale until
+-- fhir_reindex has run
+ALTER TABLE fhir_resources
+    ADD COLUMN IF NOT EXISTS index_version INTEGER,
+    ADD COLUMN IF NOT EXISTS index_revision INTEGER;
+
+-- Tables added since 0.1.0 (definitions as in schema.sql)
+CREATE TABLE IF NOT EXISTS fhir_search_index (
+    resource_id     UUID NOT NULL REFERENCES fhir_resources(id),
+    resource_type   TEXT NOT NULL,
+    param_name      TEXT NOT NULL,
+    value           TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS fhir_locks (
+    resource_id     UUID PRIMARY KEY,
+    resource_type   TEXT NOT NULL,
+    owner           TEXT NOT NULL,
+    expires_at      TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS fhir_ai_audit (
+    id              BIGSERIAL PRIMARY KEY,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    request_id      TEXT,
+    operation       TEXT NOT NULL,
+    model           TEXT NOT NULL,
+    prompt_hash     TEXT NOT NULL,
+    prompt          TEXT,
+    response        TEXT,
+    tool_calls      JSONB NOT NULL DEFAULT '[]',
+    resources       TEXT[] NOT NULL DEFAULT '{}',
+    input_tokens    BIGINT NOT NULL DEFAULT 0,
+    output_tokens   BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS fhir_backfills (
+    name            TEXT PRIMARY KEY,
+    last_id         UUID,
+    processed       BIGINT NOT NULL DEFAULT 0,
+    started_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at     TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS fhir_outbox (
+    id              BIGSERIAL PRIMARY KEY,
+    resource_type   TEXT NOT NULL,
+    resource_id     UUID NOT NULL,
+    version         INTEGER NOT NULL,
+    method          TEXT NOT NULL CHECK (method IN ('POST', 'PUT', 'DELETE')),
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    attempts        INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_error      TEXT,
+    delivered_at    TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_history_type_created
+    ON fhir_history(resource_type, created_at, id);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_search_index_param
+    ON fhir_search_index(resource_type, param_name, value);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_search_index_resource
+    ON fhir_search_index(resource_id);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_ai_audit_created
+    ON fhir_ai_audit(created_at);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_outbox_pending
+    ON fhir_outbox(next_attempt_at, id) WHERE delivered_at IS NULL;
+
+-- Row-level security (rls.rs)
+CREATE TABLE IF NOT EXISTS fhir_rls_grants (
+    role_name        TEXT NOT NULL,
+    tenant           TEXT NOT NULL,
+    security_labels  TEXT[] NOT NULL DEFAULT '{}',
+    PRIMARY KEY (role_name, tenant)
+);
+
+CREATE OR REPLACE FUNCTION fhir_rls_visible(data JSONB) RETURNS BOOLEAN
+LANGUAGE sql STABLE AS $$
+    WITH grant_row AS (
+        SELECT tenant, security_labels
+          FROM fhir_rls_grants
+         WHERE role_name = current_user
+           AND tenant = current_setting('fhir.tenant', true)
+    ), allowed AS (
+        SELECT COALESCE((SELECT tenant FROM grant_row), '') AS tenant,
+               to_jsonb(ARRAY(
+                   SELECT unnest(security_labels) FROM grant_row
+                   INTERSECT
+                   SELECT unnest(string_to_array(current_setting('fhir.security_labels', true), ','))
+               )) AS labels
+    )
+    SELECT NOT jsonb_path_exists(
+               data,
+               '$.meta.tag[*] ? (@.system == $system && @.code != $tenant)',
+               jsonb_build_object('system', 'urn:fhir:tenant', 'tenant', tenant))
+       AND NOT jsonb_path_exists(
+               data,
+               '$.meta.security[*] ? (!(@.code == $labels[*]))',
+               jsonb_build_object('labels', labels))
+      FROM allowed
+$$;
+
+-- Element-level encryption (crypto.rs)
+CREATE OR REPLACE FUNCTION fhir_try_decrypt(ciphertext TEXT, key TEXT) RETURNS TEXT
+LANGUAGE plpgsql STABLE AS $$
+BEGIN
+    RETURN pgp_sym_decrypt(decode(ciphertext, 'base64'), key);
+EXCEPTION WHEN OTHERS THEN
+    RETURN NULL;
+END
+$$;
+
+-- Functions whose signature changed
+DROP FUNCTION IF EXISTS fhir_history(TEXT, UUID);
+CREATE FUNCTION fhir_history(
+    resource_type TEXT,
+    resource_id UUID,
+    row_limit INT DEFAULT NULL,
+    row_offset INT DEFAULT 0
+) RETURNS TABLE (version INT, method TEXT, data JSONB, created_at TIMESTAMPTZ)
+LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_history_wrapper';
+
+DROP FUNCTION IF EXISTS fhir_search(TEXT, JSONB);
+CREATE FUNCTION fhir_search(resource_type TEXT, params JSONB)
+RETURNS TABLE (id UUID, data JSONB, score DOUBLE PRECISION)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_search_wrapper';
+
+DROP FUNCTION IF EXISTS fhir_update(TEXT, UUID, JSONB);
+CREATE FUNCTION fhir_update(
+    resource_type TEXT,
+    id UUID,
+    data JSONB,
+    expected_version INT DEFAULT NULL
+) RETURNS INT
+LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_update_wrapper';
+
+-- Functions added since 0.1.0
+CREATE FUNCTION fhir_ext_functions()
+RETURNS TABLE (name TEXT, signature TEXT, version TEXT)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_ext_functions_wrapper';
+
+CREATE FUNCTION fhir_get_many(resource_type TEXT, ids UUID[])
+RETURNS TABLE (id UUID, data JSONB)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_get_many_wrapper';
+
+CREATE FUNCTION fhir_transaction(bundle JSONB) RETURNS JSONB
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_transaction_wrapper';
+
+CREATE FUNCTION fhir_name_score(data JSONB, query TEXT) RETURNS DOUBLE PRECISION
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_name_score_wrapper';
+
+CREATE FUNCTION fhir_count(resource_type TEXT, params JSONB) RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_count_wrapper';
+
+CREATE FUNCTION fhir_count_estimate(resource_type TEXT, params JSONB) RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_count_estimate_wrapper';
+
+CREATE FUNCTION fhir_history_since(
+    resource_type TEXT,
+    since TIMESTAMPTZ,
+    cursor_micros BIGINT,
+    cursor_id UUID,
+    count INT
+) RETURNS TABLE (
+    resource_id UUID,
+    version INT,
+    method TEXT,
+    data JSONB,
+    created_at TIMESTAMPTZ,
+    cursor_micros BIGINT,
+    history_id UUID
+)
+LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_history_since_wrapper';
+
+CREATE FUNCTION fhir_history_at(
+    resource_type TEXT,
+    at TIMESTAMPTZ,
+    after_id UUID,
+    count INT
+) RETURNS TABLE (
+    resource_id UUID,
+    version INT,
+    method TEXT,
+    data JSONB,
+    created_at TIMESTAMPTZ
+)
+LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_history_at_wrapper';
+
+CREATE FUNCTION fhir_get_as_of(resource_type TEXT, resource_id UUID, as_of TIMESTAMPTZ)
+RETURNS JSONB
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_get_as_of_wrapper';
+
+CREATE FUNCTION fhir_export_snapshot(resource_type TEXT, as_of TIMESTAMPTZ)
+RETURNS TABLE (id UUID, version INT, data JSONB)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_export_snapshot_wrapper';
+
+CREATE FUNCTION fhir_reindex(resource_type TEXT, batch_size INT) RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_reindex_wrapper';
+
+CREATE FUNCTION fhir_reindex_check(resource_type TEXT)
+RETURNS TABLE (id UUID, version INT, index_version INT, index_revision INT)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_reindex_check_wrapper';
+
+CREATE FUNCTION fhir_lock(resource_type TEXT, resource_id UUID, owner TEXT, lease_seconds INT)
+RETURNS TIMESTAMPTZ
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_lock_wrapper';
+
+CREATE FUNCTION fhir_unlock(resource_type TEXT, resource_id UUID, owner TEXT) RETURNS BOOLEAN
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_unlock_wrapper';
+
+CREATE FUNCTION fhir_lock_holder(resource_type TEXT, resource_id UUID) RETURNS TEXT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_lock_holder_wrapper';
+
+CREATE FUNCTION fhir_maintenance_report() RETURNS JSONB
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_maintenance_report_wrapper';
+
+CREATE FUNCTION fhir_backfill(name TEXT, batch_size INT) RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_backfill_wrapper';
+
+CREATE FUNCTION fhir_backfill_reset(name TEXT) RETURNS void
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_backfill_reset_wrapper';
+
+CREATE FUNCTION fhir_backfill_status()
+RETURNS TABLE (
+    name TEXT,
+    description TEXT,
+    processed BIGINT,
+    updated_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ
+)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_backfill_status_wrapper';
+
+CREATE FUNCTION fhir_create_publication(name TEXT) RETURNS BOOLEAN
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_create_publication_wrapper';
+
+CREATE FUNCTION fhir_cdc_create_slot(slot TEXT) RETURNS BOOLEAN
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_cdc_create_slot_wrapper';
+
+CREATE FUNCTION fhir_outbox_status()
+RETURNS TABLE (pending BIGINT, failing BIGINT, oldest TIMESTAMPTZ)
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_outbox_status_wrapper';
+
+CREATE FUNCTION fhir_outbox_prune(retention_days INT) RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_outbox_prune_wrapper';
+
+CREATE FUNCTION fhir_rls_enable(api_role TEXT DEFAULT NULL) RETURNS BOOLEAN
+LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_rls_enable_wrapper';
+
+CREATE FUNCTION fhir_rls_disable() RETURNS BOOLEAN
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_rls_disable_wrapper';
+
+CREATE FUNCTION fhir_rls_grant(
+    role_name TEXT,
+    tenant TEXT,
+    security_labels TEXT[] DEFAULT ARRAY[]::TEXT[]
+) RETURNS void
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_rls_grant_wrapper';
+
+CREATE FUNCTION fhir_set_context(tenant TEXT, security_labels TEXT[] DEFAULT ARRAY[]::TEXT[])
+RETURNS BOOLEAN
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_set_context_wrapper';
+
+CREATE FUNCTION fhir_clear_context() RETURNS void
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_clear_context_wrapper';
+
+CREATE FUNCTION fhir_rotate_encryption(batch_size INT) RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_rotate_encryption_wrapper';
+
+CREATE FUNCTION fhir_encryption_pending() RETURNS BIGINT
+STRICT LANGUAGE c AS 'MODULE_PATHNAME', 'fhir_encryption_pending_wrapper';
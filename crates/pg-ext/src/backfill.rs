@@ -0,0 +1,278 @@
+//! Resumable, throttled backfills over stored resources
+//!
+//! A backfill walks every live row of `fhir_resources` in id order, a batch
+//! per call, applying one transform (re-extracting search parameters,
+//! encrypting newly configured paths, ...). Backfills that rewrite stored
+//! content also walk deleted resources and rewrite every history version of
+//! each resource. Progress is kept in
+//! `fhir_backfills`, so a backfill resumes where it stopped after a restart
+//! and runners on several connections never process the same batch.
+//!
+//! Each call holds row locks only for its own batch, which keeps large tables
+//! online while they are transformed. Two settings throttle the work:
+//! `fhir.backfill_max_batch` caps the rows per call (default 1000) and
+//! `fhir.backfill_pause_ms` sleeps before each batch (default 0).
+
+use pgrx::datum::TimestampWithTimeZone;
+use pgrx::prelude::*;
+use serde_json::Value;
+
+use crate::crypto;
+use crate::index;
+
+/// Default cap on rows per batch
+const DEFAULT_MAX_BATCH: i32 = 1000;
+
+/// Transform applied to one row; returns whether `data` was changed and must
+/// be written back
+type Transform = fn(resource_type: &str, id: pgrx::Uuid, version: i32, data: &mut Value) -> bool;
+
+/// Transform applied to stored content that is not live (history versions,
+/// deleted resources); returns whether `data` was changed
+type StoredTransform = fn(data: &mut Value) -> bool;
+
+/// A registered backfill
+struct Backfill {
+    name: &'static str,
+    description: &'static str,
+    transform: Transform,
+    /// Also applied to deleted resources and to every history version
+    stored: Option<StoredTransform>,
+}
+
+/// Every backfill `fhir_backfill` can run
+const BACKFILLS: &[Backfill] = &[
+    Backfill {
+        name: "search-index",
+        description: "Re-extract the search parameters of every live resource",
+        transform: reindex,
+        stored: None,
+    },
+    Backfill {
+        name: "encryption",
+        description: "Encrypt the configured paths of resources and history versions stored before they were configured",
+        transform: encrypt,
+        stored: Some(encrypt_stored),
+    },
+];
+
+fn reindex(resource_type: &str, id: pgrx::Uuid, version: i32, data: &mut Value) -> bool {
+    index::index_resource(resource_type, id, version, data);
+    false
+}
+
+fn encrypt(resource_type: &str, id: pgrx::Uuid, version: i32, data: &mut Value) -> bool {
+    let changed = encrypt_stored(data);
+    if changed {
+        index::index_resource(resource_type, id, version, data);
+    }
+    changed
+}
+
+fn encrypt_stored(data: &mut Value) -> bool {
+    let before = data.clone();
+    crypto::encrypt_resource(data);
+    *data != before
+}
+
+/// Apply `transform` to every history version of resource `id`
+fn transform_history(id: pgrx::Uuid, transform: StoredTransform) {
+    let versions: Vec<(pgrx::Uuid, pgrx::JsonB)> = Spi::connect_mut(|client| {
+        let mut versions = Vec::new();
+        let tup_table = client.update(
+            "SELECT id, data FROM fhir_history
+              WHERE resource_id = $1 AND data IS NOT NULL
+              FOR UPDATE",
+            None,
+            &[id.into()],
+        )?;
+
+        for row in tup_table {
+            let history_id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
+            let data: pgrx::JsonB = row.get(2)?.expect("data should not be null");
+            versions.push((history_id, data));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(versions)
+    })
+    .expect("Failed to select history versions");
+
+    for (history_id, mut data) in versions {
+        if transform(&mut data.0) {
+            Spi::run_with_args(
+                "UPDATE fhir_history SET data = $1 WHERE id = $2",
+                &[data.into(), history_id.into()],
+            )
+            .expect("Failed to store backfilled history version");
+        }
+    }
+}
+
+fn find(name: &str) -> &'static Backfill {
+    BACKFILLS
+        .iter()
+        .find(|b| b.name == name)
+        .unwrap_or_else(|| error!("Unknown backfill '{}'", name))
+}
+
+/// Integer setting, or `default` when unset or invalid
+fn int_setting(name: &str, default: i32) -> i32 {
+    Spi::get_one_with_args::<String>("SELECT current_setting($1, true)", &[name.into()])
+        .ok()
+        .flatten()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Run the next batch of backfill `name`
+///
+/// Processes up to `batch_size` rows (capped by `fhir.backfill_max_batch`)
+/// after the last row of the previous batch and records the new position.
+/// Returns the number of rows processed; 0 once the backfill has finished.
+/// Call repeatedly, each call in its own transaction.
+#[pg_extern]
+fn fhir_backfill(name: &str, batch_size: i32) -> i64 {
+    let backfill = find(name);
+    let limit = batch_size
+        .min(int_setting("fhir.backfill_max_batch", DEFAULT_MAX_BATCH))
+        .max(1);
+
+    let pause_ms = int_setting("fhir.backfill_pause_ms", 0);
+    if pause_ms > 0 {
+        Spi::run_with_args("SELECT pg_sleep($1::float8 / 1000)", &[pause_ms.into()])
+            .expect("Failed to pause backfill");
+    }
+
+    // Lock the backfill's state so concurrent runners take turns
+    Spi::run_with_args(
+        "INSERT INTO fhir_backfills (name) VALUES ($1) ON CONFLICT (name) DO NOTHING",
+        &[name.into()],
+    )
+    .expect("Failed to register backfill");
+    let (last_id, finished) = Spi::connect_mut(|client| {
+        let row = client
+            .update(
+                "SELECT last_id, finished_at IS NOT NULL FROM fhir_backfills
+                  WHERE name = $1 FOR UPDATE",
+                None,
+                &[name.into()],
+            )?
+            .first();
+        let last_id: Option<pgrx::Uuid> = row.get(1)?;
+        let finished: bool = row.get(2)?.unwrap_or(false);
+        Ok::<_, pgrx::spi::SpiError>((last_id, finished))
+    })
+    .expect("Failed to read backfill state");
+    if finished {
+        return 0;
+    }
+
+    let rows: Vec<(pgrx::Uuid, String, i32, bool, pgrx::JsonB)> = Spi::connect_mut(|client| {
+        let mut rows = Vec::new();
+        let tup_table = client.update(
+            "SELECT id, resource_type, version, deleted_at IS NULL, data FROM fhir_resources
+              WHERE ($3 OR deleted_at IS NULL) AND ($1::uuid IS NULL OR id > $1)
+              ORDER BY id
+              LIMIT $2
+              FOR UPDATE",
+            None,
+            &[
+                last_id.into(),
+                limit.into(),
+                backfill.stored.is_some().into(),
+            ],
+        )?;
+
+        for row in tup_table {
+            let id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
+            let resource_type: String = row.get(2)?.expect("resource_type should not be null");
+            let version: i32 = row.get(3)?.expect("version should not be null");
+            let live: bool = row.get(4)?.expect("live should not be null");
+            let data: pgrx::JsonB = row.get(5)?.expect("data should not be null");
+            rows.push((id, resource_type, version, live, data));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(rows)
+    })
+    .expect("Failed to select backfill batch");
+
+    let processed = rows.len() as i64;
+    let next_id = rows.last().map(|(id, ..)| *id).or(last_id);
+    for (id, resource_type, version, live, mut data) in rows {
+        if let Some(stored) = backfill.stored {
+            transform_history(id, stored);
+        }
+        let changed = match (live, backfill.stored) {
+            (true, _) => (backfill.transform)(&resource_type, id, version, &mut data.0),
+            (false, Some(stored)) => stored(&mut data.0),
+            (false, None) => false,
+        };
+        if changed {
+            Spi::run_with_args(
+                "UPDATE fhir_resources SET data = $1 WHERE id = $2",
+                &[data.into(), id.into()],
+            )
+            .expect("Failed to store backfilled resource");
+        }
+    }
+
+    Spi::run_with_args(
+        "UPDATE fhir_backfills
+            SET last_id = $2, processed = processed + $3, updated_at = NOW(),
+                finished_at = CASE WHEN $3 < $4 THEN NOW() END
+          WHERE name = $1",
+        &[
+            name.into(),
+            next_id.into(),
+            processed.into(),
+            i64::from(limit).into(),
+        ],
+    )
+    .expect("Failed to record backfill progress");
+
+    processed
+}
+
+/// Start backfill `name` over from the first row
+#[pg_extern]
+fn fhir_backfill_reset(name: &str) {
+    find(name);
+    Spi::run_with_args("DELETE FROM fhir_backfills WHERE name = $1", &[name.into()])
+        .expect("Failed to reset backfill");
+}
+
+/// Progress of every registered backfill
+///
+/// Backfills that never ran report 0 rows and no timestamps.
+#[pg_extern]
+fn fhir_backfill_status() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(description, String),
+        name!(processed, i64),
+        name!(updated_at, Option<TimestampWithTimeZone>),
+        name!(finished_at, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let rows: Vec<_> = BACKFILLS
+        .iter()
+        .map(|backfill| {
+            let state =
+                Spi::get_three_with_args::<i64, TimestampWithTimeZone, TimestampWithTimeZone>(
+                    "SELECT processed, updated_at, finished_at FROM fhir_backfills WHERE name = $1",
+                    &[backfill.name.into()],
+                )
+                .unwrap_or((None, None, None));
+            (
+                backfill.name.to_string(),
+                backfill.description.to_string(),
+                state.0.unwrap_or(0),
+                state.1,
+                state.2,
+            )
+        })
+        .collect();
+
+    TableIterator::new(rows)
+}
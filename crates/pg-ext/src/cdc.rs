@@ -0,0 +1,67 @@
+//! Change data capture helpers for logical replication
+//!
+//! Every resource write appends a row to `fhir_history`, so that table is the
+//! change stream. Two ways of consuming it are supported:
+//! - `fhir_create_publication` for `pgoutput`-based tools (e.g. Debezium)
+//! - `fhir_cdc_create_slot` for a `wal2json` slot, as read by the server's
+//!   built-in CDC consumer
+
+use pgrx::prelude::*;
+
+/// Create a publication over `fhir_history` if it does not already exist
+///
+/// Returns true if the publication was created.
+#[pg_extern]
+fn fhir_create_publication(name: &str) -> bool {
+    let exists = Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT 1 FROM pg_publication WHERE pubname = $1)",
+        &[name.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false);
+
+    if exists {
+        return false;
+    }
+
+    let ident: String = Spi::get_one_with_args("SELECT quote_ident($1)", &[name.into()])
+        .expect("Failed to quote publication name")
+        .expect("quote_ident should not return null");
+
+    Spi::run(&format!(
+        "CREATE PUBLICATION {} FOR TABLE fhir_history WITH (publish = 'insert')",
+        ident
+    ))
+    .expect("Failed to create publication");
+
+    true
+}
+
+/// Create a `wal2json` logical replication slot if it does not already exist
+///
+/// Must be called in its own transaction (PostgreSQL refuses to create a
+/// slot after the transaction has written anything). Returns true if the slot
+/// was created.
+#[pg_extern]
+fn fhir_cdc_create_slot(slot: &str) -> bool {
+    let exists = Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT 1 FROM pg_replication_slots WHERE slot_name = $1)",
+        &[slot.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false);
+
+    if exists {
+        return false;
+    }
+
+    Spi::run_with_args(
+        "SELECT pg_create_logical_replication_slot($1, 'wal2json')",
+        &[slot.into()],
+    )
+    .expect("Failed to create replication slot");
+
+    true
+}
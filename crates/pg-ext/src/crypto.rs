@@ -0,0 +1,404 @@
+//! Element-level encryption of sensitive values with pgcrypto
+//!
+//! String values at the paths listed in `fhir.encrypted_paths` are replaced
+//! on write by `enc:v1:<key id>:<base64 ciphertext>` (`pgp_sym_encrypt`,
+//! AES-256) before the resource reaches the table, history or search index.
+//! Reads decrypt the values at those paths again when the session has the
+//! key, so only roles configured with `fhir.encryption_key` see plaintext;
+//! other readers get the ciphertext. Values that fail to decrypt (wrong key,
+//! corrupt data) are returned as stored. Writes that already carry an
+//! encrypted value at a configured path are rejected, so a client cannot
+//! have a ciphertext copied from elsewhere decrypted under its own resource.
+//!
+//! Settings (usually set per database / role with `ALTER ... SET`):
+//! - `fhir.encrypted_paths`: comma-separated element paths, e.g.
+//!   `identifier[system=http://hl7.org/fhir/sid/us-ssn].value,telecom.value`
+//! - `fhir.encryption_key`: passphrase for new and existing values
+//! - `fhir.encryption_key_id`: label stored with each value (default
+//!   `default`), so values under an older key can be told apart
+//! - `fhir.encryption_previous_key` / `fhir.encryption_previous_key_id`: the
+//!   key being rotated away from; values under it are still decrypted on
+//!   read, and `fhir_rotate_encryption` re-encrypts them under the current key
+//!
+//! Encrypted elements can no longer be searched by value. Requires the
+//! `pgcrypto` extension.
+
+use serde_json::Value;
+
+use pgrx::prelude::*;
+
+use crate::index;
+
+/// Marker at the start of an encrypted value
+const PREFIX: &str = "enc:v1:";
+
+extension_sql!(
+    r#"
+-- pgp_sym_decrypt that returns NULL instead of failing on a wrong key or
+-- corrupt ciphertext
+CREATE OR REPLACE FUNCTION fhir_try_decrypt(ciphertext TEXT, key TEXT) RETURNS TEXT
+LANGUAGE plpgsql STABLE AS $$
+BEGIN
+    RETURN pgp_sym_decrypt(decode(ciphertext, 'base64'), key);
+EXCEPTION WHEN OTHERS THEN
+    RETURN NULL;
+END
+$$;
+"#,
+    name = "crypto",
+    requires = ["schema"]
+);
+
+/// Key id used when `fhir.encryption_key_id` is not set
+const DEFAULT_KEY_ID: &str = "default";
+
+/// A non-empty setting of the current session
+fn setting(name: &str) -> Option<String> {
+    Spi::get_one_with_args::<String>(
+        "SELECT NULLIF(current_setting($1, true), '')",
+        &[name.into()],
+    )
+    .ok()
+    .flatten()
+}
+
+/// A key as `(key id, passphrase)` from a pair of settings
+fn key_setting(key: &str, id: &str) -> Option<(String, String)> {
+    let key = setting(key)?;
+    let id = setting(id).unwrap_or_else(|| DEFAULT_KEY_ID.to_string());
+    Some((id, key))
+}
+
+/// The session's key, if set
+fn current_key() -> Option<(String, String)> {
+    key_setting("fhir.encryption_key", "fhir.encryption_key_id")
+}
+
+/// The key being rotated away from, if set
+fn previous_key() -> Option<(String, String)> {
+    key_setting(
+        "fhir.encryption_previous_key",
+        "fhir.encryption_previous_key_id",
+    )
+}
+
+/// One step of an element path: a field name, optionally restricted to array
+/// entries whose `key` field equals `value`
+struct Segment {
+    name: String,
+    filter: Option<(String, String)>,
+}
+
+/// Parse `identifier[system=http://x.org/ssn].value` into segments
+///
+/// Dots inside brackets belong to the filter value.
+fn parse_path(path: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = path.trim();
+    while !rest.is_empty() {
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        let name = rest[..end].to_string();
+        rest = &rest[end..];
+        let mut filter = None;
+        if let Some(inner) = rest.strip_prefix('[') {
+            let close = inner.find(']')?;
+            let (key, value) = inner[..close].split_once('=')?;
+            filter = Some((key.trim().to_string(), value.trim().to_string()));
+            rest = &inner[close + 1..];
+        }
+        if name.is_empty() {
+            return None;
+        }
+        segments.push(Segment { name, filter });
+        rest = rest.strip_prefix('.').unwrap_or(rest);
+    }
+    (!segments.is_empty()).then_some(segments)
+}
+
+/// Paths from `fhir.encrypted_paths`
+fn encrypted_paths() -> Vec<Vec<Segment>> {
+    setting("fhir.encrypted_paths")
+        .map(|paths| paths.split(',').filter_map(parse_path).collect())
+        .unwrap_or_default()
+}
+
+/// Call `f` on every string value at `path` below `value`
+fn visit(value: &mut Value, path: &[Segment], f: &mut dyn FnMut(&mut Value)) {
+    if let Value::Array(items) = value {
+        for item in items {
+            visit(item, path, f);
+        }
+        return;
+    }
+    let Some((segment, rest)) = path.split_first() else {
+        if value.is_string() {
+            f(value);
+        }
+        return;
+    };
+    let Some(child) = value.get_mut(&segment.name) else {
+        return;
+    };
+    match (&segment.filter, child) {
+        (Some((key, wanted)), Value::Array(items)) => {
+            for item in items
+                .iter_mut()
+                .filter(|item| item.get(key).and_then(Value::as_str) == Some(wanted.as_str()))
+            {
+                visit(item, rest, f);
+            }
+        }
+        (Some((key, wanted)), child) => {
+            if child.get(key).and_then(Value::as_str) == Some(wanted.as_str()) {
+                visit(child, rest, f);
+            }
+        }
+        (None, child) => visit(child, rest, f),
+    }
+}
+
+/// Encrypt `plaintext` under `key`, as stored after the prefix
+pub fn encrypt_value(key_id: &str, key: &str, plaintext: &str) -> String {
+    let ciphertext: String = Spi::get_one_with_args(
+        "SELECT translate(encode(pgp_sym_encrypt($1, $2, 'cipher-algo=aes256'), 'base64'), E'\\n', '')",
+        &[plaintext.into(), key.into()],
+    )
+    .expect("Failed to encrypt value (is pgcrypto installed?)")
+    .expect("pgp_sym_encrypt should not return null");
+    format!("{}{}:{}", PREFIX, key_id, ciphertext)
+}
+
+/// Split an encrypted value into key id and ciphertext
+pub fn parse_encrypted(value: &str) -> Option<(&str, &str)> {
+    value.strip_prefix(PREFIX)?.split_once(':')
+}
+
+/// Decrypt a ciphertext produced by [`encrypt_value`]; None if the key is
+/// wrong or the ciphertext is corrupt
+pub fn decrypt_value(key: &str, ciphertext: &str) -> Option<String> {
+    Spi::get_one_with_args(
+        "SELECT fhir_try_decrypt($1, $2)",
+        &[ciphertext.into(), key.into()],
+    )
+    .ok()
+    .flatten()
+}
+
+/// Encrypt the configured paths of a resource written by a client
+///
+/// Fails with an invalid-parameter error (`400`) if a value at a configured
+/// path is already encrypted.
+pub fn encrypt_incoming(data: &mut Value) {
+    for path in &encrypted_paths() {
+        visit(data, path, &mut |value| {
+            if value.as_str().is_some_and(|v| v.starts_with(PREFIX)) {
+                ereport!(
+                    PgLogLevel::ERROR,
+                    PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                    format!(
+                        "Encrypted element values (\"{}...\") cannot be written",
+                        PREFIX
+                    )
+                );
+            }
+        });
+    }
+    encrypt_resource(data);
+}
+
+/// Encrypt the configured paths of a stored resource
+///
+/// Does nothing unless both paths and a key are configured; values that are
+/// already encrypted are left alone.
+pub fn encrypt_resource(data: &mut Value) {
+    let paths = encrypted_paths();
+    if paths.is_empty() {
+        return;
+    }
+    let Some((key_id, key)) = current_key() else {
+        return;
+    };
+    for path in &paths {
+        visit(data, path, &mut |value| {
+            let Some(plaintext) = value.as_str() else {
+                return;
+            };
+            if parse_encrypted(plaintext).is_none() {
+                *value = Value::String(encrypt_value(&key_id, &key, plaintext));
+            }
+        });
+    }
+}
+
+/// Decrypt the values at the configured paths of a resource encrypted under
+/// the session's current or previous key
+///
+/// Values under another key id, values that fail to decrypt, or any value
+/// when the session has no key, stay encrypted.
+pub fn decrypt_resource(data: &mut Value) {
+    let keys: Vec<(String, String)> = current_key().into_iter().chain(previous_key()).collect();
+    if keys.is_empty() {
+        return;
+    }
+    for path in &encrypted_paths() {
+        visit(data, path, &mut |value| {
+            let plaintext = value.as_str().and_then(|stored| {
+                let (id, ciphertext) = parse_encrypted(stored)?;
+                let (_, key) = keys.iter().find(|(key_id, _)| key_id == id)?;
+                decrypt_value(key, ciphertext)
+            });
+            if let Some(plaintext) = plaintext {
+                *value = Value::String(plaintext);
+            }
+        });
+    }
+}
+
+/// Replace every string for which `f` returns a new value, anywhere in `data`
+fn rewrite_strings(data: &mut Value, f: &mut dyn FnMut(&str) -> Option<String>) {
+    match data {
+        Value::String(s) => {
+            if let Some(plaintext) = f(s) {
+                *s = plaintext;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| rewrite_strings(item, f)),
+        Value::Object(map) => map.values_mut().for_each(|v| rewrite_strings(v, f)),
+        _ => {}
+    }
+}
+
+/// Decrypt a stored resource for output
+pub fn decrypted(mut data: pgrx::JsonB) -> pgrx::JsonB {
+    decrypt_resource(&mut data.0);
+    data
+}
+
+/// `"enc:v1:<key id>:` as it appears in the JSON text of a row encrypted
+/// under that key
+fn key_marker(key_id: &str) -> String {
+    format!("\"{}{}:", PREFIX, key_id)
+}
+
+/// Re-encrypt one batch of rows holding values under the previous key
+///
+/// Rewrites up to `batch_size` rows of `fhir_resources` and `fhir_history` in
+/// place (no new version is recorded) and refreshes the search index of the
+/// live resources among them. A row with a value the previous key cannot
+/// decrypt is skipped with a warning and left as stored, so one corrupt row
+/// does not stop the rotation; it stays counted by
+/// `fhir_encryption_pending`. Returns the number of rows rewritten; call
+/// until it returns 0.
+#[pg_extern]
+fn fhir_rotate_encryption(batch_size: i32) -> i64 {
+    let (Some((new_id, new_key)), Some((old_id, old_key))) = (current_key(), previous_key()) else {
+        error!("fhir.encryption_key and fhir.encryption_previous_key must both be set");
+    };
+    if new_id == old_id {
+        error!(
+            "The current and previous encryption keys have the same id '{}'",
+            new_id
+        );
+    }
+    let marker = key_marker(&old_id);
+    let batch_size = i64::from(batch_size.max(1));
+
+    let mut rotated = 0i64;
+    for table in ["fhir_resources", "fhir_history"] {
+        // History rows have no live index; resources only when not deleted
+        let live = match table {
+            "fhir_resources" => "deleted_at IS NULL",
+            _ => "FALSE",
+        };
+        // Walk the table in id order past rows that were skipped
+        let mut after: Option<pgrx::Uuid> = None;
+        while rotated < batch_size {
+            let limit = batch_size - rotated;
+            let rows: Vec<(pgrx::Uuid, String, i32, bool, pgrx::JsonB)> =
+                Spi::connect_mut(|client| {
+                    let mut rows = Vec::new();
+                    let tup_table = client.update(
+                        &format!(
+                            "SELECT id, resource_type, version, {live}, data FROM {table}
+                              WHERE strpos(data::text, $1) > 0
+                                AND ($3::uuid IS NULL OR id > $3)
+                              ORDER BY id
+                              LIMIT $2
+                              FOR UPDATE SKIP LOCKED"
+                        ),
+                        None,
+                        &[marker.as_str().into(), limit.into(), after.into()],
+                    )?;
+
+                    for row in tup_table {
+                        let id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
+                        let resource_type: String =
+                            row.get(2)?.expect("resource_type should not be null");
+                        let version: i32 = row.get(3)?.expect("version should not be null");
+                        let live: bool = row.get(4)?.unwrap_or(false);
+                        let data: pgrx::JsonB = row.get(5)?.expect("data should not be null");
+                        rows.push((id, resource_type, version, live, data));
+                    }
+
+                    Ok::<_, pgrx::spi::SpiError>(rows)
+                })
+                .expect("Failed to select rows to re-encrypt");
+            let Some((last, ..)) = rows.last() else {
+                break;
+            };
+            after = Some(*last);
+
+            for (id, resource_type, version, live, mut data) in rows {
+                let mut failed = false;
+                rewrite_strings(&mut data.0, &mut |value| match parse_encrypted(value) {
+                    Some((key_id, ciphertext)) if key_id == old_id => {
+                        match decrypt_value(&old_key, ciphertext) {
+                            Some(plaintext) => Some(encrypt_value(&new_id, &new_key, &plaintext)),
+                            None => {
+                                failed = true;
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                });
+                if failed {
+                    warning!(
+                        "Skipping {} row {}: a value under key '{}' could not be decrypted",
+                        table,
+                        id,
+                        old_id
+                    );
+                    continue;
+                }
+                if live {
+                    index::index_resource(&resource_type, id, version, &data.0);
+                }
+                Spi::run_with_args(
+                    &format!("UPDATE {table} SET data = $1 WHERE id = $2"),
+                    &[data.into(), id.into()],
+                )
+                .expect("Failed to store re-encrypted row");
+                rotated += 1;
+            }
+        }
+    }
+
+    rotated
+}
+
+/// Number of rows still holding values under the previous key
+#[pg_extern]
+fn fhir_encryption_pending() -> i64 {
+    let Some((old_id, _)) = previous_key() else {
+        return 0;
+    };
+    Spi::get_one_with_args(
+        "SELECT (SELECT COUNT(*) FROM fhir_resources WHERE strpos(data::text, $1) > 0)
+              + (SELECT COUNT(*) FROM fhir_history WHERE strpos(data::text, $1) > 0)",
+        &[key_marker(&old_id).into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(0)
+}
@@ -1,62 +1,532 @@
 //! FHIR resource version history functionality
+//!
+//! When the `fhir.history_delta` setting is `on`, updates are stored as JSON
+//! merge patches against the previous version instead of full copies. Every
+//! `KEYFRAME_INTERVAL`th version is still stored in full so reconstruction
+//! never replays more than a handful of patches.
 
 use pgrx::datum::TimestampWithTimeZone;
 use pgrx::prelude::*;
+use serde_json::Value;
 
-/// Retrieve all versions of a FHIR resource
+use crate::crypto;
+use crate::delta;
+use crate::outbox;
+
+/// Store a full snapshot at least every this many versions
+const KEYFRAME_INTERVAL: i32 = 16;
+
+/// Whether delta storage is enabled for the current session
+fn delta_enabled() -> bool {
+    Spi::get_one::<bool>(
+        "SELECT COALESCE(current_setting('fhir.history_delta', true), 'off') IN ('on', 'true', '1')",
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+/// Record a new version in history
+///
+/// Version 1 is recorded as a create (`POST`), later versions as updates
+/// (`PUT`). `previous` is the content of the prior version; when given (and
+/// delta storage is enabled) a merge patch is stored if it is smaller than the
+/// full document.
+pub fn record_version(
+    resource_type: &str,
+    id: pgrx::Uuid,
+    version: i32,
+    data: &Value,
+    previous: Option<&Value>,
+) {
+    let patch = previous
+        .filter(|_| version % KEYFRAME_INTERVAL != 0)
+        .filter(|_| !delta::contains_null(data) && delta_enabled())
+        .map(|prev| delta::diff(prev, data))
+        .filter(|patch| patch.to_string().len() < data.to_string().len());
+
+    let is_delta = patch.is_some();
+    let stored = patch.unwrap_or_else(|| data.clone());
+    let method = if version == 1 { "POST" } else { "PUT" };
+
+    Spi::run_with_args(
+        "INSERT INTO fhir_history (resource_id, resource_type, version, method, data, delta) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[
+            id.into(),
+            resource_type.into(),
+            version.into(),
+            method.into(),
+            pgrx::JsonB(stored).into(),
+            is_delta.into(),
+        ],
+    )
+    .expect("Failed to insert history");
+    outbox::record(resource_type, id, version, method);
+}
+
+/// Record the deletion of a resource in history
 ///
-/// Returns all historical versions ordered by version descending (newest first).
+/// Deletions are stored with method `DELETE` and no content.
+pub fn record_deletion(resource_type: &str, id: pgrx::Uuid, version: i32) {
+    Spi::run_with_args(
+        "INSERT INTO fhir_history (resource_id, resource_type, version, method, data) VALUES ($1, $2, $3, 'DELETE', NULL)",
+        &[id.into(), resource_type.into(), version.into()],
+    )
+    .expect("Failed to insert history");
+    outbox::record(resource_type, id, version, "DELETE");
+}
+
+/// Content recorded for deletions before they were stored as `DELETE` rows
+/// without data (extension 0.1.0). The upgrade script converts these, but rows
+/// restored from older dumps may still carry it.
+fn is_legacy_deletion(data: &Value) -> bool {
+    *data == serde_json::json!({ "deleted": true })
+}
+
+/// A stored row's method and data, reading a legacy deletion marker as a
+/// `DELETE` without data
+fn stored_row(method: String, data: Option<pgrx::JsonB>) -> (String, Option<pgrx::JsonB>) {
+    match data {
+        Some(data) if is_legacy_deletion(&data.0) => ("DELETE".to_string(), None),
+        data => (method, data),
+    }
+}
+
+/// Replay stored rows (ascending by version) into full documents; deletions
+/// (no data) stay empty
+fn reconstruct<T>(rows: Vec<(i32, Option<Value>, bool, T)>) -> Vec<(i32, Option<Value>, T)> {
+    let mut current = Value::Null;
+    rows.into_iter()
+        .map(|(version, data, is_delta, extra)| match data {
+            Some(data) => {
+                if is_delta {
+                    delta::apply(&mut current, &data);
+                } else {
+                    current = data;
+                }
+                (version, Some(current.clone()), extra)
+            }
+            None => {
+                current = Value::Null;
+                (version, None, extra)
+            }
+        })
+        .collect()
+}
+
+/// Retrieve the versions of a FHIR resource
+///
+/// Returns versions ordered by version descending (newest first), skipping
+/// the newest `row_offset` and returning at most `row_limit` (all if NULL).
+/// `method` is `POST`, `PUT` or `DELETE`; deletions have no data. Deltas are
+/// replayed only from the nearest full snapshot at or before the page.
 #[pg_extern]
 fn fhir_history(
     resource_type: &str,
     resource_id: pgrx::Uuid,
+    row_limit: default!(Option<i32>, "NULL"),
+    row_offset: default!(i32, "0"),
 ) -> TableIterator<
     'static,
     (
         name!(version, i32),
-        name!(data, pgrx::JsonB),
+        name!(method, String),
+        name!(data, Option<pgrx::JsonB>),
         name!(created_at, TimestampWithTimeZone),
     ),
 > {
-    let results = Spi::connect(|client| {
-        let mut results = Vec::new();
+    let rows = Spi::connect(|client| {
+        // Versions on the requested page
+        let page = client
+            .select(
+                "SELECT MIN(version), MAX(version) FROM (
+                   SELECT version FROM fhir_history
+                    WHERE resource_id = $1 AND resource_type = $2
+                    ORDER BY version DESC
+                    LIMIT $3 OFFSET $4) page",
+                None,
+                &[
+                    resource_id.into(),
+                    resource_type.into(),
+                    row_limit.map(|n| n.max(0)).into(),
+                    row_offset.max(0).into(),
+                ],
+            )?
+            .first();
+        let oldest: Option<i32> = page.get(1)?;
+        let newest: Option<i32> = page.get(2)?;
+        let (Some(oldest), Some(newest)) = (oldest, newest) else {
+            return Ok(Vec::new());
+        };
+
+        let mut rows = Vec::new();
         let tup_table = client.select(
-            "SELECT version, data, created_at FROM fhir_history
-               WHERE resource_id = $1 AND resource_type = $2
-               ORDER BY version DESC",
+            "SELECT version, data, delta, created_at, method FROM fhir_history
+               WHERE resource_id = $1 AND resource_type = $2 AND version <= $4
+                 AND version >= COALESCE((
+                   SELECT MAX(version) FROM fhir_history
+                    WHERE resource_id = $1 AND resource_type = $2
+                      AND version <= $3 AND NOT delta), 0)
+               ORDER BY version ASC",
             None,
-            &[resource_id.into(), resource_type.into()],
+            &[
+                resource_id.into(),
+                resource_type.into(),
+                oldest.into(),
+                newest.into(),
+            ],
         )?;
 
         for row in tup_table {
             let version: i32 = row.get(1)?.expect("version should not be null");
-            let data: pgrx::JsonB = row.get(2)?.expect("data should not be null");
+            let data: Option<pgrx::JsonB> = row.get(2)?;
+            let is_delta: bool = row.get(3)?.expect("delta should not be null");
             let created_at: TimestampWithTimeZone =
-                row.get(3)?.expect("created_at should not be null");
-            results.push((version, data, created_at));
+                row.get(4)?.expect("created_at should not be null");
+            let method: String = row.get(5)?.expect("method should not be null");
+            let (method, data) = stored_row(method, data);
+            rows.push((version, data.map(|d| d.0), is_delta, (method, created_at)));
         }
 
-        Ok::<_, pgrx::spi::SpiError>(results)
+        Ok::<_, pgrx::spi::SpiError>(
+            reconstruct(rows)
+                .into_iter()
+                .filter(|(version, _, _)| *version >= oldest)
+                .collect(),
+        )
     })
     .expect("Failed to query history");
 
+    let results: Vec<_> = rows
+        .into_iter()
+        .rev()
+        .map(|(version, data, (method, created_at))| {
+            let data = data.map(|d| crypto::decrypted(pgrx::JsonB(d)));
+            (version, method, data, created_at)
+        })
+        .collect();
+
     TableIterator::new(results)
 }
 
+/// Load the full content of a specific version, replaying deltas from the
+/// nearest full snapshot at or before it; None for a deletion
+pub fn load_version(resource_type: &str, resource_id: pgrx::Uuid, version: i32) -> Option<Value> {
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        let tup_table = client.select(
+            "SELECT version, data, delta, method FROM fhir_history
+               WHERE resource_id = $1 AND resource_type = $2 AND version <= $3
+                 AND version >= COALESCE((
+                   SELECT MAX(version) FROM fhir_history
+                    WHERE resource_id = $1 AND resource_type = $2
+                      AND version <= $3 AND NOT delta), 0)
+               ORDER BY version ASC",
+            None,
+            &[resource_id.into(), resource_type.into(), version.into()],
+        )?;
+
+        for row in tup_table {
+            let v: i32 = row.get(1)?.expect("version should not be null");
+            let data: Option<pgrx::JsonB> = row.get(2)?;
+            let is_delta: bool = row.get(3)?.expect("delta should not be null");
+            let method: String = row.get(4)?.expect("method should not be null");
+            let (_, data) = stored_row(method, data);
+            rows.push((v, data.map(|d| d.0), is_delta, ()));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(rows)
+    })
+    .ok()?;
+
+    reconstruct(rows)
+        .pop()
+        .filter(|(v, _, _)| *v == version)
+        .and_then(|(_, data, _)| data)
+}
+
 /// Retrieve a specific version of a FHIR resource
 ///
-/// Returns the resource data at the specified version, or None if not found.
+/// Returns the resource data at the specified version, or None if not found
+/// or the version is a deletion.
 #[pg_extern]
 fn fhir_get_version(
     resource_type: &str,
     resource_id: pgrx::Uuid,
     version: i32,
 ) -> Option<pgrx::JsonB> {
-    Spi::get_one_with_args(
-        "SELECT data FROM fhir_history
-           WHERE resource_id = $1 AND resource_type = $2 AND version = $3",
-        &[resource_id.into(), resource_type.into(), version.into()],
+    load_version(resource_type, resource_id, version)
+        .map(pgrx::JsonB)
+        .map(crypto::decrypted)
+}
+
+/// Type-level history feed for incremental sync
+///
+/// Returns history entries of `resource_type` written at or after `since`,
+/// ordered by `(created_at, history_id)`. Pass the `cursor_micros` /
+/// `cursor_id` of the last row received to fetch the next page. Deletions
+/// have method `DELETE` and no data.
+///
+/// Rows younger than `SETTLE_WINDOW` are held back: `created_at` is the
+/// writing transaction's start time, so a slow transaction can commit a row
+/// that sorts before rows already visible. Waiting out the window means a
+/// consumer that follows the cursor sees every committed version exactly once,
+/// provided no write transaction runs longer than the window.
+#[pg_extern]
+fn fhir_history_since(
+    resource_type: &str,
+    since: TimestampWithTimeZone,
+    cursor_micros: Option<i64>,
+    cursor_id: Option<pgrx::Uuid>,
+    count: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(resource_id, pgrx::Uuid),
+        name!(version, i32),
+        name!(method, String),
+        name!(data, Option<pgrx::JsonB>),
+        name!(created_at, TimestampWithTimeZone),
+        name!(cursor_micros, i64),
+        name!(history_id, pgrx::Uuid),
+    ),
+> {
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        let tup_table = client.select(
+            "SELECT resource_id, version, data, delta, created_at,
+                    (EXTRACT(EPOCH FROM created_at) * 1000000)::bigint, id, method
+               FROM fhir_history
+              WHERE resource_type = $1
+                AND created_at >= $2
+                AND created_at < clock_timestamp() - INTERVAL '5 seconds'
+                AND ($3::bigint IS NULL
+                     OR (created_at, id) > (TIMESTAMPTZ 'epoch' + $3 * INTERVAL '1 microsecond', $4))
+              ORDER BY created_at, id
+              LIMIT $5",
+            None,
+            &[
+                resource_type.into(),
+                since.into(),
+                cursor_micros.into(),
+                cursor_id.into(),
+                count.max(1).into(),
+            ],
+        )?;
+
+        for row in tup_table {
+            let resource_id: pgrx::Uuid = row.get(1)?.expect("resource_id should not be null");
+            let version: i32 = row.get(2)?.expect("version should not be null");
+            let data: Option<pgrx::JsonB> = row.get(3)?;
+            let is_delta: bool = row.get(4)?.expect("delta should not be null");
+            let created_at: TimestampWithTimeZone =
+                row.get(5)?.expect("created_at should not be null");
+            let micros: i64 = row.get(6)?.expect("cursor should not be null");
+            let history_id: pgrx::Uuid = row.get(7)?.expect("id should not be null");
+            let method: String = row.get(8)?.expect("method should not be null");
+            let (method, data) = stored_row(method, data);
+            rows.push((
+                resource_id,
+                version,
+                method,
+                data,
+                is_delta,
+                created_at,
+                micros,
+                history_id,
+            ));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(rows)
+    })
+    .expect("Failed to query history feed");
+
+    let results: Vec<_> = rows
+        .into_iter()
+        .map(
+            |(resource_id, version, method, data, is_delta, created_at, micros, history_id)| {
+                let data = if is_delta {
+                    load_version(resource_type, resource_id, version)
+                        .map(pgrx::JsonB)
+                        .or(data)
+                } else {
+                    data
+                };
+                let data = data.map(crypto::decrypted);
+                (
+                    resource_id,
+                    version,
+                    method,
+                    data,
+                    created_at,
+                    micros,
+                    history_id,
+                )
+            },
+        )
+        .collect();
+
+    TableIterator::new(results)
+}
+
+/// Type-level history at a point in time (`_at`)
+///
+/// Returns, for each resource of `resource_type` that existed at `at`, the
+/// version current at that instant, ordered by resource id. Resources deleted
+/// (or not yet created) at `at` are left out. Pass the id of the last row
+/// received as `after_id` to fetch the next page.
+#[pg_extern]
+fn fhir_history_at(
+    resource_type: &str,
+    at: TimestampWithTimeZone,
+    after_id: Option<pgrx::Uuid>,
+    count: i32,
+) -> TableIterator<
+    'static,
+    (
+        name!(resource_id, pgrx::Uuid),
+        name!(version, i32),
+        name!(method, String),
+        name!(data, Option<pgrx::JsonB>),
+        name!(created_at, TimestampWithTimeZone),
+    ),
+> {
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        let tup_table = client.select(
+            "SELECT resource_id, version, method, data, delta, created_at
+               FROM (SELECT DISTINCT ON (resource_id)
+                            resource_id, version, method, data, delta, created_at
+                       FROM fhir_history
+                      WHERE resource_type = $1 AND created_at <= $2
+                        AND ($3::uuid IS NULL OR resource_id > $3)
+                      ORDER BY resource_id, version DESC) current
+              WHERE method <> 'DELETE'
+                AND data IS DISTINCT FROM '{\"deleted\": true}'::jsonb
+              ORDER BY resource_id
+              LIMIT $4",
+            None,
+            &[
+                resource_type.into(),
+                at.into(),
+                after_id.into(),
+                count.max(1).into(),
+            ],
+        )?;
+
+        for row in tup_table {
+            let resource_id: pgrx::Uuid = row.get(1)?.expect("resource_id should not be null");
+            let version: i32 = row.get(2)?.expect("version should not be null");
+            let method: String = row.get(3)?.expect("method should not be null");
+            let data: Option<pgrx::JsonB> = row.get(4)?;
+            let is_delta: bool = row.get(5)?.expect("delta should not be null");
+            let created_at: TimestampWithTimeZone =
+                row.get(6)?.expect("created_at should not be null");
+            rows.push((resource_id, version, method, data, is_delta, created_at));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(rows)
+    })
+    .expect("Failed to query history at");
+
+    let results: Vec<_> = rows
+        .into_iter()
+        .map(
+            |(resource_id, version, method, data, is_delta, created_at)| {
+                let data = if is_delta {
+                    load_version(resource_type, resource_id, version)
+                        .map(pgrx::JsonB)
+                        .or(data)
+                } else {
+                    data
+                };
+                let data = data.map(crypto::decrypted);
+                (resource_id, version, method, data, created_at)
+            },
+        )
+        .collect();
+
+    TableIterator::new(results)
+}
+
+/// Retrieve a FHIR resource as it was at a point in time
+///
+/// Returns the latest version written at or before `as_of`, or None if the
+/// resource did not exist yet or was deleted at that instant.
+#[pg_extern]
+fn fhir_get_as_of(
+    resource_type: &str,
+    resource_id: pgrx::Uuid,
+    as_of: TimestampWithTimeZone,
+) -> Option<pgrx::JsonB> {
+    let version: i32 = Spi::get_one_with_args(
+        "SELECT MAX(version) FROM fhir_history
+           WHERE resource_id = $1 AND resource_type = $2 AND created_at <= $3",
+        &[resource_id.into(), resource_type.into(), as_of.into()],
     )
     .ok()
-    .flatten()
+    .flatten()?;
+
+    load_version(resource_type, resource_id, version)
+        .map(pgrx::JsonB)
+        .map(crypto::decrypted)
+}
+
+/// Point-in-time snapshot of every resource of a type
+///
+/// Returns, for each resource, the latest version written at or before
+/// `as_of`, skipping resources that were deleted (or not yet created) at that
+/// instant. History pruned by the retention job cannot be reconstructed, so
+/// snapshots older than the retention period may be incomplete.
+#[pg_extern]
+fn fhir_export_snapshot(
+    resource_type: &str,
+    as_of: TimestampWithTimeZone,
+) -> TableIterator<
+    'static,
+    (
+        name!(id, pgrx::Uuid),
+        name!(version, i32),
+        name!(data, pgrx::JsonB),
+    ),
+> {
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        let tup_table = client.select(
+            "SELECT DISTINCT ON (resource_id) resource_id, version, data, delta, method
+               FROM fhir_history
+              WHERE resource_type = $1 AND created_at <= $2
+              ORDER BY resource_id, version DESC",
+            None,
+            &[resource_type.into(), as_of.into()],
+        )?;
+
+        for row in tup_table {
+            let id: pgrx::Uuid = row.get(1)?.expect("resource_id should not be null");
+            let version: i32 = row.get(2)?.expect("version should not be null");
+            let data: Option<pgrx::JsonB> = row.get(3)?;
+            let is_delta: bool = row.get(4)?.expect("delta should not be null");
+            let method: String = row.get(5)?.expect("method should not be null");
+            let (_, data) = stored_row(method, data);
+            rows.push((id, version, data, is_delta));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(rows)
+    })
+    .expect("Failed to query snapshot");
+
+    let results: Vec<_> = rows
+        .into_iter()
+        .filter_map(|(id, version, data, is_delta)| {
+            // Deletions have no data
+            let data = if is_delta {
+                load_version(resource_type, id, version).map(pgrx::JsonB)?
+            } else {
+                data?
+            };
+            Some((id, version, crypto::decrypted(data)))
+        })
+        .collect();
+
+    TableIterator::new(results)
 }
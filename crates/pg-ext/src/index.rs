@@ -0,0 +1,210 @@
+//! Search parameter extraction, reindexing and index consistency checks
+
+use pgrx::prelude::*;
+use serde_json::Value;
+
+use crate::search;
+
+/// Revision of the search parameter extraction rules.
+///
+/// Bump this whenever extraction changes (e.g. a new search parameter) so
+/// `fhir_reindex` treats every existing resource as stale. Until a stale
+/// resource is reindexed, searches match it on its JSON instead.
+pub const EXTRACTOR_REVISION: i32 = 2;
+
+/// Extract `(param_name, value)` pairs for a resource
+///
+/// Values are stored the way `fhir_search` compares them: identifiers as
+/// `system|value` (plus the bare value under `identifier-value`), phone
+/// numbers as digits and `+`, emails and string parameters lowercased.
+pub fn extract_params(resource_type: &str, data: &Value) -> Vec<(&'static str, String)> {
+    let mut params = Vec::new();
+    if resource_type != "Patient" {
+        return params;
+    }
+
+    if let Some(gender) = data.get("gender").and_then(|v| v.as_str()) {
+        params.push(("gender", gender.to_string()));
+    }
+    if let Some(birth_date) = data.get("birthDate").and_then(|v| v.as_str()) {
+        params.push(("birthdate", birth_date.to_string()));
+    }
+
+    for identifier in objects(data, "identifier") {
+        let system = identifier.get("system").and_then(|v| v.as_str());
+        let value = identifier.get("value").and_then(|v| v.as_str());
+        if system.is_some() || value.is_some() {
+            params.push((
+                "identifier",
+                format!("{}|{}", system.unwrap_or(""), value.unwrap_or("")),
+            ));
+        }
+        if let Some(value) = value {
+            params.push(("identifier-value", value.to_string()));
+        }
+    }
+
+    for contact in objects(data, "telecom") {
+        let Some(value) = contact.get("value").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        params.push(("telecom", value.to_string()));
+        match contact.get("system").and_then(|v| v.as_str()) {
+            Some("phone") => {
+                let digits = search::phone_digits(value);
+                if !digits.is_empty() {
+                    params.push(("phone", digits));
+                }
+            }
+            Some("email") => params.push(("email", value.to_lowercase())),
+            _ => {}
+        }
+    }
+
+    for (code, array, elements) in search::STRING_PARAMS {
+        for entry in objects(data, array) {
+            for element in *elements {
+                let values = match entry.get(*element) {
+                    Some(Value::Array(items)) => items.iter().collect(),
+                    Some(value) => vec![value],
+                    None => Vec::new(),
+                };
+                for value in values.into_iter().filter_map(|v| v.as_str()) {
+                    params.push((*code, value.to_lowercase()));
+                }
+            }
+        }
+    }
+
+    params
+}
+
+/// The object entries of array element `key`
+fn objects<'a>(data: &'a Value, key: &str) -> impl Iterator<Item = &'a Value> {
+    data.get(key)
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter(|v| v.is_object())
+}
+
+/// Replace the index rows of a resource and record the version they reflect
+pub fn index_resource(resource_type: &str, id: pgrx::Uuid, version: i32, data: &Value) {
+    Spi::run_with_args(
+        "DELETE FROM fhir_search_index WHERE resource_id = $1",
+        &[id.into()],
+    )
+    .expect("Failed to clear search index");
+
+    for (param, value) in extract_params(resource_type, data) {
+        Spi::run_with_args(
+            "INSERT INTO fhir_search_index (resource_id, resource_type, param_name, value) VALUES ($1, $2, $3, $4)",
+            &[id.into(), resource_type.into(), param.into(), value.into()],
+        )
+        .expect("Failed to insert search index row");
+    }
+
+    Spi::run_with_args(
+        "UPDATE fhir_resources SET index_version = $1, index_revision = $2 WHERE id = $3",
+        &[version.into(), EXTRACTOR_REVISION.into(), id.into()],
+    )
+    .expect("Failed to record index state");
+}
+
+/// Remove the index rows of a resource (e.g. on delete)
+pub fn unindex_resource(id: pgrx::Uuid) {
+    Spi::run_with_args(
+        "DELETE FROM fhir_search_index WHERE resource_id = $1",
+        &[id.into()],
+    )
+    .expect("Failed to clear search index");
+}
+
+/// Re-extract search parameters for one batch of stale resources
+///
+/// A resource is stale when its index rows were extracted from an older
+/// resource version or an older extractor revision. Rows locked by concurrent
+/// writers are skipped, so the batch never blocks writes; call repeatedly
+/// (each call in its own transaction) until it returns 0.
+///
+/// Returns the number of resources reindexed in this batch.
+#[pg_extern]
+fn fhir_reindex(resource_type: &str, batch_size: i32) -> i64 {
+    let stale: Vec<(pgrx::Uuid, i32, pgrx::JsonB)> = Spi::connect_mut(|client| {
+        let mut results = Vec::new();
+        let tup_table = client.update(
+            "SELECT id, version, data FROM fhir_resources
+               WHERE resource_type = $1 AND deleted_at IS NULL
+                 AND (index_version IS DISTINCT FROM version
+                      OR index_revision IS DISTINCT FROM $2)
+               ORDER BY id
+               LIMIT $3
+               FOR UPDATE SKIP LOCKED",
+            None,
+            &[
+                resource_type.into(),
+                EXTRACTOR_REVISION.into(),
+                batch_size.max(1).into(),
+            ],
+        )?;
+
+        for row in tup_table {
+            let id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
+            let version: i32 = row.get(2)?.expect("version should not be null");
+            let data: pgrx::JsonB = row.get(3)?.expect("data should not be null");
+            results.push((id, version, data));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(results)
+    })
+    .expect("Failed to select stale resources");
+
+    for (id, version, data) in &stale {
+        index_resource(resource_type, *id, *version, &data.0);
+    }
+
+    stale.len() as i64
+}
+
+/// Report live resources whose search index rows are stale
+///
+/// `index_version` / `index_revision` are NULL for resources that were never
+/// indexed.
+#[pg_extern]
+fn fhir_reindex_check(
+    resource_type: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(id, pgrx::Uuid),
+        name!(version, i32),
+        name!(index_version, Option<i32>),
+        name!(index_revision, Option<i32>),
+    ),
+> {
+    let results = Spi::connect(|client| {
+        let mut results = Vec::new();
+        let tup_table = client.select(
+            "SELECT id, version, index_version, index_revision FROM fhir_resources
+               WHERE resource_type = $1 AND deleted_at IS NULL
+                 AND (index_version IS DISTINCT FROM version
+                      OR index_revision IS DISTINCT FROM $2)
+               ORDER BY id",
+            None,
+            &[resource_type.into(), EXTRACTOR_REVISION.into()],
+        )?;
+
+        for row in tup_table {
+            let id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
+            let version: i32 = row.get(2)?.expect("version should not be null");
+            let index_version: Option<i32> = row.get(3)?;
+            let index_revision: Option<i32> = row.get(4)?;
+            results.push((id, version, index_version, index_revision));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(results)
+    })
+    .expect("Failed to check search index");
+
+    TableIterator::new(results)
+}
@@ -5,9 +5,19 @@
 
 use pgrx::prelude::*;
 
+mod backfill;
+mod cdc;
+mod crypto;
+mod delta;
 mod history;
+mod index;
+mod locks;
+mod maintenance;
+mod outbox;
+mod rls;
 mod search;
 mod storage;
+mod transaction;
 
 // Register this crate as a PostgreSQL extension
 pgrx::pg_module_magic!();
@@ -18,7 +28,53 @@ extension_sql_file!("schema.sql", name = "schema", bootstrap);
 /// Simple health check function to verify the extension is loaded
 #[pg_extern]
 fn fhir_ext_version() -> &'static str {
-    "fhir-pg-ext 0.1.0"
+    "fhir-pg-ext 0.2.0"
+}
+
+/// Catalog of the functions this extension installs
+///
+/// One row per function with its SQL signature and the installed extension
+/// version, so clients can check for a function before relying on it
+/// instead of inferring features from the version string.
+#[pg_extern]
+fn fhir_ext_functions() -> TableIterator<
+    'static,
+    (
+        name!(name, String),
+        name!(signature, String),
+        name!(version, String),
+    ),
+> {
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        let tup_table = client.select(
+            "SELECT p.proname::text,
+                    format('%s(%s) RETURNS %s', p.proname,
+                           pg_get_function_identity_arguments(p.oid),
+                           pg_get_function_result(p.oid)),
+                    e.extversion::text
+               FROM pg_proc p
+               JOIN pg_depend d ON d.classid = 'pg_proc'::regclass
+                               AND d.objid = p.oid AND d.deptype = 'e'
+               JOIN pg_extension e ON e.oid = d.refobjid
+              WHERE e.extname = 'fhir_pg_ext'
+              ORDER BY 1, 2",
+            None,
+            &[],
+        )?;
+
+        for row in tup_table {
+            let name: String = row.get(1)?.expect("name should not be null");
+            let signature: String = row.get(2)?.expect("signature should not be null");
+            let version: String = row.get(3)?.expect("version should not be null");
+            rows.push((name, signature, version));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(rows)
+    })
+    .expect("Failed to list extension functions");
+
+    TableIterator::new(rows)
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -28,7 +84,1052 @@ mod tests {
 
     #[pg_test]
     fn test_version() {
-        assert_eq!(fhir_ext_version(), "fhir-pg-ext 0.1.0");
+        assert_eq!(fhir_ext_version(), "fhir-pg-ext 0.2.0");
+    }
+
+    #[pg_test]
+    fn test_ext_functions() {
+        let listed = Spi::get_one::<bool>(
+            "SELECT bool_and(version <> '') AND bool_or(name = 'fhir_put')
+                    AND bool_or(name = 'fhir_ext_functions')
+               FROM fhir_ext_functions()",
+        );
+        assert_eq!(listed, Ok(Some(true)));
+    }
+
+    #[pg_test]
+    fn test_backfill_resumes_in_batches() {
+        for gender in ["male", "female", "other"] {
+            Spi::run(&format!(
+                r#"SELECT fhir_put('Patient', '{{"resourceType": "Patient", "gender": "{}"}}')"#,
+                gender
+            ))
+            .unwrap();
+        }
+        Spi::run("DELETE FROM fhir_search_index").unwrap();
+
+        // Two batches cover the three resources; the next call finds none
+        let batches: Vec<Option<i64>> = (0..3)
+            .map(|_| Spi::get_one::<i64>("SELECT fhir_backfill('search-index', 2)").unwrap())
+            .collect();
+        assert_eq!(batches, vec![Some(2), Some(1), Some(0)]);
+
+        let indexed = Spi::get_one::<i64>(
+            "SELECT COUNT(DISTINCT resource_id) FROM fhir_search_index WHERE param_name = 'gender'",
+        );
+        assert_eq!(indexed, Ok(Some(3)));
+        let finished = Spi::get_one::<bool>(
+            "SELECT finished_at IS NOT NULL AND processed = 3
+               FROM fhir_backfill_status() WHERE name = 'search-index'",
+        );
+        assert_eq!(finished, Ok(Some(true)));
+
+        // A reset starts over
+        Spi::run("SELECT fhir_backfill_reset('search-index')").unwrap();
+        let batch = Spi::get_one::<i64>("SELECT fhir_backfill('search-index', 10)");
+        assert_eq!(batch, Ok(Some(3)));
+    }
+
+    #[pg_test]
+    fn test_encryption_backfill_covers_history() {
+        Spi::run("CREATE EXTENSION IF NOT EXISTS pgcrypto").unwrap();
+        let patient = |ssn: &str| {
+            pgrx::JsonB(serde_json::json!({
+                "resourceType": "Patient",
+                "identifier": [{"system": "urn:ssn", "value": ssn}]
+            }))
+        };
+        // Stored before the path was configured: one updated, one deleted
+        let id = Spi::get_one_with_args::<pgrx::Uuid>(
+            "SELECT fhir_put('Patient', $1)",
+            &[patient("111-11-1111").into()],
+        )
+        .unwrap()
+        .unwrap();
+        Spi::run_with_args(
+            "SELECT fhir_update('Patient', $1, $2)",
+            &[id.into(), patient("222-22-2222").into()],
+        )
+        .unwrap();
+        let deleted = Spi::get_one_with_args::<pgrx::Uuid>(
+            "SELECT fhir_put('Patient', $1)",
+            &[patient("333-33-3333").into()],
+        )
+        .unwrap()
+        .unwrap();
+        Spi::run_with_args("SELECT fhir_delete('Patient', $1)", &[deleted.into()]).unwrap();
+
+        Spi::run("SET fhir.encrypted_paths = 'identifier[system=urn:ssn].value'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'test-key'").unwrap();
+        let batch = Spi::get_one::<i64>("SELECT fhir_backfill('encryption', 10)");
+        assert_eq!(batch, Ok(Some(2)));
+
+        let plaintext = Spi::get_one::<i64>(
+            "SELECT (SELECT COUNT(*) FROM fhir_resources WHERE data::text ~ '(111-11|222-22|333-33)')
+                  + (SELECT COUNT(*) FROM fhir_history WHERE data::text ~ '(111-11|222-22|333-33)')",
+        );
+        assert_eq!(plaintext, Ok(Some(0)));
+
+        // Old versions still read back decrypted
+        let first = Spi::get_one_with_args::<pgrx::JsonB>(
+            "SELECT fhir_get_version('Patient', $1, 1)",
+            &[id.into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(first.0, patient("111-11-1111").0);
+    }
+
+    #[pg_test]
+    fn test_outbox_records_writes() {
+        let count = || Spi::get_one::<i64>("SELECT COUNT(*) FROM fhir_outbox").unwrap();
+
+        // Off by default
+        Spi::run(r#"SELECT fhir_put('Patient', '{"resourceType": "Patient"}')"#).unwrap();
+        assert_eq!(count(), Some(0));
+
+        Spi::run("SET fhir.outbox = 'on'").unwrap();
+        let id = Spi::get_one::<pgrx::Uuid>(
+            r#"SELECT fhir_put('Patient', '{"resourceType": "Patient", "gender": "male"}')"#,
+        )
+        .unwrap()
+        .unwrap();
+        Spi::run_with_args(
+            r#"SELECT fhir_update('Patient', $1, '{"resourceType": "Patient", "gender": "other"}')"#,
+            &[id.into()],
+        )
+        .unwrap();
+        Spi::run_with_args("SELECT fhir_delete('Patient', $1)", &[id.into()]).unwrap();
+
+        let methods = Spi::get_one_with_args::<String>(
+            "SELECT string_agg(method || version, ',' ORDER BY id) FROM fhir_outbox WHERE resource_id = $1",
+            &[id.into()],
+        );
+        assert_eq!(methods, Ok(Some("POST1,PUT2,DELETE3".to_string())));
+        let pending = Spi::get_one::<i64>("SELECT pending FROM fhir_outbox_status()");
+        assert_eq!(pending, Ok(Some(3)));
+
+        // Only delivered rows are pruned
+        Spi::run(
+            "UPDATE fhir_outbox SET delivered_at = NOW() - interval '2 days' WHERE version = 1",
+        )
+        .unwrap();
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT fhir_outbox_prune(1)"),
+            Ok(Some(1))
+        );
+        assert_eq!(count(), Some(2));
+    }
+
+    #[pg_test]
+    fn test_update_expected_version() {
+        let id = Spi::get_one::<pgrx::Uuid>(
+            r#"SELECT fhir_put('Patient', '{"resourceType": "Patient", "gender": "male"}')"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        let version = Spi::get_one_with_args::<i32>(
+            r#"SELECT fhir_update('Patient', $1, '{"resourceType": "Patient", "gender": "other"}', 1)"#,
+            &[id.into()],
+        );
+        assert_eq!(version, Ok(Some(2)));
+
+        // Without an expected version the update is unconditional
+        let version = Spi::get_one_with_args::<i32>(
+            r#"SELECT fhir_update('Patient', $1, '{"resourceType": "Patient", "gender": "female"}')"#,
+            &[id.into()],
+        );
+        assert_eq!(version, Ok(Some(3)));
+    }
+
+    #[pg_test(
+        error = "Patient/00000000-0000-0000-0000-000000000000 is at version 1, not the expected version 5"
+    )]
+    fn test_update_version_mismatch() {
+        Spi::run(
+            r#"INSERT INTO fhir_resources (id, resource_type, version, data)
+               VALUES ('00000000-0000-0000-0000-000000000000', 'Patient', 1, '{"resourceType": "Patient"}')"#,
+        )
+        .unwrap();
+        Spi::run(
+            r#"SELECT fhir_update('Patient', '00000000-0000-0000-0000-000000000000',
+                                  '{"resourceType": "Patient"}', 5)"#,
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_lock_owner_may_write() {
+        let id = Spi::get_one::<pgrx::Uuid>(
+            r#"SELECT fhir_put('Patient', '{"resourceType": "Patient", "gender": "male"}')"#,
+        )
+        .unwrap()
+        .unwrap();
+        Spi::run_with_args("SELECT fhir_lock('Patient', $1, 'alice', 60)", &[id.into()]).unwrap();
+
+        // The holder named in fhir.lock_owner writes through its lock
+        Spi::run("SET LOCAL fhir.lock_owner = 'alice'").unwrap();
+        let version = Spi::get_one_with_args::<i32>(
+            r#"SELECT fhir_update('Patient', $1, '{"resourceType": "Patient", "gender": "other"}')"#,
+            &[id.into()],
+        );
+        assert_eq!(version, Ok(Some(2)));
+
+        // Expired leases no longer block anyone
+        Spi::run("UPDATE fhir_locks SET expires_at = NOW() - interval '1 second'").unwrap();
+        Spi::run("SET LOCAL fhir.lock_owner = 'bob'").unwrap();
+        let deleted =
+            Spi::get_one_with_args::<bool>("SELECT fhir_delete('Patient', $1)", &[id.into()]);
+        assert_eq!(deleted, Ok(Some(true)));
+    }
+
+    #[pg_test(error = "Patient/00000000-0000-0000-0000-000000000002 is locked by another owner")]
+    fn test_locked_update_fails() {
+        Spi::run(
+            r#"INSERT INTO fhir_resources (id, resource_type, version, data)
+               VALUES ('00000000-0000-0000-0000-000000000002', 'Patient', 1, '{"resourceType": "Patient"}')"#,
+        )
+        .unwrap();
+        Spi::run(
+            "SELECT fhir_lock('Patient', '00000000-0000-0000-0000-000000000002', 'alice', 60)",
+        )
+        .unwrap();
+        Spi::run("SET LOCAL fhir.lock_owner = 'bob'").unwrap();
+        Spi::run(
+            r#"SELECT fhir_update('Patient', '00000000-0000-0000-0000-000000000002',
+                                  '{"resourceType": "Patient"}')"#,
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "Patient/00000000-0000-0000-0000-000000000003 is locked by another owner")]
+    fn test_transaction_respects_locks() {
+        Spi::run(
+            r#"INSERT INTO fhir_resources (id, resource_type, version, data)
+               VALUES ('00000000-0000-0000-0000-000000000003', 'Patient', 1, '{"resourceType": "Patient"}')"#,
+        )
+        .unwrap();
+        Spi::run(
+            "SELECT fhir_lock('Patient', '00000000-0000-0000-0000-000000000003', 'alice', 60)",
+        )
+        .unwrap();
+        // Without fhir.lock_owner a locked resource cannot be written
+        Spi::run(
+            r#"SELECT fhir_transaction('{
+                "resourceType": "Bundle",
+                "type": "transaction",
+                "entry": [
+                    {"request": {"method": "DELETE", "url": "Patient/00000000-0000-0000-0000-000000000003"}}
+                ]
+            }')"#,
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_count_ignores_paging() {
+        for gender in ["male", "male", "female"] {
+            Spi::run(&format!(
+                r#"SELECT fhir_put('Patient', '{{"resourceType": "Patient", "gender": "{}"}}')"#,
+                gender
+            ))
+            .unwrap();
+        }
+
+        let count = Spi::get_one::<i64>(
+            r#"SELECT fhir_count('Patient', '{"gender": "male", "_count": 1, "_offset": 1}')"#,
+        );
+        assert_eq!(count, Ok(Some(2)));
+        let count = Spi::get_one::<i64>("SELECT fhir_count('Patient', '{}')");
+        assert_eq!(count, Ok(Some(3)));
+    }
+
+    #[pg_test]
+    fn test_search_score() {
+        for family in ["Smi", "Smith", "Smithson"] {
+            Spi::run(&format!(
+                r#"SELECT fhir_put('Patient', '{{"resourceType": "Patient", "name": [{{"family": "{}"}}]}}')"#,
+                family
+            ))
+            .unwrap();
+        }
+
+        let exact = Spi::get_one::<f64>(
+            r#"SELECT fhir_name_score('{"name": [{"family": "Smith", "given": ["Jo"]}]}', 'smith')"#,
+        );
+        assert_eq!(exact, Ok(Some(1.0)));
+
+        let ranked = Spi::get_one::<String>(
+            r#"SELECT string_agg(data->'name'->0->>'family', ',')
+                 FROM fhir_search('Patient', '{"name": "Smith", "_sort": "_score"}')"#,
+        );
+        assert_eq!(ranked, Ok(Some("Smith,Smithson".to_string())));
+    }
+
+    #[pg_test]
+    fn test_count_estimate() {
+        for gender in ["male", "female", "female"] {
+            Spi::run(&format!(
+                r#"SELECT fhir_put('Patient', '{{"resourceType": "Patient", "gender": "{}"}}')"#,
+                gender
+            ))
+            .unwrap();
+        }
+        Spi::run("ANALYZE fhir_resources").unwrap();
+
+        // Statistics are exact for a fresh ANALYZE of three rows, but the
+        // planner may still round a filtered estimate
+        let estimate = Spi::get_one::<i64>("SELECT fhir_count_estimate('Patient', '{}')");
+        assert_eq!(estimate, Ok(Some(3)));
+        let filtered =
+            Spi::get_one::<i64>(r#"SELECT fhir_count_estimate('Patient', '{"gender": "male"}')"#)
+                .unwrap()
+                .unwrap();
+        assert!((0..=3).contains(&filtered), "{}", filtered);
+    }
+
+    #[pg_test]
+    fn test_search_choice_path() {
+        for deceased in [
+            r#""deceasedBoolean": true"#,
+            r#""deceasedDateTime": "2020-05-01""#,
+            r#""deceasedBoolean": false"#,
+        ] {
+            Spi::run(&format!(
+                r#"SELECT fhir_put('Patient', '{{"resourceType": "Patient", {}}}')"#,
+                deceased
+            ))
+            .unwrap();
+        }
+
+        let count = |path_filter: &str| {
+            Spi::get_one::<i64>(&format!(
+                r#"SELECT fhir_count('Patient', '{{"_paths": [{}]}}')"#,
+                path_filter
+            ))
+        };
+        let dated = count(r#"{"path": "deceased[x]", "type": "date", "value": "ge2000-01-01"}"#);
+        assert_eq!(dated, Ok(Some(1)));
+        let flagged = count(r#"{"path": "deceased[x]", "type": "token", "value": "true"}"#);
+        assert_eq!(flagged, Ok(Some(1)));
+        let any = count(r#"{"path": "deceased[x]", "type": "string", "value": "2020"}"#);
+        assert_eq!(any, Ok(Some(1)));
+    }
+
+    #[pg_test]
+    fn test_search_active() {
+        for patient in [
+            r#"{"resourceType": "Patient"}"#,
+            r#"{"resourceType": "Patient", "active": false}"#,
+            r#"{"resourceType": "Patient", "active": true,
+                "link": [{"other": {"reference": "Patient/x"}, "type": "replaced-by"}]}"#,
+        ] {
+            Spi::run(&format!("SELECT fhir_put('Patient', '{}')", patient)).unwrap();
+        }
+
+        let current = Spi::get_one::<i64>(r#"SELECT fhir_count('Patient', '{"active": "true"}')"#);
+        assert_eq!(current, Ok(Some(1)));
+        let rest = Spi::get_one::<i64>(r#"SELECT fhir_count('Patient', '{"active": "false"}')"#);
+        assert_eq!(rest, Ok(Some(2)));
+        let all = Spi::get_one::<i64>("SELECT fhir_count('Patient', '{}')");
+        assert_eq!(all, Ok(Some(3)));
+    }
+
+    #[pg_test]
+    fn test_search_identifier() {
+        for patient in [
+            r#"{"resourceType": "Patient", "identifier": [
+                {"system": "http://hospital.org/mrn", "value": "12345"},
+                {"system": "http://other.org/id", "value": "A1"}]}"#,
+            r#"{"resourceType": "Patient", "identifier": [{"value": "12345"}]}"#,
+            r#"{"resourceType": "Patient", "identifier": [
+                {"system": "http://hospital.org/mrn", "value": "99999"}]}"#,
+            r#"{"resourceType": "Patient"}"#,
+        ] {
+            Spi::run(&format!("SELECT fhir_put('Patient', '{}')", patient)).unwrap();
+        }
+
+        let count = |identifier: &str| {
+            Spi::get_one::<i64>(&format!(
+                r#"SELECT fhir_count('Patient', '{{"identifier": "{}"}}')"#,
+                identifier
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(count("http://hospital.org/mrn|12345"), 1);
+        assert_eq!(count("12345"), 2);
+        assert_eq!(count("|12345"), 1);
+        assert_eq!(count("http://hospital.org/mrn|"), 2);
+        // System and value must match on the same identifier
+        assert_eq!(count("http://other.org/id|12345"), 0);
+        assert_eq!(count("nothing"), 0);
+    }
+
+    #[pg_test]
+    fn test_search_telecom() {
+        for patient in [
+            r#"{"resourceType": "Patient", "telecom": [
+                {"system": "phone", "value": "(555) 123-4567"},
+                {"system": "email", "value": "Ann@Example.org"}]}"#,
+            r#"{"resourceType": "Patient", "telecom": [{"system": "fax", "value": "555-123-4567"}]}"#,
+            r#"{"resourceType": "Patient"}"#,
+        ] {
+            Spi::run(&format!("SELECT fhir_put('Patient', '{}')", patient)).unwrap();
+        }
+
+        let count = |code: &str, value: &str| {
+            Spi::get_one::<i64>(&format!(
+                r#"SELECT fhir_count('Patient', '{{"{}": "{}"}}')"#,
+                code, value
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(count("phone", "555-123-4567"), 1);
+        assert_eq!(count("phone", "5551234567"), 1);
+        assert_eq!(count("email", "ann@example.org"), 1);
+        assert_eq!(count("email", "(555) 123-4567"), 0);
+        assert_eq!(count("telecom", "555-123-4567"), 1);
+        assert_eq!(count("telecom", "Ann@Example.org"), 1);
+    }
+
+    #[pg_test]
+    fn test_search_address() {
+        for patient in [
+            r#"{"resourceType": "Patient", "address": [
+                {"line": ["1 Main Street", "Apt 2"], "city": "Springfield", "state": "IL",
+                 "postalCode": "62701", "country": "USA"}]}"#,
+            r#"{"resourceType": "Patient", "address": [
+                {"city": "Boston", "state": "MA"}, {"city": "West Springfield", "state": "MA"}]}"#,
+            r#"{"resourceType": "Patient"}"#,
+        ] {
+            Spi::run(&format!("SELECT fhir_put('Patient', '{}')", patient)).unwrap();
+        }
+
+        let count = |code: &str, value: &str| {
+            Spi::get_one::<i64>(&format!(
+                r#"SELECT fhir_count('Patient', '{{"{}": "{}"}}')"#,
+                code, value
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(count("address", "apt"), 1);
+        assert_eq!(count("address", "spring"), 1);
+        assert_eq!(count("address:contains", "spring"), 2);
+        assert_eq!(count("address-city", "springfield"), 1);
+        assert_eq!(count("address-city:exact", "springfield"), 0);
+        assert_eq!(count("address-city:exact", "Springfield"), 1);
+        assert_eq!(count("address-state", "MA"), 1);
+        assert_eq!(count("address-postalcode", "627"), 1);
+        assert_eq!(count("address-country", "usa"), 1);
+        assert_eq!(count("address-country", "IL"), 0);
+    }
+
+    #[pg_test]
+    fn test_search_name_parts() {
+        for patient in [
+            r#"{"resourceType": "Patient", "name": [
+                {"family": "Smith", "given": ["John"]},
+                {"use": "maiden", "family": "Jones", "given": ["Jack", "Robert"]}]}"#,
+            r#"{"resourceType": "Patient", "name": [{"family": "Blacksmith", "given": ["Bob"]}]}"#,
+        ] {
+            Spi::run(&format!("SELECT fhir_put('Patient', '{}')", patient)).unwrap();
+        }
+
+        let count = |code: &str, value: &str| {
+            Spi::get_one::<i64>(&format!(
+                r#"SELECT fhir_count('Patient', '{{"{}": "{}"}}')"#,
+                code, value
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(count("family", "jon"), 1);
+        assert_eq!(count("family", "smith"), 1);
+        assert_eq!(count("family:contains", "smith"), 2);
+        assert_eq!(count("family:exact", "smith"), 0);
+        assert_eq!(count("given", "rob"), 1);
+        assert_eq!(count("given:exact", "Robert"), 1);
+        assert_eq!(count("given", "smith"), 0);
+    }
+
+    #[pg_test]
+    fn test_search_or_and_values() {
+        for patient in [
+            r#"{"resourceType": "Patient", "gender": "male", "name": [{"family": "Smith", "given": ["John"]}]}"#,
+            r#"{"resourceType": "Patient", "gender": "female", "name": [{"family": "Smith", "given": ["Jane"]}]}"#,
+            r#"{"resourceType": "Patient", "gender": "other", "name": [{"family": "Doe, Jr", "given": ["Jon"]}]}"#,
+        ] {
+            Spi::run(&format!("SELECT fhir_put('Patient', '{}')", patient)).unwrap();
+        }
+
+        let count = |params: &str| {
+            Spi::get_one::<i64>(&format!("SELECT fhir_count('Patient', '{}')", params))
+                .unwrap()
+                .unwrap()
+        };
+        // Commas separate alternatives, array entries must all match
+        assert_eq!(count(r#"{"gender": "male,female"}"#), 2);
+        assert_eq!(count(r#"{"gender": "male,,unknown"}"#), 1);
+        assert_eq!(count(r#"{"name": ["smith", "jane"]}"#), 1);
+        assert_eq!(count(r#"{"name": ["smith,doe", "jo"]}"#), 2);
+        assert_eq!(count(r#"{"family": ["smith", "doe"]}"#), 0);
+        // An escaped comma is part of the value
+        assert_eq!(count(r#"{"family:exact": "Doe\\, Jr"}"#), 1);
+        assert_eq!(count(r#"{"family:exact": "Doe, Jr"}"#), 0);
+    }
+
+    #[pg_test]
+    fn test_reindex_stale_resources() {
+        Spi::run(r#"SELECT fhir_put('Patient', '{"resourceType": "Patient", "gender": "male"}')"#)
+            .unwrap();
+
+        // Freshly written resources are indexed
+        let stale = Spi::get_one::<i64>("SELECT COUNT(*) FROM fhir_reindex_check('Patient')");
+        assert_eq!(stale, Ok(Some(0)));
+
+        // Simulate an extractor upgrade
+        Spi::run("UPDATE fhir_resources SET index_revision = NULL").unwrap();
+        let stale = Spi::get_one::<i64>("SELECT COUNT(*) FROM fhir_reindex_check('Patient')");
+        assert_eq!(stale, Ok(Some(1)));
+
+        let reindexed = Spi::get_one::<i64>("SELECT fhir_reindex('Patient', 10)");
+        assert_eq!(reindexed, Ok(Some(1)));
+        let stale = Spi::get_one::<i64>("SELECT COUNT(*) FROM fhir_reindex_check('Patient')");
+        assert_eq!(stale, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_search_reads_index() {
+        Spi::run(
+            r#"SELECT fhir_put('Patient', '{"resourceType": "Patient",
+                "identifier": [{"system": "http://hospital.org/mrn", "value": "12345"}]}')"#,
+        )
+        .unwrap();
+        let count = |identifier: &str| {
+            Spi::get_one::<i64>(&format!(
+                r#"SELECT fhir_count('Patient', '{{"identifier": "{}"}}')"#,
+                identifier
+            ))
+            .unwrap()
+            .unwrap()
+        };
+
+        // A current resource is matched through its index rows alone
+        Spi::run(
+            "UPDATE fhir_search_index SET value = 'http://hospital.org/mrn|67890'
+               WHERE param_name = 'identifier'",
+        )
+        .unwrap();
+        assert_eq!(count("http://hospital.org/mrn|67890"), 1);
+        assert_eq!(count("http://hospital.org/mrn|12345"), 0);
+
+        // One not yet reindexed after an extractor change is matched on its JSON
+        Spi::run("UPDATE fhir_resources SET index_revision = NULL").unwrap();
+        assert_eq!(count("http://hospital.org/mrn|67890"), 0);
+        assert_eq!(count("http://hospital.org/mrn|12345"), 1);
+
+        Spi::run("SELECT fhir_reindex('Patient', 10)").unwrap();
+        assert_eq!(count("http://hospital.org/mrn|12345"), 1);
+        let rows = Spi::get_one::<i64>(
+            "SELECT COUNT(*) FROM fhir_search_index WHERE param_name LIKE 'identifier%'",
+        );
+        assert_eq!(rows, Ok(Some(2)));
+    }
+
+    #[pg_test]
+    fn test_row_level_security() {
+        let tagged = |tenant: &str, label: Option<&str>| {
+            let mut data = serde_json::json!({
+                "resourceType": "Patient",
+                "meta": {"tag": [{"system": "urn:fhir:tenant", "code": tenant}]}
+            });
+            if let Some(label) = label {
+                data["meta"]["security"] = serde_json::json!([{"code": label}]);
+            }
+            Spi::run_with_args(
+                "SELECT fhir_put('Patient', $1)",
+                &[pgrx::JsonB(data).into()],
+            )
+            .unwrap();
+        };
+        tagged("north", None);
+        tagged("north", Some("R"));
+        tagged("south", None);
+
+        Spi::run("CREATE ROLE fhir_analyst").unwrap();
+        Spi::run("GRANT SELECT ON fhir_resources TO fhir_analyst").unwrap();
+        Spi::run("SELECT fhir_rls_enable()").unwrap();
+        Spi::run("SELECT fhir_rls_grant('fhir_analyst', 'north')").unwrap();
+
+        let visible = || {
+            Spi::get_one::<i64>("SELECT COUNT(*) FROM fhir_resources")
+                .unwrap()
+                .unwrap()
+        };
+        Spi::run("SET ROLE fhir_analyst").unwrap();
+        // No context: tagged resources are hidden
+        assert_eq!(visible(), 0);
+
+        let granted = Spi::get_one::<bool>("SELECT fhir_set_context('north', ARRAY['R'])");
+        assert_eq!(granted, Ok(Some(true)));
+        // The R label is requested but not granted
+        assert_eq!(visible(), 1);
+
+        // Setting the context by hand grants nothing extra
+        Spi::run("SET fhir.tenant = 'south'").unwrap();
+        assert_eq!(visible(), 0);
+        Spi::run("RESET ROLE").unwrap();
+
+        // The owner is not subject to the policies
+        assert_eq!(visible(), 3);
+        Spi::run("SELECT fhir_rls_grant('fhir_analyst', 'north', ARRAY['R'])").unwrap();
+        Spi::run("SET ROLE fhir_analyst").unwrap();
+        Spi::run("SELECT fhir_set_context('north', ARRAY['R'])").unwrap();
+        assert_eq!(visible(), 2);
+        Spi::run("RESET ROLE").unwrap();
+    }
+
+    #[pg_test]
+    fn test_element_encryption() {
+        Spi::run("CREATE EXTENSION IF NOT EXISTS pgcrypto").unwrap();
+        Spi::run("SET fhir.encrypted_paths = 'identifier[system=urn:ssn].value'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'test-key'").unwrap();
+        let data = serde_json::json!({
+            "resourceType": "Patient",
+            "identifier": [
+                {"system": "urn:ssn", "value": "123-45-6789"},
+                {"system": "urn:mrn", "value": "MRN-1"}
+            ]
+        });
+        let id = Spi::get_one_with_args::<pgrx::Uuid>(
+            "SELECT fhir_put('Patient', $1)",
+            &[pgrx::JsonB(data.clone()).into()],
+        )
+        .unwrap()
+        .unwrap();
+
+        // Only the configured path is stored encrypted
+        let stored = Spi::get_one_with_args::<pgrx::JsonB>(
+            "SELECT data FROM fhir_resources WHERE id = $1",
+            &[id.into()],
+        )
+        .unwrap()
+        .unwrap()
+        .0;
+        let ssn = stored["identifier"][0]["value"].as_str().unwrap();
+        assert!(ssn.starts_with("enc:v1:default:"));
+        assert_eq!(stored["identifier"][1]["value"], "MRN-1");
+
+        let read = || {
+            Spi::get_one_with_args::<pgrx::JsonB>("SELECT fhir_get('Patient', $1)", &[id.into()])
+                .unwrap()
+                .unwrap()
+                .0
+        };
+        assert_eq!(read(), data);
+
+        // Without the key, reads return the ciphertext
+        Spi::run("RESET fhir.encryption_key").unwrap();
+        assert_eq!(read()["identifier"][0]["value"], ssn);
+    }
+
+    #[pg_test]
+    fn test_encryption_decrypts_configured_paths() {
+        Spi::run("CREATE EXTENSION IF NOT EXISTS pgcrypto").unwrap();
+        Spi::run("SET fhir.encrypted_paths = 'identifier[system=urn:ssn].value'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'test-key'").unwrap();
+        let put = |data: serde_json::Value| {
+            Spi::get_one_with_args::<pgrx::Uuid>(
+                "SELECT fhir_put('Patient', $1)",
+                &[pgrx::JsonB(data).into()],
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let stored = |id: pgrx::Uuid| {
+            Spi::get_one_with_args::<pgrx::JsonB>(
+                "SELECT data FROM fhir_resources WHERE id = $1",
+                &[id.into()],
+            )
+            .unwrap()
+            .unwrap()
+            .0
+        };
+        let read = |id: pgrx::Uuid| {
+            Spi::get_one_with_args::<pgrx::JsonB>("SELECT fhir_get('Patient', $1)", &[id.into()])
+                .unwrap()
+                .unwrap()
+                .0
+        };
+        let first = put(serde_json::json!({
+            "resourceType": "Patient",
+            "identifier": [{"system": "urn:ssn", "value": "123-45-6789"}]
+        }));
+        let ciphertext = stored(first)["identifier"][0]["value"].clone();
+
+        // A ciphertext copied into an unencrypted element is not decrypted
+        let second = put(serde_json::json!({
+            "resourceType": "Patient",
+            "identifier": [{"system": "urn:ssn", "value": "987-65-4321"}],
+            "name": [{"text": ciphertext}]
+        }));
+        let patient = read(second);
+        assert_eq!(patient["identifier"][0]["value"], "987-65-4321");
+        assert_eq!(patient["name"][0]["text"], ciphertext);
+
+        // Corrupt ciphertext and a wrong key leave the value as stored
+        Spi::run_with_args(
+            "UPDATE fhir_resources
+                SET data = jsonb_set(data, '{identifier,0,value}', '\"enc:v1:default:bm90IGNpcGhlcnRleHQ=\"')
+              WHERE id = $1",
+            &[second.into()],
+        )
+        .unwrap();
+        assert_eq!(
+            read(second)["identifier"][0]["value"],
+            "enc:v1:default:bm90IGNpcGhlcnRleHQ="
+        );
+        Spi::run("SET fhir.encryption_key = 'other-key'").unwrap();
+        assert_eq!(read(first)["identifier"][0]["value"], ciphertext);
+    }
+
+    #[pg_test(error = "Encrypted element values (\"enc:v1:...\") cannot be written")]
+    fn test_encrypted_value_rejected() {
+        Spi::run("SET fhir.encrypted_paths = 'identifier[system=urn:ssn].value'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'test-key'").unwrap();
+        let data = serde_json::json!({
+            "resourceType": "Patient",
+            "identifier": [{"system": "urn:ssn", "value": "enc:v1:default:c3RvbGVu"}]
+        });
+        Spi::run_with_args(
+            "SELECT fhir_put('Patient', $1)",
+            &[pgrx::JsonB(data).into()],
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_encryption_key_rotation() {
+        Spi::run("CREATE EXTENSION IF NOT EXISTS pgcrypto").unwrap();
+        Spi::run("SET fhir.encrypted_paths = 'identifier[system=urn:ssn].value'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'old-key'").unwrap();
+        Spi::run("SET fhir.encryption_key_id = 'k1'").unwrap();
+        let data = serde_json::json!({
+            "resourceType": "Patient",
+            "identifier": [{"system": "urn:ssn", "value": "123-45-6789"}]
+        });
+        let id = Spi::get_one_with_args::<pgrx::Uuid>(
+            "SELECT fhir_put('Patient', $1)",
+            &[pgrx::JsonB(data.clone()).into()],
+        )
+        .unwrap()
+        .unwrap();
+
+        // Switch keys; the old one still decrypts until data is rotated
+        Spi::run("SET fhir.encryption_previous_key = 'old-key'").unwrap();
+        Spi::run("SET fhir.encryption_previous_key_id = 'k1'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'new-key'").unwrap();
+        Spi::run("SET fhir.encryption_key_id = 'k2'").unwrap();
+        let read = || {
+            Spi::get_one_with_args::<pgrx::JsonB>("SELECT fhir_get('Patient', $1)", &[id.into()])
+                .unwrap()
+                .unwrap()
+                .0
+        };
+        assert_eq!(read(), data);
+
+        // Resource row and its history row
+        let pending = || {
+            Spi::get_one::<i64>("SELECT fhir_encryption_pending()")
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(pending(), 2);
+        let batch = || {
+            Spi::get_one::<i64>("SELECT fhir_rotate_encryption(1)")
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(batch(), 1);
+        assert_eq!(pending(), 1);
+        assert_eq!(batch(), 1);
+        assert_eq!(batch(), 0);
+        assert_eq!(pending(), 0);
+
+        let stored = Spi::get_one_with_args::<pgrx::JsonB>(
+            "SELECT data FROM fhir_resources WHERE id = $1",
+            &[id.into()],
+        )
+        .unwrap()
+        .unwrap()
+        .0;
+        assert!(
+            stored["identifier"][0]["value"]
+                .as_str()
+                .unwrap()
+                .starts_with("enc:v1:k2:")
+        );
+
+        // The previous key is no longer needed
+        Spi::run("RESET fhir.encryption_previous_key").unwrap();
+        assert_eq!(read(), data);
+    }
+
+    #[pg_test]
+    fn test_encryption_rotation_skips_undecryptable_rows() {
+        Spi::run("CREATE EXTENSION IF NOT EXISTS pgcrypto").unwrap();
+        Spi::run("SET fhir.encrypted_paths = 'identifier[system=urn:ssn].value'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'old-key'").unwrap();
+        Spi::run("SET fhir.encryption_key_id = 'k1'").unwrap();
+        let put = |ssn: &str| {
+            let data = serde_json::json!({
+                "resourceType": "Patient",
+                "identifier": [{"system": "urn:ssn", "value": ssn}]
+            });
+            Spi::get_one_with_args::<pgrx::Uuid>(
+                "SELECT fhir_put('Patient', $1)",
+                &[pgrx::JsonB(data).into()],
+            )
+            .unwrap()
+            .unwrap()
+        };
+        put("123-45-6789");
+        let corrupt = put("987-65-4321");
+        Spi::run_with_args(
+            "UPDATE fhir_resources
+                SET data = jsonb_set(data, '{identifier,0,value}', '\"enc:v1:k1:bm90IGNpcGhlcnRleHQ=\"')
+              WHERE id = $1",
+            &[corrupt.into()],
+        )
+        .unwrap();
+
+        Spi::run("SET fhir.encryption_previous_key = 'old-key'").unwrap();
+        Spi::run("SET fhir.encryption_previous_key_id = 'k1'").unwrap();
+        Spi::run("SET fhir.encryption_key = 'new-key'").unwrap();
+        Spi::run("SET fhir.encryption_key_id = 'k2'").unwrap();
+
+        // Both history rows and the intact resource are rotated; the corrupt
+        // resource is skipped without failing the batch
+        let batch = || {
+            Spi::get_one::<i64>("SELECT fhir_rotate_encryption(10)")
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(batch(), 3);
+        assert_eq!(batch(), 0);
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT fhir_encryption_pending()").unwrap(),
+            Some(1)
+        );
+        let stored = Spi::get_one_with_args::<pgrx::JsonB>(
+            "SELECT data FROM fhir_resources WHERE id = $1",
+            &[corrupt.into()],
+        )
+        .unwrap()
+        .unwrap()
+        .0;
+        assert_eq!(
+            stored["identifier"][0]["value"],
+            "enc:v1:k1:bm90IGNpcGhlcnRleHQ="
+        );
+    }
+
+    #[pg_test]
+    fn test_delta_history_roundtrip() {
+        Spi::run("SET fhir.history_delta = 'on'").unwrap();
+        let v1 = serde_json::json!({
+            "resourceType": "Patient",
+            "name": [{"family": "Doe", "given": ["Jane"]}],
+            "gender": "female",
+            "birthDate": "1988-12-01"
+        });
+        let mut v2 = v1.clone();
+        v2["gender"] = serde_json::json!("other");
+
+        let id = Spi::get_one_with_args::<pgrx::Uuid>(
+            "SELECT fhir_put('Patient', $1)",
+            &[pgrx::JsonB(v1.clone()).into()],
+        )
+        .unwrap()
+        .unwrap();
+        Spi::run_with_args(
+            "SELECT fhir_update('Patient', $1, $2)",
+            &[id.into(), pgrx::JsonB(v2.clone()).into()],
+        )
+        .unwrap();
+
+        // Version 2 is stored as a patch but read back in full
+        let is_delta = Spi::get_one_with_args::<bool>(
+            "SELECT delta FROM fhir_history WHERE resource_id = $1 AND version = 2",
+            &[id.into()],
+        );
+        assert_eq!(is_delta, Ok(Some(true)));
+
+        let read = |version: i32| {
+            Spi::get_one_with_args::<pgrx::JsonB>(
+                "SELECT fhir_get_version('Patient', $1, $2)",
+                &[id.into(), version.into()],
+            )
+            .unwrap()
+            .unwrap()
+            .0
+        };
+        assert_eq!(read(1), v1);
+        assert_eq!(read(2), v2);
+    }
+
+    #[pg_test]
+    fn test_history_pages() {
+        Spi::run("SET fhir.history_delta = 'on'").unwrap();
+        let version = |n: i32| {
+            serde_json::json!({
+                "resourceType": "Patient",
+                "name": [{"family": "Page", "given": [format!("V{}", n)]}],
+                "gender": "female"
+            })
+        };
+        let id = Spi::get_one_with_args::<pgrx::Uuid>(
+            "SELECT fhir_put('Patient', $1)",
+            &[pgrx::JsonB(version(1)).into()],
+        )
+        .unwrap()
+        .unwrap();
+        for n in 2..=5 {
+            Spi::run_with_args(
+                "SELECT fhir_update('Patient', $1, $2)",
+                &[id.into(), pgrx::JsonB(version(n)).into()],
+            )
+            .unwrap();
+        }
+
+        // A page in the middle is replayed from the snapshot before it
+        let page = |limit: i32, offset: i32| -> Vec<(i32, serde_json::Value)> {
+            Spi::connect(|client| {
+                client
+                    .select(
+                        "SELECT version, data FROM fhir_history('Patient', $1, $2, $3)",
+                        None,
+                        &[id.into(), limit.into(), offset.into()],
+                    )?
+                    .map(|row| {
+                        let version: i32 = row.get(1)?.unwrap();
+                        let data: pgrx::JsonB = row.get(2)?.unwrap();
+                        Ok((version, data.0))
+                    })
+                    .collect::<Result<Vec<_>, pgrx::spi::SpiError>>()
+            })
+            .unwrap()
+        };
+        assert_eq!(page(2, 1), vec![(4, version(4)), (3, version(3))]);
+        assert_eq!(page(10, 3), vec![(2, version(2)), (1, version(1))]);
+        assert!(page(2, 5).is_empty());
+
+        // Without a limit every version is returned
+        let all = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir_history('Patient', $1)",
+            &[id.into()],
+        );
+        assert_eq!(all, Ok(Some(5)));
+    }
+
+    #[pg_test]
+    fn test_history_legacy_deletion_marker() {
+        let id = Spi::get_one::<pgrx::Uuid>(
+            r#"SELECT fhir_put('Patient', '{"resourceType": "Patient", "gender": "male"}')"#,
+        )
+        .unwrap()
+        .unwrap();
+        // Deletions recorded by extension 0.1.0
+        Spi::run_with_args(
+            r#"INSERT INTO fhir_history (resource_id, resource_type, version, data)
+               VALUES ($1, 'Patient', 2, '{"deleted": true}')"#,
+            &[id.into()],
+        )
+        .unwrap();
+
+        let entry = Spi::get_two_with_args::<String, pgrx::JsonB>(
+            "SELECT method, data FROM fhir_history('Patient', $1) WHERE version = 2",
+            &[id.into()],
+        )
+        .unwrap();
+        assert_eq!(entry.0, Some("DELETE".to_string()));
+        assert!(entry.1.is_none());
+
+        let read = Spi::get_one_with_args::<pgrx::JsonB>(
+            "SELECT fhir_get_version('Patient', $1, 2)",
+            &[id.into()],
+        );
+        assert!(matches!(read, Ok(None)));
+
+        let at = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir_history_at('Patient', NOW(), NULL, 10) WHERE resource_id = $1",
+            &[id.into()],
+        );
+        assert_eq!(at, Ok(Some(0)));
+        let snapshot = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir_export_snapshot('Patient', NOW()) WHERE id = $1",
+            &[id.into()],
+        );
+        assert_eq!(snapshot, Ok(Some(0)));
+    }
+
+    #[pg_test]
+    fn test_transaction_resolves_references() {
+        let bundle = serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {
+                    "fullUrl": "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a",
+                    "resource": {"resourceType": "Patient", "gender": "female"},
+                    "request": {"method": "POST", "url": "Patient"}
+                },
+                {
+                    "resource": {
+                        "resourceType": "Observation",
+                        "status": "final",
+                        "subject": {"reference": "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a"}
+                    },
+                    "request": {"method": "POST", "url": "Observation"}
+                }
+            ]
+        });
+        let response = Spi::get_one_with_args::<pgrx::JsonB>(
+            "SELECT fhir_transaction($1)",
+            &[pgrx::JsonB(bundle).into()],
+        )
+        .unwrap()
+        .unwrap()
+        .0;
+        assert_eq!(response["type"], "transaction-response");
+        assert_eq!(response["entry"][0]["response"]["status"], "201 Created");
+
+        // The Observation points at the Patient created alongside it
+        let patient = response["entry"][0]["fullUrl"].as_str().unwrap();
+        let subject = Spi::get_one::<String>(
+            "SELECT data->'subject'->>'reference' FROM fhir_resources
+              WHERE resource_type = 'Observation'",
+        );
+        assert_eq!(subject, Ok(Some(patient.to_string())));
+    }
+
+    #[pg_test(error = "Bundle entry 1: Patient/00000000-0000-0000-0000-000000000001 not found")]
+    fn test_transaction_fails_as_a_whole() {
+        Spi::run(
+            r#"SELECT fhir_transaction('{
+                "resourceType": "Bundle",
+                "type": "transaction",
+                "entry": [
+                    {"resource": {"resourceType": "Patient"}, "request": {"method": "POST", "url": "Patient"}},
+                    {"request": {"method": "DELETE", "url": "Patient/00000000-0000-0000-0000-000000000001"}}
+                ]
+            }')"#,
+        )
+        .unwrap();
     }
 }
 
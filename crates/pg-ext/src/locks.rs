@@ -0,0 +1,97 @@
+//! Pessimistic resource locks (checkout) with lease timeouts
+//!
+//! `fhir_update` and `fhir_delete` (also within `fhir_transaction`) refuse to
+//! touch a resource with an active lock unless the transaction's
+//! `fhir.lock_owner` setting names the holder, e.g.
+//! `SET LOCAL fhir.lock_owner = 'alice'`.
+
+use pgrx::datum::TimestampWithTimeZone;
+use pgrx::prelude::*;
+
+/// Acquire or renew a lock on a resource
+///
+/// Succeeds if the resource is unlocked, the existing lease has expired, or
+/// the lock is already held by `owner` (which renews the lease). Returns the
+/// new lease expiry, or None if another owner holds an active lock.
+#[pg_extern]
+fn fhir_lock(
+    resource_type: &str,
+    resource_id: pgrx::Uuid,
+    owner: &str,
+    lease_seconds: i32,
+) -> Option<TimestampWithTimeZone> {
+    Spi::get_one_with_args(
+        "INSERT INTO fhir_locks (resource_id, resource_type, owner, expires_at)
+         VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))
+         ON CONFLICT (resource_id) DO UPDATE
+            SET owner = EXCLUDED.owner, expires_at = EXCLUDED.expires_at
+          WHERE fhir_locks.owner = EXCLUDED.owner OR fhir_locks.expires_at < NOW()
+         RETURNING expires_at",
+        &[
+            resource_id.into(),
+            resource_type.into(),
+            owner.into(),
+            (lease_seconds as f64).into(),
+        ],
+    )
+    .ok()
+    .flatten()
+}
+
+/// Release a lock held by `owner`
+///
+/// Returns true if a lock was released.
+#[pg_extern]
+fn fhir_unlock(resource_type: &str, resource_id: pgrx::Uuid, owner: &str) -> bool {
+    Spi::get_one_with_args::<bool>(
+        "WITH released AS (
+           DELETE FROM fhir_locks
+            WHERE resource_id = $1 AND resource_type = $2 AND owner = $3
+            RETURNING 1)
+         SELECT EXISTS (SELECT 1 FROM released)",
+        &[resource_id.into(), resource_type.into(), owner.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+/// Current holder of an active (unexpired) lock, if any
+#[pg_extern]
+fn fhir_lock_holder(resource_type: &str, resource_id: pgrx::Uuid) -> Option<String> {
+    Spi::get_one_with_args(
+        "SELECT owner FROM fhir_locks
+          WHERE resource_id = $1 AND resource_type = $2 AND expires_at >= NOW()",
+        &[resource_id.into(), resource_type.into()],
+    )
+    .ok()
+    .flatten()
+}
+
+/// Fail with SQLSTATE 55P03 (lock not available) if another owner than the
+/// transaction's `fhir.lock_owner` holds an active lock on the resource
+///
+/// The lock row is share-locked, so it cannot be taken over or released
+/// until the calling write commits.
+pub(crate) fn ensure_unlocked(resource_type: &str, resource_id: pgrx::Uuid) {
+    let holder: Option<String> = Spi::get_one_with_args(
+        "SELECT owner FROM fhir_locks
+          WHERE resource_id = $1 AND resource_type = $2 AND expires_at >= NOW()
+            AND owner IS DISTINCT FROM NULLIF(current_setting('fhir.lock_owner', true), '')
+            FOR SHARE",
+        &[resource_id.into(), resource_type.into()],
+    )
+    .ok()
+    .flatten();
+
+    if holder.is_some() {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_LOCK_NOT_AVAILABLE,
+            format!(
+                "{}/{} is locked by another owner",
+                resource_type, resource_id
+            )
+        );
+    }
+}
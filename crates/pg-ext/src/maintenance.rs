@@ -0,0 +1,59 @@
+//! Storage maintenance introspection (bloat, TOAST usage, history growth)
+
+use pgrx::prelude::*;
+
+/// Report storage health for the FHIR tables as a JSONB document
+///
+/// Contains three sections:
+/// - `tables`: heap/TOAST/index sizes, live vs dead tuples and the dead-tuple
+///   ratio (a cheap bloat estimate), plus last vacuum/analyze times
+/// - `indexes`: size and scan count per index (unused indexes are pure overhead)
+/// - `historyGrowth`: history rows written in the last 24h/7d/30d and in total
+///
+/// The history section scans `fhir_history`, so avoid calling this in a hot loop.
+#[pg_extern]
+fn fhir_maintenance_report() -> pgrx::JsonB {
+    Spi::get_one::<pgrx::JsonB>(
+        "SELECT jsonb_build_object(
+           'tables', (
+             SELECT COALESCE(jsonb_agg(jsonb_build_object(
+                      'name', s.relname,
+                      'totalBytes', pg_total_relation_size(s.relid),
+                      'heapBytes', pg_relation_size(s.relid),
+                      'toastBytes', COALESCE(pg_total_relation_size(NULLIF(c.reltoastrelid, 0)), 0),
+                      'indexBytes', pg_indexes_size(s.relid),
+                      'liveTuples', s.n_live_tup,
+                      'deadTuples', s.n_dead_tup,
+                      'deadRatio', CASE WHEN s.n_live_tup + s.n_dead_tup = 0 THEN 0
+                                        ELSE round(s.n_dead_tup::numeric / (s.n_live_tup + s.n_dead_tup), 4) END,
+                      'lastVacuum', GREATEST(s.last_vacuum, s.last_autovacuum),
+                      'lastAnalyze', GREATEST(s.last_analyze, s.last_autoanalyze)
+                    ) ORDER BY s.relname), '[]'::jsonb)
+               FROM pg_stat_user_tables s
+               JOIN pg_class c ON c.oid = s.relid
+              WHERE s.relname LIKE 'fhir\\_%'
+           ),
+           'indexes', (
+             SELECT COALESCE(jsonb_agg(jsonb_build_object(
+                      'name', s.indexrelname,
+                      'table', s.relname,
+                      'bytes', pg_relation_size(s.indexrelid),
+                      'scans', s.idx_scan
+                    ) ORDER BY s.indexrelname), '[]'::jsonb)
+               FROM pg_stat_user_indexes s
+              WHERE s.relname LIKE 'fhir\\_%'
+           ),
+           'historyGrowth', (
+             SELECT jsonb_build_object(
+                      'last24h', COUNT(*) FILTER (WHERE created_at > NOW() - INTERVAL '1 day'),
+                      'last7d', COUNT(*) FILTER (WHERE created_at > NOW() - INTERVAL '7 days'),
+                      'last30d', COUNT(*) FILTER (WHERE created_at > NOW() - INTERVAL '30 days'),
+                      'total', COUNT(*)
+                    )
+               FROM fhir_history
+           )
+         )",
+    )
+    .expect("Failed to build maintenance report")
+    .expect("maintenance report should not be null")
+}
@@ -0,0 +1,75 @@
+//! Transactional outbox of change notifications
+//!
+//! When the `fhir.outbox` setting is `on`, every history row written also
+//! records a notification intent in `fhir_outbox`, inside the same
+//! transaction as the write: a committed change always has its intent, and
+//! a rolled-back one never does. A delivery worker claims pending rows with
+//! `FOR UPDATE SKIP LOCKED`, delivers them and marks them delivered in one
+//! transaction, so concurrent workers never claim the same row and a crash
+//! before the commit leaves the row pending for the next attempt.
+
+use pgrx::datum::TimestampWithTimeZone;
+use pgrx::prelude::*;
+
+/// Whether notification intents are recorded for the current session
+fn outbox_enabled() -> bool {
+    Spi::get_one::<bool>(
+        "SELECT COALESCE(current_setting('fhir.outbox', true), 'off') IN ('on', 'true', '1')",
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+/// Record that a version was written (`POST`, `PUT` or `DELETE`)
+pub fn record(resource_type: &str, id: pgrx::Uuid, version: i32, method: &str) {
+    if !outbox_enabled() {
+        return;
+    }
+    Spi::run_with_args(
+        "INSERT INTO fhir_outbox (resource_type, resource_id, version, method) VALUES ($1, $2, $3, $4)",
+        &[
+            resource_type.into(),
+            id.into(),
+            version.into(),
+            method.into(),
+        ],
+    )
+    .expect("Failed to record outbox entry");
+}
+
+/// Undelivered notifications and the age of the oldest one
+#[pg_extern]
+fn fhir_outbox_status() -> TableIterator<
+    'static,
+    (
+        name!(pending, i64),
+        name!(failing, i64),
+        name!(oldest, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let (pending, failing, oldest) = Spi::get_three::<i64, i64, TimestampWithTimeZone>(
+        "SELECT COUNT(*), COUNT(*) FILTER (WHERE attempts > 0), MIN(created_at)
+               FROM fhir_outbox WHERE delivered_at IS NULL",
+    )
+    .expect("Failed to read outbox status");
+
+    TableIterator::once((pending.unwrap_or(0), failing.unwrap_or(0), oldest))
+}
+
+/// Delete delivered notifications older than `retention_days`, returning
+/// the number removed
+#[pg_extern]
+fn fhir_outbox_prune(retention_days: i32) -> i64 {
+    Spi::get_one_with_args::<i64>(
+        "WITH pruned AS (
+            DELETE FROM fhir_outbox
+             WHERE delivered_at < NOW() - make_interval(days => $1)
+            RETURNING 1
+         )
+         SELECT COUNT(*) FROM pruned",
+        &[retention_days.into()],
+    )
+    .expect("Failed to prune outbox")
+    .unwrap_or(0)
+}
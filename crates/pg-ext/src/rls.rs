@@ -0,0 +1,178 @@
+//! Optional row-level security for direct database readers
+//!
+//! The API role owns the tables and is not subject to RLS; other roles
+//! (analysts querying the database directly) only see rows their grants
+//! allow:
+//! - a resource tagged with a tenant (`meta.tag` with system
+//!   `urn:fhir:tenant`) is visible only while the session's tenant is that
+//!   tenant
+//! - a resource with security labels (`meta.security`) is visible only if
+//!   every label is among the session's labels
+//!
+//! The session context is set with `fhir_set_context`, but only takes effect
+//! as far as `fhir_rls_grants` allows the current role, so setting
+//! `fhir.tenant` / `fhir.security_labels` by hand grants nothing extra.
+
+use pgrx::prelude::*;
+
+/// Tables the policies are installed on
+const TABLES: &[&str] = &["fhir_resources", "fhir_history", "fhir_search_index"];
+
+extension_sql!(
+    r#"
+-- Tenants and security labels each database role may read
+CREATE TABLE IF NOT EXISTS fhir_rls_grants (
+    role_name        TEXT NOT NULL,
+    tenant           TEXT NOT NULL,
+    security_labels  TEXT[] NOT NULL DEFAULT '{}',
+    PRIMARY KEY (role_name, tenant)
+);
+
+-- Whether a resource is visible to the current role in the current context
+CREATE OR REPLACE FUNCTION fhir_rls_visible(data JSONB) RETURNS BOOLEAN
+LANGUAGE sql STABLE AS $$
+    WITH grant_row AS (
+        SELECT tenant, security_labels
+          FROM fhir_rls_grants
+         WHERE role_name = current_user
+           AND tenant = current_setting('fhir.tenant', true)
+    ), allowed AS (
+        SELECT COALESCE((SELECT tenant FROM grant_row), '') AS tenant,
+               to_jsonb(ARRAY(
+                   SELECT unnest(security_labels) FROM grant_row
+                   INTERSECT
+                   SELECT unnest(string_to_array(current_setting('fhir.security_labels', true), ','))
+               )) AS labels
+    )
+    SELECT NOT jsonb_path_exists(
+               data,
+               '$.meta.tag[*] ? (@.system == $system && @.code != $tenant)',
+               jsonb_build_object('system', 'urn:fhir:tenant', 'tenant', tenant))
+       AND NOT jsonb_path_exists(
+               data,
+               '$.meta.security[*] ? (!(@.code == $labels[*]))',
+               jsonb_build_object('labels', labels))
+      FROM allowed
+$$;
+"#,
+    name = "rls",
+    requires = ["schema"]
+);
+
+/// Quote an SQL identifier
+fn quote_ident(name: &str) -> String {
+    Spi::get_one_with_args("SELECT quote_ident($1)", &[name.into()])
+        .expect("Failed to quote identifier")
+        .expect("quote_ident should not return null")
+}
+
+/// Enable row-level security on the resource, history and search index
+/// tables
+///
+/// Other roles get read-only access filtered by their grants. `api_role`, if
+/// given, is exempted with a permissive policy (needed when the API does not
+/// connect as the table owner). Safe to call again.
+#[pg_extern]
+fn fhir_rls_enable(api_role: default!(Option<&str>, "NULL")) -> bool {
+    for table in TABLES {
+        // History and index rows follow the visibility of their resource
+        // (history deltas may not carry the resource's tags)
+        let visible = match *table {
+            "fhir_resources" => "fhir_rls_visible(data)".to_string(),
+            _ => format!(
+                "EXISTS (SELECT 1 FROM fhir_resources r WHERE r.id = {}.resource_id)",
+                table
+            ),
+        };
+        Spi::run(&format!(
+            "ALTER TABLE {table} ENABLE ROW LEVEL SECURITY;
+             DROP POLICY IF EXISTS fhir_rls_read ON {table};
+             CREATE POLICY fhir_rls_read ON {table} FOR SELECT USING ({visible});
+             DROP POLICY IF EXISTS fhir_rls_api ON {table};",
+        ))
+        .expect("Failed to install row-level security policy");
+
+        if let Some(role) = api_role {
+            Spi::run(&format!(
+                "CREATE POLICY fhir_rls_api ON {} FOR ALL TO {} USING (true) WITH CHECK (true)",
+                table,
+                quote_ident(role)
+            ))
+            .expect("Failed to install API role policy");
+        }
+    }
+
+    // Every role evaluates the policy, so every role must read its grants
+    Spi::run("GRANT SELECT ON fhir_rls_grants TO PUBLIC")
+        .expect("Failed to grant access to fhir_rls_grants");
+    true
+}
+
+/// Remove the policies and disable row-level security again
+#[pg_extern]
+fn fhir_rls_disable() -> bool {
+    for table in TABLES {
+        Spi::run(&format!(
+            "DROP POLICY IF EXISTS fhir_rls_read ON {table};
+             DROP POLICY IF EXISTS fhir_rls_api ON {table};
+             ALTER TABLE {table} DISABLE ROW LEVEL SECURITY;",
+        ))
+        .expect("Failed to remove row-level security policy");
+    }
+    true
+}
+
+/// Allow `role_name` to read `tenant`'s resources carrying at most the given
+/// security labels (replacing an earlier grant for the same tenant)
+#[pg_extern]
+fn fhir_rls_grant(
+    role_name: &str,
+    tenant: &str,
+    security_labels: default!(Vec<String>, "ARRAY[]::TEXT[]"),
+) {
+    Spi::run_with_args(
+        "INSERT INTO fhir_rls_grants (role_name, tenant, security_labels)
+         VALUES ($1, $2, $3)
+         ON CONFLICT (role_name, tenant) DO UPDATE
+            SET security_labels = EXCLUDED.security_labels",
+        &[role_name.into(), tenant.into(), security_labels.into()],
+    )
+    .expect("Failed to record grant");
+}
+
+/// Set the session's tenant and security labels
+///
+/// Returns whether the current role has a grant for the tenant; without one
+/// the session only sees untagged, unlabeled resources.
+#[pg_extern]
+fn fhir_set_context(
+    tenant: &str,
+    security_labels: default!(Vec<String>, "ARRAY[]::TEXT[]"),
+) -> bool {
+    let labels = security_labels
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join(",");
+    Spi::run_with_args(
+        "SELECT set_config('fhir.tenant', $1, false), set_config('fhir.security_labels', $2, false)",
+        &[tenant.into(), labels.into()],
+    )
+    .expect("Failed to set session context");
+
+    Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT 1 FROM fhir_rls_grants WHERE role_name = current_user AND tenant = $1)",
+        &[tenant.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+/// Clear the session's tenant and security labels
+#[pg_extern]
+fn fhir_clear_context() {
+    Spi::run("SELECT set_config('fhir.tenant', '', false), set_config('fhir.security_labels', '', false)")
+        .expect("Failed to clear session context");
+}
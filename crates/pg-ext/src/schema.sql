@@ -6,7 +6,9 @@ CREATE TABLE IF NOT EXISTS fhir_resources (
     data            JSONB NOT NULL,
     created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
     updated_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-    deleted_at      TIMESTAMPTZ  -- NULL means not deleted (soft delete)
+    deleted_at      TIMESTAMPTZ,  -- NULL means not deleted (soft delete)
+    index_version   INTEGER,      -- resource version the search index reflects
+    index_revision  INTEGER       -- extractor revision used to build the index
 );
 
 -- FHIR History table: stores all versions of resources
@@ -15,10 +17,70 @@ CREATE TABLE IF NOT EXISTS fhir_history (
     resource_id     UUID NOT NULL,
     resource_type   TEXT NOT NULL,
     version         INTEGER NOT NULL,
-    data            JSONB NOT NULL,
+    method          TEXT NOT NULL DEFAULT 'PUT' CHECK (method IN ('POST', 'PUT', 'DELETE')),
+    data            JSONB,                           -- NULL for deletions
+    delta           BOOLEAN NOT NULL DEFAULT FALSE,  -- data is a merge patch against the previous version
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    UNIQUE (resource_id, version),
+    CHECK ((method = 'DELETE') = (data IS NULL))
+);
+
+-- FHIR Search Index table: extracted search parameter values
+CREATE TABLE IF NOT EXISTS fhir_search_index (
+    resource_id     UUID NOT NULL REFERENCES fhir_resources(id),
+    resource_type   TEXT NOT NULL,
+    param_name      TEXT NOT NULL,
+    value           TEXT NOT NULL
+);
+
+-- FHIR Locks table: pessimistic checkout locks with lease expiry
+CREATE TABLE IF NOT EXISTS fhir_locks (
+    resource_id     UUID PRIMARY KEY,
+    resource_type   TEXT NOT NULL,
+    owner           TEXT NOT NULL,
+    expires_at      TIMESTAMPTZ NOT NULL
+);
+
+-- AI interaction audit: what the assistant was asked and which data it
+-- consulted (prompt / response text only when content capture is enabled)
+CREATE TABLE IF NOT EXISTS fhir_ai_audit (
+    id              BIGSERIAL PRIMARY KEY,
     created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    request_id      TEXT,
+    operation       TEXT NOT NULL,
+    model           TEXT NOT NULL,
+    prompt_hash     TEXT NOT NULL,
+    prompt          TEXT,
+    response        TEXT,
+    tool_calls      JSONB NOT NULL DEFAULT '[]',
+    resources       TEXT[] NOT NULL DEFAULT '{}',
+    input_tokens    BIGINT NOT NULL DEFAULT 0,
+    output_tokens   BIGINT NOT NULL DEFAULT 0
+);
 
-    UNIQUE (resource_id, version)
+-- Progress of resumable backfills (see backfill.rs)
+CREATE TABLE IF NOT EXISTS fhir_backfills (
+    name            TEXT PRIMARY KEY,
+    last_id         UUID,                            -- last resource id processed
+    processed       BIGINT NOT NULL DEFAULT 0,
+    started_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at     TIMESTAMPTZ
+);
+
+-- Change notifications awaiting delivery (see outbox.rs)
+CREATE TABLE IF NOT EXISTS fhir_outbox (
+    id              BIGSERIAL PRIMARY KEY,
+    resource_type   TEXT NOT NULL,
+    resource_id     UUID NOT NULL,
+    version         INTEGER NOT NULL,
+    method          TEXT NOT NULL CHECK (method IN ('POST', 'PUT', 'DELETE')),
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    attempts        INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_error      TEXT,
+    delivered_at    TIMESTAMPTZ
 );
 
 -- Indexes for efficient querying
@@ -34,6 +96,10 @@ CREATE INDEX IF NOT EXISTS idx_fhir_history_resource_id
 CREATE INDEX IF NOT EXISTS idx_fhir_history_resource_version
     ON fhir_history(resource_id, version DESC);
 
+-- Type-level history feed ordered by (created_at, id) for cursor paging
+CREATE INDEX IF NOT EXISTS idx_fhir_history_type_created
+    ON fhir_history(resource_type, created_at, id);
+
 -- GIN index for JSONB full-text search
 CREATE INDEX IF NOT EXISTS idx_fhir_resources_data_gin
     ON fhir_resources USING GIN (data);
@@ -44,3 +110,17 @@ CREATE INDEX IF NOT EXISTS idx_fhir_resources_gender
 
 CREATE INDEX IF NOT EXISTS idx_fhir_resources_birthdate
     ON fhir_resources ((data->>'birthDate')) WHERE deleted_at IS NULL;
+
+-- Search index lookups
+CREATE INDEX IF NOT EXISTS idx_fhir_search_index_param
+    ON fhir_search_index(resource_type, param_name, value);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_search_index_resource
+    ON fhir_search_index(resource_id);
+
+CREATE INDEX IF NOT EXISTS idx_fhir_ai_audit_created
+    ON fhir_ai_audit(created_at);
+
+-- Pending outbox rows in claim order
+CREATE INDEX IF NOT EXISTS idx_fhir_outbox_pending
+    ON fhir_outbox(next_attempt_at, id) WHERE delivered_at IS NULL;
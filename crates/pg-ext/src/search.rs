@@ -2,26 +2,56 @@
 
 use pgrx::prelude::*;
 
+use crate::crypto;
+use crate::index;
+
 /// Search for FHIR resources with filtering, pagination, and sorting
 ///
+/// With a `name` parameter every row carries a match `score` from
+/// [`fhir_name_score`]; otherwise the score is NULL.
+///
 /// # Arguments
 /// * `resource_type` - The FHIR resource type (e.g., "Patient")
 /// * `params` - JSONB object with search parameters:
 ///   - `name`: substring match on patient name (family or given)
 ///   - `gender`: exact match
 ///   - `birthdate`: date with optional prefix (eq, ge, le, gt, lt)
+///   - `active`: `true` matches current records (not `active: false` and
+///     without a `replaced-by` link), `false` every other record
 ///   - `_count`: max results (default 10)
 ///   - `_offset`: skip N results (default 0)
-///   - `_sort`: field to sort by, prefix with - for descending
+///   - `_sort`: field to sort by, prefix with - for descending; `_score`
+///     ranks the best `name` matches first
+///   - `_asOf`: RFC 3339 instant; search the resource state at that time
+///     (reconstructed from history) instead of the current state
+///   - `_paths`: array of `{path, type, value}` filters over dotted element
+///     paths, used for search parameters defined by implementation guides
+///     (`type` is `token`, `string` or `date`)
+///
+/// A search parameter given several times arrives as an array of values
+/// that must all match; a value may list alternatives separated by commas
+/// (`\,` for a literal comma), any of which may match.
 #[pg_extern]
 fn fhir_search(
     resource_type: &str,
     params: pgrx::JsonB,
-) -> TableIterator<'static, (name!(id, pgrx::Uuid), name!(data, pgrx::JsonB))> {
+) -> TableIterator<
+    'static,
+    (
+        name!(id, pgrx::Uuid),
+        name!(data, pgrx::JsonB),
+        name!(score, Option<f64>),
+    ),
+> {
     let params = params.0;
 
     // Extract pagination params
-    let count = params.get("_count").and_then(|v| v.as_i64()).unwrap_or(10);
+    // `"_count": "all"` disables the limit (used for streaming exports)
+    let count = match params.get("_count") {
+        Some(v) if v.as_str() == Some("all") => "ALL".to_string(),
+        Some(v) => v.as_i64().unwrap_or(10).to_string(),
+        None => "10".to_string(),
+    };
     let offset = params.get("_offset").and_then(|v| v.as_i64()).unwrap_or(0);
 
     // Extract sort param
@@ -29,41 +59,44 @@ fn fhir_search(
         .get("_sort")
         .and_then(|v| v.as_str())
         .unwrap_or("created_at");
-    let (sort_column, sort_dir) = if let Some(field) = sort_field.strip_prefix('-') {
+    let (mut sort_column, sort_dir) = if let Some(field) = sort_field.strip_prefix('-') {
         (map_sort_field(field), "DESC")
     } else {
         (map_sort_field(sort_field), "ASC")
     };
 
-    // Build dynamic query with filters
-    let mut where_clauses = vec![
-        "resource_type = $1".to_string(),
-        "deleted_at IS NULL".to_string(),
-    ];
-
-    // Name filter (substring match on family or given name)
-    if let Some(name) = params.get("name").and_then(|v| v.as_str()) {
-        where_clauses.push(format!(
-            "(data->'name'->0->>'family' ILIKE '%{}%' OR data->'name'->0->'given'->>0 ILIKE '%{}%')",
-            escape_like(name),
-            escape_like(name)
-        ));
-    }
-
-    // Gender filter (exact match)
-    if let Some(gender) = params.get("gender").and_then(|v| v.as_str()) {
-        where_clauses.push(format!("data->>'gender' = '{}'", escape_sql(gender)));
+    // Historical searches run over a reconstructed snapshot, which only has
+    // id/version/data columns and no created_at to sort by
+    if params.get("_asOf").is_some() && sort_column == "created_at" {
+        sort_column = "id";
     }
+    let (source, where_clauses) = search_filter(&params);
 
-    // Birthdate filter with prefix operators
-    if let Some(birthdate) = params.get("birthdate").and_then(|v| v.as_str()) {
-        if let Some(clause) = build_date_clause(birthdate) {
-            where_clauses.push(clause);
-        }
-    }
+    // Name searches are scored by their best matching name; `_score` puts
+    // the best matches first
+    let names: Vec<String> = param_values(&params, "name").concat();
+    let score = match names.as_slice() {
+        [] => "NULL::float8".to_string(),
+        [name] => format!("fhir_name_score(data, '{}')", escape_sql(name)),
+        names => format!(
+            "GREATEST({})",
+            names
+                .iter()
+                .map(|name| format!("fhir_name_score(data, '{}')", escape_sql(name)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let (sort_column, sort_dir) = match (names.is_empty(), sort_field) {
+        (false, "_score") => ("score", "DESC"),
+        (false, "-_score") => ("score", "ASC"),
+        _ => (sort_column, sort_dir),
+    };
 
     let query = format!(
-        "SELECT id, data FROM fhir_resources WHERE {} ORDER BY {} {} LIMIT {} OFFSET {}",
+        "SELECT id, data, {} AS score FROM {} WHERE {} ORDER BY {} {} LIMIT {} OFFSET {}",
+        score,
+        source,
         where_clauses.join(" AND "),
         sort_column,
         sort_dir,
@@ -71,23 +104,393 @@ fn fhir_search(
         offset
     );
 
-    let results: Vec<(pgrx::Uuid, pgrx::JsonB)> = Spi::connect(|client| {
+    let results: Vec<(pgrx::Uuid, pgrx::JsonB, Option<f64>)> = Spi::connect(|client| {
         let mut results = Vec::new();
         let tup_table = client.select(&query, None, &[resource_type.into()])?;
 
         for row in tup_table {
             let id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
             let data: pgrx::JsonB = row.get(2)?.expect("data should not be null");
-            results.push((id, data));
+            results.push((id, data, row.get(3)?));
         }
 
         Ok::<_, pgrx::spi::SpiError>(results)
     })
     .expect("Failed to execute search");
 
-    TableIterator::new(results)
+    TableIterator::new(
+        results
+            .into_iter()
+            .map(|(id, data, score)| (id, crypto::decrypted(data), score)),
+    )
+}
+
+/// How well a resource's names match a name query, from 0 to 1
+///
+/// Each query word is compared with every family and given name word by
+/// Levenshtein similarity; the score averages each query word's best match,
+/// so `Smith` scores 1 against `Smith`, higher against `Smyth` than `Schmidt`,
+/// and a substring like `Smi` still ranks below the full name.
+#[pg_extern]
+fn fhir_name_score(data: pgrx::JsonB, query: &str) -> f64 {
+    let words: Vec<String> = data
+        .0
+        .get("name")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .flat_map(|name| {
+            let family = name.get("family").and_then(|v| v.as_str());
+            let given = name
+                .get("given")
+                .and_then(|v| v.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|v| v.as_str());
+            family.into_iter().chain(given).collect::<Vec<_>>()
+        })
+        .flat_map(name_words)
+        .collect();
+
+    let query = name_words(query);
+    if query.is_empty() || words.is_empty() {
+        return 0.0;
+    }
+    let total: f64 = query
+        .iter()
+        .map(|q| words.iter().map(|w| similarity(q, w)).fold(0.0, f64::max))
+        .sum();
+    total / query.len() as f64
+}
+
+/// Lowercase alphanumeric words of a name
+fn name_words(name: &str) -> Vec<String> {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Similarity from 0 to 1 by Levenshtein distance relative to the longer
+/// string
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+/// Count the resources a search matches, ignoring paging and sorting
+///
+/// Takes the same `params` as [`fhir_search`] but never reads or decrypts
+/// resource bodies, so `_summary=count` requests stay cheap.
+#[pg_extern]
+fn fhir_count(resource_type: &str, params: pgrx::JsonB) -> i64 {
+    let (source, where_clauses) = search_filter(&params.0);
+    let query = format!(
+        "SELECT COUNT(*) FROM {} WHERE {}",
+        source,
+        where_clauses.join(" AND ")
+    );
+
+    Spi::get_one_with_args::<i64>(&query, &[resource_type.into()])
+        .expect("Failed to execute count")
+        .unwrap_or(0)
+}
+
+/// The planner's estimate of the resources a search matches
+///
+/// Takes the same `params` as [`fhir_count`] but only plans the query, so it
+/// costs the same however many rows match. The estimate comes from table
+/// statistics and can be far off for selective JSON filters or right after
+/// bulk loads, before `ANALYZE` has run.
+#[pg_extern]
+fn fhir_count_estimate(resource_type: &str, params: pgrx::JsonB) -> i64 {
+    let (source, where_clauses) = search_filter(&params.0);
+    let query = format!(
+        "EXPLAIN (FORMAT JSON) SELECT 1 FROM {} WHERE {}",
+        source,
+        where_clauses.join(" AND ")
+    );
+
+    let plan = Spi::get_one_with_args::<pgrx::Json>(&query, &[resource_type.into()])
+        .expect("Failed to plan count")
+        .map(|plan| plan.0);
+    plan.as_ref()
+        .and_then(|plan| plan.pointer("/0/Plan/Plan Rows"))
+        .and_then(|rows| rows.as_f64())
+        .map_or(0, |rows| rows.round() as i64)
+}
+
+/// Row source and WHERE clauses for the filters in search `params`
+///
+/// The source takes the resource type as `$1`.
+fn search_filter(params: &serde_json::Value) -> (String, Vec<String>) {
+    // Historical searches run over a reconstructed snapshot (deleted
+    // resources are already excluded)
+    let as_of = params.get("_asOf").and_then(|v| v.as_str());
+    let (source, mut where_clauses) = match as_of {
+        Some(as_of) => (
+            format!(
+                "fhir_export_snapshot($1, '{}'::timestamptz)",
+                escape_sql(as_of)
+            ),
+            vec!["TRUE".to_string()],
+        ),
+        None => (
+            "fhir_resources".to_string(),
+            vec![
+                "resource_type = $1".to_string(),
+                "deleted_at IS NULL".to_string(),
+            ],
+        ),
+    };
+
+    // Only resources updated at or after `_since` (live searches only; the
+    // historical snapshot has no update timestamps)
+    if let (None, Some(since)) = (as_of, params.get("_since").and_then(|v| v.as_str())) {
+        where_clauses.push(format!(
+            "updated_at >= '{}'::timestamptz",
+            escape_sql(since)
+        ));
+    }
+
+    let values = |key: &str| param_values(params, key);
+    let live = as_of.is_none();
+
+    // Name filter (substring match on family or given name)
+    where_clauses.extend(match_all(&values("name"), |name| {
+        Some(format!(
+            "(data->'name'->0->>'family' ILIKE '%{}%' OR data->'name'->0->'given'->>0 ILIKE '%{}%')",
+            escape_like(name),
+            escape_like(name)
+        ))
+    }));
+
+    // Gender filter (exact match)
+    where_clauses.extend(match_all(&values("gender"), |gender| {
+        Some(indexed_clause(
+            live,
+            "gender",
+            &format!("si.value = '{}'", escape_sql(gender)),
+            format!("data->>'gender' = '{}'", escape_sql(gender)),
+        ))
+    }));
+
+    // Birthdate filter with prefix operators
+    where_clauses.extend(match_all(&values("birthdate"), |birthdate| {
+        Some(indexed_clause(
+            live,
+            "birthdate",
+            &build_date_clause("si.value", birthdate)?,
+            build_date_clause("data->>'birthDate'", birthdate)?,
+        ))
+    }));
+
+    // Identifier filter (`system|value` token over every identifier)
+    where_clauses.extend(match_all(&values("identifier"), |token| {
+        let (param, condition) = identifier_index_condition(token)?;
+        Some(indexed_clause(
+            live,
+            param,
+            &condition,
+            build_identifier_clause(token)?,
+        ))
+    }));
+
+    // Contact point filters; `phone` and `email` only look at telecom
+    // entries of that system
+    for (code, system) in [
+        ("telecom", None),
+        ("phone", Some("phone")),
+        ("email", Some("email")),
+    ] {
+        where_clauses.extend(match_all(&values(code), |value| {
+            let (param, condition) = telecom_index_condition(system, value)?;
+            Some(indexed_clause(
+                live,
+                param,
+                &condition,
+                build_telecom_clause(system, value)?,
+            ))
+        }));
+    }
+
+    // Name part and address filters, as `code` or `code:modifier`
+    for key in params.as_object().into_iter().flat_map(|map| map.keys()) {
+        let (code, modifier) = match key.split_once(':') {
+            Some((code, modifier)) => (code, Some(modifier)),
+            None => (key.as_str(), None),
+        };
+        let Some((_, array, elements)) = STRING_PARAMS.iter().find(|(c, _, _)| *c == code) else {
+            continue;
+        };
+        where_clauses.extend(match_all(&values(key.as_str()), |value| {
+            let fallback = build_string_clause(array, elements, modifier, value)?;
+            // The index holds lowercased values, so `:exact` reads the JSON
+            let pattern = escape_like(&value.to_lowercase());
+            Some(match modifier {
+                None => indexed_clause(
+                    live,
+                    code,
+                    &format!("si.value LIKE '{}%'", pattern),
+                    fallback,
+                ),
+                Some("contains") => indexed_clause(
+                    live,
+                    code,
+                    &format!("si.value LIKE '%{}%'", pattern),
+                    fallback,
+                ),
+                _ => fallback,
+            })
+        }));
+    }
+
+    // Active filter; a patient replaced by another is no longer current
+    // even if it is still marked active
+    let current = "(COALESCE(data->>'active', 'true') <> 'false' \
+                   AND NOT COALESCE(data->'link' @> '[{\"type\": \"replaced-by\"}]'::jsonb, false))";
+    where_clauses.extend(match_all(&values("active"), |active| match active {
+        "true" => Some(current.to_string()),
+        "false" => Some(format!("NOT {}", current)),
+        _ => None,
+    }));
+
+    // Element path filters
+    for filter in params
+        .get("_paths")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+    {
+        let field = |key: &str| filter.get(key).and_then(|v| v.as_str());
+        if let (Some(path), Some(param_type), Some(value)) =
+            (field("path"), field("type"), field("value"))
+        {
+            where_clauses.extend(match_all(&[split_alternatives(value)], |value| {
+                build_path_clause(path, param_type, value)
+            }));
+        }
+    }
+
+    (source, where_clauses)
+}
+
+/// The values of search parameter `key` as groups that must all match, each
+/// a list of alternatives any of which may match
+///
+/// A repeated parameter arrives as an array with one entry per occurrence;
+/// each entry separates its alternatives by commas (`\,` is a literal comma).
+fn param_values(params: &serde_json::Value, key: &str) -> Vec<Vec<String>> {
+    let entries: Vec<&str> = match params.get(key) {
+        Some(serde_json::Value::String(value)) => vec![value],
+        Some(serde_json::Value::Array(values)) => {
+            values.iter().filter_map(|v| v.as_str()).collect()
+        }
+        _ => Vec::new(),
+    };
+    entries
+        .into_iter()
+        .map(split_alternatives)
+        .filter(|alternatives| !alternatives.is_empty())
+        .collect()
+}
+
+/// Split a value on unescaped commas; empty alternatives are dropped
+fn split_alternatives(value: &str) -> Vec<String> {
+    let mut alternatives = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let current = alternatives.last_mut().expect("never empty");
+        match (c, chars.clone().next()) {
+            ('\\', Some(',')) => {
+                current.push(',');
+                chars.next();
+            }
+            (',', _) => alternatives.push(String::new()),
+            (c, _) => current.push(c),
+        }
+    }
+    alternatives.retain(|a| !a.is_empty());
+    alternatives
 }
 
+/// One clause per group of alternatives, matching any alternative `build`
+/// can filter on; a group with none is left out
+fn match_all(groups: &[Vec<String>], build: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    groups
+        .iter()
+        .filter_map(|alternatives| {
+            let clauses: Vec<String> = alternatives.iter().filter_map(|a| build(a)).collect();
+            match clauses.len() {
+                0 => None,
+                1 => clauses.into_iter().next(),
+                _ => Some(format!("({})", clauses.join(" OR "))),
+            }
+        })
+        .collect()
+}
+
+/// Match search parameter `param` through `fhir_search_index` for resources
+/// whose index rows are current, and by `fallback` over the JSON for those
+/// not yet reindexed after an extractor change
+///
+/// `condition` filters the index rows by `si.value`. Historical searches
+/// have no index rows to use and take `fallback` alone.
+fn indexed_clause(live: bool, param: &str, condition: &str, fallback: String) -> String {
+    if !live {
+        return fallback;
+    }
+    let current = format!(
+        "index_version = version AND index_revision = {}",
+        index::EXTRACTOR_REVISION
+    );
+    format!(
+        "(({0} AND id IN (SELECT si.resource_id FROM fhir_search_index si \
+                 WHERE si.resource_type = $1 AND si.param_name = '{1}' AND {2})) \
+          OR (NOT COALESCE({0}, false) AND {3}))",
+        current, param, condition, fallback
+    )
+}
+
+/// String search parameters matching parts of every entry of a Patient
+/// array, as `(code, array, elements)`
+pub(crate) const STRING_PARAMS: &[(&str, &str, &[&str])] = &[
+    ("family", "name", &["family"]),
+    ("given", "name", &["given"]),
+    (
+        "address",
+        "address",
+        &[
+            "line",
+            "city",
+            "district",
+            "state",
+            "postalCode",
+            "country",
+            "text",
+        ],
+    ),
+    ("address-city", "address", &["city"]),
+    ("address-state", "address", &["state"]),
+    ("address-postalcode", "address", &["postalCode"]),
+    ("address-country", "address", &["country"]),
+];
+
 /// Map FHIR sort fields to database columns/expressions
 fn map_sort_field(field: &str) -> &'static str {
     match field {
@@ -114,7 +517,7 @@ fn escape_sql(s: &str) -> String {
 
 /// Build date comparison clause from FHIR date prefix
 /// Supports: eq (default), ge, le, gt, lt, ne
-fn build_date_clause(birthdate: &str) -> Option<String> {
+fn build_date_clause(column: &str, birthdate: &str) -> Option<String> {
     let (op, date) = if birthdate.starts_with("ge") {
         (">=", &birthdate[2..])
     } else if birthdate.starts_with("le") {
@@ -136,5 +539,258 @@ fn build_date_clause(birthdate: &str) -> Option<String> {
         return None;
     }
 
-    Some(format!("data->>'birthDate' {} '{}'", op, escape_sql(date)))
+    Some(format!("{} {} '{}'", column, op, escape_sql(date)))
+}
+
+/// Build an identifier token filter: `value` matches in any system,
+/// `system|value` only in that system, `|value` only without a system and
+/// `system|` any identifier in the system
+fn build_identifier_clause(token: &str) -> Option<String> {
+    let (system, value) = match token.split_once('|') {
+        Some((system, value)) => (Some(system), value),
+        None => (None, token),
+    };
+    if value.is_empty() && system.is_none_or(str::is_empty) {
+        return None;
+    }
+
+    let mut conditions = Vec::new();
+    match system {
+        Some("") => conditions.push("NOT (i ? 'system')".to_string()),
+        Some(system) => conditions.push(format!("i->>'system' = '{}'", escape_sql(system))),
+        None => {}
+    }
+    if !value.is_empty() {
+        conditions.push(format!("i->>'value' = '{}'", escape_sql(value)));
+    }
+    Some(format!(
+        "EXISTS (SELECT 1 FROM jsonb_array_elements(\
+            CASE WHEN jsonb_typeof(data->'identifier') = 'array' THEN data->'identifier' ELSE '[]'::jsonb END) i \
+         WHERE jsonb_typeof(i) = 'object' AND {})",
+        conditions.join(" AND ")
+    ))
+}
+
+/// The index parameter and row condition of an identifier token, mirroring
+/// [`build_identifier_clause`]
+fn identifier_index_condition(token: &str) -> Option<(&'static str, String)> {
+    match token.split_once('|') {
+        Some(("", "")) => None,
+        Some((system, "")) => Some((
+            "identifier",
+            format!("si.value LIKE '{}|%'", escape_like(system)),
+        )),
+        Some((system, value)) => Some((
+            "identifier",
+            format!("si.value = '{}|{}'", escape_sql(system), escape_sql(value)),
+        )),
+        None if token.is_empty() => None,
+        None => Some((
+            "identifier-value",
+            format!("si.value = '{}'", escape_sql(token)),
+        )),
+    }
+}
+
+/// Build a filter matching `elements` of any entry of the Patient's
+/// `array` (every item of a repeating element such as `given` or `line`)
+///
+/// Without a modifier a value matches case-insensitively at the start,
+/// `:contains` anywhere and `:exact` only the whole value, case included.
+fn build_string_clause(
+    array: &str,
+    elements: &[&str],
+    modifier: Option<&str>,
+    value: &str,
+) -> Option<String> {
+    if value.is_empty() {
+        return None;
+    }
+    let comparison = match modifier {
+        None => format!("ILIKE '{}%'", escape_like(value)),
+        Some("contains") => format!("ILIKE '%{}%'", escape_like(value)),
+        Some("exact") => format!("= '{}'", escape_sql(value)),
+        Some(_) => return None,
+    };
+    let keys: Vec<String> = elements
+        .iter()
+        .map(|element| format!("@.key == \"{}\"", element))
+        .collect();
+    Some(format!(
+        "EXISTS (SELECT 1 FROM jsonb_array_elements(\
+            CASE WHEN jsonb_typeof(data->'{0}') = 'array' THEN data->'{0}' ELSE '[]'::jsonb END) a, \
+         jsonb_path_query(CASE WHEN jsonb_typeof(a) = 'object' THEN a ELSE '{{}}'::jsonb END, \
+            'lax $.keyvalue() ? ({1}).value[*] ? (@.type() == \"string\")') s \
+         WHERE s #>> '{{}}' {2})",
+        array,
+        keys.join(" || "),
+        comparison
+    ))
+}
+
+/// Build a contact point filter on `Patient.telecom`, restricted to one
+/// ContactPoint system if given
+///
+/// A `system|` prefix on the value is ignored, since a ContactPoint has no
+/// token system. Phone numbers are compared on digits and `+` only, so
+/// `(555) 123-4567` matches `555-123-4567`; emails compare case-insensitively.
+fn build_telecom_clause(system: Option<&str>, token: &str) -> Option<String> {
+    let value = token
+        .rsplit_once('|')
+        .map_or(token, |(_, value)| value)
+        .trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    let matches = match system {
+        Some("phone") => {
+            let digits = phone_digits(value);
+            if digits.is_empty() {
+                return None;
+            }
+            format!(
+                "regexp_replace(t->>'value', '[^0-9+]', '', 'g') = '{}'",
+                escape_sql(&digits)
+            )
+        }
+        Some("email") => format!("lower(t->>'value') = lower('{}')", escape_sql(value)),
+        _ => format!("t->>'value' = '{}'", escape_sql(value)),
+    };
+    let system_clause = system
+        .map(|system| format!(" AND t->>'system' = '{}'", escape_sql(system)))
+        .unwrap_or_default();
+    Some(format!(
+        "EXISTS (SELECT 1 FROM jsonb_array_elements(\
+            CASE WHEN jsonb_typeof(data->'telecom') = 'array' THEN data->'telecom' ELSE '[]'::jsonb END) t \
+         WHERE jsonb_typeof(t) = 'object' AND {}{})",
+        matches, system_clause
+    ))
+}
+
+/// The index parameter and row condition of a contact point search, mirroring
+/// [`build_telecom_clause`]
+fn telecom_index_condition(system: Option<&str>, token: &str) -> Option<(&'static str, String)> {
+    let value = token
+        .rsplit_once('|')
+        .map_or(token, |(_, value)| value)
+        .trim();
+    let (param, value) = match system {
+        Some("phone") => ("phone", phone_digits(value)),
+        Some("email") => ("email", value.to_lowercase()),
+        _ => ("telecom", value.to_string()),
+    };
+    if value.is_empty() {
+        return None;
+    }
+    Some((param, format!("si.value = '{}'", escape_sql(&value))))
+}
+
+/// The digits and `+` of a phone number, the form phone searches compare
+pub(crate) fn phone_digits(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '+')
+        .collect()
+}
+
+/// Build a filter over every value at a dotted element path
+///
+/// Paths are restricted to alphanumeric segments since they are spliced into
+/// a JSON path literal. A segment ending in `[x]` is a choice element and
+/// matches whichever typed form is present (`deceased[x]` matches
+/// `deceasedBoolean` and `deceasedDateTime`).
+fn build_path_clause(path: &str, param_type: &str, value: &str) -> Option<String> {
+    let valid = !path.is_empty()
+        && path.split('.').all(|segment| {
+            let segment = segment.strip_suffix("[x]").unwrap_or(segment);
+            segment
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic())
+                && segment.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if !valid || value.is_empty() {
+        return None;
+    }
+
+    let condition = match param_type {
+        // Primitive code, Coding, Identifier or CodeableConcept. `system|code`
+        // requires the system, `|code` requires no system and `system|`
+        // matches any code in the system (as `fhir_core::TokenParam`)
+        "token" => {
+            let (system, code) = match value.split_once('|') {
+                Some((system, code)) => (Some(system.trim()), code.trim()),
+                None => (None, value.trim()),
+            };
+            if code.is_empty() && system.is_none_or(str::is_empty) {
+                return None;
+            }
+            let system_clause = |elem: &str| match system {
+                Some("") => format!(" AND NOT ({} ? 'system')", elem),
+                Some(system) => format!(" AND {}->>'system' = '{}'", elem, escape_sql(system)),
+                None => String::new(),
+            };
+            let code_clause = |elem: &str, key: &str| {
+                if code.is_empty() {
+                    format!("(jsonb_typeof({0}) = 'object' AND {0} ? '{1}')", elem, key)
+                } else {
+                    format!("{}->>'{}' = '{}'", elem, key, escape_sql(code))
+                }
+            };
+            // A primitive code (or boolean) has no system, so only matches
+            // without one
+            let primitive = if code.is_empty() || system.is_some_and(|s| !s.is_empty()) {
+                "false".to_string()
+            } else {
+                format!(
+                    "(jsonb_typeof(v) IN ('string', 'boolean') AND v #>> '{{}}' = '{}')",
+                    escape_sql(code)
+                )
+            };
+            format!(
+                "({} \
+                 OR ({}{}) \
+                 OR ({}{}) \
+                 OR EXISTS (SELECT 1 FROM jsonb_array_elements(\
+                    CASE WHEN jsonb_typeof(v->'coding') = 'array' THEN v->'coding' ELSE '[]'::jsonb END) c \
+                    WHERE {}{}))",
+                primitive,
+                code_clause("v", "value"),
+                system_clause("v"),
+                code_clause("v", "code"),
+                system_clause("v"),
+                code_clause("c", "code"),
+                system_clause("c"),
+            )
+        }
+        // Case-insensitive prefix match on any string within the element
+        "string" => format!(
+            "EXISTS (SELECT 1 FROM jsonb_path_query(v, 'strict $.** ? (@.type() == \"string\")') s \
+             WHERE s #>> '{{}}' ILIKE '{}%')",
+            escape_like(value)
+        ),
+        // Only strings hold dates; a boolean choice form never matches
+        "date" => format!(
+            "(jsonb_typeof(v) = 'string' AND {})",
+            build_date_clause("v #>> '{}'", value)?
+        ),
+        _ => return None,
+    };
+
+    // `[*]` steps into arrays and is a no-op on single values
+    let json_path: String = path
+        .split('.')
+        .map(|segment| match segment.strip_suffix("[x]") {
+            Some(choice) => format!(
+                ".keyvalue() ? (@.key like_regex \"^{}[A-Z]\").value[*]",
+                choice
+            ),
+            None => format!(".\"{}\"[*]", segment),
+        })
+        .collect();
+    Some(format!(
+        "EXISTS (SELECT 1 FROM jsonb_path_query(data, 'lax ${}') v WHERE {})",
+        json_path, condition
+    ))
 }
@@ -3,13 +3,24 @@
 use pgrx::prelude::*;
 use uuid::Uuid;
 
+use crate::crypto;
+use crate::history;
+use crate::index;
+use crate::locks;
+
 /// Create a new FHIR resource
 ///
 /// Inserts a new resource with version 1, also recording it in history.
 /// Returns the generated UUID for the resource.
 #[pg_extern]
 fn fhir_put(resource_type: &str, data: pgrx::JsonB) -> pgrx::Uuid {
-    let id = Uuid::new_v4();
+    put_with_id(resource_type, Uuid::new_v4(), data)
+}
+
+/// Create a new FHIR resource under an id chosen by the caller (e.g. one
+/// that other resources of a transaction already reference)
+pub(crate) fn put_with_id(resource_type: &str, id: Uuid, mut data: pgrx::JsonB) -> pgrx::Uuid {
+    crypto::encrypt_incoming(&mut data.0);
     let id_bytes = *id.as_bytes();
     let version = 1 as i32;
 
@@ -28,17 +39,22 @@ fn fhir_put(resource_type: &str, data: pgrx::JsonB) -> pgrx::Uuid {
     )
     .expect("Failed to insert resource");
 
+    // Extract search parameters
+    index::index_resource(
+        resource_type,
+        pgrx::Uuid::from_bytes(id_bytes),
+        version,
+        &data_for_history.0,
+    );
+
     // Insert into history table
-    Spi::run_with_args(
-        "INSERT INTO fhir_history (resource_id, resource_type, version, data) VALUES ($1, $2, $3, $4)",
-        &[
-            pgrx::Uuid::from_bytes(id_bytes).into(),
-            resource_type.into(),
-            version.into(),
-            data_for_history.into(),
-        ],
-    )
-    .expect("Failed to insert history");
+    history::record_version(
+        resource_type,
+        pgrx::Uuid::from_bytes(id_bytes),
+        version,
+        &data_for_history.0,
+        None,
+    );
 
     pgrx::Uuid::from_bytes(id_bytes)
 }
@@ -47,7 +63,7 @@ fn fhir_put(resource_type: &str, data: pgrx::JsonB) -> pgrx::Uuid {
 ///
 /// Returns the resource data as JSONB, or None if not found or deleted.
 #[pg_extern]
-fn fhir_get(resource_type: &str, id: pgrx::Uuid) -> Option<pgrx::JsonB> {
+pub(crate) fn fhir_get(resource_type: &str, id: pgrx::Uuid) -> Option<pgrx::JsonB> {
     // Use ok().flatten() to convert "no rows" error to None
     Spi::get_one_with_args(
         "SELECT data FROM fhir_resources WHERE id = $1 AND resource_type = $2 AND deleted_at IS NULL",
@@ -55,14 +71,50 @@ fn fhir_get(resource_type: &str, id: pgrx::Uuid) -> Option<pgrx::JsonB> {
     )
     .ok()
     .flatten()
+    .map(crypto::decrypted)
+}
+
+/// Retrieve several FHIR resources of a type in one query
+///
+/// Missing and deleted ids are skipped; rows come back in no particular order.
+#[pg_extern]
+fn fhir_get_many(
+    resource_type: &str,
+    ids: Vec<pgrx::Uuid>,
+) -> TableIterator<'static, (name!(id, pgrx::Uuid), name!(data, pgrx::JsonB))> {
+    let results = Spi::connect(|client| {
+        let mut results = Vec::new();
+        let tup_table = client.select(
+            "SELECT id, data FROM fhir_resources
+              WHERE id = ANY($1) AND resource_type = $2 AND deleted_at IS NULL",
+            None,
+            &[ids.into(), resource_type.into()],
+        )?;
+
+        for row in tup_table {
+            let id: pgrx::Uuid = row.get(1)?.expect("id should not be null");
+            let data: pgrx::JsonB = row.get(2)?.expect("data should not be null");
+            results.push((id, data));
+        }
+
+        Ok::<_, pgrx::spi::SpiError>(results)
+    })
+    .expect("Failed to fetch resources");
+
+    TableIterator::new(
+        results
+            .into_iter()
+            .map(|(id, data)| (id, crypto::decrypted(data))),
+    )
 }
 
 /// Soft-delete a FHIR resource
 ///
 /// Sets deleted_at timestamp and records the deletion in history.
-/// Returns true if a resource was deleted, false if not found.
+/// Returns true if a resource was deleted, false if not found. Fails if
+/// another owner holds a checkout lock on it.
 #[pg_extern]
-fn fhir_delete(resource_type: &str, id: pgrx::Uuid) -> bool {
+pub(crate) fn fhir_delete(resource_type: &str, id: pgrx::Uuid) -> bool {
     // Get current version before deletion
     let current_version: Option<i32> = Spi::get_one_with_args(
         "SELECT version FROM fhir_resources WHERE id = $1 AND resource_type = $2 AND deleted_at IS NULL",
@@ -74,6 +126,7 @@ fn fhir_delete(resource_type: &str, id: pgrx::Uuid) -> bool {
     let Some(version) = current_version else {
         return false;
     };
+    locks::ensure_unlocked(resource_type, id);
 
     // Soft delete the resource
     Spi::run_with_args(
@@ -82,20 +135,10 @@ fn fhir_delete(resource_type: &str, id: pgrx::Uuid) -> bool {
     )
     .expect("Failed to delete resource");
 
-    // Record deletion in history (store empty JSON to mark deletion)
-    let new_version = version + 1;
-    let empty_data = pgrx::JsonB(serde_json::json!({"deleted": true}));
+    index::unindex_resource(id);
 
-    Spi::run_with_args(
-        "INSERT INTO fhir_history (resource_id, resource_type, version, data) VALUES ($1, $2, $3, $4)",
-        &[
-            id.into(),
-            resource_type.into(),
-            new_version.into(),
-            empty_data.into(),
-        ],
-    )
-    .expect("Failed to insert history");
+    // Record deletion in history
+    history::record_deletion(resource_type, id, version + 1);
 
     true
 }
@@ -104,19 +147,55 @@ fn fhir_delete(resource_type: &str, id: pgrx::Uuid) -> bool {
 ///
 /// Increments version and records the update in history.
 /// Returns the new version number, or None if resource not found.
+///
+/// With `expected_version`, the update only applies if the stored version
+/// still equals it; otherwise it fails with SQLSTATE 27000 (triggered data
+/// change violation), which the extension raises for nothing else, and
+/// nothing is written. Unlike a serialization failure it is no reason to
+/// retry the statement. The row is locked while the versions are compared,
+/// so concurrent updates cannot both pass. Fails if another owner holds a
+/// checkout lock on the resource.
 #[pg_extern]
-fn fhir_update(resource_type: &str, id: pgrx::Uuid, data: pgrx::JsonB) -> Option<i32> {
-    // Get current version
-    let current_version: Option<i32> = Spi::get_one_with_args(
-        "SELECT version FROM fhir_resources WHERE id = $1 AND resource_type = $2 AND deleted_at IS NULL",
-        &[id.into(), resource_type.into()],
-    )
-    .ok()
-    .flatten();
-
-    let Some(version) = current_version else {
+pub(crate) fn fhir_update(
+    resource_type: &str,
+    id: pgrx::Uuid,
+    mut data: pgrx::JsonB,
+    expected_version: default!(Option<i32>, "NULL"),
+) -> Option<i32> {
+    crypto::encrypt_incoming(&mut data.0);
+
+    // Lock the current version and content (the latter for delta history)
+    let current = Spi::connect_mut(|client| {
+        let row = client
+            .update(
+                "SELECT version, data FROM fhir_resources
+                  WHERE id = $1 AND resource_type = $2 AND deleted_at IS NULL
+                  FOR UPDATE",
+                None,
+                &[id.into(), resource_type.into()],
+            )?
+            .first();
+        let version: Option<i32> = row.get(1)?;
+        let data: Option<pgrx::JsonB> = row.get(2)?;
+        Ok::<_, pgrx::spi::SpiError>((version, data))
+    })
+    .ok();
+
+    let Some((Some(version), previous)) = current else {
         return None;
     };
+    locks::ensure_unlocked(resource_type, id);
+
+    if let Some(expected) = expected_version.filter(|&expected| expected != version) {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_TRIGGERED_DATA_CHANGE_VIOLATION,
+            format!(
+                "{}/{} is at version {}, not the expected version {}",
+                resource_type, id, version, expected
+            )
+        );
+    }
 
     let new_version = version + 1;
     let data_for_history = pgrx::JsonB(data.0.clone());
@@ -133,17 +212,17 @@ fn fhir_update(resource_type: &str, id: pgrx::Uuid, data: pgrx::JsonB) -> Option
     )
     .expect("Failed to update resource");
 
+    // Re-extract search parameters
+    index::index_resource(resource_type, id, new_version, &data_for_history.0);
+
     // Record in history
-    Spi::run_with_args(
-        "INSERT INTO fhir_history (resource_id, resource_type, version, data) VALUES ($1, $2, $3, $4)",
-        &[
-            id.into(),
-            resource_type.into(),
-            new_version.into(),
-            data_for_history.into(),
-        ],
-    )
-    .expect("Failed to insert history");
+    history::record_version(
+        resource_type,
+        id,
+        new_version,
+        &data_for_history.0,
+        previous.as_ref().map(|p| &p.0),
+    );
 
     Some(new_version)
 }
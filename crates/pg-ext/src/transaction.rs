@@ -0,0 +1,251 @@
+//! FHIR transaction Bundles
+//!
+//! `fhir_transaction` performs every entry of a `transaction` Bundle within
+//! the calling statement, so the entries are written together or, if any of
+//! them fails, not at all. Entries run in the order the FHIR specification
+//! prescribes (deletes, creates, updates, then reads) and are answered in the
+//! order given. Creates get their ids up front, so a `urn:uuid:` fullUrl
+//! referenced by other entries is rewritten to `Type/id` before anything is
+//! written.
+
+use std::collections::HashMap;
+
+use pgrx::prelude::*;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::storage;
+
+/// One entry's request, after validation
+struct Request {
+    method: String,
+    resource_type: String,
+    id: Option<Uuid>,
+    resource: Option<Value>,
+    if_match: Option<i32>,
+}
+
+/// Fail the transaction with an invalid-parameter error (`400`)
+fn invalid(index: usize, message: &str) -> ! {
+    ereport!(
+        PgLogLevel::ERROR,
+        PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+        format!("Bundle entry {}: {}", index, message)
+    );
+    unreachable!()
+}
+
+/// Fail the transaction because an entry's target does not exist (`404`)
+fn not_found(index: usize, resource_type: &str, id: Uuid) -> ! {
+    ereport!(
+        PgLogLevel::ERROR,
+        PgSqlErrorCode::ERRCODE_NO_DATA_FOUND,
+        format!("Bundle entry {}: {}/{} not found", index, resource_type, id)
+    );
+    unreachable!()
+}
+
+/// Position of a method in the processing order
+fn method_rank(method: &str) -> usize {
+    match method {
+        "DELETE" => 0,
+        "POST" => 1,
+        "PUT" => 2,
+        _ => 3,
+    }
+}
+
+/// Validate one entry's request against its resource
+fn parse_request(index: usize, entry: &Value) -> Request {
+    let request = entry.get("request").unwrap_or(&Value::Null);
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_else(|| invalid(index, "request.method is required"))
+        .to_ascii_uppercase();
+    let url = request
+        .get("url")
+        .and_then(Value::as_str)
+        .unwrap_or_else(|| invalid(index, "request.url is required"));
+    if url.contains('?') {
+        invalid(index, "conditional and search requests are not supported");
+    }
+
+    let (resource_type, id) = match url.trim_matches('/').split_once('/') {
+        Some((resource_type, id)) => {
+            let id = Uuid::parse_str(id)
+                .unwrap_or_else(|_| invalid(index, &format!("\"{}\" is not a resource id", id)));
+            (resource_type, Some(id))
+        }
+        None => (url.trim_matches('/'), None),
+    };
+    let expects_id = matches!(method.as_str(), "PUT" | "DELETE" | "GET");
+    match (method.as_str(), id.is_some()) {
+        ("POST", false) => {}
+        ("PUT" | "DELETE" | "GET", true) => {}
+        ("POST" | "PUT" | "DELETE" | "GET", _) => invalid(
+            index,
+            &format!(
+                "{} needs a url of the form {}",
+                method,
+                if expects_id { "Type/id" } else { "Type" }
+            ),
+        ),
+        _ => invalid(index, &format!("method {} is not supported", method)),
+    }
+
+    let resource = entry.get("resource").cloned();
+    if matches!(method.as_str(), "POST" | "PUT") {
+        let actual = resource
+            .as_ref()
+            .and_then(|r| r.get("resourceType"))
+            .and_then(Value::as_str);
+        if actual != Some(resource_type) {
+            invalid(
+                index,
+                &format!(
+                    "expected a {} resource, got {}",
+                    resource_type,
+                    actual.unwrap_or("none")
+                ),
+            );
+        }
+    }
+
+    let if_match = request.get("ifMatch").and_then(Value::as_str).map(|etag| {
+        let etag = etag.trim();
+        etag.strip_prefix("W/")
+            .unwrap_or(etag)
+            .trim_matches('"')
+            .parse()
+            .unwrap_or_else(|_| invalid(index, "ifMatch must be a version ETag"))
+    });
+
+    Request {
+        method,
+        resource_type: resource_type.to_string(),
+        id,
+        resource,
+        if_match,
+    }
+}
+
+/// Replace every `reference` naming a key of `ids` by its `Type/id`
+fn resolve_references(value: &mut Value, ids: &HashMap<String, String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, member) in map.iter_mut() {
+                match (key.as_str(), member.as_str().and_then(|r| ids.get(r))) {
+                    ("reference", Some(resolved)) => *member = Value::String(resolved.clone()),
+                    _ => resolve_references(member, ids),
+                }
+            }
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| resolve_references(item, ids)),
+        _ => {}
+    }
+}
+
+/// Perform a `transaction` Bundle, returning its `transaction-response`
+/// Bundle
+#[pg_extern]
+fn fhir_transaction(bundle: pgrx::JsonB) -> pgrx::JsonB {
+    let bundle = bundle.0;
+    if bundle.get("type").and_then(Value::as_str) != Some("transaction") {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            "Expected a Bundle of type transaction"
+        );
+    }
+    let entries = bundle
+        .get("entry")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut requests: Vec<Request> = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| parse_request(index, entry))
+        .collect();
+
+    // Creates get their ids now, so references to them can be resolved
+    let mut ids: HashMap<String, String> = HashMap::new();
+    for (request, entry) in requests.iter_mut().zip(&entries) {
+        if request.method != "POST" {
+            continue;
+        }
+        let id = Uuid::new_v4();
+        request.id = Some(id);
+        if let Some(full_url) = entry.get("fullUrl").and_then(Value::as_str) {
+            ids.insert(
+                full_url.to_string(),
+                format!("{}/{}", request.resource_type, id),
+            );
+        }
+    }
+    for resource in requests.iter_mut().filter_map(|r| r.resource.as_mut()) {
+        resolve_references(resource, &ids);
+    }
+
+    let mut order: Vec<usize> = (0..requests.len()).collect();
+    order.sort_by_key(|&index| method_rank(&requests[index].method));
+
+    let mut responses = vec![Value::Null; requests.len()];
+    for index in order {
+        let request = &requests[index];
+        let resource_type = request.resource_type.as_str();
+        let id = request.id.expect("every request has an id by now");
+        let pg_id = pgrx::Uuid::from_bytes(*id.as_bytes());
+        let data = || pgrx::JsonB(request.resource.clone().unwrap_or(Value::Null));
+
+        responses[index] = match request.method.as_str() {
+            "DELETE" => match storage::fhir_delete(resource_type, pg_id) {
+                true => json!({"response": {"status": "204 No Content"}}),
+                false => not_found(index, resource_type, id),
+            },
+            "POST" => {
+                storage::put_with_id(resource_type, id, data());
+                json!({
+                    "fullUrl": format!("{}/{}", resource_type, id),
+                    "response": {
+                        "status": "201 Created",
+                        "location": format!("{}/{}/_history/1", resource_type, id),
+                        "etag": "W/\"1\""
+                    }
+                })
+            }
+            "PUT" => match storage::fhir_update(resource_type, pg_id, data(), request.if_match) {
+                Some(version) => json!({
+                    "fullUrl": format!("{}/{}", resource_type, id),
+                    "response": {
+                        "status": "200 OK",
+                        "location": format!("{}/{}/_history/{}", resource_type, id, version),
+                        "etag": format!("W/\"{}\"", version)
+                    }
+                }),
+                None => not_found(index, resource_type, id),
+            },
+            _ => match storage::fhir_get(resource_type, pg_id) {
+                Some(mut data) => {
+                    data.0["id"] = Value::String(id.to_string());
+                    json!({
+                        "fullUrl": format!("{}/{}", resource_type, id),
+                        "resource": data.0,
+                        "response": {"status": "200 OK"}
+                    })
+                }
+                None => not_found(index, resource_type, id),
+            },
+        };
+    }
+
+    pgrx::JsonB(json!({
+        "resourceType": "Bundle",
+        "type": "transaction-response",
+        "entry": responses
+    }))
+}
@@ -0,0 +1,63 @@
+//! Audit records of AI interactions
+//!
+//! Every `$nl-search`, `$generate` and `$chat` call that reached the model is
+//! recorded in `fhir_ai_audit`: the prompt's SHA-256, model, tool calls,
+//! resources touched and token counts. Prompt and reply text are only kept
+//! when content capture is enabled, since both may contain PHI.
+
+use deadpool_postgres::Pool;
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+
+use super::client::Usage;
+use crate::db::AiAuditRepository;
+
+/// Whether AI interactions are stored with their content
+#[derive(Debug, Clone, Copy)]
+pub struct AiAudit {
+    pub capture_content: bool,
+}
+
+/// One AI interaction to record
+#[derive(Debug, Clone)]
+pub struct AiInteraction {
+    pub request_id: Option<String>,
+    pub operation: &'static str,
+    pub model: String,
+    pub prompt: String,
+    pub response: String,
+    pub tool_calls: JsonValue,
+    /// `Patient/<id>` references of the resources read or written
+    pub resources: Vec<String>,
+    pub usage: Usage,
+}
+
+/// Hex SHA-256 of a prompt, so repeated prompts can be correlated without
+/// storing them
+pub fn prompt_hash(prompt: &str) -> String {
+    Sha256::digest(prompt.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl AiAudit {
+    /// Store an interaction in the background; failures are logged, never
+    /// surfaced to the caller
+    pub fn record(&self, pool: Pool, interaction: AiInteraction) {
+        tracing::info!(
+            operation = interaction.operation,
+            resources = interaction.resources.len(),
+            input_tokens = interaction.usage.input_tokens,
+            output_tokens = interaction.usage.output_tokens,
+            "AI interaction"
+        );
+        let capture_content = self.capture_content;
+        tokio::spawn(async move {
+            let repo = AiAuditRepository::new(pool);
+            if let Err(e) = repo.insert(&interaction, capture_content).await {
+                tracing::error!(error = ?e, "Failed to record AI interaction");
+            }
+        });
+    }
+}
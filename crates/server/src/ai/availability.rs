@@ -0,0 +1,30 @@
+//! Which AI operations are switched on
+
+/// The AI operations and whether each is enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AiOperations {
+    pub nl_search: bool,
+    pub generate: bool,
+    pub chat: bool,
+    /// Whether a Claude client is configured (`ANTHROPIC_API_KEY` is set)
+    pub configured: bool,
+}
+
+impl AiOperations {
+    /// Whether the operation named `code` (`nl-search`, `generate`, `chat`)
+    /// is switched on, regardless of an API key being configured
+    pub fn enabled(&self, code: &str) -> bool {
+        match code {
+            "nl-search" => self.nl_search,
+            "generate" => self.generate,
+            "chat" => self.chat,
+            _ => false,
+        }
+    }
+
+    /// Whether the operation named `code` is switched on and can be served:
+    /// `nl-search` falls back to keyword rules, the others need Claude
+    pub fn available(&self, code: &str) -> bool {
+        self.enabled(code) && (self.configured || code == "nl-search")
+    }
+}
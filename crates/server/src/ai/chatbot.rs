@@ -1,7 +1,11 @@
 //! AI chatbot with tool calling for FHIR data queries
 
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use super::client::{ClaudeClient, Content, ContentBlock, Message, Tool};
 use crate::db::PatientRepository;
+use serde::Serialize;
 use serde_json::{Value as JsonValue, json};
 
 const SYSTEM_PROMPT: &str = r#"You are a helpful FHIR Patient data assistant. You can search for patients, retrieve specific patient records, and count patients in the system.
@@ -13,6 +17,68 @@ When presenting results, format them clearly and concisely. Include relevant det
 /// Maximum agentic loop iterations to prevent runaway
 const MAX_ITERATIONS: u32 = 10;
 
+/// Bounds on the work a single chat may do
+#[derive(Debug, Clone, Copy)]
+pub struct ChatLimits {
+    /// Tool calls executed per conversation; later calls are refused
+    pub max_tool_calls: usize,
+    /// Patients included in one tool result
+    pub max_result_rows: usize,
+    /// Size of one tool result sent back to Claude
+    pub max_result_bytes: usize,
+    /// Wall-clock time for the whole agentic loop
+    pub time_budget: Duration,
+}
+
+/// Why a chat produced no answer
+#[derive(Debug)]
+pub enum ChatError {
+    /// The loop ran past its time budget
+    TimedOut(Duration),
+    Failed(String),
+}
+
+impl From<String> for ChatError {
+    fn from(msg: String) -> Self {
+        ChatError::Failed(msg)
+    }
+}
+
+impl std::fmt::Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatError::TimedOut(budget) => {
+                write!(
+                    f,
+                    "Chat exceeded its time budget of {} ms",
+                    budget.as_millis()
+                )
+            }
+            ChatError::Failed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// One tool call made while answering, for auditing what data was consulted
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolCall {
+    pub tool: String,
+    pub input: JsonValue,
+    /// Patients returned (or counted); absent when the call failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u64>,
+    pub duration_ms: u64,
+}
+
+/// The chatbot's final answer, the tool calls behind it and the patients
+/// those calls returned
+pub struct ChatReply {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub patients: Vec<uuid::Uuid>,
+}
+
 /// Define the tools available to the chatbot
 fn chat_tools() -> Vec<Tool> {
     vec![
@@ -92,64 +158,149 @@ fn build_search_params(input: &JsonValue) -> JsonValue {
     if let Some(bd) = input.get("birthdate").and_then(|v| v.as_str()) {
         params.insert("birthdate".to_string(), json!(bd));
     }
-    JsonValue::Object(params)
+    crate::search_values::normalize(JsonValue::Object(params))
+}
+
+/// Serialize as many of `rows` as fit in `max_bytes`, followed by a
+/// `[N more rows]` marker when fewer than `total` are shown. Returns the text
+/// and the number of rows shown.
+fn render_rows(rows: &[JsonValue], total: u64, max_bytes: usize) -> (String, usize) {
+    let mut shown = rows.len();
+    loop {
+        let json = serde_json::to_string(&rows[..shown]).unwrap_or_else(|_| "[]".to_string());
+        let text = match total.saturating_sub(shown as u64) {
+            0 => json,
+            more => format!("{json}\n[{more} more rows]"),
+        };
+        if text.len() <= max_bytes || shown == 0 {
+            return (text, shown);
+        }
+        shown -= 1;
+    }
+}
+
+/// Cut `text` to at most `max_bytes`, marking how much was left out
+fn truncate_result(text: String, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let cut = (0..=max_bytes)
+        .rev()
+        .find(|i| text.is_char_boundary(*i))
+        .unwrap_or(0);
+    format!(
+        "{}\n[truncated, {} more bytes]",
+        &text[..cut],
+        text.len() - cut
+    )
+}
+
+/// Fetch every patient requested by `get_patient` calls in one query
+async fn prefetch_patients(
+    repo: &PatientRepository,
+    tool_uses: &[(String, String, JsonValue)],
+) -> Result<HashMap<uuid::Uuid, JsonValue>, String> {
+    let ids: Vec<uuid::Uuid> = tool_uses
+        .iter()
+        .filter(|(_, name, _)| name == "get_patient")
+        .filter_map(|(_, _, input)| input.get("id").and_then(|v| v.as_str()))
+        .filter_map(|id| uuid::Uuid::parse_str(id).ok())
+        .collect();
+
+    repo.get_many(&ids)
+        .await
+        .map_err(|e| format!("Error getting patient: {e:?}"))
 }
 
 /// Execute a tool call against the database
-async fn execute_tool(repo: &PatientRepository, name: &str, input: &JsonValue) -> String {
+///
+/// `get_patient` answers from `patients`, prefetched for the whole turn.
+/// Results are cut down to `limits`. Returns the result for Claude, the
+/// number of patients it covers and the ids of the patients whose data it
+/// contains.
+async fn execute_tool(
+    repo: &PatientRepository,
+    patients: &Result<HashMap<uuid::Uuid, JsonValue>, String>,
+    limits: &ChatLimits,
+    name: &str,
+    input: &JsonValue,
+) -> (String, Option<u64>, Vec<uuid::Uuid>) {
     match name {
         "search_patients" => {
-            let mut params = build_search_params(input);
+            let params = build_search_params(input);
+            let mut page = params.clone();
             // Limit results to avoid huge responses sent back to Claude
-            if let Some(obj) = params.as_object_mut() {
-                obj.insert("_count".to_string(), json!(20));
+            if let Some(obj) = page.as_object_mut() {
+                obj.insert("_count".to_string(), json!(limits.max_result_rows));
             }
 
-            match repo.search(params).await {
-                Ok(results) => {
-                    let patients: Vec<JsonValue> = results
-                        .into_iter()
-                        .map(|(id, data)| json!({"id": id.to_string(), "resource": data}))
-                        .collect();
-                    serde_json::to_string(&patients).unwrap_or_else(|_| "[]".to_string())
-                }
-                Err(e) => format!("Error searching patients: {e:?}"),
-            }
+            let results = match repo.search(page).await {
+                Ok(results) => results,
+                Err(e) => return (format!("Error searching patients: {e:?}"), None, Vec::new()),
+            };
+            // A full page may have more matches behind it
+            let total = match results.len() < limits.max_result_rows {
+                true => results.len() as u64,
+                false => repo
+                    .count(params)
+                    .await
+                    .map(|count| count as u64)
+                    .unwrap_or(results.len() as u64),
+            };
+            let rows: Vec<JsonValue> = results
+                .iter()
+                .map(|(id, data)| json!({"id": id.to_string(), "resource": data}))
+                .collect();
+            let (text, shown) = render_rows(&rows, total, limits.max_result_bytes);
+            let ids: Vec<uuid::Uuid> = results.iter().take(shown).map(|(id, _)| *id).collect();
+            (text, Some(ids.len() as u64), ids)
         }
         "get_patient" => {
             let id_str = input.get("id").and_then(|v| v.as_str()).unwrap_or("");
             match uuid::Uuid::parse_str(id_str) {
-                Ok(id) => match repo.get(id).await {
-                    Ok(Some(data)) => {
-                        serde_json::to_string(&data).unwrap_or_else(|_| "null".to_string())
-                    }
-                    Ok(None) => format!("Patient {id} not found"),
-                    Err(e) => format!("Error getting patient: {e:?}"),
+                Ok(id) => match patients.as_ref().map(|p| p.get(&id)) {
+                    Ok(Some(data)) => (
+                        truncate_result(
+                            serde_json::to_string(data).unwrap_or_else(|_| "null".to_string()),
+                            limits.max_result_bytes,
+                        ),
+                        Some(1),
+                        vec![id],
+                    ),
+                    Ok(None) => (format!("Patient {id} not found"), Some(0), Vec::new()),
+                    Err(e) => (e.clone(), None, Vec::new()),
                 },
-                Err(_) => format!("Invalid UUID: {id_str}"),
+                Err(_) => (format!("Invalid UUID: {id_str}"), None, Vec::new()),
             }
         }
         "count_patients" => {
             let params = build_search_params(input);
             match repo.count(params).await {
-                Ok(count) => format!("{count}"),
-                Err(e) => format!("Error counting patients: {e:?}"),
+                Ok(count) => (format!("{count}"), Some(count as u64), Vec::new()),
+                Err(e) => (format!("Error counting patients: {e:?}"), None, Vec::new()),
             }
         }
-        _ => format!("Unknown tool: {name}"),
+        _ => (format!("Unknown tool: {name}"), None, Vec::new()),
     }
 }
 
 /// Run the chatbot agentic loop.
 ///
 /// Sends the user message to Claude with tools, executes any tool calls,
-/// and continues until Claude produces a final text response.
+/// and continues until Claude produces a final text response. Every tool call
+/// made along the way is returned with the answer. Tool calls past
+/// `limits.max_tool_calls` are refused rather than executed, and the whole
+/// loop fails with [`ChatError::TimedOut`] once `limits.time_budget` is spent.
 pub async fn chat(
     client: &ClaudeClient,
     repo: &PatientRepository,
     user_message: &str,
-) -> Result<String, String> {
+    limits: &ChatLimits,
+) -> Result<ChatReply, ChatError> {
+    let deadline = tokio::time::Instant::now() + limits.time_budget;
     let tools = chat_tools();
+    let mut tool_calls = Vec::new();
+    let mut touched: Vec<uuid::Uuid> = Vec::new();
 
     let mut messages = vec![Message {
         role: "user".to_string(),
@@ -157,9 +308,12 @@ pub async fn chat(
     }];
 
     for iteration in 0..MAX_ITERATIONS {
-        let response = client
-            .send(Some(SYSTEM_PROMPT), messages.clone(), Some(tools.clone()))
-            .await?;
+        let response = tokio::time::timeout_at(
+            deadline,
+            client.send(Some(SYSTEM_PROMPT), messages.clone(), Some(tools.clone())),
+        )
+        .await
+        .map_err(|_| ChatError::TimedOut(limits.time_budget))??;
 
         tracing::debug!(
             iteration = iteration,
@@ -169,7 +323,12 @@ pub async fn chat(
 
         // If Claude is done talking, return the text
         if response.stop_reason == "end_turn" {
-            return client.extract_text(&response);
+            let text = client.extract_text(&response)?;
+            return Ok(ChatReply {
+                text,
+                tool_calls,
+                patients: touched,
+            });
         }
 
         if response.stop_reason == "tool_use" {
@@ -192,11 +351,48 @@ pub async fn chat(
                 content: Content::Blocks(response.content),
             });
 
-            // Execute each tool and collect results
+            // Execute each tool the call budget still allows and collect results
+            let allowed = limits
+                .max_tool_calls
+                .saturating_sub(tool_calls.len())
+                .min(tool_uses.len());
+            let patients =
+                tokio::time::timeout_at(deadline, prefetch_patients(repo, &tool_uses[..allowed]))
+                    .await
+                    .map_err(|_| ChatError::TimedOut(limits.time_budget))?;
             let mut result_blocks = Vec::new();
-            for (tool_id, tool_name, tool_input) in &tool_uses {
+            for (index, (tool_id, tool_name, tool_input)) in tool_uses.iter().enumerate() {
+                if index >= allowed {
+                    tracing::warn!(tool = %tool_name, "Chat tool call limit reached");
+                    result_blocks.push(ContentBlock::ToolResult {
+                        tool_use_id: tool_id.clone(),
+                        content: format!(
+                            "Tool call limit of {} per conversation reached; \
+                             answer with the data already retrieved",
+                            limits.max_tool_calls
+                        ),
+                    });
+                    continue;
+                }
                 tracing::info!(tool = %tool_name, "Executing chat tool");
-                let result = execute_tool(repo, tool_name, tool_input).await;
+                let started = Instant::now();
+                let (result, rows, ids) = tokio::time::timeout_at(
+                    deadline,
+                    execute_tool(repo, &patients, limits, tool_name, tool_input),
+                )
+                .await
+                .map_err(|_| ChatError::TimedOut(limits.time_budget))?;
+                for id in ids {
+                    if !touched.contains(&id) {
+                        touched.push(id);
+                    }
+                }
+                tool_calls.push(ToolCall {
+                    tool: tool_name.clone(),
+                    input: tool_input.clone(),
+                    rows,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                });
                 result_blocks.push(ContentBlock::ToolResult {
                     tool_use_id: tool_id.clone(),
                     content: result,
@@ -210,9 +406,16 @@ pub async fn chat(
             });
         } else {
             // Unexpected stop reason — return whatever text we got
-            return client.extract_text(&response);
+            let text = client.extract_text(&response)?;
+            return Ok(ChatReply {
+                text,
+                tool_calls,
+                patients: touched,
+            });
         }
     }
 
-    Err("Chat loop exceeded maximum iterations".to_string())
+    Err(ChatError::Failed(
+        "Chat loop exceeded maximum iterations".to_string(),
+    ))
 }
@@ -1,9 +1,10 @@
 //! Claude API client for the Anthropic Messages API
 
+use std::sync::{Arc, Mutex};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 
-const API_URL: &str = "https://api.anthropic.com/v1/messages";
 const API_VERSION: &str = "2023-06-01";
 const DEFAULT_MODEL: &str = "claude-sonnet-4-5-20250929";
 
@@ -12,7 +13,31 @@ const DEFAULT_MODEL: &str = "claude-sonnet-4-5-20250929";
 pub struct ClaudeClient {
     http: reqwest::Client,
     api_key: String,
+    /// Messages API endpoint (`<base url>/v1/messages`)
+    messages_url: String,
     model: String,
+    /// Where token usage is tallied, for clients returned by [`ClaudeClient::metered`]
+    usage: Option<Arc<Mutex<Usage>>>,
+}
+
+/// Token usage reported by the Messages API
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+}
+
+/// Running total of the tokens used by a metered client
+#[derive(Clone)]
+pub struct UsageMeter(Arc<Mutex<Usage>>);
+
+impl UsageMeter {
+    /// Tokens used so far
+    pub fn total(&self) -> Usage {
+        *self.0.lock().unwrap()
+    }
 }
 
 /// A message in the conversation
@@ -76,6 +101,8 @@ pub struct ApiResponse {
     pub id: String,
     pub content: Vec<ContentBlock>,
     pub stop_reason: String,
+    #[serde(default)]
+    pub usage: Usage,
 }
 
 /// Error detail from the Messages API
@@ -90,15 +117,36 @@ struct ApiErrorDetail {
 }
 
 impl ClaudeClient {
-    /// Create a new client with the given API key
-    pub fn new(api_key: String) -> Self {
+    /// Create a new client with the given API key, sending requests to the
+    /// Anthropic API at `base_url` (or a gateway in front of it)
+    ///
+    /// Like every outbound client, it honors `HTTPS_PROXY` / `NO_PROXY`.
+    pub fn new(api_key: String, base_url: &str) -> Self {
         Self {
             http: reqwest::Client::new(),
             api_key,
+            messages_url: format!("{}/v1/messages", base_url.trim_end_matches('/')),
             model: DEFAULT_MODEL.to_string(),
+            usage: None,
         }
     }
 
+    /// Model used for requests
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// A copy of this client that tallies the tokens of every request it
+    /// sends, e.g. for one API call's audit record
+    pub fn metered(&self) -> (Self, UsageMeter) {
+        let usage = Arc::new(Mutex::new(Usage::default()));
+        let client = Self {
+            usage: Some(usage.clone()),
+            ..self.clone()
+        };
+        (client, UsageMeter(usage))
+    }
+
     /// Send a simple message with an optional system prompt, return text response
     pub async fn message(
         &self,
@@ -131,7 +179,7 @@ impl ClaudeClient {
 
         let response = self
             .http
-            .post(API_URL)
+            .post(&self.messages_url)
             .header("x-api-key", &self.api_key)
             .header("anthropic-version", API_VERSION)
             .json(&request)
@@ -151,10 +199,17 @@ impl ClaudeClient {
             return Err(format!("Claude API error ({}): {}", status, body));
         }
 
-        response
+        let response = response
             .json::<ApiResponse>()
             .await
-            .map_err(|e| format!("Failed to parse response: {}", e))
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+        if let Some(ref usage) = self.usage {
+            let mut total = usage.lock().unwrap();
+            total.input_tokens += response.usage.input_tokens;
+            total.output_tokens += response.usage.output_tokens;
+        }
+        Ok(response)
     }
 
     /// Extract text content from an API response
@@ -0,0 +1,147 @@
+//! Output guard for model-generated text
+//!
+//! The model only ever sees patients returned by its tools, which are the
+//! patients the caller was shown. Details of any other patient in a reply
+//! did not come from data the caller was allowed to read (a hallucination,
+//! or data smuggled in through the prompt). The guard looks for such details
+//! — patient ids, and identifier values (MRNs and the like), name parts and
+//! birth dates that belong to a stored patient outside that set — and
+//! redacts them, or withholds the whole reply.
+
+use std::collections::HashSet;
+
+use uuid::Uuid;
+
+use crate::config::AiOutputGuard;
+use crate::db::PatientRepository;
+
+/// Replacement for a redacted id or word
+const REDACTED: &str = "[redacted]";
+
+/// Reply returned instead of one that was blocked
+const WITHHELD: &str =
+    "The reply was withheld because it referenced patients outside the data consulted.";
+
+/// Words of a reply looked up per kind, to bound the query
+const MAX_TERMS: usize = 500;
+
+/// Id-shaped tokens in `text` (as written) and the ids they parse to
+fn mentioned_ids(text: &str) -> Vec<(&str, Uuid)> {
+    text.split(|c: char| !(c.is_ascii_hexdigit() || c == '-'))
+        .filter(|token| token.len() == 36)
+        .filter_map(|token| Uuid::parse_str(token).ok().map(|id| (token, id)))
+        .collect()
+}
+
+/// A whitespace-separated word without surrounding punctuation
+fn core(word: &str) -> &str {
+    word.trim_matches(|c: char| !c.is_alphanumeric())
+}
+
+/// Lowercased form of a capitalized word, as name parts are indexed
+fn name_key(word: &str) -> Option<String> {
+    let mut chars = word.chars();
+    let capitalized = chars.next().is_some_and(char::is_uppercase)
+        && chars.all(|c| c.is_alphabetic() || c == '-' || c == '\'');
+    (capitalized && word.chars().count() > 1).then(|| word.to_lowercase())
+}
+
+/// A word with a digit, which may be an identifier value or a birth date
+fn number_key(word: &str) -> Option<&str> {
+    word.chars().any(|c| c.is_ascii_digit()).then_some(word)
+}
+
+/// Words of `text` that identify a patient outside `visible`
+async fn identifying_words<'a>(
+    repo: &PatientRepository,
+    text: &'a str,
+    visible: &[Uuid],
+) -> Result<Vec<&'a str>, String> {
+    let words: Vec<&str> = text.split_whitespace().map(core).collect();
+    let mut names: Vec<String> = words.iter().filter_map(|w| name_key(w)).collect();
+    let mut numbers: Vec<String> = words
+        .iter()
+        .filter_map(|w| number_key(w))
+        .map(str::to_string)
+        .collect();
+    for terms in [&mut names, &mut numbers] {
+        terms.sort_unstable();
+        terms.dedup();
+        terms.truncate(MAX_TERMS);
+    }
+
+    let found = repo
+        .identifying_terms_outside(&names, &numbers, visible)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(words
+        .into_iter()
+        .filter(|w| {
+            name_key(w).is_some_and(|key| found.contains(&key))
+                || number_key(w).is_some_and(|key| found.contains(key))
+        })
+        .collect())
+}
+
+/// Replace every whitespace-separated word whose core is in `words`
+fn redact_words(text: &str, words: &HashSet<&str>) -> String {
+    text.split_inclusive(char::is_whitespace)
+        .map(|piece| {
+            let word = core(piece);
+            match words.contains(word) {
+                true => piece.replacen(word, REDACTED, 1),
+                false => piece.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Apply `policy` to `text`, given the ids of the patients the caller was
+/// shown (those the tools returned)
+///
+/// Fails closed: if the details cannot be checked, the reply is withheld.
+pub async fn screen(
+    policy: AiOutputGuard,
+    repo: &PatientRepository,
+    text: &str,
+    visible: &[Uuid],
+) -> String {
+    if policy == AiOutputGuard::Off {
+        return text.to_string();
+    }
+    let leaked_ids: Vec<&str> = mentioned_ids(text)
+        .into_iter()
+        .filter(|(_, id)| !visible.contains(id))
+        .map(|(token, _)| token)
+        .collect();
+    let leaked_words: HashSet<&str> = match identifying_words(repo, text, visible).await {
+        Ok(words) => words.into_iter().collect(),
+        Err(e) => {
+            tracing::warn!(error = %e, "AI output could not be checked for patient details");
+            metrics::counter!("fhir_ai_output_guard_total", "action" => "block").increment(1);
+            return WITHHELD.to_string();
+        }
+    };
+    if leaked_ids.is_empty() && leaked_words.is_empty() {
+        return text.to_string();
+    }
+
+    tracing::warn!(
+        ids = leaked_ids.len(),
+        details = leaked_words.len(),
+        "AI output referenced patients the caller was not shown"
+    );
+    match policy {
+        AiOutputGuard::Block => {
+            metrics::counter!("fhir_ai_output_guard_total", "action" => "block").increment(1);
+            WITHHELD.to_string()
+        }
+        _ => {
+            metrics::counter!("fhir_ai_output_guard_total", "action" => "redact").increment(1);
+            let text = leaked_ids.iter().fold(text.to_string(), |text, token| {
+                text.replace(token, REDACTED)
+            });
+            redact_words(&text, &leaked_words)
+        }
+    }
+}
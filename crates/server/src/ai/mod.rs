@@ -1,8 +1,13 @@
 //! AI features powered by Claude API
 
+pub mod audit;
+pub mod availability;
 pub mod chatbot;
 pub mod client;
 pub mod generator;
+pub mod guard;
 pub mod nl_search;
 
+pub use audit::{AiAudit, AiInteraction};
+pub use availability::AiOperations;
 pub use client::ClaudeClient;
@@ -12,10 +12,13 @@ Return ONLY a JSON object with these possible keys:
 
 Only include parameters that are relevant to the query. Do not include parameters that weren't mentioned.
 
+If part of the query cannot be expressed with these parameters (e.g. location, diagnosis), add an "_unsupported" key with a list of those parts instead of guessing.
+
 Examples:
 - "Find all male patients" → {"gender": "male"}
 - "Patients named Smith born after 1990" → {"name": "Smith", "birthdate": "ge1990-01-01"}
 - "Female patients born before 2000" → {"gender": "female", "birthdate": "lt2000-01-01"}
+- "Male diabetics in Boston" → {"gender": "male", "_unsupported": ["diabetics", "in Boston"]}
 
 Return ONLY the JSON object, no other text."#;
 
@@ -29,6 +32,137 @@ pub async fn convert_to_params(client: &ClaudeClient, query: &str) -> Result<Jso
     serde_json::from_str(&json_str).map_err(|e| format!("Failed to parse search params: {}", e))
 }
 
+/// Words that select a gender
+const GENDER_WORDS: &[(&str, &str)] = &[
+    ("male", "male"),
+    ("males", "male"),
+    ("man", "male"),
+    ("men", "male"),
+    ("boy", "male"),
+    ("boys", "male"),
+    ("female", "female"),
+    ("females", "female"),
+    ("woman", "female"),
+    ("women", "female"),
+    ("girl", "female"),
+    ("girls", "female"),
+];
+
+/// Words after which the next word is taken as a name
+const NAME_MARKERS: &[&str] = &["named", "called", "name", "surname"];
+
+/// Capitalised words that are not names
+const STOP_WORDS: &[&str] = &[
+    "patient", "patients", "people", "person", "born", "find", "show", "list", "all", "with",
+    "and", "or", "in", "the",
+];
+
+/// Convert a natural language query into FHIR search parameters with
+/// keyword rules, for when Claude is not available
+///
+/// Recognises a gender ("women", "male patients"), a birth year with a
+/// direction ("born after 1990", "before 2000") and a name ("named Smith",
+/// or a capitalised word past the first). A year without a direction and a
+/// query naming both genders are reported under `_unsupported`, like the
+/// parts Claude cannot express.
+pub fn parse_rules(query: &str) -> JsonValue {
+    let words: Vec<&str> = query
+        .split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '-')
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    let mut genders: Vec<&str> = Vec::new();
+    let mut birthdate = None;
+    let mut name = None;
+    let mut unsupported = Vec::new();
+    for (index, word) in words.iter().enumerate() {
+        let lower = word.to_lowercase();
+        let previous = index
+            .checked_sub(1)
+            .map(|i| words[i].to_lowercase())
+            .unwrap_or_default();
+        let gender = GENDER_WORDS
+            .iter()
+            .find(|(keyword, _)| *keyword == lower)
+            .map(|(_, gender)| *gender);
+        let year = Some(*word)
+            .filter(|w| w.len() == 4)
+            .and_then(|w| w.parse::<u16>().ok())
+            .filter(|y| (1800..=2100).contains(y));
+        let capitalised = word.starts_with(|c: char| c.is_uppercase());
+
+        match (gender, year) {
+            (Some(gender), _) if !genders.contains(&gender) => genders.push(gender),
+            (Some(_), _) => {}
+            (None, Some(year)) => match previous.as_str() {
+                "after" | "since" | "from" => birthdate = Some(format!("ge{}-01-01", year)),
+                "before" | "until" => birthdate = Some(format!("lt{}-01-01", year)),
+                _ => unsupported.push(format!("born in {}", year)),
+            },
+            (None, None) if name.is_some() || STOP_WORDS.contains(&lower.as_str()) => {}
+            (None, None) if NAME_MARKERS.contains(&previous.as_str()) => name = Some(*word),
+            (None, None) if index > 0 && capitalised => name = Some(*word),
+            (None, None) => {}
+        }
+    }
+
+    let mut params = serde_json::Map::new();
+    match genders.as_slice() {
+        [gender] => {
+            params.insert("gender".to_string(), JsonValue::from(*gender));
+        }
+        [] => {}
+        _ => unsupported.push(genders.join(" and ")),
+    }
+    if let Some(birthdate) = birthdate {
+        params.insert("birthdate".to_string(), JsonValue::from(birthdate));
+    }
+    if let Some(name) = name {
+        params.insert("name".to_string(), JsonValue::from(name));
+    }
+    if !unsupported.is_empty() {
+        params.insert("_unsupported".to_string(), JsonValue::from(unsupported));
+    }
+    JsonValue::Object(params)
+}
+
+/// Search parameters the converted query may use
+const SUPPORTED_PARAMS: &[&str] = &["name", "gender", "birthdate"];
+
+/// Keep only supported search parameters, returning a description of every
+/// part of the query that was dropped
+pub fn split_unsupported(params: JsonValue) -> (JsonValue, Vec<String>) {
+    let JsonValue::Object(map) = params else {
+        return (
+            JsonValue::Object(Default::default()),
+            vec!["Conversion did not produce search parameters".to_string()],
+        );
+    };
+
+    let mut supported = serde_json::Map::new();
+    let mut dropped = Vec::new();
+    for (key, value) in map {
+        match key.as_str() {
+            "_unsupported" => dropped.extend(
+                value
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|part| part.as_str())
+                    .map(|part| format!("'{}' could not be expressed as a search parameter", part)),
+            ),
+            key if SUPPORTED_PARAMS.contains(&key) && value.is_string() => {
+                supported.insert(key.to_string(), value);
+            }
+            _ => dropped.push(format!(
+                "Unsupported search parameter '{}' was dropped",
+                key
+            )),
+        }
+    }
+    (JsonValue::Object(supported), dropped)
+}
+
 /// Extract a JSON object from text that might contain markdown code blocks
 fn extract_json(text: &str) -> Result<String, String> {
     let trimmed = text.trim();
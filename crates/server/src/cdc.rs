@@ -0,0 +1,201 @@
+//! Change data capture consumer
+//!
+//! Polls a `wal2json` (format version 2) logical replication slot created by
+//! `fhir_cdc_create_slot`, converts inserts into `fhir_history` into
+//! [`ChangeEvent`]s and POSTs them to `NOTIFICATION_URL` like outbox
+//! notifications. This scales to high write volumes where per-row NOTIFY
+//! would not.
+//!
+//! Only the scheduler leader consumes the slot, and it advances the slot only
+//! after delivery, so a change is redelivered (with the same notification id,
+//! its WAL position) rather than lost when delivery or the server fails.
+//!
+//! Each wal2json v2 message is one JSON object per row, e.g.:
+//!
+//! ```json
+//! {"action": "I", "schema": "public", "table": "fhir_history",
+//!  "columns": [{"name": "resource_id", "type": "uuid", "value": "…"},
+//!              {"name": "resource_type", "type": "text", "value": "Patient"},
+//!              {"name": "version", "type": "integer", "value": 2},
+//!              {"name": "method", "type": "text", "value": "PUT"},
+//!              {"name": "data", "type": "jsonb", "value": "{…}"}, …]}
+//! ```
+
+use std::time::Duration;
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use tokio_postgres::types::PgLsn;
+use uuid::Uuid;
+
+use crate::outbox::OutboxDelivery;
+use crate::scheduler::SchedulerHandle;
+
+/// Maximum number of WAL changes peeked per poll
+const BATCH_SIZE: i32 = 1000;
+
+/// Kind of change, derived from the history row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeAction {
+    Create,
+    Update,
+    Delete,
+}
+
+/// A FHIR resource change decoded from the replication stream
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEvent {
+    pub resource_type: String,
+    pub id: Uuid,
+    pub version: i32,
+    pub action: ChangeAction,
+}
+
+/// Decode one wal2json v2 message; non-insert and non-history rows yield None
+pub fn decode_change(message: &JsonValue) -> Option<ChangeEvent> {
+    if message.get("action")?.as_str()? != "I" || message.get("table")?.as_str()? != "fhir_history"
+    {
+        return None;
+    }
+
+    let column = |name: &str| {
+        message
+            .get("columns")?
+            .as_array()?
+            .iter()
+            .find(|c| c.get("name").and_then(|n| n.as_str()) == Some(name))?
+            .get("value")
+    };
+
+    let id = Uuid::parse_str(column("resource_id")?.as_str()?).ok()?;
+    let resource_type = column("resource_type")?.as_str()?.to_string();
+    let version = column("version")?.as_i64()? as i32;
+
+    let action = match column("method").and_then(|v| v.as_str()) {
+        Some("DELETE") => ChangeAction::Delete,
+        Some("POST") => ChangeAction::Create,
+        _ => ChangeAction::Update,
+    };
+
+    Some(ChangeEvent {
+        resource_type,
+        id,
+        version,
+        action,
+    })
+}
+
+/// Spawn the slot consumer, polling every `interval` while this replica is
+/// the scheduler leader
+pub(crate) fn spawn_consumer(
+    pool: Pool,
+    slot: String,
+    interval: Duration,
+    delivery: OutboxDelivery,
+    scheduler: SchedulerHandle,
+) {
+    tokio::spawn(async move {
+        let mut slot_ready = false;
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if !scheduler.is_leader() {
+                continue;
+            }
+            if !slot_ready {
+                match ensure_slot(&pool, &slot).await {
+                    Ok(true) => tracing::info!(slot = %slot, "Created CDC replication slot"),
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::warn!(slot = %slot, error = %e, "CDC slot setup failed");
+                        continue;
+                    }
+                }
+                slot_ready = true;
+            }
+            match poll(&pool, &slot, &delivery).await {
+                Ok(0) => {}
+                Ok(n) => tracing::debug!(slot = %slot, events = n, "CDC events delivered"),
+                Err(e) => tracing::warn!(slot = %slot, error = %e, "CDC poll failed"),
+            }
+        }
+    });
+}
+
+/// Create the slot if needed (returns true if created)
+async fn ensure_slot(pool: &Pool, slot: &str) -> Result<bool, String> {
+    let client = pool.get().await.map_err(|e| e.to_string())?;
+    let row = client
+        .query_one("SELECT fhir_cdc_create_slot($1)", &[&slot])
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(row.get(0))
+}
+
+/// Deliver one batch of changes from the slot, returning the number of
+/// events delivered
+///
+/// Changes are peeked, not consumed: the slot is advanced past a transaction
+/// only once every event in it was delivered. A failed delivery stops the
+/// batch, and the rest of its transaction is peeked again on the next poll.
+async fn poll(pool: &Pool, slot: &str, delivery: &OutboxDelivery) -> Result<usize, String> {
+    let client = pool.get().await.map_err(|e| e.to_string())?;
+    let rows = client
+        .query(
+            "SELECT lsn, (lsn - '0/0'::pg_lsn)::bigint, data
+               FROM pg_logical_slot_peek_changes($1, NULL, $2,
+                    'format-version', '2', 'add-tables', '*.fhir_history')",
+            &[&slot, &BATCH_SIZE],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut events = 0;
+    let mut confirmed: Option<PgLsn> = None;
+    let mut failure = None;
+    for row in rows {
+        let lsn: PgLsn = row.get(0);
+        let position: i64 = row.get(1);
+        let data: String = row.get(2);
+        let Ok(message) = serde_json::from_str::<JsonValue>(&data) else {
+            continue;
+        };
+        // A commit message closes a transaction whose events were all delivered
+        if message.get("action").and_then(|a| a.as_str()) == Some("C") {
+            confirmed = Some(lsn);
+            continue;
+        }
+        let Some(event) = decode_change(&message) else {
+            continue;
+        };
+        let action = format!("{:?}", event.action).to_lowercase();
+        let resource_type = event.resource_type.clone();
+        // The WAL position identifies the change across redeliveries
+        if let Err(e) = delivery.deliver(position, event).await {
+            failure = Some(e);
+            break;
+        }
+        metrics::counter!(
+            "fhir_change_events_total",
+            "resource_type" => resource_type,
+            "action" => action
+        )
+        .increment(1);
+        events += 1;
+    }
+
+    if let Some(lsn) = confirmed {
+        client
+            .query("SELECT pg_replication_slot_advance($1, $2)", &[&slot, &lsn])
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(events),
+    }
+}
@@ -1,21 +1,382 @@
 //! Server configuration
 
+/// Access policy applied to an operational (non-FHIR) route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteAccess {
+    /// No authentication and no rate limiting
+    Public,
+    /// Rate limited, but no authentication
+    RateLimited,
+    /// Rate limited and requires a valid `X-API-Key`
+    Protected,
+}
+
+impl RouteAccess {
+    /// Parse a policy name (`public`, `rate-limited`, `protected`)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "public" => Some(RouteAccess::Public),
+            "rate-limited" | "rate_limited" => Some(RouteAccess::RateLimited),
+            "protected" => Some(RouteAccess::Protected),
+            _ => None,
+        }
+    }
+}
+
+/// Operational routes a route policy may name, as served by `build_app`
+pub const POLICY_ROUTES: &[&str] = &["/metadata", "/health", "/readyz", "/metrics"];
+
+/// A single entry in the route policy table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePolicy {
+    pub path: String,
+    pub access: RouteAccess,
+}
+
+/// What to do with narratives (`text.div`) outside the FHIR XHTML subset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NarrativePolicy {
+    /// Reject the write with 400
+    Reject,
+    /// Strip the offending elements and attributes and store the result
+    Sanitize,
+}
+
+impl NarrativePolicy {
+    /// Parse a policy name (`reject`, `sanitize`)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Some(NarrativePolicy::Reject),
+            "sanitize" => Some(NarrativePolicy::Sanitize),
+            _ => None,
+        }
+    }
+}
+
+/// Largest inline `Patient.photo` accepted, in decoded bytes
+///
+/// Installed as a request extension for the Patient write handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhotoLimit(pub usize);
+
+/// Whether reads of a Patient with a `replaced-by` link answer
+/// `301 Moved Permanently` to the surviving Patient
+///
+/// Installed as a request extension for the Patient read handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplacedRedirect(pub bool);
+
+/// What to do with AI output that mentions patients no tool returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AiOutputGuard {
+    /// Replace the offending ids
+    Redact,
+    /// Withhold the whole reply
+    Block,
+    /// Pass output through unchanged
+    Off,
+}
+
+impl AiOutputGuard {
+    /// Parse a guard mode (`redact`, `block`, `off`)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "redact" => Some(AiOutputGuard::Redact),
+            "block" => Some(AiOutputGuard::Block),
+            "off" => Some(AiOutputGuard::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Console / file log line format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON object per line, for log aggregation
+    Json,
+    /// `key=value` pairs
+    Logfmt,
+    /// Multi-line, human-readable output for local development
+    Pretty,
+}
+
+impl LogFormat {
+    /// Parse a format name (`json`, `logfmt`, `pretty`)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Some(LogFormat::Json),
+            "logfmt" => Some(LogFormat::Logfmt),
+            "pretty" => Some(LogFormat::Pretty),
+            _ => None,
+        }
+    }
+}
+
+/// How often the log file is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl LogRotation {
+    /// Parse a rotation name (`hourly`, `daily`, `never`)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hourly" => Some(LogRotation::Hourly),
+            "daily" => Some(LogRotation::Daily),
+            "never" => Some(LogRotation::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Log output settings
+///
+/// Loaded separately from [`Config`] because logging is set up first, so
+/// that warnings about the rest of the configuration are not lost.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Default `EnvFilter` directives
+    pub filter: String,
+    pub format: LogFormat,
+    /// Write logs to this file instead of stdout
+    pub file: Option<String>,
+    pub rotation: LogRotation,
+    /// Rotated files kept besides the current one (0 keeps all)
+    pub max_files: usize,
+}
+
+impl LogConfig {
+    /// Load log settings from environment variables
+    pub fn from_env() -> Self {
+        let filter =
+            std::env::var("RUST_LOG").unwrap_or_else(|_| "info,tower_http=debug".to_string());
+
+        let format = std::env::var("LOG_FORMAT")
+            .ok()
+            .and_then(|s| LogFormat::parse(&s))
+            .unwrap_or(LogFormat::Json);
+
+        let file = std::env::var("LOG_FILE").ok().filter(|s| !s.is_empty());
+
+        let rotation = std::env::var("LOG_ROTATION")
+            .ok()
+            .and_then(|s| LogRotation::parse(&s))
+            .unwrap_or(LogRotation::Daily);
+
+        let max_files = std::env::var("LOG_MAX_FILES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(7);
+
+        Self {
+            filter,
+            format,
+            file,
+            rotation,
+            max_files,
+        }
+    }
+}
+
 /// Server configuration loaded from environment variables
 pub struct Config {
     pub database_url: String,
     pub bind_address: String,
     pub api_key: Option<String>,
+    /// Per-client API keys as (client name, key); the name is the client's
+    /// principal, e.g. the owner of its checkout locks
+    pub api_clients: Vec<(String, String)>,
+    /// Principals allowed on administrative routes such as `/admin/export`,
+    /// `/admin/log-level` and `/admin/ai-audit` (`default` is the shared
+    /// `API_KEY`)
+    pub admin_clients: Vec<String>,
     pub cors_origins: Vec<String>,
+    /// Allowed CORS methods (`*` allows any)
+    pub cors_allow_methods: Vec<String>,
+    /// Allowed CORS request headers (`*` allows any)
+    pub cors_allow_headers: Vec<String>,
+    /// How long browsers may cache preflight responses
+    pub cors_max_age_secs: Option<u64>,
+    /// Whether to send `Access-Control-Allow-Credentials: true`
+    pub cors_allow_credentials: bool,
     pub rate_limit_rps: u32,
     pub anthropic_api_key: Option<String>,
+    /// Anthropic API base URL, e.g. an internal gateway
+    pub anthropic_base_url: String,
+    /// Requests per minute per API key to `$generate`, `$chat` and `$nl-search`
+    pub ai_rate_limit_rpm: u32,
+    /// Concurrent AI requests per API key
+    pub ai_max_concurrent: usize,
+    /// Largest AI request body accepted
+    pub ai_max_body_bytes: usize,
+    /// Whether AI audit records keep prompt and reply text (hash only otherwise)
+    pub ai_audit_content: bool,
+    /// Handling of AI output that mentions patients outside the tool results
+    pub ai_output_guard: AiOutputGuard,
+    /// Whether `$nl-search` is served (`false` when `AI_ENABLED=false`)
+    pub ai_nl_search_enabled: bool,
+    /// Whether `$generate` is served (`false` when `AI_ENABLED=false`)
+    pub ai_generate_enabled: bool,
+    /// Whether `$chat` is served (`false` when `AI_ENABLED=false`)
+    pub ai_chat_enabled: bool,
+    /// Tool calls `$chat` may execute per conversation
+    pub ai_chat_max_tool_calls: usize,
+    /// Patients included in one `$chat` tool result
+    pub ai_chat_max_result_rows: usize,
+    /// Largest `$chat` tool result sent back to the model, in bytes
+    pub ai_chat_max_result_bytes: usize,
+    /// Wall-clock budget of one `$chat` request
+    pub ai_chat_time_budget_ms: u64,
+    /// Policy overrides for `/metadata`, `/health` and `/metrics`.
+    /// Routes not listed here are public.
+    pub route_policies: Vec<RoutePolicy>,
+    /// `ROUTE_POLICIES` entries that do not parse; [`Config::validate`]
+    /// refuses to start with any, so a typo cannot leave a route public
+    pub invalid_route_policies: Vec<String>,
+    /// URLs that receive administrative event webhooks
+    pub webhook_urls: Vec<String>,
+    /// Shared secret used to HMAC-sign webhook payloads
+    pub webhook_secret: Option<String>,
+    /// Auth failures per minute that trigger an alert (0 disables)
+    pub auth_failure_alert_threshold: u32,
+    /// Whether background maintenance jobs run
+    pub scheduler_enabled: bool,
+    /// Superseded history versions older than this are pruned (disabled if unset)
+    pub history_retention_days: Option<i32>,
+    /// wal2json replication slot consumed for change events (disabled if unset)
+    pub cdc_slot: Option<String>,
+    /// How often the CDC slot is polled
+    pub cdc_poll_interval_ms: u64,
+    /// Endpoint that change notifications from the outbox are delivered to
+    /// (disabled if unset)
+    pub notification_url: Option<String>,
+    /// How often the outbox is polled for due notifications
+    pub outbox_poll_interval_ms: u64,
+    /// Handling of unsafe narrative XHTML on ingest
+    pub narrative_policy: NarrativePolicy,
+    /// Largest inline `Patient.photo` data accepted, in decoded bytes
+    pub photo_max_bytes: usize,
+    /// Whether reads of a Patient replaced by another redirect to it
+    pub redirect_replaced_patients: bool,
+    /// Identifier systems whose values a Patient update cannot change
+    pub immutable_identifier_systems: Vec<String>,
+    /// Scope (from `X-Scopes`) required to change a Patient's `birthDate`
+    pub birthdate_change_scope: Option<String>,
+    /// `Type.element` paths every written resource of the type must carry
+    pub required_elements: Vec<String>,
+    /// Values a primitive `Type.element` may take
+    pub allowed_values: Vec<(String, Vec<String>)>,
+    /// Directory where bulk export NDJSON files are written
+    pub export_dir: String,
+    /// Public base URL of the server (e.g. `https://fhir.example.org`) for
+    /// the `$export` status and file URLs; relative URLs if unset
+    pub public_base_url: Option<String>,
+    /// How long finished export output is kept before cleanup
+    pub export_retention_secs: u64,
+    /// Per-statement timeout for database queries (0 disables)
+    pub statement_timeout_ms: u64,
+    /// Connections to open and prepare hot statements on at startup (0 disables)
+    pub pool_warmup_connections: usize,
+    /// Warmed connections kept idle in the pool at all times (0 disables)
+    pub pool_min_idle: usize,
+    /// Consecutive failed connection checkouts that open the database
+    /// circuit breaker (0 disables)
+    pub db_breaker_failures: u32,
+    /// How long the open breaker turns requests away before a trial
+    pub db_breaker_open_secs: u64,
+    /// Implementation guide packages loaded in addition to the bundled US
+    /// Core package: `.tgz` files, directories or `name#version` references
+    pub ig_packages: Vec<String>,
+    /// FHIR package registry that `name#version` packages are fetched from
+    pub ig_registry_url: String,
+    /// Directory where downloaded packages are cached
+    pub ig_cache_dir: String,
+    /// Directory of FHIR JSON / NDJSON fixtures loaded idempotently at startup
+    pub seed_dir: Option<String>,
+    /// Log redacted request bodies of mutations with their audit events
+    pub audit_capture_bodies: bool,
+    /// Maximum bytes of a captured request body
+    pub audit_body_limit: usize,
+    /// JSON fields redacted from captured bodies
+    pub audit_redact_fields: Vec<String>,
+    /// URL that receives a JSON report for every panic and internal error
+    pub error_report_url: Option<String>,
+    /// Sentry DSN that panics and internal errors are reported to
+    pub sentry_dsn: Option<String>,
+    /// Log directives toggled on and off by `SIGUSR1`
+    pub log_signal_directives: String,
+    /// Fraction of successful GET / HEAD requests written to the request log
+    pub request_log_sample_reads: f64,
+    /// Fraction of other successful requests written to the request log
+    pub request_log_sample_writes: f64,
+    /// Fraction of 4xx / 5xx responses written to the request log
+    pub request_log_sample_errors: f64,
+    /// Include the request id in the SQL comment tags of repository queries
+    /// (statements are then prepared on every use)
+    pub sql_tag_request_ids: bool,
+    /// Nominatim-compatible geocoding service used to add coordinates to
+    /// written addresses
+    pub geocoder_url: Option<String>,
+    /// Rows per batch when re-encrypting data under a rotated key (job
+    /// disabled if unset)
+    pub encryption_rotation_batch: Option<i32>,
+    /// Extension backfills (`search-index`, `encryption`) run to completion
+    /// in the background
+    pub backfills: Vec<String>,
+    /// Rows per backfill batch
+    pub backfill_batch: i32,
 }
 
+/// Fields redacted from captured audit bodies unless `AUDIT_REDACT_FIELDS`
+/// is set: names, birth dates, contact details, identifiers and attachments
+const DEFAULT_REDACT_FIELDS: &str = "name,birthDate,telecom,address,identifier,photo";
+
 impl Config {
+    /// Check settings the server cannot run with as given
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cdc_slot.is_some() && self.notification_url.is_none() {
+            return Err("CDC_SLOT requires NOTIFICATION_URL to deliver change events to".into());
+        }
+        if !self.invalid_route_policies.is_empty() {
+            return Err(format!(
+                "Invalid ROUTE_POLICIES entries: {} (expected <path>=public|rate-limited|protected)",
+                self.invalid_route_policies.join(", ")
+            ));
+        }
+        let unknown: Vec<&str> = self
+            .route_policies
+            .iter()
+            .map(|p| p.path.as_str())
+            .filter(|path| !POLICY_ROUTES.contains(path))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!(
+                "ROUTE_POLICIES names unknown routes: {} (policies apply to {})",
+                unknown.join(", "),
+                POLICY_ROUTES.join(", ")
+            ));
+        }
+        crate::middleware::cors_layer(self)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
     /// Load configuration from environment variables
     pub fn from_env() -> Self {
-        let cors_origins = std::env::var("CORS_ORIGINS")
-            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect())
-            .unwrap_or_else(|_| vec!["*".to_string()]);
+        let cors_origins = env_list("CORS_ORIGINS");
+        let cors_allow_methods = env_list("CORS_ALLOW_METHODS");
+        let cors_allow_headers = env_list("CORS_ALLOW_HEADERS");
+
+        let cors_max_age_secs = std::env::var("CORS_MAX_AGE")
+            .ok()
+            .and_then(|s| s.parse().ok());
+
+        let cors_allow_credentials = std::env::var("CORS_ALLOW_CREDENTIALS")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
 
         let database_url = std::env::var("DATABASE_URL")
             .unwrap_or_else(|_| "host=localhost user=postgres dbname=fhir".into());
@@ -24,20 +385,442 @@ impl Config {
 
         let api_key = std::env::var("API_KEY").ok();
 
+        let api_clients = std::env::var("API_CLIENTS")
+            .map(|s| parse_api_clients(&s))
+            .unwrap_or_default();
+
+        let admin_clients = std::env::var("ADMIN_CLIENTS")
+            .map(|s| {
+                s.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec![crate::middleware::auth::SHARED_KEY_PRINCIPAL.to_string()]);
+
         let rate_limit_rps = std::env::var("RATE_LIMIT_RPS")
             .ok()
             .and_then(|s| s.parse().ok())
             .unwrap_or(100);
 
+        let ai_rate_limit_rpm = std::env::var("AI_RATE_LIMIT_RPM")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(20);
+
+        let ai_max_concurrent = std::env::var("AI_MAX_CONCURRENT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(2);
+
+        let ai_max_body_bytes = std::env::var("AI_MAX_BODY_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(16 * 1024);
+
+        let ai_audit_content = std::env::var("AI_AUDIT_CONTENT")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+
+        let ai_output_guard = std::env::var("AI_OUTPUT_GUARD")
+            .ok()
+            .and_then(|s| AiOutputGuard::parse(&s))
+            .unwrap_or(AiOutputGuard::Redact);
+
+        // AI_ENABLED=false switches every AI operation off at once
+        let ai_enabled = std::env::var("AI_ENABLED")
+            .map(|s| s != "false" && s != "0")
+            .unwrap_or(true);
+        let ai_operation_enabled = |name: &str| {
+            ai_enabled
+                && std::env::var(name)
+                    .map(|s| s != "false" && s != "0")
+                    .unwrap_or(true)
+        };
+        let ai_nl_search_enabled = ai_operation_enabled("AI_NL_SEARCH_ENABLED");
+        let ai_generate_enabled = ai_operation_enabled("AI_GENERATE_ENABLED");
+        let ai_chat_enabled = ai_operation_enabled("AI_CHAT_ENABLED");
+
+        let ai_chat_max_tool_calls = std::env::var("AI_CHAT_MAX_TOOL_CALLS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10);
+
+        let ai_chat_max_result_rows = std::env::var("AI_CHAT_MAX_RESULT_ROWS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(20);
+
+        let ai_chat_max_result_bytes = std::env::var("AI_CHAT_MAX_RESULT_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(32 * 1024);
+
+        let ai_chat_time_budget_ms = std::env::var("AI_CHAT_TIME_BUDGET_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(60_000);
+
         let anthropic_api_key = std::env::var("ANTHROPIC_API_KEY").ok();
 
+        let anthropic_base_url = std::env::var("ANTHROPIC_BASE_URL")
+            .unwrap_or_else(|_| "https://api.anthropic.com".to_string());
+
+        let (route_policies, invalid_route_policies) = std::env::var("ROUTE_POLICIES")
+            .map(|s| parse_route_policies(&s))
+            .unwrap_or_default();
+
+        let webhook_urls = std::env::var("WEBHOOK_URLS")
+            .map(|s| {
+                s.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let webhook_secret = std::env::var("WEBHOOK_SECRET").ok();
+
+        let auth_failure_alert_threshold = std::env::var("AUTH_FAILURE_ALERT_THRESHOLD")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(20);
+
+        let scheduler_enabled = std::env::var("SCHEDULER_ENABLED")
+            .map(|s| s != "false" && s != "0")
+            .unwrap_or(true);
+
+        let history_retention_days = std::env::var("HISTORY_RETENTION_DAYS")
+            .ok()
+            .and_then(|s| s.parse().ok());
+
+        let cdc_slot = std::env::var("CDC_SLOT").ok();
+
+        let cdc_poll_interval_ms = std::env::var("CDC_POLL_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1000);
+
+        let notification_url = std::env::var("NOTIFICATION_URL").ok();
+
+        let outbox_poll_interval_ms = std::env::var("OUTBOX_POLL_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1000);
+
+        let narrative_policy = std::env::var("NARRATIVE_POLICY")
+            .ok()
+            .and_then(|s| NarrativePolicy::parse(&s))
+            .unwrap_or(NarrativePolicy::Reject);
+
+        let photo_max_bytes = std::env::var("PHOTO_MAX_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1024 * 1024);
+
+        let redirect_replaced_patients = std::env::var("REDIRECT_REPLACED_PATIENTS")
+            .map(|s| s != "false" && s != "0")
+            .unwrap_or(true);
+
+        let export_dir = std::env::var("EXPORT_DIR").unwrap_or_else(|_| {
+            std::env::temp_dir()
+                .join("fhir-export")
+                .to_string_lossy()
+                .into_owned()
+        });
+
+        let public_base_url = std::env::var("PUBLIC_BASE_URL").ok();
+
+        let export_retention_secs = std::env::var("EXPORT_RETENTION_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(3600);
+
+        let statement_timeout_ms = std::env::var("STATEMENT_TIMEOUT_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(30_000);
+
+        let pool_warmup_connections = std::env::var("POOL_WARMUP_CONNECTIONS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+
+        let pool_min_idle = std::env::var("POOL_MIN_IDLE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+
+        let db_breaker_failures = std::env::var("DB_BREAKER_FAILURES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(5);
+
+        let db_breaker_open_secs = std::env::var("DB_BREAKER_OPEN_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(30);
+
+        let ig_packages = std::env::var("IG_PACKAGES")
+            .map(|s| {
+                s.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let ig_registry_url = std::env::var("IG_REGISTRY_URL")
+            .unwrap_or_else(|_| "https://packages.fhir.org".to_string());
+
+        let ig_cache_dir = std::env::var("IG_CACHE_DIR").unwrap_or_else(|_| {
+            std::env::temp_dir()
+                .join("fhir-packages")
+                .to_string_lossy()
+                .into_owned()
+        });
+
+        let seed_dir = std::env::var("SEED_DIR").ok().filter(|s| !s.is_empty());
+
+        let audit_capture_bodies = std::env::var("AUDIT_CAPTURE_BODIES")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+
+        let audit_body_limit = std::env::var("AUDIT_BODY_LIMIT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(4096);
+
+        let immutable_identifier_systems = std::env::var("IMMUTABLE_IDENTIFIER_SYSTEMS")
+            .map(|s| {
+                s.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let birthdate_change_scope = std::env::var("BIRTHDATE_CHANGE_SCOPE").ok();
+
+        let required_elements = std::env::var("REQUIRED_ELEMENTS")
+            .map(|s| {
+                s.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let allowed_values = std::env::var("ALLOWED_VALUES")
+            .map(|s| parse_allowed_values(&s))
+            .unwrap_or_default();
+
+        let audit_redact_fields = std::env::var("AUDIT_REDACT_FIELDS")
+            .unwrap_or_else(|_| DEFAULT_REDACT_FIELDS.to_string())
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let error_report_url = std::env::var("ERROR_REPORT_URL").ok();
+
+        let sentry_dsn = std::env::var("SENTRY_DSN").ok();
+
+        let log_signal_directives = std::env::var("LOG_SIGNAL_DIRECTIVES")
+            .unwrap_or_else(|_| "fhir_server=debug".to_string());
+
+        let request_log_sample_reads = env_rate("REQUEST_LOG_SAMPLE_READS");
+        let request_log_sample_writes = env_rate("REQUEST_LOG_SAMPLE_WRITES");
+        let request_log_sample_errors = env_rate("REQUEST_LOG_SAMPLE_ERRORS");
+
+        let sql_tag_request_ids = std::env::var("SQL_TAG_REQUEST_IDS")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+
+        let geocoder_url = std::env::var("GEOCODER_URL").ok();
+
+        let encryption_rotation_batch = std::env::var("ENCRYPTION_ROTATION_BATCH")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|n: &i32| *n > 0);
+
+        let backfills = std::env::var("BACKFILLS")
+            .map(|s| {
+                s.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let backfill_batch = std::env::var("BACKFILL_BATCH")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(500);
+
         Self {
             database_url,
             bind_address,
             api_key,
+            api_clients,
+            admin_clients,
             cors_origins,
+            cors_allow_methods,
+            cors_allow_headers,
+            cors_max_age_secs,
+            cors_allow_credentials,
             rate_limit_rps,
             anthropic_api_key,
+            anthropic_base_url,
+            ai_rate_limit_rpm,
+            ai_max_concurrent,
+            ai_max_body_bytes,
+            ai_audit_content,
+            ai_output_guard,
+            ai_nl_search_enabled,
+            ai_generate_enabled,
+            ai_chat_enabled,
+            ai_chat_max_tool_calls,
+            ai_chat_max_result_rows,
+            ai_chat_max_result_bytes,
+            ai_chat_time_budget_ms,
+            route_policies,
+            invalid_route_policies,
+            webhook_urls,
+            webhook_secret,
+            auth_failure_alert_threshold,
+            scheduler_enabled,
+            history_retention_days,
+            cdc_slot,
+            cdc_poll_interval_ms,
+            notification_url,
+            outbox_poll_interval_ms,
+            narrative_policy,
+            photo_max_bytes,
+            redirect_replaced_patients,
+            immutable_identifier_systems,
+            birthdate_change_scope,
+            required_elements,
+            allowed_values,
+            export_dir,
+            public_base_url,
+            export_retention_secs,
+            statement_timeout_ms,
+            pool_warmup_connections,
+            pool_min_idle,
+            db_breaker_failures,
+            db_breaker_open_secs,
+            ig_packages,
+            ig_registry_url,
+            ig_cache_dir,
+            seed_dir,
+            audit_capture_bodies,
+            audit_body_limit,
+            audit_redact_fields,
+            error_report_url,
+            sentry_dsn,
+            log_signal_directives,
+            request_log_sample_reads,
+            request_log_sample_writes,
+            request_log_sample_errors,
+            sql_tag_request_ids,
+            geocoder_url,
+            encryption_rotation_batch,
+            backfills,
+            backfill_batch,
+        }
+    }
+
+    /// Look up the access policy for an operational route (defaults to public)
+    pub fn route_access(&self, path: &str) -> RouteAccess {
+        self.route_policies
+            .iter()
+            .find(|p| p.path == path)
+            .map(|p| p.access)
+            .unwrap_or(RouteAccess::Public)
+    }
+}
+
+/// Sampling rate between 0 and 1 (default 1, i.e. everything)
+fn env_rate(name: &str) -> f64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|r| r.is_finite())
+        .map(|r| r.clamp(0.0, 1.0))
+        .unwrap_or(1.0)
+}
+
+/// Read a comma-separated list from the environment, defaulting to `*`
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .map(|s| s.split(',').map(|s| s.trim().to_string()).collect())
+        .unwrap_or_else(|_| vec!["*".to_string()])
+}
+
+/// Parse per-client API keys such as `alice=key1,bob=key2`.
+///
+/// Malformed entries are logged and skipped.
+fn parse_api_clients(s: &str) -> Vec<(String, String)> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .map(|(name, key)| (name.trim().to_string(), key.trim().to_string()))
+                .filter(|(name, key)| !name.is_empty() && !key.is_empty());
+            if parsed.is_none() {
+                tracing::warn!("Ignoring invalid API_CLIENTS entry");
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Parse allowed values such as `Patient.gender=male|female,Observation.status=final`.
+///
+/// Malformed entries are logged and skipped.
+fn parse_allowed_values(s: &str) -> Vec<(String, Vec<String>)> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .filter(|(path, _)| path.contains('.'))
+                .map(|(path, values)| {
+                    (
+                        path.trim().to_string(),
+                        values.split('|').map(|v| v.trim().to_string()).collect(),
+                    )
+                });
+            if parsed.is_none() {
+                tracing::warn!(entry = entry, "Ignoring invalid ALLOWED_VALUES entry");
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Parse a policy table such as `/metrics=protected,/metadata=rate-limited`.
+///
+/// Malformed entries are returned after the policies, for
+/// [`Config::validate`] to reject.
+fn parse_route_policies(s: &str) -> (Vec<RoutePolicy>, Vec<String>) {
+    let mut policies = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('=').and_then(|(path, access)| {
+            RouteAccess::parse(access).map(|access| RoutePolicy {
+                path: path.trim().to_string(),
+                access,
+            })
+        });
+        match parsed {
+            Some(policy) => policies.push(policy),
+            None => invalid.push(entry.to_string()),
         }
     }
+    (policies, invalid)
 }
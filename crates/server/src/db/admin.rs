@@ -0,0 +1,129 @@
+//! Repository for administrative database queries
+
+use deadpool_postgres::Pool;
+use futures_util::{Stream, StreamExt};
+use serde_json::Value as JsonValue;
+use tokio_postgres::types::ToSql;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use super::{CancellableClient, tag};
+use crate::error::AppError;
+
+/// Repository for maintenance and introspection queries
+#[derive(Clone)]
+pub struct AdminRepository {
+    pool: Pool,
+}
+
+impl AdminRepository {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    /// Storage bloat, TOAST usage and history growth report
+    pub async fn maintenance_report(&self) -> Result<JsonValue, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("", "maintenance_report")
+            .query_one("SELECT fhir_maintenance_report()", &[])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// State of every resource of a type at `as_of` (RFC 3339)
+    pub async fn export_snapshot(
+        &self,
+        resource_type: &str,
+        as_of: &str,
+    ) -> Result<Vec<(Uuid, i32, JsonValue)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged(resource_type, "export_snapshot")
+            .query(
+                "SELECT id, version, data FROM fhir_export_snapshot($1, $2::text::timestamptz)",
+                &[&resource_type, &as_of],
+            )
+            .await?;
+
+        let results = rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+
+        Ok(results)
+    }
+
+    /// Resource types with at least one live resource
+    pub async fn resource_types(&self) -> Result<Vec<String>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("", "resource_types")
+            .query(
+                "SELECT DISTINCT resource_type FROM fhir_resources
+                  WHERE deleted_at IS NULL ORDER BY resource_type",
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Number of live resources of a type matching `fhir_search` parameters
+    pub async fn count_matching(
+        &self,
+        resource_type: &str,
+        params: &JsonValue,
+    ) -> Result<i64, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(resource_type, "count_matching")
+            .query_one(
+                "SELECT COUNT(*) FROM fhir_search($1, $2::jsonb)",
+                &[&resource_type, params],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Stream live resources of a type matching `fhir_search` parameters
+    ///
+    /// The pooled connection is held until the stream is dropped and runs
+    /// without a statement timeout.
+    pub async fn stream_matching(
+        &self,
+        resource_type: &str,
+        params: &JsonValue,
+    ) -> Result<
+        impl Stream<Item = Result<(Uuid, JsonValue), tokio_postgres::Error>> + Send + 'static,
+        AppError,
+    > {
+        let mut client = CancellableClient::get(&self.pool).await?;
+        // Exports may legitimately outlast the request statement timeout; the
+        // connection is closed afterwards so the setting never leaks
+        client.batch_execute("SET statement_timeout = 0").await?;
+        client.detach_on_drop();
+
+        let rows = client
+            .query_stream(
+                &tag(
+                    "stream_matching",
+                    "SELECT id, data FROM fhir_search($1, $2::jsonb)",
+                ),
+                [
+                    &resource_type as &(dyn ToSql + Sync),
+                    params as &(dyn ToSql + Sync),
+                ],
+            )
+            .instrument(tracing::info_span!(
+                "db",
+                resource_type,
+                operation = "stream_matching"
+            ))
+            .await?;
+
+        Ok(rows.map(|row| {
+            let row = row?;
+            Ok((row.get(0), row.get(1)))
+        }))
+    }
+}
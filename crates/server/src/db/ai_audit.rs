@@ -0,0 +1,94 @@
+//! Repository for the AI interaction audit table
+
+use deadpool_postgres::Pool;
+use serde_json::Value as JsonValue;
+
+use super::CancellableClient;
+use crate::ai::audit::{AiInteraction, prompt_hash};
+use crate::error::AppError;
+
+/// Filters for listing audited AI interactions
+#[derive(Debug, Default)]
+pub struct AiAuditFilter {
+    /// Only interactions at or after this RFC 3339 instant
+    pub since: Option<String>,
+    pub operation: Option<String>,
+    /// Only interactions that touched this `Patient/<id>` reference
+    pub resource: Option<String>,
+    pub limit: i64,
+}
+
+/// Repository for `fhir_ai_audit`
+#[derive(Clone)]
+pub struct AiAuditRepository {
+    pool: Pool,
+}
+
+impl AiAuditRepository {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    /// Record an interaction, keeping prompt and reply text only if
+    /// `capture_content` is set
+    pub async fn insert(
+        &self,
+        interaction: &AiInteraction,
+        capture_content: bool,
+    ) -> Result<(), AppError> {
+        // Not cancellable: the record must be written even if the caller has
+        // gone away
+        let client = self.pool.get().await?;
+        let (prompt, response) = match capture_content {
+            true => (
+                Some(interaction.prompt.as_str()),
+                Some(interaction.response.as_str()),
+            ),
+            false => (None, None),
+        };
+        client
+            .execute(
+                "INSERT INTO fhir_ai_audit
+                    (request_id, operation, model, prompt_hash, prompt, response,
+                     tool_calls, resources, input_tokens, output_tokens)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                &[
+                    &interaction.request_id,
+                    &interaction.operation,
+                    &interaction.model,
+                    &prompt_hash(&interaction.prompt),
+                    &prompt,
+                    &response,
+                    &interaction.tool_calls,
+                    &interaction.resources,
+                    &(interaction.usage.input_tokens as i64),
+                    &(interaction.usage.output_tokens as i64),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Recorded interactions matching `filter`, newest first
+    pub async fn list(&self, filter: &AiAuditFilter) -> Result<Vec<JsonValue>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("", "list")
+            .query(
+                "SELECT to_jsonb(a) FROM fhir_ai_audit a
+                  WHERE ($1::text IS NULL OR created_at >= $1::text::timestamptz)
+                    AND ($2::text IS NULL OR operation = $2)
+                    AND ($3::text IS NULL OR $3 = ANY(resources))
+                  ORDER BY created_at DESC, id DESC
+                  LIMIT $4",
+                &[
+                    &filter.since,
+                    &filter.operation,
+                    &filter.resource,
+                    &filter.limit,
+                ],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+}
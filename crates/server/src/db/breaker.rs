@@ -0,0 +1,159 @@
+//! Circuit breaker for the database
+//!
+//! When the database is down or the pool cannot hand out connections, every
+//! request would otherwise wait out its own failing connection attempt.
+//! Checkouts made while serving a request are counted; after a number of
+//! consecutive failures the breaker opens and requests are answered `503`
+//! with `Retry-After` at once. Once the open period has passed a single
+//! request is let through as a trial: a successful checkout closes the
+//! breaker, a failed one opens it again.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    /// Breaker the checkouts of the current request are counted by
+    static BREAKER: DbBreaker;
+}
+
+/// State of a [`DbBreaker`], as reported by `/readyz` and in metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests reach the database
+    Closed,
+    /// Requests are turned away until the open period has passed
+    Open,
+    /// One trial request is finding out whether the database is back
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        }
+    }
+
+    /// Value of the `fhir_db_breaker_state` gauge
+    fn gauge(&self) -> f64 {
+        match self {
+            BreakerState::Closed => 0.0,
+            BreakerState::HalfOpen => 1.0,
+            BreakerState::Open => 2.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Inner {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+/// Shared breaker state, cloned into request extensions
+#[derive(Debug, Clone)]
+pub struct DbBreaker {
+    inner: Arc<Mutex<Inner>>,
+    /// Consecutive failed checkouts that open the breaker; 0 never opens it
+    threshold: u32,
+    open_for: Duration,
+}
+
+impl DbBreaker {
+    pub fn new(threshold: u32, open_for: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::Closed { failures: 0 })),
+            threshold,
+            open_for,
+        }
+    }
+
+    /// Current state
+    pub fn state(&self) -> BreakerState {
+        match *self.inner.lock().unwrap() {
+            Inner::Closed { .. } => BreakerState::Closed,
+            Inner::Open { until } if until > Instant::now() => BreakerState::Open,
+            Inner::Open { .. } | Inner::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a request may go ahead; if not, how long until it is worth
+    /// trying again
+    ///
+    /// The first request after the open period becomes the trial. Should it
+    /// not reach the database, another is let through after the same period.
+    pub fn admit(&self) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match *inner {
+            Inner::Closed { .. } => Ok(()),
+            Inner::Open { until } if until > now => Err(until - now),
+            Inner::HalfOpen { since } if since + self.open_for > now => {
+                Err(since + self.open_for - now)
+            }
+            Inner::Open { .. } | Inner::HalfOpen { .. } => {
+                *inner = Inner::HalfOpen { since: now };
+                set_gauge(BreakerState::HalfOpen);
+                Ok(())
+            }
+        }
+    }
+
+    /// Count one connection checkout
+    pub fn record(&self, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if ok {
+            if !matches!(*inner, Inner::Closed { .. }) {
+                tracing::info!("Database circuit breaker closed");
+                set_gauge(BreakerState::Closed);
+            }
+            *inner = Inner::Closed { failures: 0 };
+            return;
+        }
+
+        metrics::counter!("fhir_db_connection_failures_total").increment(1);
+        let failures = match *inner {
+            Inner::Closed { failures } => failures + 1,
+            // A failed trial reopens at once
+            Inner::HalfOpen { .. } => self.threshold,
+            Inner::Open { .. } => return,
+        };
+        if self.threshold == 0 || failures < self.threshold {
+            *inner = Inner::Closed { failures };
+            return;
+        }
+        tracing::warn!(
+            failures = failures,
+            open_secs = self.open_for.as_secs(),
+            "Database circuit breaker opened"
+        );
+        metrics::counter!("fhir_db_breaker_trips_total").increment(1);
+        set_gauge(BreakerState::Open);
+        *inner = Inner::Open {
+            until: Instant::now() + self.open_for,
+        };
+    }
+
+    /// Run `f` with its connection checkouts counted by this breaker
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        BREAKER.scope(self.clone(), f).await
+    }
+}
+
+fn set_gauge(state: BreakerState) {
+    metrics::gauge!("fhir_db_breaker_state").set(state.gauge());
+}
+
+/// The breaker of the current request, if any
+pub(crate) fn current() -> Option<DbBreaker> {
+    BREAKER.try_with(DbBreaker::clone).ok()
+}
+
+/// Count a checkout against the current request's breaker
+pub(crate) fn record_checkout(ok: bool) {
+    let _ = BREAKER.try_with(|breaker| breaker.record(ok));
+}
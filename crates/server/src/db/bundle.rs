@@ -0,0 +1,37 @@
+//! Repository for Bundles processed as a unit
+
+use deadpool_postgres::Pool;
+use serde_json::Value as JsonValue;
+
+use super::CancellableClient;
+use crate::error::AppError;
+
+/// Repository running transaction Bundles through `fhir_transaction`
+#[derive(Clone)]
+pub struct BundleRepository {
+    pool: Pool,
+}
+
+impl BundleRepository {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    /// Perform every entry of a transaction Bundle in one database
+    /// transaction, returning the `transaction-response` Bundle
+    ///
+    /// A failing entry fails the whole Bundle and nothing is written, e.g. one
+    /// writing a resource checked out by another lock owner.
+    pub async fn transaction(&self, bundle: &JsonValue) -> Result<JsonValue, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Bundle", "transaction")
+            .query_one(
+                "SELECT fhir_transaction($1::jsonb)
+                   FROM set_config('fhir.lock_owner', $2, true)",
+                &[bundle, &super::lock_owner()],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+}
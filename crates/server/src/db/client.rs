@@ -0,0 +1,328 @@
+//! Pooled connections that cancel their in-flight query when abandoned
+//!
+//! Axum drops a handler's future when the client disconnects. Dropping a
+//! `tokio_postgres` query future does not stop the query on the server, so an
+//! abandoned expensive search would keep running to completion. A
+//! [`CancellableClient`] notices that it is dropped mid-query, sends the
+//! backend a cancel request and detaches the connection from the pool, so the
+//! cancellation can never hit a query issued later on the same connection.
+//!
+//! Repository queries go through [`CancellableClient::tagged`], which runs
+//! them in a `db` tracing span (resource type, operation, row count) and
+//! prefixes the SQL with a comment such as `/* req:<id> op:search */`, so
+//! `pg_stat_statements` and `pg_stat_activity` map back to API operations.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use deadpool_postgres::{Object, Pool, PoolError};
+use futures_util::{Stream, StreamExt};
+use tokio_postgres::types::{BorrowToSql, ToSql};
+use tokio_postgres::{Error, NoTls, Row, Statement};
+use tracing::Instrument;
+
+/// Longest request id copied into a SQL comment
+const MAX_TAG_REQUEST_ID: usize = 64;
+
+tokio::task_local! {
+    /// Request id included in the SQL comment tags of the current request
+    static TAG_REQUEST_ID: String;
+
+    /// Checkout lock owner whose locks the current request's writes pass
+    static LOCK_OWNER: String;
+}
+
+/// Run `f` with its writes passing the checkout locks held by `owner`
+pub async fn with_lock_owner<F: Future>(owner: String, f: F) -> F::Output {
+    LOCK_OWNER.scope(owner, f).await
+}
+
+/// The current request's lock owner, empty if it has none
+///
+/// Write statements pass it to `fhir.lock_owner`, which `fhir_update` and
+/// `fhir_delete` check against the resource's lock.
+pub(crate) fn lock_owner() -> String {
+    LOCK_OWNER.try_with(String::clone).unwrap_or_default()
+}
+
+/// Run `f` with its queries' SQL comment tags naming `request_id`
+///
+/// The id is reduced to characters that cannot end the comment. Statements
+/// tagged with a request id are unique, so they are prepared on every use
+/// instead of being served from the statement cache.
+pub async fn with_request_id_tags<F: Future>(request_id: &str, f: F) -> F::Output {
+    let request_id: String = request_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(MAX_TAG_REQUEST_ID)
+        .collect();
+    if request_id.is_empty() {
+        return f.await;
+    }
+    TAG_REQUEST_ID.scope(request_id, f).await
+}
+
+/// `f` with the request id tags, lock owner and circuit breaker of the
+/// current task, for work spawned onto another task
+pub fn inherit_request_scope<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let request_id = TAG_REQUEST_ID.try_with(String::clone).ok();
+    let lock_owner = LOCK_OWNER.try_with(String::clone).ok();
+    let breaker = super::breaker::current();
+    async move {
+        let f = async move {
+            match breaker {
+                Some(breaker) => breaker.scope(f).await,
+                None => f.await,
+            }
+        };
+        let f = async move {
+            match lock_owner {
+                Some(owner) => LOCK_OWNER.scope(owner, f).await,
+                None => f.await,
+            }
+        };
+        match request_id {
+            Some(request_id) => TAG_REQUEST_ID.scope(request_id, f).await,
+            None => f.await,
+        }
+    }
+}
+
+/// Prefix `statement` with the SQL comment tag for `operation`
+pub(crate) fn tag(operation: &str, statement: &str) -> String {
+    TAG_REQUEST_ID
+        .try_with(|id| format!("/* req:{} op:{} */ {}", id, operation, statement))
+        .unwrap_or_else(|_| format!("/* op:{} */ {}", operation, statement))
+}
+
+/// A pooled client whose running query is cancelled if it is dropped
+///
+/// Statements are prepared once per connection and cached.
+pub struct CancellableClient {
+    client: Option<Object>,
+    in_flight: AtomicBool,
+    detach: bool,
+}
+
+impl CancellableClient {
+    /// Check a connection out of the pool, counting the attempt against the
+    /// request's circuit breaker
+    pub async fn get(pool: &Pool) -> Result<Self, PoolError> {
+        let client = pool.get().await;
+        super::breaker::record_checkout(client.is_ok());
+        Ok(Self {
+            client: Some(client?),
+            in_flight: AtomicBool::new(false),
+            detach: false,
+        })
+    }
+
+    fn client(&self) -> &Object {
+        self.client.as_ref().expect("client is only taken on drop")
+    }
+
+    /// Close the connection instead of returning it to the pool, e.g. after
+    /// changing session settings
+    pub fn detach_on_drop(&mut self) {
+        self.detach = true;
+    }
+
+    /// Prepare a statement, from the connection's cache unless it carries a
+    /// request id (which would make every cached entry single-use)
+    async fn prepare(&self, statement: &str) -> Result<Statement, Error> {
+        if statement.starts_with("/* req:") {
+            self.client().prepare(statement).await
+        } else {
+            self.client().prepare_cached(statement).await
+        }
+    }
+
+    /// Queries for one repository operation on `resource_type`
+    pub fn tagged<'a>(&'a self, resource_type: &'a str, operation: &'static str) -> Tagged<'a> {
+        Tagged {
+            client: self,
+            resource_type,
+            operation,
+        }
+    }
+
+    /// Run a query future, leaving the in-flight flag set if it is dropped
+    async fn track<T>(&self, query: impl Future<Output = T>) -> T {
+        self.in_flight.store(true, Ordering::Relaxed);
+        let result = query.await;
+        self.in_flight.store(false, Ordering::Relaxed);
+        result
+    }
+
+    pub async fn query(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, Error> {
+        self.track(async {
+            let statement = self.prepare(statement).await?;
+            self.client().query(&statement, params).await
+        })
+        .await
+    }
+
+    pub async fn query_one(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, Error> {
+        self.track(async {
+            let statement = self.prepare(statement).await?;
+            self.client().query_one(&statement, params).await
+        })
+        .await
+    }
+
+    pub async fn query_opt(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, Error> {
+        self.track(async {
+            let statement = self.prepare(statement).await?;
+            self.client().query_opt(&statement, params).await
+        })
+        .await
+    }
+
+    pub async fn batch_execute(&self, sql: &str) -> Result<(), Error> {
+        self.track(self.client().batch_execute(sql)).await
+    }
+
+    /// Stream the rows of a query, holding the connection until the stream
+    /// ends or is dropped
+    ///
+    /// The query counts as in flight until the last row has been read, so
+    /// dropping the stream early cancels it.
+    pub async fn query_stream<P, I>(
+        self,
+        statement: &str,
+        params: I,
+    ) -> Result<impl Stream<Item = Result<Row, Error>> + Send + 'static, Error>
+    where
+        P: BorrowToSql,
+        I: IntoIterator<Item = P>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let rows = self
+            .track(async {
+                let statement = self.prepare(statement).await?;
+                self.client().query_raw(&statement, params).await
+            })
+            .await?;
+        let rows = Box::pin(rows);
+        self.in_flight.store(true, Ordering::Relaxed);
+
+        Ok(futures_util::stream::unfold(
+            (self, rows),
+            |(client, mut rows)| async move {
+                let row = rows.next().await;
+                if row.is_none() {
+                    client.in_flight.store(false, Ordering::Relaxed);
+                }
+                row.map(|row| (row, (client, rows)))
+            },
+        ))
+    }
+}
+
+/// Queries of one repository operation, traced and tagged
+pub struct Tagged<'a> {
+    client: &'a CancellableClient,
+    resource_type: &'a str,
+    operation: &'static str,
+}
+
+impl Tagged<'_> {
+    /// Span of one query; queries not tied to a resource type have an
+    /// empty `resource_type` and leave the field unset
+    fn span(&self) -> tracing::Span {
+        let span = tracing::info_span!(
+            "db",
+            resource_type = tracing::field::Empty,
+            operation = self.operation,
+            rows = tracing::field::Empty,
+        );
+        if !self.resource_type.is_empty() {
+            span.record("resource_type", self.resource_type);
+        }
+        span
+    }
+
+    pub async fn query(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, Error> {
+        let span = self.span();
+        let rows = self
+            .client
+            .query(&tag(self.operation, statement), params)
+            .instrument(span.clone())
+            .await?;
+        span.record("rows", rows.len());
+        Ok(rows)
+    }
+
+    pub async fn query_one(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, Error> {
+        let span = self.span();
+        let row = self
+            .client
+            .query_one(&tag(self.operation, statement), params)
+            .instrument(span.clone())
+            .await?;
+        span.record("rows", 1);
+        Ok(row)
+    }
+
+    pub async fn query_opt(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, Error> {
+        let span = self.span();
+        let row = self
+            .client
+            .query_opt(&tag(self.operation, statement), params)
+            .instrument(span.clone())
+            .await?;
+        span.record("rows", row.is_some() as u64);
+        Ok(row)
+    }
+}
+
+impl Drop for CancellableClient {
+    fn drop(&mut self) {
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        if !in_flight && !self.detach {
+            return;
+        }
+        let Some(object) = self.client.take() else {
+            return;
+        };
+
+        // Taking the client out of the pool closes the connection once dropped
+        let client = Object::take(object);
+        if !in_flight {
+            return;
+        }
+
+        metrics::counter!("fhir_db_queries_cancelled_total").increment(1);
+        let token = client.cancel_token();
+        tokio::spawn(async move {
+            if let Err(e) = token.cancel_query(NoTls).await {
+                tracing::warn!(error = %e, "Failed to cancel abandoned query");
+            }
+            drop(client);
+        });
+    }
+}
@@ -0,0 +1,69 @@
+//! Repository for stored terminology and conformance resources (ConceptMap,
+//! ...), which are few and always loaded whole
+
+use deadpool_postgres::Pool;
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+use super::CancellableClient;
+use crate::error::AppError;
+
+/// Repository for the resources of one conformance resource type
+#[derive(Clone)]
+pub struct ConformanceRepository {
+    pool: Pool,
+    resource_type: &'static str,
+}
+
+impl ConformanceRepository {
+    pub fn new(pool: Pool, resource_type: &'static str) -> Self {
+        Self {
+            pool,
+            resource_type,
+        }
+    }
+
+    /// Store a new resource
+    pub async fn create(&self, data: JsonValue) -> Result<Uuid, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(self.resource_type, "create")
+            .query_one(
+                "SELECT fhir_put($1, $2::jsonb)",
+                &[&self.resource_type, &data],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Get a resource by ID
+    pub async fn get(&self, id: Uuid) -> Result<Option<JsonValue>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(self.resource_type, "get")
+            .query_opt("SELECT fhir_get($1, $2::uuid)", &[&self.resource_type, &id])
+            .await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    /// Every live resource of the type, with its id set
+    pub async fn all(&self) -> Result<Vec<(Uuid, JsonValue)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged(self.resource_type, "all")
+            .query(
+                "SELECT id, data FROM fhir_search($1, '{\"_count\": \"all\"}'::jsonb)",
+                &[&self.resource_type],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let id: Uuid = row.get(0);
+                let mut data: JsonValue = row.get(1);
+                data["id"] = JsonValue::String(id.to_string());
+                (id, data)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,134 @@
+//! Extension feature negotiation
+//!
+//! At startup the server reads `fhir_ext_functions()` once and records which
+//! extension functions exist. Routes and interactions that depend on a newer
+//! function check [`supports`] (or [`require`]) and are disabled, with `501`
+//! and without being advertised in the CapabilityStatement, when it is
+//! missing, instead of failing with a raw SQL error. Extensions older than
+//! the catalog are probed through `pg_proc`.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use deadpool_postgres::Pool;
+use fhir_core::CapabilityStatement;
+use serde::Serialize;
+
+use super::CancellableClient;
+use crate::error::AppError;
+
+static FEATURES: OnceLock<ExtensionFeatures> = OnceLock::new();
+
+/// CapabilityStatement interactions and the extension function each needs
+const INTERACTION_FUNCTIONS: &[(&str, &str)] = &[
+    ("vread", "fhir_get_version"),
+    ("history-instance", "fhir_history"),
+    ("history-type", "fhir_history_since"),
+    ("transaction", "fhir_transaction"),
+    ("batch", "fhir_transaction"),
+];
+
+/// Functions offered by the installed extension
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtensionFeatures {
+    /// Installed extension version, if the catalog is available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// SQL signatures keyed by function name
+    pub functions: BTreeMap<String, Vec<String>>,
+}
+
+impl ExtensionFeatures {
+    /// Whether the extension provides `function`
+    pub fn has(&self, function: &str) -> bool {
+        self.functions.contains_key(function)
+    }
+}
+
+/// Read the extension's function catalog and record it for [`supports`]
+///
+/// The first successful negotiation in the process wins; later calls return
+/// the recorded catalog.
+pub async fn negotiate_features(pool: &Pool) -> Result<&'static ExtensionFeatures, AppError> {
+    if let Some(features) = FEATURES.get() {
+        return Ok(features);
+    }
+    let client = CancellableClient::get(pool).await?;
+    let catalog = client
+        .query_opt(
+            "SELECT 1 FROM pg_proc WHERE proname = 'fhir_ext_functions'",
+            &[],
+        )
+        .await?;
+
+    // Without the catalog, fall back to the `fhir_` functions in pg_proc
+    let rows = match catalog {
+        Some(_) => {
+            client
+                .query(
+                    "SELECT name, signature, version FROM fhir_ext_functions()",
+                    &[],
+                )
+                .await?
+        }
+        None => {
+            client
+                .query(
+                    "SELECT proname::text,
+                            format('%s(%s) RETURNS %s', proname,
+                                   pg_get_function_identity_arguments(oid),
+                                   pg_get_function_result(oid)),
+                            NULL::text
+                       FROM pg_proc
+                      WHERE proname LIKE 'fhir\\_%'
+                      ORDER BY 1, 2",
+                    &[],
+                )
+                .await?
+        }
+    };
+
+    let mut features = ExtensionFeatures::default();
+    for row in rows {
+        features
+            .functions
+            .entry(row.get(0))
+            .or_default()
+            .push(row.get(1));
+        features.version = row.get(2);
+    }
+
+    Ok(FEATURES.get_or_init(|| features))
+}
+
+/// The negotiated catalog, if [`negotiate_features`] has run
+pub fn extension_features() -> Option<&'static ExtensionFeatures> {
+    FEATURES.get()
+}
+
+/// Whether the extension provides `function`
+///
+/// Before negotiation every function is assumed to exist.
+pub fn supports(function: &str) -> bool {
+    extension_features().is_none_or(|features| features.has(function))
+}
+
+/// Fail with `501 Not Implemented` unless the extension provides `function`
+pub fn require(function: &str) -> Result<(), AppError> {
+    match supports(function) {
+        true => Ok(()),
+        false => Err(AppError::NotImplemented(format!(
+            "This operation needs {}(), which the installed extension does not provide",
+            function
+        ))),
+    }
+}
+
+/// Remove interactions whose extension function is missing
+pub fn restrict_capabilities(statement: &mut CapabilityStatement) {
+    for (interaction, function) in INTERACTION_FUNCTIONS {
+        if !supports(function) {
+            statement.remove_interaction(interaction);
+        }
+    }
+}
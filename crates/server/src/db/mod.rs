@@ -1,15 +1,52 @@
 //! Database connection and operations
 
+mod admin;
+mod ai_audit;
+mod breaker;
+mod bundle;
+mod client;
+mod conformance;
+mod features;
 mod repository;
+mod resource;
+mod schema_check;
+mod transaction;
+mod warmup;
 
+pub use admin::AdminRepository;
+pub(crate) use ai_audit::{AiAuditFilter, AiAuditRepository};
+pub use breaker::{BreakerState, DbBreaker};
+pub use bundle::BundleRepository;
+pub use client::{
+    CancellableClient, Tagged, inherit_request_scope, with_lock_owner, with_request_id_tags,
+};
+pub(crate) use client::{lock_owner, tag};
+pub use conformance::ConformanceRepository;
+pub use features::{
+    ExtensionFeatures, extension_features, negotiate_features, require, restrict_capabilities,
+    supports,
+};
 pub use repository::PatientRepository;
+pub use resource::ResourceRepository;
+pub use schema_check::{
+    CheckStatus, SchemaCheck, SchemaReport, check_schema, log_report, readiness,
+};
+pub use transaction::Transaction;
+pub use warmup::{EXPECTED_EXT_VERSION, spawn_min_idle, top_up, warm_up};
 
 use deadpool_postgres::{Config, Pool, Runtime};
 use tokio_postgres::NoTls;
 
 /// Create a connection pool from a database URL
-pub async fn create_pool(database_url: &str) -> Result<Pool, deadpool_postgres::CreatePoolError> {
+///
+/// Every connection starts with `statement_timeout` set to
+/// `statement_timeout_ms` (0 disables the timeout).
+pub async fn create_pool(
+    database_url: &str,
+    statement_timeout_ms: u64,
+) -> Result<Pool, deadpool_postgres::CreatePoolError> {
     let mut cfg = Config::new();
     cfg.url = Some(database_url.to_string());
+    cfg.options = Some(format!("-c statement_timeout={}", statement_timeout_ms));
     cfg.create_pool(Some(Runtime::Tokio1), NoTls)
 }
@@ -1,11 +1,43 @@
 //! Patient repository for database operations
 
+use std::collections::{HashMap, HashSet};
+
 use deadpool_postgres::Pool;
+use futures_util::{Stream, StreamExt};
 use serde_json::Value as JsonValue;
+use tokio_postgres::types::ToSql;
+use tracing::Instrument;
 use uuid::Uuid;
 
+use super::{CancellableClient, Transaction, tag};
 use crate::error::AppError;
 
+const PUT_SQL: &str = "SELECT fhir_put('Patient', $1::jsonb)";
+const GET_SQL: &str = "SELECT fhir_get('Patient', $1::uuid)";
+const UPDATE_SQL: &str = "SELECT fhir_update('Patient', $1::uuid, $2::jsonb)
+                            FROM set_config('fhir.lock_owner', $3, true)";
+const UPDATE_IF_SQL: &str = "SELECT fhir_update('Patient', $1::uuid, $2::jsonb, $3)
+                               FROM set_config('fhir.lock_owner', $4, true)";
+const DELETE_SQL: &str = "SELECT fhir_delete('Patient', $1::uuid)
+                            FROM set_config('fhir.lock_owner', $2, true)";
+const SEARCH_SQL: &str = "SELECT id, data FROM fhir_search('Patient', $1::jsonb)";
+const SEARCH_SCORED_SQL: &str = "SELECT id, data, score FROM fhir_search('Patient', $1::jsonb)";
+const COUNT_SQL: &str = "SELECT fhir_count('Patient', $1::jsonb)";
+const COUNT_ESTIMATE_SQL: &str = "SELECT fhir_count_estimate('Patient', $1::jsonb)";
+
+/// Statements on the request hot path, by operation, prepared ahead of time
+/// by [`super::warm_up`]
+pub(crate) const HOT_STATEMENTS: &[(&str, &str)] = &[
+    ("get", GET_SQL),
+    ("create", PUT_SQL),
+    ("update", UPDATE_SQL),
+    ("search", SEARCH_SCORED_SQL),
+    ("count", COUNT_SQL),
+];
+
+/// One row of the type-level history feed
+pub type HistoryFeedRow = (Uuid, i32, String, Option<JsonValue>, String, i64, Uuid);
+
 /// Repository for Patient CRUD operations
 #[derive(Clone)]
 pub struct PatientRepository {
@@ -19,18 +51,29 @@ impl PatientRepository {
 
     /// Create a new patient
     pub async fn create(&self, data: JsonValue) -> Result<Uuid, AppError> {
-        let client = self.pool.get().await?;
+        let client = CancellableClient::get(&self.pool).await?;
         let row = client
-            .query_one("SELECT fhir_put('Patient', $1::jsonb)", &[&data])
+            .tagged("Patient", "create")
+            .query_one(PUT_SQL, &[&data])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Create a new patient as part of `tx`
+    pub async fn create_in(&self, tx: &Transaction, data: JsonValue) -> Result<Uuid, AppError> {
+        let row = tx
+            .tagged("Patient", "create")
+            .query_one(PUT_SQL, &[&data])
             .await?;
         Ok(row.get(0))
     }
 
     /// Get a patient by ID
     pub async fn get(&self, id: Uuid) -> Result<Option<JsonValue>, AppError> {
-        let client = self.pool.get().await?;
+        let client = CancellableClient::get(&self.pool).await?;
         let row = client
-            .query_opt("SELECT fhir_get('Patient', $1::uuid)", &[&id])
+            .tagged("Patient", "get")
+            .query_opt(GET_SQL, &[&id])
             .await?;
 
         match row {
@@ -39,13 +82,126 @@ impl PatientRepository {
         }
     }
 
-    /// Update a patient
-    pub async fn update(&self, id: Uuid, data: JsonValue) -> Result<Option<i32>, AppError> {
-        let client = self.pool.get().await?;
+    /// Get a patient with its current version
+    pub async fn get_current(&self, id: Uuid) -> Result<Option<(JsonValue, i32)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "get_current")
+            .query_opt(
+                "SELECT fhir_get('Patient', id), version FROM fhir_resources
+                  WHERE id = $1 AND resource_type = 'Patient' AND deleted_at IS NULL",
+                &[&id],
+            )
+            .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    /// Live patients that may be `patient` stored under another id: those
+    /// sharing one of its identifiers (system and value) or its birth date
+    pub async fn duplicate_candidates(
+        &self,
+        patient: &JsonValue,
+    ) -> Result<Vec<(Uuid, JsonValue)>, AppError> {
+        let birth_date = patient.get("birthDate").and_then(JsonValue::as_str);
+        let identifiers: Vec<JsonValue> = patient
+            .get("identifier")
+            .and_then(JsonValue::as_array)
+            .into_iter()
+            .flatten()
+            .filter(|i| i.get("value").is_some())
+            .map(|i| {
+                let mut pattern = serde_json::Map::new();
+                for key in ["system", "value"] {
+                    if let Some(value) = i.get(key) {
+                        pattern.insert(key.to_string(), value.clone());
+                    }
+                }
+                JsonValue::Object(pattern)
+            })
+            .collect();
+        if birth_date.is_none() && identifiers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let identifiers = JsonValue::Array(identifiers);
+
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("Patient", "duplicate_candidates")
+            .query(
+                "SELECT id, fhir_get('Patient', id) FROM fhir_resources
+                  WHERE resource_type = 'Patient' AND deleted_at IS NULL
+                    AND (data->>'birthDate' = $1
+                         OR EXISTS (SELECT 1 FROM jsonb_array_elements($2::jsonb) i
+                                     WHERE data->'identifier' @> jsonb_build_array(i)))",
+                &[&birth_date, &identifiers],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Which of `names` (lowercased) and `numbers` are a name part, or an
+    /// identifier value or birth date, of a live patient outside `visible`
+    /// and of none of the patients in it
+    pub async fn identifying_terms_outside(
+        &self,
+        names: &[String],
+        numbers: &[String],
+        visible: &[Uuid],
+    ) -> Result<HashSet<String>, AppError> {
+        if names.is_empty() && numbers.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("Patient", "identifying_terms_outside")
+            .query(
+                "WITH terms (param_name, value) AS (
+                     SELECT p, v FROM unnest($1::text[]) v, unnest(ARRAY['family', 'given']) p
+                     UNION ALL
+                     SELECT p, v FROM unnest($2::text[]) v,
+                                      unnest(ARRAY['identifier-value', 'birthdate']) p
+                 )
+                 SELECT i.value FROM fhir_search_index i JOIN terms USING (param_name, value)
+                  WHERE i.resource_type = 'Patient' AND i.resource_id <> ALL($3::uuid[])
+                 EXCEPT
+                 SELECT i.value FROM fhir_search_index i JOIN terms USING (param_name, value)
+                  WHERE i.resource_type = 'Patient' AND i.resource_id = ANY($3::uuid[])",
+                &[&names, &numbers, &visible],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Get several patients in one round trip, keyed by id
+    ///
+    /// Missing or deleted ids are absent from the result.
+    pub async fn get_many(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, JsonValue>, AppError> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("Patient", "get_many")
+            .query(
+                "SELECT id, data FROM fhir_get_many('Patient', $1::uuid[])",
+                &[&ids],
+            )
+            .await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Get a patient as it was at `as_of` (RFC 3339)
+    pub async fn get_as_of(&self, id: Uuid, as_of: &str) -> Result<Option<JsonValue>, AppError> {
+        super::require("fhir_get_as_of")?;
+        let client = CancellableClient::get(&self.pool).await?;
         let row = client
+            .tagged("Patient", "get_as_of")
             .query_opt(
-                "SELECT fhir_update('Patient', $1::uuid, $2::jsonb)",
-                &[&id, &data],
+                "SELECT fhir_get_as_of('Patient', $1::uuid, $2::text::timestamptz)",
+                &[&id, &as_of],
             )
             .await?;
 
@@ -55,23 +211,71 @@ impl PatientRepository {
         }
     }
 
+    /// Update a patient
+    pub async fn update(&self, id: Uuid, data: JsonValue) -> Result<Option<i32>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "update")
+            .query_opt(UPDATE_SQL, &[&id, &data, &super::lock_owner()])
+            .await?;
+
+        match row {
+            Some(row) => Ok(row.get(0)),
+            None => Ok(None),
+        }
+    }
+
+    /// Update a patient only if it is still at `expected_version`
+    ///
+    /// Fails with [`AppError::PreconditionFailed`] if another write got there
+    /// first.
+    pub async fn update_if(
+        &self,
+        id: Uuid,
+        data: JsonValue,
+        expected_version: i32,
+    ) -> Result<Option<i32>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "update_if")
+            .query_opt(
+                UPDATE_IF_SQL,
+                &[&id, &data, &expected_version, &super::lock_owner()],
+            )
+            .await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    /// Update a patient as part of `tx`
+    pub async fn update_in(
+        &self,
+        tx: &Transaction,
+        id: Uuid,
+        data: JsonValue,
+    ) -> Result<Option<i32>, AppError> {
+        let row = tx
+            .tagged("Patient", "update")
+            .query_opt(UPDATE_SQL, &[&id, &data, &super::lock_owner()])
+            .await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
     /// Delete a patient
     pub async fn delete(&self, id: Uuid) -> Result<bool, AppError> {
-        let client = self.pool.get().await?;
+        let client = CancellableClient::get(&self.pool).await?;
         let row = client
-            .query_one("SELECT fhir_delete('Patient', $1::uuid)", &[&id])
+            .tagged("Patient", "delete")
+            .query_one(DELETE_SQL, &[&id, &super::lock_owner()])
             .await?;
         Ok(row.get(0))
     }
 
     /// Search for patients
     pub async fn search(&self, params: JsonValue) -> Result<Vec<(Uuid, JsonValue)>, AppError> {
-        let client = self.pool.get().await?;
+        let client = CancellableClient::get(&self.pool).await?;
         let rows = client
-            .query(
-                "SELECT id, data FROM fhir_search('Patient', $1::jsonb)",
-                &[&params],
-            )
+            .tagged("Patient", "search")
+            .query(SEARCH_SQL, &[&params])
             .await?;
 
         let results = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
@@ -79,38 +283,232 @@ impl PatientRepository {
         Ok(results)
     }
 
-    /// Count total patients matching search criteria (for pagination)
-    pub async fn count(&self, params: JsonValue) -> Result<i64, AppError> {
-        let client = self.pool.get().await?;
-        // Remove pagination params for counting
-        let mut count_params = params.clone();
-        if let Some(obj) = count_params.as_object_mut() {
-            obj.remove("_count");
+    /// Search for patients with each match's score (set for name searches)
+    pub async fn search_scored(
+        &self,
+        params: JsonValue,
+    ) -> Result<Vec<(Uuid, JsonValue, Option<f64>)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("Patient", "search")
+            .query(SEARCH_SCORED_SQL, &[&params])
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect())
+    }
+
+    /// Stream every patient matching the search criteria, ignoring paging
+    ///
+    /// Rows are forwarded as the database produces them; the pooled
+    /// connection is held until the stream is dropped, and dropping it early
+    /// cancels the query.
+    pub async fn search_stream(
+        &self,
+        params: JsonValue,
+    ) -> Result<
+        impl Stream<Item = Result<(Uuid, JsonValue), tokio_postgres::Error>> + Send + 'static,
+        AppError,
+    > {
+        let mut params = params;
+        if let Some(obj) = params.as_object_mut() {
             obj.remove("_offset");
+            obj.insert("_count".to_string(), JsonValue::String("all".to_string()));
         }
 
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .query_stream(&tag("search", SEARCH_SQL), [&params as &(dyn ToSql + Sync)])
+            .instrument(tracing::info_span!(
+                "db",
+                resource_type = "Patient",
+                operation = "search_stream"
+            ))
+            .await?;
+
+        Ok(rows.map(|row| {
+            let row = row?;
+            Ok((row.get(0), row.get(1)))
+        }))
+    }
+
+    /// Count total patients matching search criteria (for pagination)
+    pub async fn count(&self, params: JsonValue) -> Result<i64, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        // `fhir_count` ignores the pagination params
         let row = client
-            .query_one(
-                "SELECT COUNT(*) FROM fhir_search('Patient', $1::jsonb)",
-                &[&count_params],
-            )
+            .tagged("Patient", "count")
+            .query_one(COUNT_SQL, &[&params])
             .await?;
 
         Ok(row.get(0))
     }
 
-    /// Get all versions of a patient (history)
-    pub async fn history(&self, id: Uuid) -> Result<Vec<(i32, JsonValue)>, AppError> {
-        let client = self.pool.get().await?;
+    /// Planner estimate of the patients matching search criteria, without
+    /// running the search
+    pub async fn count_estimate(&self, params: JsonValue) -> Result<i64, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "count_estimate")
+            .query_one(COUNT_ESTIMATE_SQL, &[&params])
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    /// A page of a patient's versions (history), newest first, as
+    /// `(version, method, data, last_modified)`; deletions have no data
+    pub async fn history(
+        &self,
+        id: Uuid,
+        count: i32,
+        offset: i32,
+    ) -> Result<Vec<(i32, String, Option<JsonValue>, String)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
         let rows = client
+            .tagged("Patient", "history")
             .query(
-                "SELECT version, data FROM fhir_history('Patient', $1::uuid)",
-                &[&id],
+                "SELECT version, method, data,
+                        to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"')
+                   FROM fhir_history('Patient', $1::uuid, $2, $3)",
+                &[&id, &count, &offset],
             )
             .await?;
 
-        let results = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        let results = rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect();
 
         Ok(results)
     }
+
+    /// One version of a patient with its last-modified time (RFC 3339), or
+    /// `None` if the version does not exist or is a deletion
+    pub async fn get_version(
+        &self,
+        id: Uuid,
+        version: i32,
+    ) -> Result<Option<(JsonValue, String)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "get_version")
+            .query_opt(
+                "SELECT fhir_get_version('Patient', $1::uuid, $2),
+                        to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"')
+                   FROM fhir_history
+                  WHERE resource_type = 'Patient' AND resource_id = $1 AND version = $2",
+                &[&id, &version],
+            )
+            .await?;
+
+        Ok(row.and_then(|row| {
+            let data: Option<JsonValue> = row.get(0);
+            data.map(|data| (data, row.get(1)))
+        }))
+    }
+
+    /// Type-level history feed: versions written at or after `since`
+    /// (RFC 3339), resuming after `cursor` (`(micros, history_id)` of the last
+    /// row received). Rows are
+    /// `(id, version, method, data, last_modified, cursor_micros, history_id)`;
+    /// deletions have no data.
+    pub async fn history_since(
+        &self,
+        since: &str,
+        cursor: Option<(i64, Uuid)>,
+        count: i32,
+    ) -> Result<Vec<HistoryFeedRow>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let (cursor_micros, cursor_id) = cursor.unzip();
+        let rows = client
+            .tagged("Patient", "history_since")
+            .query(
+                "SELECT resource_id, version, method, data,
+                        to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'),
+                        cursor_micros, history_id
+                   FROM fhir_history_since('Patient', $1::text::timestamptz, $2, $3, $4)",
+                &[&since, &cursor_micros, &cursor_id, &count],
+            )
+            .await?;
+
+        let results = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get(0),
+                    row.get(1),
+                    row.get(2),
+                    row.get(3),
+                    row.get(4),
+                    row.get(5),
+                    row.get(6),
+                )
+            })
+            .collect();
+
+        Ok(results)
+    }
+
+    /// Patients as they were at `at` (RFC 3339): the version current at that
+    /// instant of each Patient that existed then, ordered by id and starting
+    /// after `after`. Rows are `(id, version, method, data, last_modified)`.
+    pub async fn history_at(
+        &self,
+        at: &str,
+        after: Option<Uuid>,
+        count: i32,
+    ) -> Result<Vec<(Uuid, i32, String, Option<JsonValue>, String)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged("Patient", "history_at")
+            .query(
+                "SELECT resource_id, version, method, data,
+                        to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"')
+                   FROM fhir_history_at('Patient', $1::text::timestamptz, $2, $3)",
+                &[&at, &after, &count],
+            )
+            .await?;
+
+        let results = rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4)))
+            .collect();
+
+        Ok(results)
+    }
+
+    /// Acquire or renew a checkout lock; returns the lease expiry (as text)
+    /// or None if another owner holds the lock
+    pub async fn lock(
+        &self,
+        id: Uuid,
+        owner: &str,
+        lease_seconds: i32,
+    ) -> Result<Option<String>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "lock")
+            .query_one(
+                "SELECT fhir_lock('Patient', $1::uuid, $2, $3)::text",
+                &[&id, &owner, &lease_seconds],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Release a checkout lock held by `owner`
+    pub async fn unlock(&self, id: Uuid, owner: &str) -> Result<bool, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged("Patient", "unlock")
+            .query_one(
+                "SELECT fhir_unlock('Patient', $1::uuid, $2)",
+                &[&id, &owner],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
 }
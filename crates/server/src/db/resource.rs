@@ -0,0 +1,154 @@
+//! Repository for resources of any type without a dedicated repository
+
+use deadpool_postgres::Pool;
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+use super::{CancellableClient, Transaction};
+use crate::error::AppError;
+
+/// Repository for the resources of one resource type
+#[derive(Clone)]
+pub struct ResourceRepository {
+    pool: Pool,
+    resource_type: String,
+}
+
+impl ResourceRepository {
+    pub fn new(pool: Pool, resource_type: impl Into<String>) -> Self {
+        Self {
+            pool,
+            resource_type: resource_type.into(),
+        }
+    }
+
+    /// Store a new resource
+    pub async fn create(&self, data: JsonValue) -> Result<Uuid, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "create")
+            .query_one(
+                "SELECT fhir_put($1, $2::jsonb)",
+                &[&self.resource_type, &data],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Store a new resource as part of `tx`
+    pub async fn create_in(&self, tx: &Transaction, data: JsonValue) -> Result<Uuid, AppError> {
+        let row = tx
+            .tagged(&self.resource_type, "create")
+            .query_one(
+                "SELECT fhir_put($1, $2::jsonb)",
+                &[&self.resource_type, &data],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Get a resource by ID
+    pub async fn get(&self, id: Uuid) -> Result<Option<JsonValue>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "get")
+            .query_opt("SELECT fhir_get($1, $2::uuid)", &[&self.resource_type, &id])
+            .await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    /// A live resource whose `url` is `url`
+    pub async fn find_by_url(&self, url: &str) -> Result<Option<Uuid>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "find_by_url")
+            .query_opt(
+                "SELECT id FROM fhir_resources
+                  WHERE resource_type = $1 AND deleted_at IS NULL AND data->>'url' = $2
+                  LIMIT 1",
+                &[&self.resource_type, &url],
+            )
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// A live resource carrying `identifier` (matched on system and value)
+    pub async fn find_by_identifier(
+        &self,
+        identifier: &JsonValue,
+    ) -> Result<Option<Uuid>, AppError> {
+        let mut pattern = serde_json::Map::new();
+        for key in ["system", "value"] {
+            if let Some(value) = identifier.get(key) {
+                pattern.insert(key.to_string(), value.clone());
+            }
+        }
+        let pattern = JsonValue::Array(vec![JsonValue::Object(pattern)]);
+
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "find_by_identifier")
+            .query_opt(
+                "SELECT id FROM fhir_resources
+                  WHERE resource_type = $1 AND deleted_at IS NULL AND data->'identifier' @> $2::jsonb
+                  LIMIT 1",
+                &[&self.resource_type, &pattern],
+            )
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Update a resource, returning its new version
+    pub async fn update(&self, id: Uuid, data: JsonValue) -> Result<Option<i32>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "update")
+            .query_opt(
+                "SELECT fhir_update($1, $2::uuid, $3::jsonb)
+                   FROM set_config('fhir.lock_owner', $4, true)",
+                &[&self.resource_type, &id, &data, &super::lock_owner()],
+            )
+            .await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    /// Delete a resource
+    pub async fn delete(&self, id: Uuid) -> Result<bool, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "delete")
+            .query_one(
+                "SELECT fhir_delete($1, $2::uuid)
+                   FROM set_config('fhir.lock_owner', $3, true)",
+                &[&self.resource_type, &id, &super::lock_owner()],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Search resources of the type
+    pub async fn search(&self, params: JsonValue) -> Result<Vec<(Uuid, JsonValue)>, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let rows = client
+            .tagged(&self.resource_type, "search")
+            .query(
+                "SELECT id, data FROM fhir_search($1, $2::jsonb)",
+                &[&self.resource_type, &params],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Count resources matching the search, ignoring paging
+    pub async fn count(&self, params: JsonValue) -> Result<i64, AppError> {
+        let client = CancellableClient::get(&self.pool).await?;
+        let row = client
+            .tagged(&self.resource_type, "count")
+            .query_one(
+                "SELECT fhir_count($1, $2::jsonb)",
+                &[&self.resource_type, &params],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+}
@@ -0,0 +1,229 @@
+//! Startup schema self-check
+//!
+//! Verifies that the tables, indexes and functions the server relies on
+//! exist, that no critical index was left invalid (e.g. by a failed
+//! `CREATE INDEX CONCURRENTLY`) and that the Patient search index is current.
+//! Every failed check carries a repair hint. The report is logged at boot and
+//! served by `/readyz`.
+
+use std::sync::Mutex;
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+
+use super::CancellableClient;
+use crate::error::AppError;
+
+/// Tables created by the extension's schema
+const REQUIRED_TABLES: &[&str] = &[
+    "fhir_resources",
+    "fhir_history",
+    "fhir_search_index",
+    "fhir_locks",
+    "fhir_ai_audit",
+];
+
+/// Indexes whose absence makes reads or searches scan whole tables
+const CRITICAL_INDEXES: &[&str] = &[
+    "idx_fhir_resources_type_deleted",
+    "idx_fhir_history_resource_version",
+    "idx_fhir_history_type_created",
+    "idx_fhir_search_index_param",
+    "idx_fhir_search_index_resource",
+];
+
+/// Functions every request path depends on
+const REQUIRED_FUNCTIONS: &[&str] = &[
+    "fhir_put",
+    "fhir_get",
+    "fhir_update",
+    "fhir_delete",
+    "fhir_search",
+    "fhir_count",
+];
+
+/// Most recent report, reused by `/readyz` while it passes
+static LAST_REPORT: Mutex<Option<SchemaReport>> = Mutex::new(None);
+
+/// Severity of a failed check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    /// Degraded but serving (e.g. a stale search index)
+    Warning,
+    /// The server cannot serve correctly until repaired
+    Error,
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaCheck {
+    /// What was checked, e.g. `table fhir_history`
+    pub name: String,
+    pub status: CheckStatus,
+    /// How to repair a failed check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl SchemaCheck {
+    fn new(name: String, passed: bool, status: CheckStatus, hint: String) -> Self {
+        match passed {
+            true => Self {
+                name,
+                status: CheckStatus::Ok,
+                hint: None,
+            },
+            false => Self {
+                name,
+                status,
+                hint: Some(hint),
+            },
+        }
+    }
+}
+
+/// Result of all schema checks
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaReport {
+    /// False if any check is an error
+    pub ready: bool,
+    pub checks: Vec<SchemaCheck>,
+}
+
+/// Run every schema check against the database
+pub async fn check_schema(pool: &Pool) -> Result<SchemaReport, AppError> {
+    let client = CancellableClient::get(pool).await?;
+    let mut checks = Vec::new();
+
+    let tables: Vec<String> = client
+        .query(
+            "SELECT relname::text FROM pg_class WHERE relkind IN ('r', 'p') AND relname::text = ANY($1::text[])",
+            &[&REQUIRED_TABLES],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    for table in REQUIRED_TABLES {
+        checks.push(SchemaCheck::new(
+            format!("table {}", table),
+            tables.iter().any(|t| t == table),
+            CheckStatus::Error,
+            format!(
+                "Table {} is missing: install the current extension build and run ALTER EXTENSION fhir_pg_ext UPDATE, or apply its CREATE TABLE from crates/pg-ext/src/schema.sql",
+                table
+            ),
+        ));
+    }
+
+    let indexes: Vec<(String, bool)> = client
+        .query(
+            "SELECT c.relname::text, i.indisvalid
+               FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid
+              WHERE c.relname::text = ANY($1::text[])",
+            &[&CRITICAL_INDEXES],
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    for index in CRITICAL_INDEXES {
+        let check = match indexes.iter().find(|(name, _)| name == index) {
+            None => SchemaCheck::new(
+                format!("index {}", index),
+                false,
+                CheckStatus::Error,
+                format!(
+                    "Index {} is missing: apply its CREATE INDEX from crates/pg-ext/src/schema.sql",
+                    index
+                ),
+            ),
+            Some((_, valid)) => SchemaCheck::new(
+                format!("index {}", index),
+                *valid,
+                CheckStatus::Error,
+                format!(
+                    "Index {} is invalid: run REINDEX INDEX CONCURRENTLY {}",
+                    index, index
+                ),
+            ),
+        };
+        checks.push(check);
+    }
+
+    let functions: Vec<String> = client
+        .query(
+            "SELECT DISTINCT proname::text FROM pg_proc WHERE proname::text = ANY($1::text[])",
+            &[&REQUIRED_FUNCTIONS],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    for function in REQUIRED_FUNCTIONS {
+        checks.push(SchemaCheck::new(
+            format!("function {}", function),
+            functions.iter().any(|f| f == function),
+            CheckStatus::Error,
+            format!(
+                "Function {}() is missing: install the current extension build and run ALTER EXTENSION fhir_pg_ext UPDATE",
+                function
+            ),
+        ));
+    }
+
+    // Only meaningful once the index tables exist
+    let ready = checks.iter().all(|c| c.status != CheckStatus::Error);
+    if ready && super::supports("fhir_reindex_check") {
+        let stale: i64 = client
+            .query_one("SELECT COUNT(*) FROM fhir_reindex_check('Patient')", &[])
+            .await?
+            .get(0);
+        checks.push(SchemaCheck::new(
+            "search index Patient".to_string(),
+            stale == 0,
+            CheckStatus::Warning,
+            format!(
+                "{} Patient resources have a stale search index: run SELECT fhir_reindex('Patient', 500) until it returns 0",
+                stale
+            ),
+        ));
+    }
+
+    Ok(SchemaReport { ready, checks })
+}
+
+/// Log every failed check with its repair hint
+pub fn log_report(report: &SchemaReport) {
+    for check in &report.checks {
+        let hint = check.hint.as_deref().unwrap_or_default();
+        match check.status {
+            CheckStatus::Ok => {}
+            CheckStatus::Warning => tracing::warn!(check = %check.name, hint, "Schema check"),
+            CheckStatus::Error => tracing::error!(check = %check.name, hint, "Schema check failed"),
+        }
+    }
+    if report.ready {
+        tracing::info!(checks = report.checks.len(), "Schema self-check passed");
+    }
+}
+
+/// The last report if it passed, otherwise a fresh one
+///
+/// A failing report is re-checked on every call so a repaired database
+/// becomes ready without a restart.
+pub async fn readiness(pool: &Pool) -> Result<SchemaReport, AppError> {
+    let cached = LAST_REPORT
+        .lock()
+        .unwrap()
+        .clone()
+        .filter(|report| report.ready);
+    if let Some(report) = cached {
+        return Ok(report);
+    }
+    let report = check_schema(pool).await?;
+    *LAST_REPORT.lock().unwrap() = Some(report.clone());
+    Ok(report)
+}
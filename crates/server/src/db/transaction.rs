@@ -0,0 +1,67 @@
+//! Explicit transactions for multi-step operations
+//!
+//! A [`Transaction`] holds one pooled connection between `BEGIN` and
+//! `COMMIT`, so the writes of a multi-step handler (outbox delivery, bundle
+//! processing, ...) become visible together or not at all. Repository
+//! methods ending in `_in` run on a transaction instead of checking out
+//! their own connection.
+
+use std::ops::Deref;
+
+use deadpool_postgres::Pool;
+
+use super::CancellableClient;
+use crate::error::AppError;
+
+/// An open transaction on a pooled connection
+///
+/// Dropping it without [`commit`](Self::commit) or
+/// [`rollback`](Self::rollback), e.g. when a handler returns early with an
+/// error, closes the connection, which rolls the transaction back.
+pub struct Transaction {
+    client: CancellableClient,
+    finished: bool,
+}
+
+impl Transaction {
+    /// Check out a connection and start a transaction on it
+    pub async fn begin(pool: &Pool) -> Result<Self, AppError> {
+        let client = CancellableClient::get(pool).await?;
+        client.batch_execute("BEGIN").await?;
+        Ok(Self {
+            client,
+            finished: false,
+        })
+    }
+
+    /// Make every write of the transaction visible
+    pub async fn commit(mut self) -> Result<(), AppError> {
+        self.client.batch_execute("COMMIT").await?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Discard every write of the transaction
+    pub async fn rollback(mut self) -> Result<(), AppError> {
+        self.client.batch_execute("ROLLBACK").await?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Deref for Transaction {
+    type Target = CancellableClient;
+
+    fn deref(&self) -> &CancellableClient {
+        &self.client
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        // Never return a connection with an open transaction to the pool
+        if !self.finished {
+            self.client.detach_on_drop();
+        }
+    }
+}
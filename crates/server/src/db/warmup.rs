@@ -0,0 +1,84 @@
+//! Startup connection warm-up
+//!
+//! Opening connections and planning statements on first use makes the first
+//! requests after a deploy noticeably slower. Warming checks out several
+//! connections at once, verifies the extension on each and prepares the hot
+//! statements so they sit in each connection's statement cache.
+//!
+//! Connections the pool later loses (closed after a cancelled query, broken
+//! by a database restart) are replaced in the background when a minimum of
+//! idle connections is configured, warmed the same way.
+
+use std::time::Duration;
+
+use deadpool_postgres::Pool;
+use futures_util::future::try_join_all;
+
+use super::repository::HOT_STATEMENTS;
+
+/// Version string the server expects from `fhir_ext_version()`
+pub const EXPECTED_EXT_VERSION: &str = concat!("fhir-pg-ext ", env!("CARGO_PKG_VERSION"));
+
+/// Warm up to `connections` pooled connections, returning the extension
+/// version reported by the database
+pub async fn warm_up(pool: &Pool, connections: usize) -> Result<String, String> {
+    let connections = connections.min(pool.status().max_size).max(1);
+
+    // Hold every client until all are warmed so each lands on its own connection
+    let clients = try_join_all((0..connections).map(|_| pool.get()))
+        .await
+        .map_err(|e| format!("Failed to open connection: {}", e))?;
+
+    let versions = try_join_all(clients.iter().map(|client| async move {
+        let row = client
+            .query_one("SELECT fhir_ext_version()", &[])
+            .await
+            .map_err(|e| format!("Extension handshake failed: {}", e))?;
+        for (operation, statement) in HOT_STATEMENTS {
+            // Repositories send statements with their operation tag
+            let statement = super::tag(operation, statement);
+            client
+                .prepare_cached(&statement)
+                .await
+                .map_err(|e| format!("Failed to prepare '{}': {}", statement, e))?;
+        }
+        Ok::<String, String>(row.get(0))
+    }))
+    .await?;
+
+    Ok(versions.into_iter().next().unwrap_or_default())
+}
+
+/// How often the pool is topped up to its minimum of idle connections
+const MIN_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Open and warm connections until `min_idle` are idle, as far as the pool
+/// has room without waiting for busy ones; returns how many were opened
+pub async fn top_up(pool: &Pool, min_idle: usize) -> Result<usize, String> {
+    let status = pool.status();
+    let target = min_idle
+        .min(status.max_size)
+        .min(status.available + status.max_size.saturating_sub(status.size));
+    if status.available >= target {
+        return Ok(0);
+    }
+    // Idle connections are checked out first, so warming `target` opens
+    // just the missing ones
+    warm_up(pool, target).await?;
+    Ok(pool.status().size.saturating_sub(status.size))
+}
+
+/// Keep at least `min_idle` warmed connections idle in the background
+pub fn spawn_min_idle(pool: Pool, min_idle: usize) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(MIN_IDLE_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match top_up(&pool, min_idle).await {
+                Ok(0) => {}
+                Ok(n) => tracing::debug!(opened = n, "Idle connections topped up"),
+                Err(e) => tracing::warn!(error = %e, "Idle connection top-up failed"),
+            }
+        }
+    });
+}
@@ -1,11 +1,73 @@
 //! Application error handling
+//!
+//! Errors are reported as an OperationOutcome in the format the request
+//! negotiated (see [`ErrorFormat`]), wherever in the stack they are raised.
+
+use std::future::Future;
 
 use axum::{
     Json,
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
-use fhir_core::OperationOutcome;
+use fhir_core::{OperationOutcome, OperationOutcomeIssue};
+
+/// Format OperationOutcome error responses are written in
+///
+/// Negotiated from the request's `Accept` header by
+/// [`crate::middleware::error_format_middleware`], which runs the rest of
+/// the request inside [`ErrorFormat::scope`]. Outside a request errors are
+/// JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    #[default]
+    Json,
+    /// FHIR XML (`application/fhir+xml`)
+    Xml,
+    /// A single OperationOutcome line (`application/fhir+ndjson`)
+    Ndjson,
+}
+
+tokio::task_local! {
+    static ERROR_FORMAT: ErrorFormat;
+}
+
+impl ErrorFormat {
+    /// Run `f` with the error responses created inside it in this format
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        ERROR_FORMAT.scope(self, f).await
+    }
+
+    fn current() -> Self {
+        ERROR_FORMAT.try_with(|format| *format).unwrap_or_default()
+    }
+}
+
+/// Error response carrying `outcome` in the request's negotiated format
+pub fn outcome_response(status: StatusCode, outcome: OperationOutcome) -> Response {
+    let (content_type, body) = match ErrorFormat::current() {
+        ErrorFormat::Json => return (status, Json(outcome)).into_response(),
+        ErrorFormat::Xml => {
+            let outcome = serde_json::to_value(&outcome).expect("outcomes always serialize");
+            ("application/fhir+xml", fhir_core::xml::to_xml(&outcome))
+        }
+        ErrorFormat::Ndjson => {
+            let line = serde_json::to_string(&outcome).expect("outcomes always serialize");
+            ("application/fhir+ndjson", format!("{}\n", line))
+        }
+    };
+    (
+        status,
+        [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+        body,
+    )
+        .into_response()
+}
+
+/// Message of an [`AppError::Internal`], attached to its response so the
+/// error reporting middleware can see it
+#[derive(Debug, Clone)]
+pub struct InternalErrorMessage(pub String);
 
 /// Application error type
 #[allow(dead_code)]
@@ -14,22 +76,88 @@ pub enum AppError {
     NotFound(String),
     BadRequest(String),
     Conflict(String),
+    /// An `If-Match` precondition does not hold
+    PreconditionFailed(String),
+    Locked(String),
+    /// The database cancelled the query (statement timeout)
+    Timeout(String),
+    /// The installed extension lacks a function the request needs
+    NotImplemented(String),
+    /// The write breaks business rules (one `business-rule` issue each)
+    BusinessRule(Vec<OperationOutcomeIssue>),
     Internal(String),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, outcome) = match self {
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::NotFound(msg)
+            | AppError::BadRequest(msg)
+            | AppError::Conflict(msg)
+            | AppError::PreconditionFailed(msg)
+            | AppError::Locked(msg)
+            | AppError::Timeout(msg)
+            | AppError::NotImplemented(msg)
+            | AppError::Internal(msg) => f.write_str(msg),
+            AppError::BusinessRule(issues) => {
+                let messages: Vec<&str> = issues
+                    .iter()
+                    .filter_map(|issue| issue.diagnostics.as_deref())
+                    .collect();
+                f.write_str(&messages.join("; "))
+            }
+        }
+    }
+}
+
+impl AppError {
+    /// HTTP status and OperationOutcome reporting this error
+    pub fn status_and_outcome(self) -> (StatusCode, OperationOutcome) {
+        match self {
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, OperationOutcome::not_found(&msg)),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, OperationOutcome::invalid(&msg)),
             AppError::Conflict(msg) => (StatusCode::CONFLICT, OperationOutcome::conflict(&msg)),
+            AppError::PreconditionFailed(msg) => (
+                StatusCode::PRECONDITION_FAILED,
+                OperationOutcome::conflict(&msg),
+            ),
+            AppError::Locked(msg) => (
+                StatusCode::LOCKED,
+                OperationOutcome::error(fhir_core::IssueType::LockError, &msg),
+            ),
+            AppError::Timeout(msg) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                OperationOutcome::error(fhir_core::IssueType::Timeout, &msg),
+            ),
+            AppError::NotImplemented(msg) => (
+                StatusCode::NOT_IMPLEMENTED,
+                OperationOutcome::error(fhir_core::IssueType::NotSupported, &msg),
+            ),
+            AppError::BusinessRule(issues) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                OperationOutcome::from_issues(issues),
+            ),
             AppError::Internal(msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 OperationOutcome::error(fhir_core::IssueType::Exception, &msg),
             ),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let internal = match &self {
+            AppError::Internal(msg) => Some(InternalErrorMessage(msg.clone())),
+            _ => None,
         };
+        let (status, outcome) = self.status_and_outcome();
 
-        (status, Json(outcome)).into_response()
+        let mut response = outcome_response(status, outcome);
+        if let Some(internal) = internal {
+            response.extensions_mut().insert(internal);
+        }
+        response
     }
 }
 
@@ -41,6 +169,30 @@ impl From<deadpool_postgres::PoolError> for AppError {
 
 impl From<tokio_postgres::Error> for AppError {
     fn from(err: tokio_postgres::Error) -> Self {
-        AppError::Internal(format!("Database error: {}", err))
+        use tokio_postgres::error::SqlState;
+
+        let message = || {
+            err.as_db_error()
+                .map(|e| e.message().to_string())
+                .unwrap_or_else(|| err.to_string())
+        };
+        match err.code() {
+            Some(code) if *code == SqlState::QUERY_CANCELED => {
+                AppError::Timeout("Database query exceeded the statement timeout".to_string())
+            }
+            // Raised by fhir_update, and only there, when the expected
+            // version is stale
+            Some(code) if *code == SqlState::TRIGGERED_DATA_CHANGE_VIOLATION => {
+                AppError::PreconditionFailed(message())
+            }
+            // Raised by fhir_transaction for malformed entries and missing targets
+            Some(code) if *code == SqlState::INVALID_PARAMETER_VALUE => {
+                AppError::BadRequest(message())
+            }
+            Some(code) if *code == SqlState::NO_DATA_FOUND => AppError::NotFound(message()),
+            // Raised by fhir_update / fhir_delete for resources locked by another owner
+            Some(code) if *code == SqlState::LOCK_NOT_AVAILABLE => AppError::Locked(message()),
+            _ => AppError::Internal(format!("Database error: {}", err)),
+        }
     }
 }
@@ -0,0 +1,182 @@
+//! Error reporting for panics and internal errors
+//!
+//! Reports go to a generic JSON webhook (`ERROR_REPORT_URL`) and/or Sentry
+//! (`SENTRY_DSN`, via the store endpoint). Delivery is best-effort and runs in
+//! the background; request bodies, query strings and resource ids are never
+//! included.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Longest error message forwarded to a reporting backend
+const MAX_MESSAGE_LEN: usize = 1000;
+
+/// What went wrong
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Panic,
+    InternalError,
+}
+
+/// A single error occurrence with its sanitized request context
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub request_id: String,
+    pub method: String,
+    /// Request path with ids replaced by `{id}`
+    pub route: String,
+}
+
+impl ErrorReport {
+    pub fn new(kind: ErrorKind, message: &str, request_id: &str, method: &str, path: &str) -> Self {
+        let mut message = message.to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        Self {
+            kind,
+            message,
+            request_id: request_id.to_string(),
+            method: method.to_string(),
+            route: sanitize_path(path),
+        }
+    }
+}
+
+/// Replace path segments that identify resources (UUIDs, numbers) with `{id}`
+fn sanitize_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if Uuid::parse_str(segment).is_ok()
+                || (!segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()))
+            {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Sentry project parsed from a DSN (`https://<key>@<host>/<project>`)
+#[derive(Debug, Clone)]
+struct SentryTarget {
+    store_url: String,
+    auth_header: String,
+}
+
+impl SentryTarget {
+    fn parse(dsn: &str) -> Option<Self> {
+        let url = reqwest::Url::parse(dsn).ok()?;
+        let key = url.username();
+        let project = url.path().trim_matches('/');
+        if key.is_empty() || project.is_empty() {
+            return None;
+        }
+        let host = url.host_str()?;
+        let port = url.port().map(|p| format!(":{}", p)).unwrap_or_default();
+        Some(Self {
+            store_url: format!("{}://{}{}/api/{}/store/", url.scheme(), host, port, project),
+            auth_header: format!(
+                "Sentry sentry_version=7, sentry_key={}, sentry_client=fhir-server/{}",
+                key,
+                env!("CARGO_PKG_VERSION")
+            ),
+        })
+    }
+
+    fn event(report: &ErrorReport) -> serde_json::Value {
+        serde_json::json!({
+            "event_id": Uuid::new_v4().simple().to_string(),
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": match report.kind {
+                ErrorKind::Panic => "fatal",
+                ErrorKind::InternalError => "error",
+            },
+            "platform": "rust",
+            "logger": "fhir-server",
+            "release": concat!("fhir-server@", env!("CARGO_PKG_VERSION")),
+            "transaction": format!("{} {}", report.method, report.route),
+            "message": {"formatted": report.message},
+            "tags": {
+                "kind": report.kind,
+                "request_id": report.request_id,
+                "route": report.route,
+                "method": report.method,
+            },
+        })
+    }
+}
+
+/// Sends error reports to the configured backends
+#[derive(Clone)]
+pub struct ErrorReporter {
+    http: reqwest::Client,
+    webhook_url: Option<Arc<String>>,
+    sentry: Option<Arc<SentryTarget>>,
+}
+
+impl ErrorReporter {
+    pub fn new(webhook_url: Option<String>, sentry_dsn: Option<String>) -> Self {
+        let sentry = sentry_dsn.and_then(|dsn| {
+            let target = SentryTarget::parse(&dsn);
+            if target.is_none() {
+                tracing::error!("Invalid SENTRY_DSN; Sentry reporting disabled");
+            }
+            target
+        });
+        Self {
+            http: reqwest::Client::new(),
+            webhook_url: webhook_url.map(Arc::new),
+            sentry: sentry.map(Arc::new),
+        }
+    }
+
+    /// Whether any backend is configured
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some() || self.sentry.is_some()
+    }
+
+    /// Deliver a report to every backend in the background
+    pub fn report(&self, report: ErrorReport) {
+        if !self.is_enabled() {
+            return;
+        }
+        metrics::counter!("fhir_errors_reported_total", "kind" => match report.kind {
+            ErrorKind::Panic => "panic",
+            ErrorKind::InternalError => "internal_error",
+        })
+        .increment(1);
+
+        if let Some(url) = self.webhook_url.clone() {
+            let request = self.http.post(url.as_str()).json(&report);
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                    tracing::warn!(url = %url, error = %e, "Error report delivery failed");
+                }
+            });
+        }
+        if let Some(sentry) = self.sentry.clone() {
+            let request = self
+                .http
+                .post(&sentry.store_url)
+                .header("X-Sentry-Auth", &sentry.auth_header)
+                .json(&SentryTarget::event(&report));
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                    tracing::warn!(error = %e, "Sentry event delivery failed");
+                }
+            });
+        }
+    }
+}
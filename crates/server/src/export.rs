@@ -0,0 +1,449 @@
+//! Bulk Data `$export` jobs
+//!
+//! A kick-off request starts a background job that streams each requested
+//! resource type into an NDJSON file under the export directory. Clients poll
+//! the status URL for progress and the completion manifest, and may cancel a
+//! job with `DELETE`. Output of finished jobs is removed once the retention
+//! period has passed.
+//!
+//! Cohort extracts (`$extract-cohort`) are export jobs with a
+//! de-identification profile: every resource is de-identified and given a
+//! pseudonymous id before it is written.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::Pool;
+use fhir_core::DeidProfile;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::db::AdminRepository;
+
+/// Resources a running job counted and exported so far
+#[derive(Debug, Default)]
+struct JobProgress {
+    total: u64,
+    exported: u64,
+}
+
+/// State of an export job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportState {
+    InProgress,
+    Completed,
+    Failed(String),
+}
+
+/// Standard kick-off filters (`_type`, `_since`, `_typeFilter`)
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    /// Resource types to export (every live type if empty)
+    pub types: Vec<String>,
+    /// Only resources updated at or after this instant (RFC 3339, UTC)
+    pub since: Option<String>,
+    /// `fhir_search` parameters per type; a resource is exported if it
+    /// matches any of its type's filters
+    pub type_filters: HashMap<String, Vec<JsonValue>>,
+    /// De-identify resources with this profile before writing them
+    pub deidentify: Option<DeidProfile>,
+}
+
+impl ExportFilter {
+    /// `fhir_search` parameter sets selecting the resources of a type
+    fn queries(&self, resource_type: &str) -> Vec<JsonValue> {
+        let filters = self
+            .type_filters
+            .get(resource_type)
+            .cloned()
+            .unwrap_or_else(|| vec![JsonValue::Object(Default::default())]);
+
+        filters
+            .into_iter()
+            .map(|mut params| {
+                if let Some(obj) = params.as_object_mut() {
+                    obj.insert("_count".to_string(), JsonValue::from("all"));
+                    if let Some(ref since) = self.since {
+                        obj.insert("_since".to_string(), JsonValue::from(since.as_str()));
+                    }
+                }
+                params
+            })
+            .collect()
+    }
+}
+
+/// De-identifies resources of one job, replacing ids with keyed hashes
+///
+/// The key is random per job and never stored, so pseudonyms can neither be
+/// reversed nor linked across extracts.
+struct Pseudonymizer {
+    profile: DeidProfile,
+    key: Uuid,
+}
+
+impl Pseudonymizer {
+    fn new(profile: DeidProfile) -> Self {
+        Self {
+            profile,
+            key: Uuid::new_v4(),
+        }
+    }
+
+    fn apply(&self, id: Uuid, resource: &JsonValue) -> JsonValue {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(id.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let pseudonym: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        self.profile.apply(resource, &pseudonym)
+    }
+}
+
+/// One NDJSON output file of a job
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportFile {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    /// File name within the job directory
+    #[serde(skip)]
+    pub name: String,
+    pub count: u64,
+}
+
+/// Snapshot of a job as seen by the status endpoint
+#[derive(Debug, Clone)]
+pub struct ExportJob {
+    pub id: Uuid,
+    /// The kick-off request URL, echoed in the manifest
+    pub request: String,
+    /// Server time the export started (RFC 3339)
+    pub transaction_time: String,
+    pub state: ExportState,
+    pub filter: ExportFilter,
+    /// Resources written so far
+    pub exported: u64,
+    /// Resources expected in total (known once counting finishes)
+    pub total: Option<u64>,
+    pub files: Vec<ExportFile>,
+    finished_at: Option<Instant>,
+}
+
+struct JobEntry {
+    job: ExportJob,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Registry of export jobs and their output directory
+#[derive(Clone)]
+pub struct ExportManager {
+    jobs: Arc<Mutex<HashMap<Uuid, JobEntry>>>,
+    dir: Arc<PathBuf>,
+    retention: Duration,
+    base_url: Arc<str>,
+}
+
+impl ExportManager {
+    /// Manage jobs writing to `dir`, handing out status and file URLs under
+    /// `base_url` (relative URLs if None)
+    pub fn new(dir: PathBuf, retention: Duration, base_url: Option<&str>) -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            dir: Arc::new(dir),
+            retention,
+            base_url: base_url.unwrap_or_default().trim_end_matches('/').into(),
+        }
+    }
+
+    /// Base of the URLs handed out to clients, e.g. `https://fhir.example.org`
+    /// (empty for relative URLs)
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// How long output of finished jobs is kept
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Start a job exporting the resources selected by `filter`
+    pub fn start(&self, pool: Pool, request: String, filter: ExportFilter) -> Uuid {
+        let id = Uuid::new_v4();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let job = ExportJob {
+            id,
+            request,
+            transaction_time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            state: ExportState::InProgress,
+            filter,
+            exported: 0,
+            total: None,
+            files: Vec::new(),
+            finished_at: None,
+        };
+        self.jobs.lock().unwrap().insert(
+            id,
+            JobEntry {
+                job,
+                cancelled: cancelled.clone(),
+            },
+        );
+        metrics::counter!("fhir_export_jobs_total", "outcome" => "started").increment(1);
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let result = manager.run(pool, id, &cancelled).await;
+            if cancelled.load(Ordering::Relaxed) {
+                // The job is already gone; remove anything written after cancellation
+                manager.remove_output(id).await;
+                return;
+            }
+            let outcome = if result.is_ok() {
+                "completed"
+            } else {
+                "failed"
+            };
+            metrics::counter!("fhir_export_jobs_total", "outcome" => outcome).increment(1);
+            manager.update(id, |job| {
+                job.state = match result {
+                    Ok(()) => ExportState::Completed,
+                    Err(e) => {
+                        tracing::error!(job_id = %id, error = %e, "Export failed");
+                        ExportState::Failed(e)
+                    }
+                };
+                job.finished_at = Some(Instant::now());
+            });
+        });
+
+        id
+    }
+
+    /// Current snapshot of a job
+    pub fn get(&self, id: Uuid) -> Option<ExportJob> {
+        self.jobs.lock().unwrap().get(&id).map(|e| e.job.clone())
+    }
+
+    /// Cancel a job (running or finished) and delete its output.
+    ///
+    /// Returns `false` if the job does not exist.
+    pub async fn cancel(&self, id: Uuid) -> bool {
+        let Some(entry) = self.jobs.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        entry.cancelled.store(true, Ordering::Relaxed);
+        if entry.job.state == ExportState::InProgress {
+            metrics::counter!("fhir_export_jobs_total", "outcome" => "cancelled").increment(1);
+        }
+        self.remove_output(id).await;
+        tracing::info!(job_id = %id, "Export cancelled");
+        true
+    }
+
+    /// Path of an output file, if the job and file exist
+    pub fn file_path(&self, id: Uuid, name: &str) -> Option<PathBuf> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = &jobs.get(&id)?.job;
+        job.files
+            .iter()
+            .any(|f| f.name == name)
+            .then(|| self.job_dir(id).join(name))
+    }
+
+    /// Delete jobs that finished longer ago than the retention period,
+    /// returning how many were removed
+    pub async fn remove_expired(&self) -> usize {
+        let expired: Vec<Uuid> = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let expired: Vec<Uuid> = jobs
+                .values()
+                .filter(|e| {
+                    e.job
+                        .finished_at
+                        .is_some_and(|t| t.elapsed() > self.retention)
+                })
+                .map(|e| e.job.id)
+                .collect();
+            for id in &expired {
+                jobs.remove(id);
+            }
+            expired
+        };
+
+        for id in &expired {
+            self.remove_output(*id).await;
+        }
+        expired.len()
+    }
+
+    fn job_dir(&self, id: Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn update(&self, id: Uuid, f: impl FnOnce(&mut ExportJob)) {
+        if let Some(entry) = self.jobs.lock().unwrap().get_mut(&id) {
+            f(&mut entry.job);
+        }
+    }
+
+    async fn remove_output(&self, id: Uuid) {
+        let dir = self.job_dir(id);
+        match tokio::fs::remove_dir_all(&dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!(path = %dir.display(), error = %e, "Failed to remove export output");
+            }
+            _ => {}
+        }
+    }
+
+    /// Run a job, keeping the aggregate progress gauges of running jobs up
+    /// to date
+    async fn run(&self, pool: Pool, id: Uuid, cancelled: &AtomicBool) -> Result<(), String> {
+        let mut progress = JobProgress::default();
+        metrics::gauge!("fhir_export_jobs_running").increment(1.0);
+        let result = self.export(pool, id, cancelled, &mut progress).await;
+        // The finished job no longer counts towards the running jobs' progress
+        metrics::gauge!("fhir_export_jobs_running").decrement(1.0);
+        metrics::gauge!("fhir_export_total_resources").decrement(progress.total as f64);
+        metrics::gauge!("fhir_export_exported_resources").decrement(progress.exported as f64);
+        result
+    }
+
+    /// Export every requested type, checking for cancellation between rows
+    async fn export(
+        &self,
+        pool: Pool,
+        id: Uuid,
+        cancelled: &AtomicBool,
+        progress: &mut JobProgress,
+    ) -> Result<(), String> {
+        let repo = AdminRepository::new(pool);
+
+        let filter = self.get(id).map(|j| j.filter).unwrap_or_default();
+        let types = if filter.types.is_empty() {
+            repo.resource_types().await.map_err(|e| e.to_string())?
+        } else {
+            filter.types.clone()
+        };
+
+        // Overlapping type filters make this an upper bound
+        let mut total = 0u64;
+        for resource_type in &types {
+            for params in filter.queries(resource_type) {
+                total += repo
+                    .count_matching(resource_type, &params)
+                    .await
+                    .map_err(|e| e.to_string())? as u64;
+            }
+        }
+        self.update(id, |job| job.total = Some(total));
+        progress.total = total;
+        metrics::gauge!("fhir_export_total_resources").increment(total as f64);
+
+        let pseudonymizer = filter.deidentify.map(Pseudonymizer::new);
+
+        let dir = self.job_dir(id);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        for resource_type in &types {
+            let name = format!("{}.ndjson", resource_type);
+            let count = self
+                .export_type(
+                    &repo,
+                    resource_type,
+                    &filter.queries(resource_type),
+                    &dir.join(&name),
+                    pseudonymizer.as_ref(),
+                    cancelled,
+                    |n| {
+                        progress.exported += n;
+                        let exported = progress.exported;
+                        self.update(id, |job| job.exported = exported);
+                        metrics::gauge!("fhir_export_exported_resources").increment(n as f64);
+                    },
+                )
+                .await?;
+            if cancelled.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            if count > 0 {
+                self.update(id, |job| {
+                    job.files.push(ExportFile {
+                        resource_type: resource_type.clone(),
+                        name,
+                        count,
+                    })
+                });
+            }
+        }
+
+        tracing::info!(job_id = %id, exported = progress.exported, "Export completed");
+        Ok(())
+    }
+
+    /// Write the resources of one type matching any of `queries` to an NDJSON
+    /// file (de-identified if requested), reporting progress in batches
+    async fn export_type(
+        &self,
+        repo: &AdminRepository,
+        resource_type: &str,
+        queries: &[JsonValue],
+        path: &Path,
+        pseudonymizer: Option<&Pseudonymizer>,
+        cancelled: &AtomicBool,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64, String> {
+        const PROGRESS_BATCH: u64 = 100;
+
+        let file = tokio::fs::File::create(path)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut out = tokio::io::BufWriter::new(file);
+
+        // Resources matching several filters are written once
+        let mut seen = HashSet::new();
+        let mut count = 0u64;
+        for params in queries {
+            let mut rows = Box::pin(
+                repo.stream_matching(resource_type, params)
+                    .await
+                    .map_err(|e| e.to_string())?,
+            );
+            while let Some(row) = rows.next().await {
+                if cancelled.load(Ordering::Relaxed) {
+                    return Ok(count);
+                }
+                let (id, resource) = row.map_err(|e| e.to_string())?;
+                if queries.len() > 1 && !seen.insert(id) {
+                    continue;
+                }
+                let resource = match pseudonymizer {
+                    Some(p) => p.apply(id, &resource),
+                    None => resource,
+                };
+                let mut line = serde_json::to_vec(&resource).map_err(|e| e.to_string())?;
+                line.push(b'\n');
+                out.write_all(&line).await.map_err(|e| e.to_string())?;
+
+                count += 1;
+                if count % PROGRESS_BATCH == 0 {
+                    progress(PROGRESS_BATCH);
+                }
+            }
+        }
+        progress(count % PROGRESS_BATCH);
+
+        out.flush().await.map_err(|e| e.to_string())?;
+        Ok(count)
+    }
+}
@@ -0,0 +1,233 @@
+//! Address geocoding at write time
+//!
+//! When a [`Geocoder`] is configured, addresses of written Patients and
+//! Locations that do not yet carry coordinates get the standard
+//! `geolocation` extension (latitude / longitude) so geo search and
+//! catchment analytics can use them. Providers implement [`Geocoder`];
+//! [`NominatimGeocoder`] talks to any Nominatim-compatible `/search`
+//! endpoint. Geocoding failures never fail the write, and a write spends at
+//! most [`GEOCODE_BUDGET`] on lookups for at most [`MAX_ADDRESSES`]
+//! addresses; the rest are stored without coordinates.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+/// Extension holding an address's coordinates
+pub const GEOLOCATION_URL: &str = "http://hl7.org/fhir/StructureDefinition/geolocation";
+
+/// Resource types whose addresses are geocoded
+const GEOCODED_TYPES: &[&str] = &["Patient", "Location"];
+
+/// Total time a write may spend waiting for the geocoder
+pub const GEOCODE_BUDGET: Duration = Duration::from_secs(2);
+
+/// Addresses looked up per write
+pub const MAX_ADDRESSES: usize = 5;
+
+/// Boxed future returned by [`Geocoder::geocode`]
+pub type GeocodeFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<Coordinates>, String>> + Send + 'a>>;
+
+/// A point in WGS84
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// A geocoding provider
+pub trait Geocoder: Send + Sync + 'static {
+    /// Provider name (used in logs)
+    fn name(&self) -> &'static str;
+
+    /// Look up a one-line address; `Ok(None)` if the provider has no match
+    fn geocode<'a>(&'a self, address: &'a str) -> GeocodeFuture<'a>;
+}
+
+/// Geocoder shared through request extensions (None when disabled)
+#[derive(Clone, Default)]
+pub struct Geocoding(pub Option<Arc<dyn Geocoder>>);
+
+impl Geocoding {
+    /// Geocoding through the Nominatim service at `base_url`, if set
+    pub fn nominatim(base_url: Option<&str>) -> Self {
+        Self(base_url.map(|url| Arc::new(NominatimGeocoder::new(url)) as Arc<dyn Geocoder>))
+    }
+
+    /// Add coordinates to the addresses of a Patient or Location when a
+    /// geocoder is configured; other resource types are left alone
+    pub async fn apply(&self, resource: &mut Value) {
+        let Some(ref geocoder) = self.0 else {
+            return;
+        };
+        let geocoded = resource
+            .get("resourceType")
+            .and_then(Value::as_str)
+            .is_some_and(|t| GEOCODED_TYPES.contains(&t));
+        if geocoded {
+            enrich(geocoder.as_ref(), resource).await;
+        }
+    }
+}
+
+/// Geocoder for Nominatim's `/search` API
+pub struct NominatimGeocoder {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl NominatimGeocoder {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .user_agent(concat!("fhir-server/", env!("CARGO_PKG_VERSION")))
+                .timeout(GEOCODE_BUDGET)
+                .build()
+                .unwrap_or_default(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl Geocoder for NominatimGeocoder {
+    fn name(&self) -> &'static str {
+        "nominatim"
+    }
+
+    fn geocode<'a>(&'a self, address: &'a str) -> GeocodeFuture<'a> {
+        Box::pin(async move {
+            let results: Vec<Value> = self
+                .http
+                .get(format!("{}/search", self.base_url))
+                .query(&[("q", address), ("format", "jsonv2"), ("limit", "1")])
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?
+                .json()
+                .await
+                .map_err(|e| e.to_string())?;
+
+            // Nominatim returns coordinates as strings
+            let coordinate =
+                |result: &Value, key: &str| result.get(key)?.as_str()?.parse::<f64>().ok();
+            Ok(results.first().and_then(|result| {
+                Some(Coordinates {
+                    latitude: coordinate(result, "lat")?,
+                    longitude: coordinate(result, "lon")?,
+                })
+            }))
+        })
+    }
+}
+
+/// One-line form of an Address (`line, city, state postalCode, country`);
+/// None if it has nothing to look up
+fn address_query(address: &Value) -> Option<String> {
+    let field = |key: &str| address.get(key).and_then(Value::as_str);
+    let mut parts: Vec<String> = address
+        .get("line")
+        .and_then(Value::as_array)
+        .map(|lines| {
+            lines
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    parts.extend(field("city").map(str::to_string));
+    let region: Vec<&str> = [field("state"), field("postalCode")]
+        .into_iter()
+        .flatten()
+        .collect();
+    if !region.is_empty() {
+        parts.push(region.join(" "));
+    }
+    parts.extend(field("country").map(str::to_string));
+
+    let query = parts
+        .iter()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if query.is_empty() {
+        return field("text").map(str::to_string);
+    }
+    Some(query)
+}
+
+fn has_geolocation(address: &Value) -> bool {
+    address
+        .get("extension")
+        .and_then(Value::as_array)
+        .is_some_and(|exts| exts.iter().any(|e| e["url"] == GEOLOCATION_URL))
+}
+
+fn geolocation_extension(point: Coordinates) -> Value {
+    serde_json::json!({
+        "url": GEOLOCATION_URL,
+        "extension": [
+            {"url": "latitude", "valueDecimal": point.latitude},
+            {"url": "longitude", "valueDecimal": point.longitude},
+        ],
+    })
+}
+
+/// Add coordinates to the addresses of `resource` that lack them, within
+/// [`GEOCODE_BUDGET`] and [`MAX_ADDRESSES`]
+///
+/// Handles `address` as an array (Patient, Practitioner) or a single object
+/// (Location). Returns how many addresses were geocoded.
+pub async fn enrich(geocoder: &dyn Geocoder, resource: &mut Value) -> usize {
+    let deadline = tokio::time::Instant::now() + GEOCODE_BUDGET;
+    let addresses: Vec<&mut Value> = match resource.get_mut("address") {
+        Some(Value::Array(addresses)) => addresses.iter_mut().collect(),
+        Some(address) if address.is_object() => vec![address],
+        _ => return 0,
+    };
+
+    let mut geocoded = 0;
+    let pending = addresses
+        .into_iter()
+        .filter(|address| !has_geolocation(address))
+        .filter_map(|address| Some((address_query(address)?, address)))
+        .take(MAX_ADDRESSES);
+    for (query, address) in pending {
+        let Ok(result) = tokio::time::timeout_at(deadline, geocoder.geocode(&query)).await else {
+            tracing::warn!(
+                provider = geocoder.name(),
+                "Geocoding budget exhausted; remaining addresses stored without coordinates"
+            );
+            break;
+        };
+        match result {
+            Ok(Some(point)) => {
+                let Some(obj) = address.as_object_mut() else {
+                    continue;
+                };
+                let extensions = obj
+                    .entry("extension")
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Some(extensions) = extensions.as_array_mut() {
+                    extensions.push(geolocation_extension(point));
+                    geocoded += 1;
+                }
+            }
+            Ok(None) => {
+                tracing::debug!(provider = geocoder.name(), "Address not found by geocoder");
+            }
+            Err(e) => {
+                tracing::warn!(provider = geocoder.name(), error = %e, "Geocoding failed");
+            }
+        }
+    }
+
+    metrics::counter!("fhir_addresses_geocoded_total").increment(geocoded as u64);
+    geocoded
+}
@@ -0,0 +1,113 @@
+//! Implementation guide packages
+//!
+//! `IG_PACKAGES` entries are either local paths (`.tgz` or unpacked
+//! directories) or `name#version` references. Referenced packages are
+//! downloaded from `IG_REGISTRY_URL` into `IG_CACHE_DIR` before the app is
+//! built; later startups reuse the cached tarball.
+
+use std::path::{Path, PathBuf};
+
+use fhir_core::PackageRegistry;
+
+use crate::config::Config;
+
+/// A configured package
+enum PackageSpec<'a> {
+    Path(&'a Path),
+    Registry { name: &'a str, version: &'a str },
+}
+
+impl<'a> PackageSpec<'a> {
+    fn parse(spec: &'a str) -> Self {
+        match spec.split_once('#') {
+            Some((name, version)) if !name.contains('/') && !version.is_empty() => {
+                PackageSpec::Registry { name, version }
+            }
+            _ => PackageSpec::Path(Path::new(spec)),
+        }
+    }
+}
+
+/// Location of a registry package in the cache
+fn cache_path(config: &Config, name: &str, version: &str) -> PathBuf {
+    Path::new(&config.ig_cache_dir).join(format!("{}#{}.tgz", name, version))
+}
+
+/// Download every `name#version` package that is not cached yet
+///
+/// Failures are logged; the package is then skipped by [`load_registry`].
+pub async fn prefetch_packages(config: &Config) {
+    let client = reqwest::Client::new();
+    for spec in &config.ig_packages {
+        let PackageSpec::Registry { name, version } = PackageSpec::parse(spec) else {
+            continue;
+        };
+        let path = cache_path(config, name, version);
+        if path.exists() {
+            continue;
+        }
+        match download(&client, config, name, version, &path).await {
+            Ok(bytes) => tracing::info!(package = %spec, bytes, "Downloaded IG package"),
+            Err(e) => tracing::error!(package = %spec, error = %e, "Failed to download IG package"),
+        }
+    }
+}
+
+async fn download(
+    client: &reqwest::Client,
+    config: &Config,
+    name: &str,
+    version: &str,
+    path: &Path,
+) -> Result<usize, String> {
+    let url = format!(
+        "{}/{}/{}",
+        config.ig_registry_url.trim_end_matches('/'),
+        name,
+        version
+    );
+    let body = client
+        .get(&url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Write beside the final name so a partial download is never loaded
+    tokio::fs::create_dir_all(&config.ig_cache_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    let partial = path.with_extension("tgz.part");
+    tokio::fs::write(&partial, &body)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(body.len())
+}
+
+/// Build the package registry: bundled US Core plus every configured package
+/// that is available locally
+pub fn load_registry(config: &Config) -> PackageRegistry {
+    let mut registry = PackageRegistry::with_us_core();
+    for spec in &config.ig_packages {
+        let path = match PackageSpec::parse(spec) {
+            PackageSpec::Path(path) => path.to_path_buf(),
+            PackageSpec::Registry { name, version } => cache_path(config, name, version),
+        };
+        match registry.load_path(&path) {
+            Ok(package) => tracing::info!(
+                package = %package.name,
+                version = %package.version,
+                resources = package.resources,
+                "Loaded IG package"
+            ),
+            Err(e) => tracing::error!(package = %spec, error = %e, "Failed to load IG package"),
+        }
+    }
+    registry
+}
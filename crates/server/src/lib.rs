@@ -4,18 +4,35 @@
 //! The actual binary entrypoint is in `main.rs`.
 
 mod ai;
+pub mod cdc;
 pub mod config;
 pub mod db;
 mod error;
+mod error_report;
+mod export;
+mod geocode;
+pub mod ig;
+pub mod logging;
 mod middleware;
+pub mod outbox;
 mod routes;
+mod scheduler;
+mod search_values;
+pub mod seed;
+pub mod urls;
+mod webhook;
+pub mod write_policy;
 
-use axum::{Extension, Router, middleware as axum_mw, routing::get};
+use axum::{
+    Extension, Router, middleware as axum_mw,
+    routing::{MethodRouter, get},
+};
 use deadpool_postgres::Pool;
-use tower_http::cors::{Any, CorsLayer};
+use metrics_exporter_prometheus::{Matcher, PrometheusHandle};
+use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
 
-use config::Config;
+use config::{Config, PhotoLimit, ReplacedRedirect, RouteAccess};
 use middleware::ApiKeyAuth;
 
 /// Build the full application router with all routes and middleware.
@@ -23,68 +40,275 @@ use middleware::ApiKeyAuth;
 /// Extracted from `main()` so integration tests can construct the app
 /// without binding to a TCP port.
 pub fn build_app(pool: Pool, config: &Config) -> Router {
+    build_app_with_policies(
+        pool,
+        config,
+        write_policy::WritePolicies::from_config(config),
+    )
+}
+
+/// Build the application with the given write policies in place of the
+/// configured ones, for deployments that register their own.
+pub fn build_app_with_policies(
+    pool: Pool,
+    config: &Config,
+    write_policies: write_policy::WritePolicies,
+) -> Router {
     // Create auth state
-    let auth = ApiKeyAuth::new(config.api_key.clone());
+    let auth = ApiKeyAuth::new(config.api_key.clone(), config.api_clients.clone());
 
     // Create rate limiter
     let rate_limiter = middleware::create_rate_limiter(config.rate_limit_rps);
+    let ai_limits = middleware::AiLimits::new(
+        config.ai_rate_limit_rpm,
+        config.ai_max_concurrent,
+        config.ai_max_body_bytes,
+    );
+
+    // Create webhook notifier for administrative events
+    let notifier = webhook::WebhookNotifier::new(
+        config.webhook_urls.clone(),
+        config.webhook_secret.clone(),
+        config.auth_failure_alert_threshold,
+    );
+
+    // Bulk export jobs and their output directory
+    let exports = export::ExportManager::new(
+        std::path::PathBuf::from(&config.export_dir),
+        std::time::Duration::from_secs(config.export_retention_secs),
+        config.public_base_url.as_deref(),
+    );
+
+    // Conformance resources from the bundled and configured IG packages
+    let packages = std::sync::Arc::new(ig::load_registry(config));
+
+    // Register background maintenance jobs
+    let mut scheduler = scheduler::Scheduler::new();
+    if let Some(days) = config.history_retention_days {
+        scheduler.register(scheduler::jobs::HistoryPruneJob {
+            retention_days: days,
+        });
+    }
+    scheduler.register(scheduler::jobs::DuplicateDetectionJob);
+    scheduler.register(scheduler::jobs::SearchIndexRebuildJob {
+        resource_type: "Patient",
+        batch_size: 500,
+    });
+    if let Some(batch_size) = config.encryption_rotation_batch {
+        scheduler.register(scheduler::jobs::EncryptionKeyRotationJob { batch_size });
+    }
+    if !config.backfills.is_empty() {
+        scheduler.register(scheduler::jobs::BackfillJob {
+            backfills: config.backfills.clone(),
+            batch_size: config.backfill_batch,
+        });
+    }
+    scheduler.register(scheduler::jobs::ExportCleanupJob {
+        manager: exports.clone(),
+    });
+    let scheduler_handle = scheduler.handle();
+    // Jobs and the CDC consumer run on the elected leader only
+    if config.scheduler_enabled || config.cdc_slot.is_some() {
+        scheduler_handle.spawn_election(pool.clone());
+    }
+    if config.scheduler_enabled {
+        scheduler.start(pool.clone(), notifier.clone());
+    }
+
+    // Deliver change events from the replication slot, if one is configured
+    if let (Some(slot), Some(url)) = (&config.cdc_slot, &config.notification_url) {
+        cdc::spawn_consumer(
+            pool.clone(),
+            slot.clone(),
+            std::time::Duration::from_millis(config.cdc_poll_interval_ms),
+            outbox::OutboxDelivery::new(url.clone(), config.webhook_secret.clone())
+                .with_alerts(notifier.clone()),
+            scheduler_handle.clone(),
+        );
+    }
+
+    // Deliver change notifications recorded in the outbox
+    if let Some(ref url) = config.notification_url {
+        outbox::spawn_worker(
+            pool.clone(),
+            outbox::OutboxDelivery::new(url.clone(), config.webhook_secret.clone())
+                .with_alerts(notifier.clone()),
+            std::time::Duration::from_millis(config.outbox_poll_interval_ms),
+        );
+    }
+
+    // Requests fail fast while the database keeps failing
+    let db_breaker = db::DbBreaker::new(
+        config.db_breaker_failures,
+        std::time::Duration::from_secs(config.db_breaker_open_secs),
+    );
+
+    // Address geocoding at write time (disabled unless a provider is set)
+    let geocoding = geocode::Geocoding::nominatim(config.geocoder_url.as_deref());
 
     // Create Claude client (None if ANTHROPIC_API_KEY not set)
     let claude_client: Option<ai::ClaudeClient> = config
         .anthropic_api_key
         .as_ref()
-        .map(|key| ai::ClaudeClient::new(key.clone()));
+        .map(|key| ai::ClaudeClient::new(key.clone(), &config.anthropic_base_url));
+
+    // Switched-off AI operations answer 501 and are left out of /metadata
+    let ai_operations = ai::AiOperations {
+        nl_search: config.ai_nl_search_enabled,
+        generate: config.ai_generate_enabled,
+        chat: config.ai_chat_enabled,
+        configured: claude_client.is_some(),
+    };
+
+    // Install the Prometheus metrics recorder once per process; repeated
+    // calls (e.g. in integration tests) share its handle for /metrics
+    static PROMETHEUS: std::sync::OnceLock<PrometheusHandle> = std::sync::OnceLock::new();
+    let prometheus_handle = PROMETHEUS
+        .get_or_init(|| {
+            let recorder = metrics_exporter_prometheus::PrometheusBuilder::new()
+                .set_buckets_for_metric(
+                    Matcher::Full("http_request_duration_seconds".to_string()),
+                    middleware::metrics::LATENCY_BUCKETS,
+                )
+                .expect("latency buckets are not empty")
+                .build_recorder();
+            let handle = recorder.handle();
+            let _ = metrics::set_global_recorder(recorder);
+            handle
+        })
+        .clone();
+    let exemplars = middleware::metrics::Exemplars::default();
+
+    // Operational routes are placed according to the configured route policy
+    // table; `config::POLICY_ROUTES` lists the same paths
+    let operational_routes: [(&str, MethodRouter<Pool>); 4] = [
+        ("/metadata", get(routes::metadata::get)),
+        ("/health", get(routes::health::check)),
+        ("/readyz", get(routes::health::ready)),
+        ("/metrics", get(routes::metrics::get)),
+    ];
+
+    let mut public_routes = Router::new();
+    let mut rate_limited_routes = Router::new();
+    // The default FHIR base negotiates its version; the R5 base is pinned
+    let mut protected_routes = Router::new()
+        .nest(
+            middleware::fhir_version::R5_BASE_PATH,
+            routes::fhir_routes().layer(axum_mw::from_fn_with_state(
+                Some(fhir_core::FhirVersion::R5),
+                middleware::fhir_version_middleware,
+            )),
+        )
+        .nest(
+            "/fhir",
+            routes::fhir_routes().layer(axum_mw::from_fn_with_state(
+                None,
+                middleware::fhir_version_middleware,
+            )),
+        )
+        .nest("/admin", routes::admin_routes())
+        .layer(axum_mw::from_fn(middleware::db_breaker_middleware));
+    for (path, handler) in operational_routes {
+        match config.route_access(path) {
+            RouteAccess::Public => public_routes = public_routes.route(path, handler),
+            RouteAccess::RateLimited => {
+                rate_limited_routes = rate_limited_routes.route(path, handler)
+            }
+            RouteAccess::Protected => protected_routes = protected_routes.route(path, handler),
+        }
+    }
 
     // Protected routes (require auth)
-    let protected_routes = Router::new()
-        .nest("/fhir", routes::fhir_routes())
+    let protected_routes = protected_routes
         .layer(axum_mw::from_fn(middleware::auth::auth_middleware))
         .layer(Extension(auth))
+        .layer(Extension(middleware::AdminClients(
+            config.admin_clients.clone(),
+        )))
         .layer(Extension(claude_client))
+        .layer(Extension(ai_limits))
+        .layer(Extension(ai::AiAudit {
+            capture_content: config.ai_audit_content,
+        }))
+        .layer(Extension(config.ai_output_guard))
+        .layer(Extension(ai::chatbot::ChatLimits {
+            max_tool_calls: config.ai_chat_max_tool_calls,
+            max_result_rows: config.ai_chat_max_result_rows.max(1),
+            max_result_bytes: config.ai_chat_max_result_bytes,
+            time_budget: std::time::Duration::from_millis(config.ai_chat_time_budget_ms),
+        }))
+        .layer(Extension(scheduler_handle))
+        .layer(Extension(config.narrative_policy))
+        .layer(Extension(PhotoLimit(config.photo_max_bytes)))
+        .layer(Extension(ReplacedRedirect(
+            config.redirect_replaced_patients,
+        )))
+        .layer(Extension(geocoding))
+        .layer(Extension(write_policies))
+        .layer(Extension(exports))
+        .layer(axum_mw::from_fn(middleware::rate_limit_middleware))
+        .layer(Extension(rate_limiter.clone()));
+
+    // Rate-limited routes (no auth required)
+    let rate_limited_routes = rate_limited_routes
         .layer(axum_mw::from_fn(middleware::rate_limit_middleware))
         .layer(Extension(rate_limiter));
 
-    // Install Prometheus metrics recorder.
-    // Use build_recorder() + set_global_recorder() so that repeated calls
-    // (e.g. in integration tests) don't panic — the second install is
-    // silently ignored and we still get a valid handle for /metrics.
-    let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
-    let prometheus_handle = recorder.handle();
-    let _ = metrics::set_global_recorder(recorder);
-
-    // Public routes (no auth required)
-    let public_routes = Router::new()
-        .route("/metadata", get(routes::metadata::get))
-        .route("/health", get(routes::health::check))
-        .route("/metrics", get(routes::metrics::get))
-        .layer(Extension(prometheus_handle));
-
-    // Build CORS layer
-    let cors = if config.cors_origins.iter().any(|o| o == "*") {
+    // Build CORS layer; `main` refuses to start with settings it rejects, so
+    // an app built anyway allows no cross-origin requests
+    let cors = middleware::cors_layer(config).unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Invalid CORS configuration, cross-origin requests disabled");
         CorsLayer::new()
-            .allow_origin(Any)
-            .allow_methods(Any)
-            .allow_headers(Any)
-    } else {
-        let origins: Vec<_> = config
-            .cors_origins
-            .iter()
-            .filter_map(|o| o.parse().ok())
-            .collect();
-        CorsLayer::new()
-            .allow_origin(origins)
-            .allow_methods(Any)
-            .allow_headers(Any)
-    };
+    });
 
     // Build application
     Router::new()
         .merge(public_routes)
+        .merge(rate_limited_routes)
         .merge(protected_routes)
+        .route_layer(axum_mw::from_fn(
+            middleware::metrics::matched_route_middleware,
+        ))
+        .layer(Extension(prometheus_handle))
+        .layer(Extension(exemplars.clone()))
+        .layer(Extension(packages))
+        .layer(Extension(ai_operations))
+        .layer(Extension(db_breaker))
         .with_state(pool)
         .layer(axum_mw::from_fn(middleware::audit_middleware))
+        .layer(Extension(middleware::audit::AuditCapture {
+            enabled: config.audit_capture_bodies,
+            max_bytes: config.audit_body_limit,
+            redact_fields: config.audit_redact_fields.clone(),
+        }))
+        .layer(Extension(notifier))
+        .layer(axum_mw::from_fn(middleware::error_report_middleware))
+        .layer(Extension(error_report::ErrorReporter::new(
+            config.error_report_url.clone(),
+            config.sentry_dsn.clone(),
+        )))
+        .layer(axum_mw::from_fn(middleware::request_log_middleware))
+        .layer(Extension(middleware::request_log::RequestLogSampling {
+            reads: config.request_log_sample_reads,
+            writes: config.request_log_sample_writes,
+            errors: config.request_log_sample_errors,
+        }))
+        .layer(axum_mw::from_fn(middleware::error_format_middleware))
         .layer(axum_mw::from_fn(middleware::request_id_middleware))
+        .layer(Extension(middleware::request_id::SqlRequestTags(
+            config.sql_tag_request_ids,
+        )))
         .layer(cors)
-        .layer(TraceLayer::new_for_http())
-        .layer(axum_mw::from_fn(middleware::metrics_middleware))
+        // Inside the request span, so exemplars can take its trace id
+        .layer(axum_mw::from_fn_with_state(
+            exemplars,
+            middleware::metrics_middleware,
+        ))
+        // Per-request events come from the sampled request log
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging::request_span)
+                .on_request(())
+                .on_response(()),
+        )
 }
@@ -0,0 +1,387 @@
+//! Tracing setup and runtime log level control
+//!
+//! Logs are written as JSON (default), logfmt or pretty text to stdout, or
+//! to a file rotated hourly or daily by `tracing-appender` (`LOG_FORMAT`,
+//! `LOG_FILE`, `LOG_ROTATION`, `LOG_MAX_FILES`). File writes go through a
+//! background thread, so a slow disk never blocks a request.
+//!
+//! The `EnvFilter` sits behind a reload layer so directives can be changed
+//! while the server runs (`/admin/log-level`, `SIGUSR1`). Overrides are
+//! appended to the startup filter, so `fhir_server::db=debug` raises one
+//! module without touching the rest; an optional TTL reverts them.
+//!
+//! Spans also carry an OpenTelemetry trace context, continuing the caller's
+//! W3C `traceparent` for the request span, so the current trace id can be
+//! read anywhere a request is handled (metric exemplars).
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use axum::{extract::Request, http::HeaderMap};
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use serde::Serialize;
+use tracing::{Event, Span, Subscriber, field::Field};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{InitError, RollingFileAppender, Rotation};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry,
+    fmt::{FmtContext, FormatEvent, FormatFields, format, writer::BoxMakeWriter},
+    layer::{Layered, SubscriberExt},
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+};
+
+use crate::config::{LogConfig, LogFormat, LogRotation};
+
+static LOG_LEVELS: OnceLock<LogLevels> = OnceLock::new();
+
+/// Reloadable log filter installed by [`init`]
+pub struct LogLevels {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// Filter the process started with (`RUST_LOG`)
+    default: String,
+    state: Mutex<Overrides>,
+}
+
+#[derive(Default)]
+struct Overrides {
+    directives: Option<String>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Bumped on every change so a stale TTL timer does not revert a newer one
+    generation: u64,
+}
+
+/// Current log filter, as reported by `GET /admin/log-level`
+#[derive(Debug, Serialize)]
+pub struct LogLevelStatus {
+    pub default: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overrides: Option<String>,
+    /// Effective filter: the default plus any overrides
+    pub filter: String,
+    #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+/// Install the global subscriber with a reloadable filter
+///
+/// Does nothing if a filter was already installed. When logging to a file,
+/// the returned guard flushes buffered lines when dropped, so keep it alive
+/// until the process exits.
+#[must_use]
+pub fn init(config: &LogConfig) -> Option<WorkerGuard> {
+    if LOG_LEVELS.get().is_some() {
+        return None;
+    }
+    let (writer, ansi, guard) = match &config.file {
+        Some(path) => match rolling_file(path, config.rotation, config.max_files) {
+            Ok(file) => {
+                let (writer, guard) = tracing_appender::non_blocking(file);
+                (BoxMakeWriter::new(writer), false, Some(guard))
+            }
+            Err(e) => {
+                eprintln!("Cannot open log file {}: {}; logging to stdout", path, e);
+                (BoxMakeWriter::new(std::io::stdout), true, None)
+            }
+        },
+        None => (BoxMakeWriter::new(std::io::stdout), true, None),
+    };
+
+    let filter = EnvFilter::try_new(&config.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    if tracing_subscriber::registry()
+        .with(filter)
+        .with(format_layer(config.format, writer, ansi))
+        .with(trace_context_layer())
+        .try_init()
+        .is_err()
+    {
+        return None;
+    }
+    let _ = LOG_LEVELS.set(LogLevels {
+        handle,
+        default: config.filter.clone(),
+        state: Mutex::new(Overrides::default()),
+    });
+    guard
+}
+
+/// Appender for `path`: the current period's file is `<file>.<period>`
+/// (`server.log.2026-10-16`, or `…-13` hourly), or `<file>` itself with
+/// rotation disabled; `max_files` rotated files are kept besides it
+fn rolling_file(
+    path: &str,
+    rotation: LogRotation,
+    max_files: usize,
+) -> Result<RollingFileAppender, InitError> {
+    let path = Path::new(path);
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "server.log".to_string());
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        })
+        .filename_prefix(name);
+    if max_files > 0 && rotation != LogRotation::Never {
+        builder = builder.max_log_files(max_files + 1);
+    }
+    builder.build(dir)
+}
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+fn format_layer(
+    format: LogFormat,
+    writer: BoxMakeWriter,
+    ansi: bool,
+) -> Box<dyn Layer<Filtered> + Send + Sync> {
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Json => layer.json().boxed(),
+        LogFormat::Logfmt => layer.event_format(Logfmt).with_ansi(false).boxed(),
+        LogFormat::Pretty => layer.pretty().with_ansi(ansi).boxed(),
+    }
+}
+
+/// Layer giving every enabled span an OpenTelemetry trace context; nothing
+/// is exported, the ids only tie metrics to the requests behind them
+pub fn trace_context_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let tracer = SdkTracerProvider::builder().build().tracer("fhir-server");
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Span wrapping each request, at `info` so the default filter keeps it;
+/// its trace continues the one in the request's `traceparent` header, if any
+pub(crate) fn request_span(request: &Request) -> Span {
+    let span = tracing::info_span!("request", method = %request.method());
+    let parent = TraceContextPropagator::new().extract(&TraceHeaders(request.headers()));
+    // Fails only when no trace context layer is installed
+    let _ = span.set_parent(parent);
+    span
+}
+
+/// Request headers as a source of W3C trace context
+struct TraceHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for TraceHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// The installed log filter, if [`init`] has run
+pub fn levels() -> Option<&'static LogLevels> {
+    LOG_LEVELS.get()
+}
+
+impl LogLevels {
+    fn effective(&self, overrides: Option<&str>) -> String {
+        match overrides {
+            Some(o) if !self.default.is_empty() => format!("{},{}", self.default, o),
+            Some(o) => o.to_string(),
+            None => self.default.clone(),
+        }
+    }
+
+    pub fn status(&self) -> LogLevelStatus {
+        let state = self.state.lock().unwrap();
+        LogLevelStatus {
+            default: self.default.clone(),
+            overrides: state.directives.clone(),
+            filter: self.effective(state.directives.as_deref()),
+            expires_at: state.expires_at.map(|t| t.to_rfc3339()),
+        }
+    }
+
+    /// Apply `directives` on top of the default filter, reverting after
+    /// `ttl` if one is given
+    pub fn set(
+        &'static self,
+        directives: &str,
+        ttl: Option<std::time::Duration>,
+    ) -> Result<LogLevelStatus, String> {
+        let filter = EnvFilter::try_new(self.effective(Some(directives)))
+            .map_err(|e| format!("Invalid log directives: {}", e))?;
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            self.handle.reload(filter).map_err(|e| e.to_string())?;
+            state.generation += 1;
+            state.directives = Some(directives.to_string());
+            state.expires_at = ttl.and_then(|ttl| {
+                chrono::Duration::from_std(ttl)
+                    .ok()
+                    .map(|d| chrono::Utc::now() + d)
+            });
+            state.generation
+        };
+        tracing::warn!(directives = %directives, ttl_secs = ttl.map(|t| t.as_secs()), "Log level overridden");
+
+        if let Some(ttl) = ttl {
+            tokio::spawn(async move {
+                tokio::time::sleep(ttl).await;
+                if self.state.lock().unwrap().generation == generation {
+                    let _ = self.reset();
+                }
+            });
+        }
+        Ok(self.status())
+    }
+
+    /// Drop all overrides and return to the default filter
+    pub fn reset(&self) -> Result<LogLevelStatus, String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let filter =
+                EnvFilter::try_new(&self.default).unwrap_or_else(|_| EnvFilter::new("info"));
+            self.handle.reload(filter).map_err(|e| e.to_string())?;
+            state.generation += 1;
+            state.directives = None;
+            state.expires_at = None;
+        }
+        tracing::warn!("Log level overrides cleared");
+        Ok(self.status())
+    }
+
+    fn is_overridden(&self) -> bool {
+        self.state.lock().unwrap().directives.is_some()
+    }
+}
+
+/// Toggle `directives` on each `SIGUSR1`: the first signal applies them, the
+/// next one restores the default filter
+#[cfg(unix)]
+pub fn spawn_signal_handler(directives: String) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let Some(levels) = levels() else {
+        return;
+    };
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to install SIGUSR1 handler");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let result = if levels.is_overridden() {
+                levels.reset()
+            } else {
+                levels.set(&directives, None)
+            };
+            if let Err(e) = result {
+                tracing::error!(error = %e, "SIGUSR1 log level change failed");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_signal_handler(_directives: String) {}
+
+/// logfmt event formatter: `ts=… level=… target=… msg=… key=value…`
+struct Logfmt;
+
+/// Quote a logfmt value if it contains spaces, quotes or `=`
+fn logfmt_value(out: &mut String, value: &str) {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c.is_control())
+    {
+        out.push_str(value);
+        return;
+    }
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Collects event fields as logfmt pairs, keeping the message apart
+#[derive(Default)]
+struct LogfmtFields {
+    message: Option<String>,
+    pairs: String,
+}
+
+impl tracing::field::Visit for LogfmtFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_string());
+        } else {
+            let _ = write!(self.pairs, " {}=", field.name());
+            logfmt_value(&mut self.pairs, value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Logfmt
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        let mut fields = LogfmtFields::default();
+        event.record(&mut fields);
+
+        let mut line = format!(
+            "ts={} level={} target=",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            meta.level().as_str().to_ascii_lowercase()
+        );
+        logfmt_value(&mut line, meta.target());
+        if let Some(scope) = ctx.event_scope() {
+            let spans: Vec<&str> = scope.from_root().map(|span| span.name()).collect();
+            line.push_str(" span=");
+            logfmt_value(&mut line, &spans.join(":"));
+        }
+        if let Some(message) = &fields.message {
+            line.push_str(" msg=");
+            logfmt_value(&mut line, message);
+        }
+        line.push_str(&fields.pairs);
+        writeln!(writer, "{}", line)
+    }
+}
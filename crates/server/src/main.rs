@@ -1,40 +1,108 @@
 //! fhir-server: FHIR R4 HTTP Server binary entrypoint.
 
 use std::net::SocketAddr;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use fhir_server::config::Config;
+use fhir_server::config::{Config, LogConfig};
 
 #[tokio::main]
 async fn main() {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "info,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer().json())
-        .init();
+    // Initialize tracing; the guard flushes file logs on exit
+    let _log_guard = fhir_server::logging::init(&LogConfig::from_env());
 
     // Load configuration
     let config = Config::from_env();
+    if let Err(e) = config.validate() {
+        tracing::error!(error = %e, "Invalid configuration");
+        std::process::exit(1);
+    }
+    fhir_server::logging::spawn_signal_handler(config.log_signal_directives.clone());
 
     // Create database pool
-    let pool = fhir_server::db::create_pool(&config.database_url)
+    let pool = fhir_server::db::create_pool(&config.database_url, config.statement_timeout_ms)
         .await
         .expect("Failed to create database pool");
 
+    // Prepare hot statements before taking traffic, on at least the
+    // connections that are to stay idle
+    let warmup_connections = config.pool_warmup_connections.max(config.pool_min_idle);
+    if warmup_connections > 0 {
+        let started = std::time::Instant::now();
+        let version = fhir_server::db::warm_up(&pool, warmup_connections)
+            .await
+            .expect("Database warm-up failed");
+        if version != fhir_server::db::EXPECTED_EXT_VERSION {
+            tracing::warn!(
+                found = %version,
+                expected = fhir_server::db::EXPECTED_EXT_VERSION,
+                "Extension version mismatch"
+            );
+        }
+        tracing::info!(
+            connections = warmup_connections,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            extension = %version,
+            "Connection pool warmed up"
+        );
+    }
+    if config.pool_min_idle > 0 {
+        fhir_server::db::spawn_min_idle(pool.clone(), config.pool_min_idle);
+    }
+
+    // Record which extension functions are available
+    match fhir_server::db::negotiate_features(&pool).await {
+        Ok(features) => tracing::info!(
+            version = ?features.version,
+            functions = features.functions.len(),
+            "Extension features negotiated"
+        ),
+        Err(e) => tracing::warn!(error = ?e, "Extension feature negotiation failed"),
+    }
+
+    // Verify tables, indexes and functions, logging repair hints
+    match fhir_server::db::readiness(&pool).await {
+        Ok(report) => fhir_server::db::log_report(&report),
+        Err(e) => tracing::error!(error = ?e, "Schema self-check failed to run"),
+    }
+
+    // Provision fixtures for demo and test environments
+    if let Some(ref dir) = config.seed_dir {
+        match fhir_server::seed::load_dir(&pool, std::path::Path::new(dir)).await {
+            Ok(report) => tracing::info!(
+                dir = %dir,
+                created = report.created,
+                existing = report.existing,
+                skipped = report.skipped,
+                "Seed data loaded"
+            ),
+            Err(e) => tracing::error!(dir = %dir, error = ?e, "Seed data loading failed"),
+        }
+    }
+
     // Log startup info
-    if config.api_key.is_some() {
+    if config.api_key.is_some() || !config.api_clients.is_empty() {
         tracing::info!("API key authentication enabled");
     } else {
-        tracing::warn!("API key authentication disabled (no API_KEY env var)");
+        tracing::warn!("API key authentication disabled (no API_KEY or API_CLIENTS env var)");
     }
     if config.anthropic_api_key.is_some() {
         tracing::info!("Anthropic API key configured, AI features enabled");
     } else {
         tracing::warn!("ANTHROPIC_API_KEY not set, AI features disabled");
     }
+    // reqwest reads the proxy variables itself; the URL may carry credentials
+    if ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+    {
+        tracing::info!("Outbound HTTP requests use the configured egress proxy");
+    }
     tracing::info!("Rate limiting: {} requests/second", config.rate_limit_rps);
+    for policy in &config.route_policies {
+        tracing::info!(path = %policy.path, access = ?policy.access, "Route policy override");
+    }
+
+    // Fetch registry packages before building the app (which loads them)
+    fhir_server::ig::prefetch_packages(&config).await;
 
     // Build application
     let app = fhir_server::build_app(pool, &config);
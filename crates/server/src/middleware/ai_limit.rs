@@ -0,0 +1,116 @@
+//! Stricter limits for the AI endpoints (`$generate`, `$chat`, `$nl-search`)
+//!
+//! A single AI request can cost several upstream LLM calls, so these routes
+//! get their own per-key request rate, per-key concurrency cap and payload
+//! size limit on top of the global rate limit.
+
+use axum::{body::Body, extract::Request, http::StatusCode, middleware::Next, response::Response};
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use fhir_core::OperationOutcome;
+
+use crate::webhook::WebhookNotifier;
+
+/// Key used for requests without an `X-API-Key` header
+const ANONYMOUS: &str = "anonymous";
+
+/// Shared limits for the AI routes
+#[derive(Clone)]
+pub struct AiLimits {
+    limiter: Arc<DefaultKeyedRateLimiter<String>>,
+    in_flight: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+    requests_per_minute: u32,
+    max_concurrent: usize,
+    max_body_bytes: usize,
+}
+
+impl AiLimits {
+    /// Limits allowing `requests_per_minute` and `max_concurrent` requests of
+    /// at most `max_body_bytes` per API key
+    pub fn new(requests_per_minute: u32, max_concurrent: usize, max_body_bytes: usize) -> Self {
+        let quota = Quota::per_minute(NonZeroU32::new(requests_per_minute.max(1)).unwrap());
+        Self {
+            limiter: Arc::new(RateLimiter::keyed(quota)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            requests_per_minute: requests_per_minute.max(1),
+            max_concurrent: max_concurrent.max(1),
+            max_body_bytes,
+        }
+    }
+
+    /// A permit for one more concurrent request for `key`, if under the cap
+    ///
+    /// Idle entries are pruned and the permit is taken under the same lock,
+    /// so an entry is never dropped between being looked up and being used.
+    fn acquire(&self, key: &str) -> Option<OwnedSemaphorePermit> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Drop idle entries so the map does not grow with every key seen
+        in_flight.retain(|_, s| s.available_permits() < self.max_concurrent);
+        in_flight
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_concurrent)))
+            .clone()
+            .try_acquire_owned()
+            .ok()
+    }
+}
+
+fn rejection(status: StatusCode, issue: fhir_core::IssueType, message: &str) -> Response {
+    let outcome = OperationOutcome::error(issue, message);
+    crate::error::outcome_response(status, outcome)
+}
+
+/// Enforce the AI limits for the request's API key
+pub async fn ai_limit_middleware(request: Request<Body>, next: Next) -> Response {
+    let Some(limits) = request.extensions().get::<AiLimits>().cloned() else {
+        return next.run(request).await;
+    };
+    let key = request
+        .headers()
+        .get("X-API-Key")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(ANONYMOUS)
+        .to_string();
+
+    if limits.limiter.check_key(&key).is_err() {
+        metrics::counter!("fhir_ai_requests_rejected_total", "reason" => "rate").increment(1);
+        if let Some(notifier) = request.extensions().get::<WebhookNotifier>() {
+            notifier.record_budget_exhausted(
+                "ai_requests_per_minute",
+                u64::from(limits.requests_per_minute),
+            );
+        }
+        return rejection(
+            StatusCode::TOO_MANY_REQUESTS,
+            fhir_core::IssueType::Throttled,
+            "AI request rate limit exceeded. Please try again later.",
+        );
+    }
+
+    let Some(_permit) = limits.acquire(&key) else {
+        metrics::counter!("fhir_ai_requests_rejected_total", "reason" => "concurrency")
+            .increment(1);
+        return rejection(
+            StatusCode::TOO_MANY_REQUESTS,
+            fhir_core::IssueType::Throttled,
+            "Too many concurrent AI requests for this API key",
+        );
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, limits.max_body_bytes).await else {
+        metrics::counter!("fhir_ai_requests_rejected_total", "reason" => "size").increment(1);
+        return rejection(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            fhir_core::IssueType::TooLong,
+            "AI request body is too large",
+        );
+    };
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
@@ -0,0 +1,28 @@
+//! Per-operation AI switches (`AI_ENABLED`, `AI_*_ENABLED`)
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::ai::AiOperations;
+use crate::error::AppError;
+
+/// Middleware answering `501` when the AI operation `code` is switched off
+pub async fn require_ai_operation_middleware(
+    State(code): State<&'static str>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let enabled = request
+        .extensions()
+        .get::<AiOperations>()
+        .is_some_and(|operations| operations.enabled(code));
+    match enabled {
+        true => next.run(request).await,
+        false => AppError::NotImplemented(format!("${} is disabled on this server", code))
+            .into_response(),
+    }
+}
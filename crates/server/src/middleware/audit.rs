@@ -1,10 +1,81 @@
 //! Audit logging middleware for mutations
 
-use axum::{body::Body, extract::Request, http::Method, middleware::Next, response::Response};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value as JsonValue;
 
 use super::request_id::RequestId;
 
+/// Largest request body buffered for capture (matches axum's default body
+/// limit, so capture never admits a body the handlers would reject)
+const MAX_CAPTURE_READ: usize = 2 * 1024 * 1024;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Request body capture for the audit log
+///
+/// Installed as a request extension; without it (or when disabled) only the
+/// method, path and status of mutations are logged.
+#[derive(Debug, Clone, Default)]
+pub struct AuditCapture {
+    pub enabled: bool,
+    /// Captured bodies are truncated to this many bytes
+    pub max_bytes: usize,
+    /// JSON fields whose values are replaced before capture (any depth)
+    pub redact_fields: Vec<String>,
+}
+
+impl AuditCapture {
+    /// Redacted, size-limited rendering of a request body, and whether it
+    /// was truncated
+    fn render(&self, bytes: &[u8]) -> (String, bool) {
+        if bytes.is_empty() {
+            return (String::new(), false);
+        }
+        // Only JSON can be redacted, so anything else is summarized
+        let Ok(mut json) = serde_json::from_slice::<JsonValue>(bytes) else {
+            return (format!("<{} bytes, not JSON>", bytes.len()), false);
+        };
+        self.redact(&mut json);
+
+        let mut text = json.to_string();
+        if text.len() <= self.max_bytes {
+            return (text, false);
+        }
+        let mut end = self.max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        (text, true)
+    }
+
+    fn redact(&self, value: &mut JsonValue) {
+        match value {
+            JsonValue::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.redact_fields.iter().any(|f| f == key) {
+                        *value = JsonValue::String(REDACTED.to_string());
+                    } else {
+                        self.redact(value);
+                    }
+                }
+            }
+            JsonValue::Array(items) => items.iter_mut().for_each(|item| self.redact(item)),
+            _ => {}
+        }
+    }
+}
+
 /// Middleware to log mutations (POST, PUT, DELETE) for audit purposes
+///
+/// With [`AuditCapture`] enabled, the redacted request body and the resource
+/// location and version from the response are logged with the event.
 pub async fn audit_middleware(request: Request<Body>, next: Next) -> Response {
     let method = request.method().clone();
     let uri = request.uri().path().to_string();
@@ -14,21 +85,78 @@ pub async fn audit_middleware(request: Request<Body>, next: Next) -> Response {
         .map(|r| r.0.clone())
         .unwrap_or_else(|| "unknown".to_string());
 
+    let is_mutation = matches!(
+        method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    let capture = request
+        .extensions()
+        .get::<AuditCapture>()
+        .filter(|c| c.enabled && is_mutation)
+        .cloned();
+
+    // Buffer the body so it can be both captured and handed on
+    let (request, captured) = match capture {
+        Some(capture) => {
+            let (parts, body) = request.into_parts();
+            let bytes = match axum::body::to_bytes(body, MAX_CAPTURE_READ).await {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    let outcome = fhir_core::OperationOutcome::error(
+                        fhir_core::IssueType::TooLong,
+                        "Request body is too large",
+                    );
+                    return crate::error::outcome_response(StatusCode::PAYLOAD_TOO_LARGE, outcome);
+                }
+            };
+            let captured = capture.render(&bytes);
+            (
+                Request::from_parts(parts, Body::from(bytes)),
+                Some(captured),
+            )
+        }
+        None => (request, None),
+    };
+
     // Run the request first to get the response status
     let response = next.run(request).await;
 
     // Only log mutations (POST, PUT, DELETE)
-    if matches!(method, Method::POST | Method::PUT | Method::DELETE) {
+    if is_mutation {
         let status = response.status().as_u16();
 
-        tracing::info!(
-            target: "audit",
-            request_id = %request_id,
-            method = %method,
-            path = %uri,
-            status = %status,
-            "Mutation request"
-        );
+        match captured {
+            Some((body, truncated)) => {
+                let header_value = |name: header::HeaderName| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("")
+                        .to_string()
+                };
+                tracing::info!(
+                    target: "audit",
+                    request_id = %request_id,
+                    method = %method,
+                    path = %uri,
+                    status = %status,
+                    request_body = %body,
+                    body_truncated = truncated,
+                    resource_location = %header_value(header::LOCATION),
+                    resource_version = %header_value(header::ETAG),
+                    "Mutation request"
+                );
+            }
+            None => tracing::info!(
+                target: "audit",
+                request_id = %request_id,
+                method = %method,
+                path = %uri,
+                status = %status,
+                "Mutation request"
+            ),
+        }
     }
 
     response
@@ -1,71 +1,153 @@
 //! API Key authentication middleware
+//!
+//! Callers authenticate with the shared `API_KEY` or a per-client key from
+//! `API_CLIENTS`. The matching client name becomes the request's
+//! [`Principal`] and the owner of the checkout locks its writes may pass.
+//! Administrative routes additionally require a principal listed in
+//! `ADMIN_CLIENTS`.
 
 use axum::{
-    Json,
     body::Body,
     extract::Request,
     http::{HeaderMap, StatusCode},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::Response,
 };
 use fhir_core::OperationOutcome;
 
+use crate::webhook::WebhookNotifier;
+
+/// Header naming the lock owner when authentication is disabled
+pub const LOCK_OWNER_HEADER: &str = "X-Lock-Owner";
+
+/// Principal of the shared `API_KEY`
+pub const SHARED_KEY_PRINCIPAL: &str = "default";
+
+/// The authenticated client of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal(pub String);
+
+/// Principals allowed on administrative routes
+#[derive(Debug, Clone, Default)]
+pub struct AdminClients(pub Vec<String>);
+
 /// API Key authentication state
 #[derive(Clone)]
 pub struct ApiKeyAuth {
-    api_key: Option<String>,
+    /// (principal, key) pairs
+    keys: Vec<(String, String)>,
 }
 
 impl ApiKeyAuth {
-    pub fn new(api_key: Option<String>) -> Self {
-        Self { api_key }
+    pub fn new(api_key: Option<String>, clients: Vec<(String, String)>) -> Self {
+        let shared = api_key.map(|key| (SHARED_KEY_PRINCIPAL.to_string(), key));
+        Self {
+            keys: shared.into_iter().chain(clients).collect(),
+        }
     }
 
-    /// Check if authentication is required and valid
-    pub fn validate(&self, headers: &HeaderMap) -> Result<(), Box<Response>> {
+    /// Check if authentication is required and valid, returning the caller's
+    /// principal (None if authentication is disabled)
+    pub fn validate(&self, headers: &HeaderMap) -> Result<Option<Principal>, Box<Response>> {
         // If no API key configured, auth is disabled
-        let Some(ref expected_key) = self.api_key else {
-            return Ok(());
-        };
+        if self.keys.is_empty() {
+            return Ok(None);
+        }
 
         // Get the X-API-Key header
         let provided_key = headers.get("X-API-Key").and_then(|v| v.to_str().ok());
+        let principal = provided_key.and_then(|provided| {
+            self.keys
+                .iter()
+                .find(|(_, key)| key == provided)
+                .map(|(name, _)| Principal(name.clone()))
+        });
 
-        match provided_key {
-            Some(key) if key == expected_key => Ok(()),
-            Some(_) => {
+        match (provided_key, principal) {
+            (_, Some(principal)) => Ok(Some(principal)),
+            (Some(_), None) => {
                 let outcome =
                     OperationOutcome::error(fhir_core::IssueType::Security, "Invalid API key");
-                Err(Box::new(
-                    (StatusCode::UNAUTHORIZED, Json(outcome)).into_response(),
-                ))
+                Err(Box::new(crate::error::outcome_response(
+                    StatusCode::UNAUTHORIZED,
+                    outcome,
+                )))
             }
-            None => {
+            (None, None) => {
                 let outcome = OperationOutcome::error(
                     fhir_core::IssueType::Security,
                     "Missing X-API-Key header",
                 );
-                Err(Box::new(
-                    (StatusCode::UNAUTHORIZED, Json(outcome)).into_response(),
-                ))
+                Err(Box::new(crate::error::outcome_response(
+                    StatusCode::UNAUTHORIZED,
+                    outcome,
+                )))
             }
         }
     }
 }
 
 /// Middleware function for API key authentication
-pub async fn auth_middleware(headers: HeaderMap, request: Request<Body>, next: Next) -> Response {
+///
+/// Writes of the request pass checkout locks held by its principal, or by
+/// the `X-Lock-Owner` header if authentication is disabled.
+pub async fn auth_middleware(
+    headers: HeaderMap,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
     // Get auth state from request extensions
     let auth = request
         .extensions()
         .get::<ApiKeyAuth>()
         .cloned()
-        .unwrap_or_else(|| ApiKeyAuth::new(None));
+        .unwrap_or_else(|| ApiKeyAuth::new(None, Vec::new()));
 
     // Validate API key
-    if let Err(response) = auth.validate(&headers) {
-        return *response;
+    let principal = match auth.validate(&headers) {
+        Ok(principal) => principal,
+        Err(response) => {
+            if let Some(notifier) = request.extensions().get::<WebhookNotifier>() {
+                notifier.record_auth_failure();
+            }
+            return *response;
+        }
+    };
+
+    let lock_owner = match principal {
+        Some(Principal(ref name)) => Some(name.clone()),
+        None => headers
+            .get(LOCK_OWNER_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+    };
+    if let Some(principal) = principal {
+        request.extensions_mut().insert(principal);
     }
+    match lock_owner {
+        Some(owner) => crate::db::with_lock_owner(owner, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
 
+/// Middleware answering `403 Forbidden` unless the caller is an admin client.
+///
+/// Runs inside [`auth_middleware`]; with authentication disabled there is no
+/// principal and every caller is let through.
+pub async fn require_admin_middleware(request: Request<Body>, next: Next) -> Response {
+    let allowed = match request.extensions().get::<Principal>() {
+        Some(Principal(name)) => request
+            .extensions()
+            .get::<AdminClients>()
+            .is_some_and(|admins| admins.0.contains(name)),
+        None => true,
+    };
+    if !allowed {
+        let outcome = OperationOutcome::error(
+            fhir_core::IssueType::Forbidden,
+            "This route is restricted to admin clients",
+        );
+        return crate::error::outcome_response(StatusCode::FORBIDDEN, outcome);
+    }
     next.run(request).await
 }
@@ -0,0 +1,148 @@
+//! CORS layer construction from configuration
+
+use std::fmt;
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+
+use crate::config::Config;
+
+/// A CORS configuration the server refuses to start with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsConfigError {
+    /// `CORS_ORIGINS=*` together with `CORS_ALLOW_CREDENTIALS=true`, which
+    /// would let any site make credentialed requests
+    WildcardWithCredentials,
+    /// Origins, methods or headers that do not parse
+    Invalid {
+        kind: &'static str,
+        values: Vec<String>,
+    },
+}
+
+impl fmt::Display for CorsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorsConfigError::WildcardWithCredentials => write!(
+                f,
+                "CORS_ORIGINS=* cannot be combined with CORS_ALLOW_CREDENTIALS; list the allowed origins"
+            ),
+            CorsConfigError::Invalid { kind, values } => {
+                write!(
+                    f,
+                    "Invalid CORS {} configuration: {}",
+                    kind,
+                    values.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CorsConfigError {}
+
+/// Build the CORS layer from configuration.
+///
+/// Invalid origins, methods or headers are an error rather than being
+/// silently dropped from the allowlist, as is a wildcard origin with
+/// credentials.
+pub fn cors_layer(config: &Config) -> Result<CorsLayer, CorsConfigError> {
+    let wildcard = |values: &[String]| values.iter().any(|v| v == "*");
+    let credentials = config.cors_allow_credentials;
+
+    let origin = if wildcard(&config.cors_origins) {
+        if credentials {
+            return Err(CorsConfigError::WildcardWithCredentials);
+        }
+        AllowOrigin::from(Any)
+    } else {
+        AllowOrigin::list(parse_all::<HeaderValue>(
+            "origin",
+            &config.cors_origins,
+            is_valid_origin,
+        )?)
+    };
+
+    // Browsers reject `*` methods and headers when credentials are allowed,
+    // so mirror the request instead; the origin allowlist still applies
+    let methods = if wildcard(&config.cors_allow_methods) {
+        if credentials {
+            AllowMethods::mirror_request()
+        } else {
+            AllowMethods::from(Any)
+        }
+    } else {
+        AllowMethods::list(parse_all::<Method>(
+            "method",
+            &config.cors_allow_methods,
+            |_| true,
+        )?)
+    };
+
+    let headers = if wildcard(&config.cors_allow_headers) {
+        if credentials {
+            AllowHeaders::mirror_request()
+        } else {
+            AllowHeaders::from(Any)
+        }
+    } else {
+        AllowHeaders::list(parse_all::<HeaderName>(
+            "header",
+            &config.cors_allow_headers,
+            |_| true,
+        )?)
+    };
+
+    let mut cors = CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(credentials)
+        .expose_headers([
+            header::ETAG,
+            header::LOCATION,
+            // Lowercase form of `REQUEST_ID_HEADER` (required by `from_static`)
+            HeaderName::from_static("x-request-id"),
+        ]);
+
+    if let Some(secs) = config.cors_max_age_secs {
+        cors = cors.max_age(Duration::from_secs(secs));
+    }
+
+    Ok(cors)
+}
+
+/// Parse every configured value, rejecting the lot if any is invalid
+fn parse_all<T: std::str::FromStr>(
+    kind: &'static str,
+    values: &[String],
+    accept: impl Fn(&str) -> bool,
+) -> Result<Vec<T>, CorsConfigError> {
+    let mut parsed = Vec::new();
+    let mut invalid = Vec::new();
+
+    for value in values {
+        match value.parse::<T>() {
+            Ok(v) if accept(value) => parsed.push(v),
+            _ => invalid.push(value.clone()),
+        }
+    }
+
+    if !invalid.is_empty() {
+        return Err(CorsConfigError::Invalid {
+            kind,
+            values: invalid,
+        });
+    }
+
+    Ok(parsed)
+}
+
+/// An origin is `scheme://host[:port]` with no path, query or trailing slash
+fn is_valid_origin(origin: &str) -> bool {
+    let Some((scheme, rest)) = origin.split_once("://") else {
+        return false;
+    };
+    matches!(scheme, "http" | "https") && !rest.is_empty() && !rest.contains(['/', '?', '#', ' '])
+}
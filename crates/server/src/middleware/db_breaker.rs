@@ -0,0 +1,41 @@
+//! Fast failure while the database circuit breaker is open
+//!
+//! Requests to the FHIR and admin routes pass through
+//! [`db_breaker_middleware`], which answers `503 Service Unavailable` with
+//! `Retry-After` while the [`DbBreaker`] is open and otherwise counts the
+//! request's connection checkouts against it.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use fhir_core::OperationOutcome;
+
+use crate::db::DbBreaker;
+
+/// Middleware turning requests away while the database breaker is open
+pub async fn db_breaker_middleware(request: Request<Body>, next: Next) -> Response {
+    let Some(breaker) = request.extensions().get::<DbBreaker>().cloned() else {
+        return next.run(request).await;
+    };
+
+    if let Err(retry_after) = breaker.admit() {
+        metrics::counter!("fhir_db_breaker_rejections_total").increment(1);
+        let outcome = OperationOutcome::error(
+            fhir_core::IssueType::Transient,
+            "The database is unavailable. Please try again later.",
+        );
+        let mut response = crate::error::outcome_response(StatusCode::SERVICE_UNAVAILABLE, outcome);
+        // Rounded up so clients never come back before the trial is allowed
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        return response;
+    }
+
+    breaker.scope(next.run(request)).await
+}
@@ -0,0 +1,33 @@
+//! Error response format negotiation
+//!
+//! Picks the [`ErrorFormat`] for the request from its `Accept` header, so
+//! that errors raised by any later layer or handler come back as XML or
+//! NDJSON when the client asked for that instead of JSON.
+
+use axum::{body::Body, extract::Request, http::header, middleware::Next, response::Response};
+
+use super::xml::{is_json, is_xml, media_types};
+use crate::error::ErrorFormat;
+
+/// MIME types of FHIR NDJSON
+const NDJSON_CONTENT_TYPES: &[&str] = &["application/fhir+ndjson", "application/ndjson"];
+
+/// Middleware running the request with its negotiated [`ErrorFormat`]
+pub async fn error_format_middleware(request: Request<Body>, next: Next) -> Response {
+    let accept = media_types(request.headers(), header::ACCEPT);
+    // JSON wins whenever it is acceptable, as for successful responses
+    let format = if accept.iter().any(|m| is_json(m)) {
+        ErrorFormat::Json
+    } else if accept.iter().any(|m| is_xml(m)) {
+        ErrorFormat::Xml
+    } else if accept.iter().any(|m| {
+        NDJSON_CONTENT_TYPES
+            .iter()
+            .any(|ndjson| m.eq_ignore_ascii_case(ndjson))
+    }) {
+        ErrorFormat::Ndjson
+    } else {
+        ErrorFormat::Json
+    };
+    format.scope(next.run(request)).await
+}
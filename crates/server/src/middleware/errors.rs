@@ -0,0 +1,66 @@
+//! Panic catching and error reporting middleware
+
+use std::panic::AssertUnwindSafe;
+
+use axum::{
+    body::Body,
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::FutureExt;
+
+use super::request_id::RequestId;
+use crate::error::{AppError, InternalErrorMessage};
+use crate::error_report::{ErrorKind, ErrorReport, ErrorReporter};
+
+/// Middleware that turns handler panics into a 500 OperationOutcome and
+/// reports panics and internal errors to the configured [`ErrorReporter`]
+pub async fn error_report_middleware(request: Request<Body>, next: Next) -> Response {
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|r| r.0.clone())
+        .unwrap_or_else(|| "unknown".to_string());
+    let reporter = request.extensions().get::<ErrorReporter>().cloned();
+
+    let (response, kind, message) = match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+        Ok(response) => {
+            let Some(InternalErrorMessage(message)) =
+                response.extensions().get::<InternalErrorMessage>().cloned()
+            else {
+                return response;
+            };
+            (response, ErrorKind::InternalError, message)
+        }
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<dyn Any>".to_string());
+            tracing::error!(
+                request_id = %request_id,
+                method = %method,
+                path = %path,
+                panic = %message,
+                "Request handler panicked"
+            );
+            let response = AppError::Internal("Internal server error".to_string()).into_response();
+            (response, ErrorKind::Panic, message)
+        }
+    };
+
+    if let Some(reporter) = reporter {
+        reporter.report(ErrorReport::new(
+            kind,
+            &message,
+            &request_id,
+            &method,
+            &path,
+        ));
+    }
+    response
+}
@@ -0,0 +1,24 @@
+//! Route gating on extension functions
+//!
+//! Routes that call a function a newer extension introduced are layered with
+//! [`require_function_middleware`], which answers `501 Not Implemented` when
+//! the negotiated extension lacks it.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Middleware rejecting the request unless the extension provides `function`
+pub async fn require_function_middleware(
+    State(function): State<&'static str>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    match crate::db::require(function) {
+        Ok(()) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
@@ -0,0 +1,75 @@
+//! FHIR version negotiation
+//!
+//! The version comes from the mount point (`/fhir/R5`) or, on the default
+//! `/fhir` base, from the `fhirVersion` parameter of `Content-Type` or
+//! `Accept`. It is stored in request extensions for handlers and echoed in the
+//! response `Content-Type`.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use fhir_core::FhirVersion;
+
+use crate::error::AppError;
+
+/// Base path of the R5 endpoint
+pub const R5_BASE_PATH: &str = "/fhir/R5";
+
+/// Base path under which resources of a version are served
+pub fn base_path(version: FhirVersion) -> &'static str {
+    match version {
+        FhirVersion::R4B => "/fhir",
+        FhirVersion::R5 => R5_BASE_PATH,
+    }
+}
+
+/// Middleware resolving the FHIR version of a request.
+///
+/// `fixed` is the version pinned by the mount point, if any.
+pub async fn fhir_version_middleware(
+    State(fixed): State<Option<FhirVersion>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let version = fixed.unwrap_or_else(|| {
+        [header::CONTENT_TYPE, header::ACCEPT]
+            .iter()
+            .filter_map(|name| request.headers().get(name))
+            .filter_map(|v| v.to_str().ok())
+            .find_map(FhirVersion::from_mime)
+            .unwrap_or_default()
+    });
+
+    if !version.is_supported() {
+        return AppError::BadRequest(format!(
+            "FHIR version {} is not supported by this server",
+            version.full()
+        ))
+        .into_response();
+    }
+
+    request.extensions_mut().insert(version);
+    let mut response = next.run(request).await;
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+    if is_json {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!(
+                "application/fhir+json; fhirVersion={}",
+                version.code()
+            ))
+            .unwrap(),
+        );
+    }
+
+    response
+}
@@ -1,51 +1,199 @@
 //! Prometheus metrics collection middleware
 //!
 //! Records `http_requests_total` (counter) and `http_request_duration_seconds`
-//! (histogram) for every request, with method/path/status labels.
-
-use axum::{extract::Request, middleware::Next, response::Response};
-use std::time::Instant;
-
-/// Normalize request paths to avoid high-cardinality labels.
-/// Replaces UUID segments with `:id` so all per-resource requests share one label.
-fn normalize_path(path: &str) -> String {
-    path.split('/')
-        .map(|seg| {
-            if uuid::Uuid::try_parse(seg).is_ok() {
-                ":id"
-            } else {
-                seg
+//! (histogram) for every request, labelled by method and the route template
+//! the router matched (`/fhir/Patient/{id}`), and the counter also by status.
+//! Templates keep label cardinality bounded whatever the ids look like
+//! (UUIDs, client-assigned ids, version ids); the requested path itself only
+//! appears in the request's tracing span. Each latency bucket keeps the trace
+//! id of the latest request that fell into it as an exemplar, so a slow
+//! bucket leads straight to the trace of a request that was slow; exemplars
+//! are shown when `/metrics` is scraped in OpenMetrics format.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label of requests no route matched
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Route template a request matched, carried back on its response
+#[derive(Clone, Debug)]
+pub struct MatchedRoute(pub String);
+
+/// Route-level middleware putting the matched route template on the response,
+/// where [`metrics_middleware`] (which runs before routing) can see it
+pub async fn matched_route_middleware(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| MatchedRoute(path.as_str().to_string()));
+    let mut response = next.run(request).await;
+    if let Some(route) = route {
+        response.extensions_mut().insert(route);
+    }
+    response
+}
+
+/// A request observed in a latency bucket
+#[derive(Debug, Clone)]
+struct Exemplar {
+    trace_id: String,
+    seconds: f64,
+    timestamp: f64,
+}
+
+/// Latest exemplar of each latency bucket, by method, route and bucket index
+/// (`LATENCY_BUCKETS.len()` for `+Inf`)
+#[derive(Debug, Clone, Default)]
+pub struct Exemplars(Arc<Mutex<HashMap<(String, String, usize), Exemplar>>>);
+
+impl Exemplars {
+    fn record(&self, method: &str, route: &str, trace_id: String, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        self.0.lock().unwrap().insert(
+            (method.to_string(), route.to_string(), bucket),
+            Exemplar {
+                trace_id,
+                seconds,
+                timestamp,
+            },
+        );
+    }
+
+    /// Prometheus text output rewritten as OpenMetrics, with exemplars on
+    /// the latency buckets
+    pub fn openmetrics(&self, rendered: &str) -> String {
+        let exemplars = self.0.lock().unwrap();
+        let counters: Vec<&str> = rendered
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+            .collect();
+        let mut out = String::with_capacity(rendered.len());
+        for line in rendered.lines() {
+            // OpenMetrics names a counter without its `_total` suffix
+            let metadata = ["# TYPE ", "# HELP "]
+                .into_iter()
+                .find_map(|kind| Some((kind, line.strip_prefix(kind)?)));
+            if let Some((kind, rest)) = metadata {
+                let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                match name.strip_suffix("_total") {
+                    Some(base) if counters.contains(&name) => {
+                        out.push_str(&format!("{}{} {}\n", kind, base, rest))
+                    }
+                    _ => out.push_str(&format!("{}\n", line)),
+                }
+                continue;
+            }
+
+            out.push_str(line);
+            if let Some(e) = exemplar_key(line).and_then(|key| exemplars.get(&key)) {
+                out.push_str(&format!(
+                    " # {{trace_id=\"{}\"}} {} {:.3}",
+                    e.trace_id, e.seconds, e.timestamp
+                ));
             }
-        })
-        .collect::<Vec<_>>()
-        .join("/")
+            out.push('\n');
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// Method, route and bucket index of a rendered latency bucket line; `None`
+/// for any other line
+fn exemplar_key(line: &str) -> Option<(String, String, usize)> {
+    let line = line.strip_prefix("http_request_duration_seconds_bucket")?;
+    let method = label(line, "method")?;
+    let route = label(line, "route")?;
+    let bucket = bucket_index(label(line, "le")?)?;
+    Some((method.to_string(), route.to_string(), bucket))
+}
+
+/// Value of label `name` in a rendered sample line
+fn label<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = [format!("{{{}=\"", name), format!(",{}=\"", name)]
+        .iter()
+        .find_map(|prefix| line.find(prefix.as_str()).map(|i| i + prefix.len()))?;
+    let end = start + line[start..].find('"')?;
+    Some(&line[start..end])
+}
+
+/// Index of the latency bucket with upper bound `le`
+fn bucket_index(le: &str) -> Option<usize> {
+    if le == "+Inf" {
+        return Some(LATENCY_BUCKETS.len());
+    }
+    let le: f64 = le.parse().ok()?;
+    LATENCY_BUCKETS
+        .iter()
+        .position(|bound| (bound - le).abs() < 1e-9)
 }
 
 /// Middleware that records request count and duration metrics.
-pub async fn metrics_middleware(request: Request, next: Next) -> Response {
+pub async fn metrics_middleware(
+    State(exemplars): State<Exemplars>,
+    request: Request,
+    next: Next,
+) -> Response {
     let method = request.method().to_string();
-    let path = normalize_path(request.uri().path());
+    // Trace of the request span this middleware runs in; invalid when no
+    // trace context layer is installed
+    let span_context = tracing::Span::current()
+        .context()
+        .span()
+        .span_context()
+        .clone();
 
     let start = Instant::now();
     let response = next.run(request).await;
     let duration = start.elapsed().as_secs_f64();
 
     let status = response.status().as_u16().to_string();
+    let route = response
+        .extensions()
+        .get::<MatchedRoute>()
+        .map_or(UNMATCHED_ROUTE, |route| route.0.as_str())
+        .to_string();
 
     metrics::counter!(
         "http_requests_total",
         "method" => method.clone(),
-        "path" => path.clone(),
+        "route" => route.clone(),
         "status" => status
     )
     .increment(1);
 
     metrics::histogram!(
         "http_request_duration_seconds",
-        "method" => method,
-        "path" => path
+        "method" => method.clone(),
+        "route" => route.clone()
     )
     .record(duration);
 
+    if span_context.is_valid() {
+        let trace_id = span_context.trace_id().to_string();
+        exemplars.record(&method, &route, trace_id, duration);
+    }
+
     response
 }
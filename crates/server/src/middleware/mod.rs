@@ -1,13 +1,33 @@
 //! HTTP middleware
 
+pub mod ai_limit;
+pub mod ai_toggle;
 pub mod audit;
 pub mod auth;
+pub mod cors;
+pub mod db_breaker;
+pub mod error_format;
+pub mod errors;
+pub mod extension;
+pub mod fhir_version;
 pub mod metrics;
 pub mod rate_limit;
 pub mod request_id;
+pub mod request_log;
+pub mod xml;
 
+pub use ai_limit::{AiLimits, ai_limit_middleware};
+pub use ai_toggle::require_ai_operation_middleware;
 pub use audit::audit_middleware;
-pub use auth::ApiKeyAuth;
+pub use auth::{AdminClients, ApiKeyAuth, Principal, require_admin_middleware};
+pub use cors::cors_layer;
+pub use db_breaker::db_breaker_middleware;
+pub use error_format::error_format_middleware;
+pub use errors::error_report_middleware;
+pub use extension::require_function_middleware;
+pub use fhir_version::fhir_version_middleware;
 pub use metrics::metrics_middleware;
 pub use rate_limit::{create_rate_limiter, rate_limit_middleware};
 pub use request_id::request_id_middleware;
+pub use request_log::request_log_middleware;
+pub use xml::xml_middleware;
@@ -1,13 +1,6 @@
 //! Rate limiting middleware
 
-use axum::{
-    Json,
-    body::Body,
-    extract::Request,
-    http::StatusCode,
-    middleware::Next,
-    response::{IntoResponse, Response},
-};
+use axum::{body::Body, extract::Request, http::StatusCode, middleware::Next, response::Response};
 use governor::{Quota, RateLimiter, clock::DefaultClock, state::InMemoryState};
 use std::num::NonZeroU32;
 use std::sync::Arc;
@@ -36,7 +29,7 @@ pub async fn rate_limit_middleware(request: Request<Body>, next: Next) -> Respon
                 fhir_core::IssueType::Throttled,
                 "Rate limit exceeded. Please try again later.",
             );
-            return (StatusCode::TOO_MANY_REQUESTS, Json(outcome)).into_response();
+            return crate::error::outcome_response(StatusCode::TOO_MANY_REQUESTS, outcome);
         }
     }
 
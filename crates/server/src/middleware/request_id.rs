@@ -29,8 +29,16 @@ pub async fn request_id_middleware(mut request: Request<Body>, next: Next) -> Re
         "Incoming request"
     );
 
-    // Run the request
-    let mut response = next.run(request).await;
+    // Run the request, tagging its queries with the ID if configured
+    let tag_queries = request
+        .extensions()
+        .get::<SqlRequestTags>()
+        .is_some_and(|tags| tags.0);
+    let mut response = if tag_queries {
+        crate::db::with_request_id_tags(&request_id, next.run(request)).await
+    } else {
+        next.run(request).await
+    };
 
     // Add request ID to response headers
     response.headers_mut().insert(
@@ -44,3 +52,10 @@ pub async fn request_id_middleware(mut request: Request<Body>, next: Next) -> Re
 /// Request ID stored in request extensions
 #[derive(Clone, Debug)]
 pub struct RequestId(pub String);
+
+/// Whether the request ID goes into SQL comment tags
+///
+/// Installed as a request extension; without it queries are tagged with
+/// their operation only.
+#[derive(Clone, Copy, Debug)]
+pub struct SqlRequestTags(pub bool);
@@ -0,0 +1,120 @@
+//! Sampled request logging
+//!
+//! Logs one `request` event per sampled request with method, path and query,
+//! status, latency and sizes. Successful reads are usually the bulk of the
+//! traffic and can be sampled at a low rate while errors are kept in full.
+
+use std::time::Instant;
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderMap, Method, header},
+    middleware::Next,
+    response::Response,
+};
+
+use super::request_id::RequestId;
+
+/// Fraction (0.0–1.0) of requests logged, by outcome
+///
+/// Installed as a request extension; without it every request is logged.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLogSampling {
+    /// Successful GET / HEAD requests
+    pub reads: f64,
+    /// Other successful requests
+    pub writes: f64,
+    /// Responses with a 4xx or 5xx status
+    pub errors: f64,
+}
+
+impl Default for RequestLogSampling {
+    fn default() -> Self {
+        Self {
+            reads: 1.0,
+            writes: 1.0,
+            errors: 1.0,
+        }
+    }
+}
+
+impl RequestLogSampling {
+    fn rate(&self, method: &Method, status: u16) -> f64 {
+        if status >= 400 {
+            self.errors
+        } else if matches!(*method, Method::GET | Method::HEAD) {
+            self.reads
+        } else {
+            self.writes
+        }
+    }
+}
+
+/// Whether a request should be logged at `rate`
+fn sampled(rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+    // v4 UUIDs carry 122 random bits; 64 of them are plenty here
+    let random = uuid::Uuid::new_v4().as_u64_pair().1;
+    (random as f64) < rate * u64::MAX as f64
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Middleware that logs a sample of requests and their responses
+pub async fn request_log_middleware(request: Request<Body>, next: Next) -> Response {
+    let sampling = request
+        .extensions()
+        .get::<RequestLogSampling>()
+        .copied()
+        .unwrap_or_default();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or("").to_string();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|r| r.0.clone())
+        .unwrap_or_else(|| "unknown".to_string());
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let request_bytes = content_length(request.headers());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let status = response.status().as_u16();
+    let rate = sampling.rate(&method, status);
+    if sampled(rate) {
+        tracing::info!(
+            target: "request",
+            request_id = %request_id,
+            method = %method,
+            path = %path,
+            query = %query,
+            status,
+            latency_ms,
+            request_bytes,
+            response_bytes = content_length(response.headers()),
+            user_agent = %user_agent,
+            sample_rate = rate,
+            "Request completed"
+        );
+    }
+    response
+}
@@ -0,0 +1,148 @@
+//! FHIR XML content negotiation
+//!
+//! Request bodies sent as `application/fhir+xml` are converted to JSON before
+//! the handler runs, and JSON responses (resources, Bundles and
+//! OperationOutcomes alike) are converted to XML when the `Accept` header
+//! asks for XML and not JSON. Handlers only ever see JSON. A strong `ETag`
+//! of a converted response gets an `-xml` suffix, since it identifies the
+//! exact bytes of the JSON representation.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderMap, HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use fhir_core::{FhirError, FhirVersion};
+use serde_json::Value as JsonValue;
+
+use crate::error::AppError;
+
+/// MIME types of FHIR XML (the first is sent back)
+const XML_CONTENT_TYPES: &[&str] = &["application/fhir+xml", "application/xml", "text/xml"];
+
+/// MIME types of FHIR JSON
+const JSON_CONTENT_TYPES: &[&str] = &["application/fhir+json", "application/json"];
+
+/// Largest XML request body read, matching the JSON extractor's limit
+const MAX_XML_BODY: usize = 2 * 1024 * 1024;
+
+/// Media types listed in a header, without parameters
+pub(super) fn media_types<'a>(headers: &'a HeaderMap, name: header::HeaderName) -> Vec<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .map(|m| m.split(';').next().unwrap_or("").trim())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub(super) fn is_xml(mime: &str) -> bool {
+    XML_CONTENT_TYPES
+        .iter()
+        .any(|xml| mime.eq_ignore_ascii_case(xml))
+}
+
+pub(super) fn is_json(mime: &str) -> bool {
+    JSON_CONTENT_TYPES
+        .iter()
+        .any(|json| mime.eq_ignore_ascii_case(json))
+}
+
+/// Middleware translating XML request and response bodies to and from JSON
+pub async fn xml_middleware(request: Request<Body>, next: Next) -> Response {
+    let accept = media_types(request.headers(), header::ACCEPT);
+    let wants_xml = accept.iter().any(|m| is_xml(m)) && !accept.iter().any(|m| is_json(m));
+    let sends_xml = media_types(request.headers(), header::CONTENT_TYPE)
+        .first()
+        .is_some_and(|m| is_xml(m));
+    let version = request
+        .extensions()
+        .get::<FhirVersion>()
+        .copied()
+        .unwrap_or_default();
+
+    let request = match sends_xml {
+        true => match xml_request(request).await {
+            Ok(request) => request,
+            Err(e) => return xml_response(e.into_response(), version, wants_xml).await,
+        },
+        false => request,
+    };
+
+    let response = next.run(request).await;
+    xml_response(response, version, wants_xml).await
+}
+
+/// Replace an XML request body with its JSON equivalent
+async fn xml_request(request: Request<Body>) -> Result<Request<Body>, AppError> {
+    let (mut parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_XML_BODY)
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Failed to read request body: {}", e)))?;
+    let xml = std::str::from_utf8(&bytes)
+        .map_err(|_| AppError::BadRequest("XML request body is not UTF-8".to_string()))?;
+    let json = fhir_core::xml::from_xml(xml).map_err(|e| match e {
+        FhirError::Invalid(msg) => AppError::BadRequest(msg),
+        other => AppError::BadRequest(other.to_string()),
+    })?;
+
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(JSON_CONTENT_TYPES[0]),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = serde_json::to_vec(&json).expect("JSON values always serialize");
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+/// Convert a JSON resource response to XML if the client asked for it
+async fn xml_response(response: Response, version: FhirVersion, wants_xml: bool) -> Response {
+    let is_json_response = media_types(response.headers(), header::CONTENT_TYPE)
+        .first()
+        .is_some_and(|m| is_json(m));
+    if !wants_xml || !is_json_response {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return AppError::Internal(format!("Failed to read response body: {}", e))
+                .into_response();
+        }
+    };
+    let resource = serde_json::from_slice::<JsonValue>(&bytes)
+        .ok()
+        .filter(|v| v.get("resourceType").is_some());
+    let Some(resource) = resource else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&format!(
+            "{}; fhirVersion={}",
+            XML_CONTENT_TYPES[0],
+            version.code()
+        ))
+        .unwrap(),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Some(etag) = parts.headers.get(header::ETAG).and_then(xml_etag) {
+        parts.headers.insert(header::ETAG, etag);
+    }
+    Response::from_parts(parts, Body::from(fhir_core::xml::to_xml(&resource)))
+}
+
+/// The XML representation's ETag for a strong JSON ETag (`"1"` → `"1-xml"`);
+/// weak ETags are shared by both representations
+fn xml_etag(etag: &HeaderValue) -> Option<HeaderValue> {
+    let tag = etag.to_str().ok()?.strip_prefix('"')?.strip_suffix('"')?;
+    HeaderValue::from_str(&format!("\"{}-xml\"", tag)).ok()
+}
@@ -0,0 +1,246 @@
+//! Change notification delivery from the transactional outbox
+//!
+//! With `fhir.outbox` enabled, the extension records a notification intent in
+//! `fhir_outbox` in the same transaction as every write. The delivery worker
+//! claims a batch of due rows in a short transaction that leases them
+//! (pushes `next_attempt_at` past the time the batch may take) and commits,
+//! then POSTs each to `NOTIFICATION_URL` and marks it delivered. No
+//! transaction stays open across the HTTP calls, so:
+//!
+//! - a notification is never lost: rows stay pending until a delivery
+//!   succeeds, across restarts;
+//! - concurrent workers (several server instances) never claim the same row
+//!   while its lease runs;
+//! - a crash between delivery and marking redelivers the row once its lease
+//!   expires, with the same `notificationId` (also sent as
+//!   `X-Notification-Id`), so receivers that deduplicate on it process every
+//!   change exactly once.
+//!
+//! Notifications of one resource are delivered in order: a row is only
+//! claimed when no earlier row of the same resource is pending elsewhere
+//! (leased by another worker or waiting for a retry), and a failed delivery
+//! holds back the later rows of its resource in the batch. Failures are
+//! retried with exponential backoff (capped at an hour). After
+//! `ALERT_AFTER_ATTEMPTS` failures an administrative webhook event is sent.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::cdc::{ChangeAction, ChangeEvent};
+use crate::db::{CancellableClient, Transaction};
+use crate::error::AppError;
+use crate::webhook::{self, AdminEvent, WebhookNotifier};
+
+/// Header carrying the idempotency key of a notification
+pub const NOTIFICATION_ID_HEADER: &str = "X-Notification-Id";
+
+/// Rows claimed per batch
+const BATCH_SIZE: i64 = 100;
+
+/// Timeout of one delivery attempt
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long claimed rows are withheld from other workers: long enough for a
+/// whole batch of attempts that time out
+const CLAIM_LEASE: Duration =
+    Duration::from_secs(BATCH_SIZE as u64 * DELIVERY_TIMEOUT.as_secs() + 60);
+
+/// Advisory lock key serializing claims ("fhirObox")
+const CLAIM_LOCK_KEY: i64 = 0x6668_6972_4f62_6f78;
+
+/// Failed attempts after which an administrative alert is sent
+const ALERT_AFTER_ATTEMPTS: i32 = 8;
+
+/// Body POSTed for each change
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+    notification_id: i64,
+    #[serde(flatten)]
+    change: ChangeEvent,
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+}
+
+/// Delivers outbox rows to one endpoint
+#[derive(Clone)]
+pub struct OutboxDelivery {
+    http: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    alerts: Option<WebhookNotifier>,
+}
+
+impl OutboxDelivery {
+    /// Deliver to `url`, signing bodies with `secret` like administrative
+    /// webhooks
+    pub fn new(url: String, secret: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()
+                .expect("HTTP client configuration is valid"),
+            url,
+            secret,
+            alerts: None,
+        }
+    }
+
+    /// Report repeatedly failing notifications through `notifier`
+    pub(crate) fn with_alerts(mut self, notifier: WebhookNotifier) -> Self {
+        self.alerts = Some(notifier);
+        self
+    }
+
+    /// POST the notification of one change, returning the failure if it was
+    /// not accepted
+    pub(crate) async fn deliver(
+        &self,
+        notification_id: i64,
+        change: ChangeEvent,
+    ) -> Result<(), String> {
+        let notification = Notification {
+            notification_id,
+            change,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        let body = serde_json::to_vec(&notification).map_err(|e| e.to_string())?;
+        let mut request = self
+            .http
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header(
+                NOTIFICATION_ID_HEADER,
+                notification.notification_id.to_string(),
+            );
+        if let Some(ref secret) = self.secret {
+            request = request.header(webhook::SIGNATURE_HEADER, webhook::sign(secret, &body));
+        }
+        let response = request.body(body).send().await.map_err(|e| e.to_string())?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("endpoint answered {}", response.status())),
+        }
+    }
+}
+
+/// Claim and deliver one batch of due notifications, returning the number
+/// delivered
+pub async fn deliver_pending(pool: &Pool, delivery: &OutboxDelivery) -> Result<usize, AppError> {
+    // Claims take turns, so a row is never claimed while an earlier row of
+    // its resource is being claimed by another worker
+    let tx = Transaction::begin(pool).await?;
+    tx.query("SELECT pg_advisory_xact_lock($1)", &[&CLAIM_LOCK_KEY])
+        .await?;
+    let mut rows = tx
+        .query(
+            "UPDATE fhir_outbox
+                SET next_attempt_at = NOW() + make_interval(secs => $2)
+              WHERE id IN (
+                SELECT id FROM fhir_outbox o
+                 WHERE delivered_at IS NULL AND next_attempt_at <= NOW()
+                   AND NOT EXISTS (
+                     SELECT 1 FROM fhir_outbox e
+                      WHERE e.resource_id = o.resource_id AND e.id < o.id
+                        AND e.delivered_at IS NULL AND e.next_attempt_at > NOW())
+                 ORDER BY id
+                 LIMIT $1)
+              RETURNING id, resource_type, resource_id, version, method, attempts",
+            &[&BATCH_SIZE, &CLAIM_LEASE.as_secs_f64()],
+        )
+        .await?;
+    tx.commit().await?;
+    rows.sort_by_key(|row| row.get::<_, i64>(0));
+
+    let client = CancellableClient::get(pool).await?;
+    let mut held_back: Vec<Uuid> = Vec::new();
+    let mut delivered = 0;
+    for row in rows {
+        let id: i64 = row.get(0);
+        let resource_id: Uuid = row.get(2);
+        let method: String = row.get(4);
+        let attempts: i32 = row.get(5);
+
+        // Later changes of a resource wait for its failed one
+        if held_back.contains(&resource_id) {
+            client
+                .query(
+                    "UPDATE fhir_outbox SET next_attempt_at = NOW() WHERE id = $1",
+                    &[&id],
+                )
+                .await?;
+            continue;
+        }
+
+        let change = ChangeEvent {
+            resource_type: row.get(1),
+            id: resource_id,
+            version: row.get(3),
+            action: match method.as_str() {
+                "POST" => ChangeAction::Create,
+                "DELETE" => ChangeAction::Delete,
+                _ => ChangeAction::Update,
+            },
+        };
+
+        match delivery.deliver(id, change).await {
+            Ok(()) => {
+                client
+                    .query(
+                        "UPDATE fhir_outbox SET delivered_at = NOW(), attempts = attempts + 1
+                          WHERE id = $1",
+                        &[&id],
+                    )
+                    .await?;
+                metrics::counter!("fhir_notifications_delivered_total").increment(1);
+                delivered += 1;
+            }
+            Err(error) => {
+                client
+                    .query(
+                        "UPDATE fhir_outbox
+                            SET attempts = attempts + 1, last_error = $2,
+                                next_attempt_at = NOW() + make_interval(secs => LEAST(power(2, attempts), 3600))
+                          WHERE id = $1",
+                        &[&id, &error],
+                    )
+                    .await?;
+                metrics::counter!("fhir_notifications_failed_total").increment(1);
+                tracing::warn!(notification_id = id, attempt = attempts + 1, error = %error, "Notification delivery failed");
+                if let (true, Some(alerts)) =
+                    (attempts + 1 == ALERT_AFTER_ATTEMPTS, &delivery.alerts)
+                {
+                    alerts.notify(AdminEvent::SubscriptionDeliveryFailed {
+                        subscription_id: format!("outbox/{}", id),
+                        endpoint: delivery.url.clone(),
+                        error,
+                    });
+                }
+                held_back.push(resource_id);
+            }
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// Spawn the delivery worker, polling every `interval`
+pub(crate) fn spawn_worker(pool: Pool, delivery: OutboxDelivery, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match deliver_pending(&pool, &delivery).await {
+                Ok(0) => {}
+                Ok(n) => tracing::debug!(delivered = n, "Notifications delivered"),
+                Err(e) => tracing::warn!(error = ?e, "Outbox delivery failed"),
+            }
+        }
+    });
+}
@@ -0,0 +1,168 @@
+//! Administrative endpoints
+
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use deadpool_postgres::Pool;
+use fhir_core::{Bundle, BundleEntry};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use super::params::parse_instant;
+use crate::db::{
+    AdminRepository, AiAuditFilter, AiAuditRepository, ExtensionFeatures, extension_features,
+};
+use crate::error::AppError;
+use crate::scheduler::{JobStatus, SchedulerHandle};
+
+/// Response body for admin stats
+#[derive(Serialize)]
+pub struct StatsResponse {
+    jobs: Vec<JobStatus>,
+    /// Whether this replica is the scheduler leader that runs the jobs
+    leader: bool,
+    /// Storage maintenance report (omitted if the database query fails)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<JsonValue>,
+    /// Extension functions negotiated at startup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extension: Option<&'static ExtensionFeatures>,
+}
+
+/// GET /admin/stats - Report background job status and storage health
+pub async fn stats(
+    State(pool): State<Pool>,
+    Extension(scheduler): Extension<SchedulerHandle>,
+) -> Json<StatsResponse> {
+    let repo = AdminRepository::new(pool);
+    let maintenance = match repo.maintenance_report().await {
+        Ok(report) => Some(report),
+        Err(e) => {
+            tracing::warn!(error = ?e, "Failed to build maintenance report");
+            None
+        }
+    };
+
+    Json(StatsResponse {
+        jobs: scheduler.statuses(),
+        leader: scheduler.is_leader(),
+        maintenance,
+        extension: extension_features(),
+    })
+}
+
+/// Query parameters for a point-in-time export
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    #[serde(rename = "_type")]
+    pub resource_type: Option<String>,
+    #[serde(rename = "_asOf")]
+    pub as_of: String,
+}
+
+/// GET /admin/export?_asOf=<instant> - Point-in-time snapshot of all resources
+///
+/// Returns a `collection` Bundle holding the version of every resource that
+/// was current at `_asOf`, for audits and environment refreshes.
+pub async fn export(
+    State(pool): State<Pool>,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let as_of = parse_instant("_asOf", &params.as_of)?;
+    let resource_type = params.resource_type.as_deref().unwrap_or("Patient");
+
+    let repo = AdminRepository::new(pool);
+    let rows = repo.export_snapshot(resource_type, &as_of).await?;
+
+    tracing::info!(
+        resource_type = resource_type,
+        as_of = %as_of,
+        resources = rows.len(),
+        "Point-in-time export"
+    );
+
+    let entries: Vec<BundleEntry> = rows
+        .into_iter()
+        .map(|(id, version, data)| {
+            BundleEntry::new(
+                Some(format!(
+                    "/fhir/{}/{}/_history/{}",
+                    resource_type, id, version
+                )),
+                data,
+            )
+        })
+        .collect();
+
+    Ok(Json(Bundle::collection(entries)))
+}
+
+/// Query parameters for the AI interaction audit
+#[derive(Debug, Deserialize)]
+pub struct AiAuditParams {
+    #[serde(rename = "_since")]
+    pub since: Option<String>,
+    pub operation: Option<String>,
+    /// `Patient/<id>` the interaction touched
+    pub resource: Option<String>,
+    #[serde(rename = "_count")]
+    pub count: Option<i64>,
+}
+
+/// GET /admin/ai-audit - Recorded AI interactions, newest first
+pub async fn ai_audit(
+    State(pool): State<Pool>,
+    Query(params): Query<AiAuditParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let since = params
+        .since
+        .as_deref()
+        .map(|s| parse_instant("_since", s))
+        .transpose()?;
+    let filter = AiAuditFilter {
+        since,
+        operation: params.operation,
+        resource: params.resource,
+        limit: params.count.unwrap_or(100).clamp(1, 1000),
+    };
+    let entries = AiAuditRepository::new(pool).list(&filter).await?;
+    Ok(Json(serde_json::json!({ "entries": entries })))
+}
+
+/// Request body for a log level change
+#[derive(Debug, Deserialize)]
+pub struct LogLevelRequest {
+    /// `EnvFilter` directives applied on top of the default filter
+    pub directives: String,
+    /// Revert to the default filter after this many seconds
+    pub ttl_secs: Option<u64>,
+}
+
+fn log_levels() -> Result<&'static crate::logging::LogLevels, AppError> {
+    crate::logging::levels()
+        .ok_or_else(|| AppError::Internal("Runtime log level control is not installed".to_string()))
+}
+
+/// GET /admin/log-level - Current log filter and overrides
+pub async fn get_log_level() -> Result<impl IntoResponse, AppError> {
+    Ok(Json(log_levels()?.status()))
+}
+
+/// PUT /admin/log-level - Override log directives (e.g.
+/// `fhir_server::db=debug`), optionally for a limited time
+pub async fn set_log_level(
+    Json(body): Json<LogLevelRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let ttl = body.ttl_secs.map(std::time::Duration::from_secs);
+    let status = log_levels()?
+        .set(&body.directives, ttl)
+        .map_err(AppError::BadRequest)?;
+    Ok(Json(status))
+}
+
+/// DELETE /admin/log-level - Restore the default log filter
+pub async fn reset_log_level() -> Result<impl IntoResponse, AppError> {
+    Ok(Json(log_levels()?.reset().map_err(AppError::Internal)?))
+}
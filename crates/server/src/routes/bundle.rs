@@ -0,0 +1,293 @@
+//! Transaction and batch Bundle handler
+
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::State, http::HeaderMap};
+use deadpool_postgres::Pool;
+use fhir_core::resource_type::is_resource_type;
+use fhir_core::{FhirVersion, PackageRegistry};
+use serde_json::{Value as JsonValue, json};
+
+use super::patient;
+use crate::config::{NarrativePolicy, PhotoLimit};
+use crate::db::{BundleRepository, ResourceRepository};
+use crate::error::AppError;
+use crate::geocode::Geocoding;
+use crate::write_policy::{WriteContext, WriteInteraction, WritePolicies};
+
+/// How entry resources are checked before they are written
+struct WriteChecks {
+    version: FhirVersion,
+    narrative_policy: NarrativePolicy,
+    photo_limit: PhotoLimit,
+    packages: Arc<PackageRegistry>,
+    geocoding: Geocoding,
+    policies: WritePolicies,
+    pool: Pool,
+    headers: HeaderMap,
+}
+
+impl WriteChecks {
+    /// Give an entry's resource the same checks as the single-resource
+    /// endpoints (narrative, choice elements and extensions; Patients also
+    /// the photo checks; Patients and Locations geocoding), and
+    /// evaluate the write policies against it; a DELETE entry has its
+    /// policies evaluated against the resource it deletes
+    async fn prepare(&self, index: usize, entry: &mut JsonValue) -> Result<(), AppError> {
+        let Some(resource) = entry.get_mut("resource").map(JsonValue::take) else {
+            return self
+                .evaluate_delete(entry)
+                .await
+                .map_err(|e| in_entry(index, e));
+        };
+        let resource_type = resource
+            .get("resourceType")
+            .and_then(|v| v.as_str())
+            .map(str::to_string);
+        let resource = match resource_type.as_deref() {
+            Some("Patient") => {
+                let mut resource = patient::prepare_write(
+                    self.version,
+                    self.narrative_policy,
+                    self.photo_limit,
+                    &self.packages,
+                    resource,
+                )?;
+                self.geocoding.apply(&mut resource).await;
+                resource
+            }
+            Some(resource_type) if is_resource_type(resource_type) => {
+                let mut resource = resource;
+                patient::check_narrative(self.narrative_policy, &mut resource)?;
+                patient::check_choices(resource_type, &resource)?;
+                patient::check_extensions(&self.packages, &resource)?;
+                self.version.tag(&mut resource);
+                self.geocoding.apply(&mut resource).await;
+                resource
+            }
+            other => {
+                return Err(AppError::BadRequest(format!(
+                    "Bundle entry {}: unknown resource type {}",
+                    index,
+                    other.unwrap_or("(none)")
+                )));
+            }
+        };
+        self.evaluate_policies(entry, &resource)
+            .await
+            .map_err(|e| in_entry(index, e))?;
+        entry["resource"] = resource;
+        Ok(())
+    }
+
+    /// Evaluate the write policies for a DELETE entry against the current
+    /// version of its target; a missing target is left for the database
+    /// to report
+    async fn evaluate_delete(&self, entry: &JsonValue) -> Result<(), AppError> {
+        let request = &entry["request"];
+        let is_delete = request["method"]
+            .as_str()
+            .is_some_and(|m| m.eq_ignore_ascii_case("DELETE"));
+        if self.policies.is_empty() || !is_delete {
+            return Ok(());
+        }
+        let target = request["url"]
+            .as_str()
+            .and_then(|url| url.trim_matches('/').split_once('/'))
+            .filter(|(resource_type, _)| is_resource_type(resource_type))
+            .and_then(|(resource_type, id)| Some((resource_type, uuid::Uuid::parse_str(id).ok()?)));
+        let Some((resource_type, id)) = target else {
+            return Ok(());
+        };
+        let current = ResourceRepository::new(self.pool.clone(), resource_type)
+            .get(id)
+            .await?;
+        match current {
+            Some(current) => self.policies.evaluate(
+                Some(&current),
+                &JsonValue::Null,
+                &WriteContext::new(resource_type, WriteInteraction::Delete, &self.headers),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Evaluate the write policies for an entry writing `resource`; a PUT
+    /// is compared with the current version of its target
+    async fn evaluate_policies(
+        &self,
+        entry: &JsonValue,
+        resource: &JsonValue,
+    ) -> Result<(), AppError> {
+        if self.policies.is_empty() {
+            return Ok(());
+        }
+        let resource_type = resource
+            .get("resourceType")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let request = &entry["request"];
+        let method = request["method"]
+            .as_str()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        let target = request["url"]
+            .as_str()
+            .and_then(|url| url.trim_matches('/').split_once('/'))
+            .and_then(|(_, id)| uuid::Uuid::parse_str(id).ok());
+
+        let (interaction, current) = match (method.as_str(), target) {
+            ("PUT", Some(id)) => (
+                WriteInteraction::Update,
+                ResourceRepository::new(self.pool.clone(), resource_type)
+                    .get(id)
+                    .await?,
+            ),
+            ("PUT", None) => (WriteInteraction::Update, None),
+            _ => (WriteInteraction::Create, None),
+        };
+        self.policies.evaluate(
+            current.as_ref(),
+            resource,
+            &WriteContext::new(resource_type, interaction, &self.headers),
+        )
+    }
+}
+
+/// `error` of Bundle entry `index`, with the entry named in business rule
+/// issues
+fn in_entry(index: usize, error: AppError) -> AppError {
+    match error {
+        AppError::BusinessRule(issues) => AppError::BusinessRule(
+            issues
+                .into_iter()
+                .map(|mut issue| {
+                    issue.diagnostics = issue
+                        .diagnostics
+                        .map(|d| format!("Bundle entry {}: {}", index, d));
+                    issue
+                })
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// POST /fhir - Process a transaction or batch Bundle
+///
+/// A transaction is handed to the database as a whole, which performs all
+/// entries or none. A batch runs each entry on its own: a failing entry is
+/// answered with its status and OperationOutcome in the `batch-response`
+/// while the others still apply.
+pub async fn process(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(narrative_policy): Extension<NarrativePolicy>,
+    Extension(photo_limit): Extension<PhotoLimit>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(geocoding): Extension<Geocoding>,
+    Extension(policies): Extension<WritePolicies>,
+    headers: HeaderMap,
+    Json(mut bundle): Json<JsonValue>,
+) -> Result<Json<JsonValue>, AppError> {
+    if bundle.get("resourceType").and_then(|v| v.as_str()) != Some("Bundle") {
+        return Err(AppError::BadRequest("Expected a Bundle".to_string()));
+    }
+    let checks = WriteChecks {
+        version,
+        narrative_policy,
+        photo_limit,
+        packages,
+        geocoding,
+        policies,
+        pool: pool.clone(),
+        headers,
+    };
+    let repo = BundleRepository::new(pool);
+
+    let bundle_type = bundle
+        .get("type")
+        .and_then(|v| v.as_str())
+        .map(str::to_string);
+    match bundle_type.as_deref() {
+        Some("transaction") => {
+            let entries = bundle
+                .get_mut("entry")
+                .and_then(|e| e.as_array_mut())
+                .map(|e| e.as_mut_slice())
+                .unwrap_or_default();
+            for (index, entry) in entries.iter_mut().enumerate() {
+                checks.prepare(index, entry).await?;
+            }
+
+            let count = entries.len();
+            let response = repo.transaction(&bundle).await?;
+            tracing::info!(entries = count, "Transaction bundle processed");
+            Ok(Json(response))
+        }
+        Some("batch") => {
+            let entries = match bundle.get_mut("entry").map(JsonValue::take) {
+                Some(JsonValue::Array(entries)) => entries,
+                _ => Vec::new(),
+            };
+
+            let mut responses = Vec::with_capacity(entries.len());
+            let mut failed = 0;
+            for (index, mut entry) in entries.into_iter().enumerate() {
+                let response = match checks.prepare(index, &mut entry).await {
+                    Ok(()) => batch_entry(&repo, entry).await,
+                    Err(e) => Err(e),
+                };
+                responses.push(response.unwrap_or_else(|e| {
+                    failed += 1;
+                    failed_entry(e)
+                }));
+            }
+
+            tracing::info!(
+                entries = responses.len(),
+                failed = failed,
+                "Batch bundle processed"
+            );
+            Ok(Json(json!({
+                "resourceType": "Bundle",
+                "type": "batch-response",
+                "entry": responses
+            })))
+        }
+        other => Err(AppError::BadRequest(format!(
+            "Only transaction and batch Bundles are supported, got {}",
+            other.unwrap_or("no type")
+        ))),
+    }
+}
+
+/// Perform one batch entry as a transaction of its own
+async fn batch_entry(repo: &BundleRepository, entry: JsonValue) -> Result<JsonValue, AppError> {
+    let mut response = repo
+        .transaction(&json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [entry]
+        }))
+        .await?;
+    Ok(response["entry"][0].take())
+}
+
+/// The response entry of a batch entry that failed with `error`
+fn failed_entry(error: AppError) -> JsonValue {
+    if let AppError::Internal(msg) = &error {
+        tracing::error!(error = %msg, "Batch entry failed");
+    }
+    let (status, outcome) = error.status_and_outcome();
+    json!({
+        "response": {
+            "status": format!(
+                "{} {}",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default()
+            ),
+            "outcome": outcome
+        }
+    })
+}
@@ -0,0 +1,221 @@
+//! ConceptMap handlers: storage and `$translate`
+//!
+//! ConceptMaps are stored like any other resource; `$translate` maps a code
+//! between systems (e.g. local lab codes to LOINC) with one stored map
+//! (`/ConceptMap/{id}/$translate`, or `url`) or every map that fits the
+//! source and target systems.
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use deadpool_postgres::Pool;
+use fhir_core::concept_map::{self, Translation};
+use fhir_core::{Bundle, BundleEntry, Coding, FhirVersion};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+use crate::db::ConformanceRepository;
+use crate::error::AppError;
+use crate::middleware::fhir_version::base_path;
+use crate::urls::UrlBuilder;
+
+const RESOURCE_TYPE: &str = "ConceptMap";
+
+fn repository(pool: Pool) -> ConformanceRepository {
+    ConformanceRepository::new(pool, RESOURCE_TYPE)
+}
+
+/// Query parameters for ConceptMap search
+#[derive(Debug, Deserialize, Default)]
+pub struct SearchParams {
+    pub url: Option<String>,
+}
+
+/// Input parameters of `$translate`
+#[derive(Debug, Deserialize, Default)]
+pub struct TranslateParams {
+    /// Canonical URL of the ConceptMap to use
+    pub url: Option<String>,
+    pub system: Option<String>,
+    pub code: Option<String>,
+    /// System to translate into
+    pub targetsystem: Option<String>,
+}
+
+impl TranslateParams {
+    /// Read the parameters from a `Parameters` resource
+    fn from_parameters(body: &JsonValue) -> Result<Self, AppError> {
+        if body.get("resourceType").and_then(|v| v.as_str()) != Some("Parameters") {
+            return Err(AppError::BadRequest(
+                "Expected a Parameters resource".to_string(),
+            ));
+        }
+        let parameters = body["parameter"].as_array().cloned().unwrap_or_default();
+        let value = |name: &str| {
+            parameters
+                .iter()
+                .find(|p| p["name"] == name)
+                .and_then(|p| {
+                    ["valueUri", "valueCanonical", "valueCode", "valueString"]
+                        .iter()
+                        .find_map(|key| p.get(*key).and_then(|v| v.as_str()))
+                })
+                .map(str::to_string)
+        };
+        let coding = parameters
+            .iter()
+            .find(|p| p["name"] == "coding")
+            .and_then(|p| p.get("valueCoding"))
+            .and_then(Coding::from_json);
+
+        let (system, code) = match coding {
+            Some(coding) => (coding.system, coding.code),
+            None => (value("system"), value("code")),
+        };
+        Ok(Self {
+            url: value("url"),
+            system,
+            code,
+            targetsystem: value("targetsystem"),
+        })
+    }
+}
+
+fn validate(body: &JsonValue) -> Result<(), AppError> {
+    if body.get("resourceType").and_then(|v| v.as_str()) != Some(RESOURCE_TYPE) {
+        return Err(AppError::BadRequest(
+            "Expected a ConceptMap resource".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// POST /fhir/ConceptMap - Store a ConceptMap
+pub async fn create(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Json(body): Json<JsonValue>,
+) -> Result<impl IntoResponse, AppError> {
+    validate(&body)?;
+    let id = repository(pool).create(body).await?;
+
+    tracing::info!(concept_map_id = %id, "ConceptMap created");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::LOCATION,
+        UrlBuilder::new(base_path(version))
+            .segment("ConceptMap")
+            .segment(id)
+            .header(),
+    );
+    headers.insert("ETag", "W/\"1\"".parse().unwrap());
+
+    Ok((StatusCode::CREATED, headers))
+}
+
+/// GET /fhir/ConceptMap/{id} - Read a ConceptMap
+pub async fn read(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut data = repository(pool)
+        .get(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("ConceptMap/{} not found", id)))?;
+    data["id"] = JsonValue::String(id.to_string());
+    Ok(Json(data))
+}
+
+/// GET /fhir/ConceptMap - Search stored ConceptMaps by `url`
+pub async fn search(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Query(params): Query<SearchParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let maps = repository(pool).all().await?;
+    let entries: Vec<BundleEntry> = maps
+        .into_iter()
+        .filter(|(_, map)| {
+            params
+                .url
+                .as_deref()
+                .is_none_or(|url| concept_map::url(map) == Some(url))
+        })
+        .map(|(id, map)| {
+            BundleEntry::new(
+                Some(format!("{}/ConceptMap/{}", base_path(version), id)),
+                map,
+            )
+        })
+        .collect();
+    Ok(Json(Bundle::searchset(entries.len() as u32, entries)))
+}
+
+/// Translate with the given maps
+fn translate_with(maps: &[JsonValue], params: &TranslateParams) -> Result<JsonValue, AppError> {
+    let code = params
+        .code
+        .as_deref()
+        .ok_or_else(|| AppError::BadRequest("$translate requires a code or coding".to_string()))?;
+    let coding = Coding::new(params.system.as_deref(), code);
+
+    let translations: Vec<Translation> = maps
+        .iter()
+        .filter(|map| {
+            params
+                .url
+                .as_deref()
+                .is_none_or(|url| concept_map::url(map) == Some(url))
+        })
+        .flat_map(|map| concept_map::translate(map, &coding, params.targetsystem.as_deref()))
+        .collect();
+
+    tracing::info!(
+        system = ?params.system,
+        code = code,
+        matches = translations.len(),
+        "ConceptMap translate"
+    );
+    Ok(concept_map::to_parameters(&translations))
+}
+
+/// GET /fhir/ConceptMap/$translate - Translate a code with the stored maps
+pub async fn translate(
+    State(pool): State<Pool>,
+    Query(params): Query<TranslateParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let maps: Vec<JsonValue> = repository(pool)
+        .all()
+        .await?
+        .into_iter()
+        .map(|(_, map)| map)
+        .collect();
+    Ok(Json(translate_with(&maps, &params)?))
+}
+
+/// POST /fhir/ConceptMap/$translate - `$translate` with a `Parameters` body
+pub async fn translate_post(
+    State(pool): State<Pool>,
+    Json(body): Json<JsonValue>,
+) -> Result<impl IntoResponse, AppError> {
+    let params = TranslateParams::from_parameters(&body)?;
+    translate(State(pool), Query(params)).await
+}
+
+/// GET /fhir/ConceptMap/{id}/$translate - Translate a code with one map
+pub async fn translate_instance(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<TranslateParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let map = repository(pool)
+        .get(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("ConceptMap/{} not found", id)))?;
+    Ok(Json(translate_with(&[map], &params)?))
+}
@@ -0,0 +1,361 @@
+//! Bulk Data `$export` handlers (kick-off, status, cancellation, download)
+
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{OriginalUri, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use deadpool_postgres::Pool;
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+use super::params::parse_instant;
+use crate::error::AppError;
+use crate::export::{ExportFilter, ExportManager, ExportState};
+use crate::urls::UrlBuilder;
+
+/// Search parameters `fhir_search` supports in a `_typeFilter`
+const FILTER_PARAMS: &[&str] = &["name", "gender", "birthdate"];
+
+/// Parse the `_type`, `_since` and `_typeFilter` kick-off parameters.
+///
+/// `scope` restricts the export to the given types (type-level kick-off).
+fn parse_filter(
+    params: &[(String, String)],
+    scope: Option<&[&str]>,
+) -> Result<ExportFilter, AppError> {
+    let mut filter = ExportFilter::default();
+
+    for (name, value) in params {
+        match name.as_str() {
+            "_type" => filter.types.extend(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string),
+            ),
+            "_since" => filter.since = Some(parse_instant("_since", value)?),
+            "_typeFilter" => {
+                for query in split_type_filters(value) {
+                    let (resource_type, search) = parse_type_filter(&query)?;
+                    filter
+                        .type_filters
+                        .entry(resource_type)
+                        .or_default()
+                        .push(search);
+                }
+            }
+            "_outputFormat" => {
+                if !matches!(
+                    value.as_str(),
+                    "application/fhir+ndjson" | "application/ndjson" | "ndjson"
+                ) {
+                    return Err(AppError::BadRequest(format!(
+                        "Unsupported _outputFormat '{}'",
+                        value
+                    )));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Type names become output file names, so only plain names are accepted
+    let invalid = filter
+        .types
+        .iter()
+        .chain(filter.type_filters.keys())
+        .find(|t| t.is_empty() || !t.chars().all(|c| c.is_ascii_alphanumeric()));
+    if let Some(t) = invalid {
+        return Err(AppError::BadRequest(format!(
+            "Invalid resource type '{}'",
+            t
+        )));
+    }
+
+    if let Some(scope) = scope {
+        if filter.types.is_empty() {
+            filter.types = scope.iter().map(|t| t.to_string()).collect();
+        } else {
+            filter.types.retain(|t| scope.contains(&t.as_str()));
+            if filter.types.is_empty() {
+                return Err(AppError::BadRequest(format!(
+                    "_type must include one of: {}",
+                    scope.join(", ")
+                )));
+            }
+        }
+    }
+
+    // A filter for a type outside `_type` would never apply
+    let stray = filter
+        .type_filters
+        .keys()
+        .find(|t| !filter.types.is_empty() && !filter.types.contains(t));
+    if let Some(t) = stray {
+        return Err(AppError::BadRequest(format!(
+            "_typeFilter for {} which is not in _type",
+            t
+        )));
+    }
+
+    Ok(filter)
+}
+
+/// Split a comma-separated `_typeFilter` value into `Type?query` parts,
+/// keeping commas that belong to a query value
+fn split_type_filters(value: &str) -> Vec<String> {
+    let mut queries: Vec<String> = Vec::new();
+    for part in value.split(',') {
+        match queries.last_mut() {
+            Some(last) if !part.contains('?') => {
+                last.push(',');
+                last.push_str(part);
+            }
+            _ => queries.push(part.trim().to_string()),
+        }
+    }
+    queries
+}
+
+/// Parse `Type?param=value&...` into a type and `fhir_search` parameters
+fn parse_type_filter(query: &str) -> Result<(String, JsonValue), AppError> {
+    let (resource_type, search) = query
+        .split_once('?')
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid _typeFilter '{}'", query)))?;
+
+    let mut params = serde_json::Map::new();
+    for (name, value) in form_urlencoded::parse(search.as_bytes()) {
+        if !FILTER_PARAMS.contains(&name.as_ref()) {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported _typeFilter parameter '{}'",
+                name
+            )));
+        }
+        params.insert(name.into_owned(), JsonValue::String(value.into_owned()));
+    }
+
+    Ok((resource_type.trim().to_string(), JsonValue::Object(params)))
+}
+
+/// Start an export job after checking the kick-off headers
+fn kick_off(
+    pool: Pool,
+    manager: &ExportManager,
+    headers: &HeaderMap,
+    uri: &OriginalUri,
+    filter: ExportFilter,
+) -> Result<Response, AppError> {
+    let respond_async = headers
+        .get("Prefer")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|p| p.trim() == "respond-async"));
+    if !respond_async {
+        return Err(AppError::BadRequest(
+            "Export kick-off requires the 'Prefer: respond-async' header".to_string(),
+        ));
+    }
+
+    let base = manager.base_url();
+    let id = manager.start(pool, format!("{}{}", base, uri.0), filter);
+    tracing::info!(job_id = %id, "Export started");
+
+    let status_url = UrlBuilder::new(base)
+        .segment("fhir")
+        .segment("$export-status")
+        .segment(id);
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::CONTENT_LOCATION, status_url.header())],
+    )
+        .into_response())
+}
+
+/// GET /fhir/$export - System-level export (every type unless `_type` is given)
+pub async fn system_export(
+    State(pool): State<Pool>,
+    Extension(manager): Extension<ExportManager>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Response, AppError> {
+    let filter = parse_filter(&params, None)?;
+    kick_off(pool, &manager, &headers, &uri, filter)
+}
+
+/// GET /fhir/Patient/$export - Export Patient resources
+pub async fn patient_export(
+    State(pool): State<Pool>,
+    Extension(manager): Extension<ExportManager>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Response, AppError> {
+    let filter = parse_filter(&params, Some(&["Patient"]))?;
+    kick_off(pool, &manager, &headers, &uri, filter)
+}
+
+/// `$extract-cohort` request body
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CohortRequest {
+    /// Patient search parameters selecting the cohort
+    /// (`gender=female&birthdate=lt1980`); every patient if absent
+    pub criteria: Option<String>,
+    /// De-identification profile (`safe-harbor` or `limited`)
+    pub profile: String,
+    pub output_format: Option<String>,
+}
+
+/// POST /fhir/Patient/$extract-cohort - Export a de-identified cohort
+///
+/// Runs as an export job; progress and output use the `$export-status`
+/// and `$export-file` endpoints.
+pub async fn extract_cohort(
+    State(pool): State<Pool>,
+    Extension(manager): Extension<ExportManager>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Json(body): Json<CohortRequest>,
+) -> Result<Response, AppError> {
+    let profile = fhir_core::DeidProfile::parse(&body.profile).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "Unknown de-identification profile '{}' (expected safe-harbor or limited)",
+            body.profile
+        ))
+    })?;
+    match body.output_format.as_deref() {
+        None | Some("application/fhir+ndjson" | "application/ndjson" | "ndjson") => {}
+        Some("application/vnd.apache.parquet" | "parquet") => {
+            return Err(AppError::BadRequest(
+                "Parquet output is not supported; use application/fhir+ndjson".to_string(),
+            ));
+        }
+        Some(other) => {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported outputFormat '{}'",
+                other
+            )));
+        }
+    }
+
+    let mut filter = ExportFilter {
+        types: vec!["Patient".to_string()],
+        deidentify: Some(profile),
+        ..ExportFilter::default()
+    };
+    if let Some(criteria) = body.criteria.as_deref() {
+        let (resource_type, search) = parse_type_filter(&format!("Patient?{}", criteria))?;
+        filter.type_filters.insert(resource_type, vec![search]);
+    }
+
+    tracing::info!(profile = profile.code(), "Cohort extract requested");
+    kick_off(pool, &manager, &headers, &uri, filter)
+}
+
+/// GET /fhir/$export-status/{id} - Poll an export job
+///
+/// Returns 202 with `X-Progress` while running and the completion manifest
+/// once done.
+pub async fn status(
+    Extension(manager): Extension<ExportManager>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let job = manager
+        .get(id)
+        .ok_or_else(|| AppError::NotFound(format!("Export job {} not found", id)))?;
+
+    match job.state {
+        ExportState::InProgress => {
+            let progress = match job.total {
+                Some(total) => format!("{}/{} resources exported", job.exported, total),
+                None => "counting resources".to_string(),
+            };
+            Ok((
+                StatusCode::ACCEPTED,
+                [
+                    ("X-Progress", HeaderValue::from_str(&progress).unwrap()),
+                    ("Retry-After", HeaderValue::from_static("5")),
+                ],
+            )
+                .into_response())
+        }
+        ExportState::Failed(error) => Err(AppError::Internal(format!("Export failed: {}", error))),
+        ExportState::Completed => {
+            let base = manager.base_url();
+            let output: Vec<_> = job
+                .files
+                .iter()
+                .map(|f| {
+                    json!({
+                        "type": f.resource_type,
+                        "url": UrlBuilder::new(base)
+                            .segment("fhir")
+                            .segment("$export-file")
+                            .segment(id)
+                            .segment(&f.name)
+                            .build(),
+                        "count": f.count,
+                    })
+                })
+                .collect();
+
+            Ok(Json(json!({
+                "transactionTime": job.transaction_time,
+                "request": job.request,
+                "requiresAccessToken": true,
+                "output": output,
+                "error": [],
+            }))
+            .into_response())
+        }
+    }
+}
+
+/// DELETE /fhir/$export-status/{id} - Cancel a job and delete its output
+pub async fn cancel(
+    Extension(manager): Extension<ExportManager>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    if manager.cancel(id).await {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(AppError::NotFound(format!("Export job {} not found", id)))
+    }
+}
+
+/// GET /fhir/$export-file/{id}/{file} - Download an NDJSON output file
+pub async fn download(
+    Extension(manager): Extension<ExportManager>,
+    Path((id, name)): Path<(Uuid, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let path = manager
+        .file_path(id, &name)
+        .ok_or_else(|| AppError::NotFound(format!("Export file {} not found", name)))?;
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| AppError::NotFound(format!("Export file {} not found", name)))?;
+
+    let chunks = futures_util::stream::try_unfold(file, |mut file| async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        buf.truncate(n);
+        Ok(Some((buf, file)))
+    });
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/fhir+ndjson"),
+        )],
+        Body::from_stream(chunks),
+    ))
+}
@@ -1,9 +1,11 @@
 //! Health check endpoint
 
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum::{Extension, Json, extract::State, http::StatusCode, response::IntoResponse};
 use deadpool_postgres::Pool;
 use serde::Serialize;
 
+use crate::db::{BreakerState, DbBreaker, SchemaCheck};
+
 /// Health check response
 #[derive(Serialize)]
 pub struct HealthResponse {
@@ -46,3 +48,66 @@ pub async fn check(State(pool): State<Pool>) -> impl IntoResponse {
         }
     }
 }
+
+/// Readiness response
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// State of the database circuit breaker: `closed`, `open` or `half-open`
+    breaker: &'static str,
+    checks: Vec<SchemaCheck>,
+}
+
+/// GET /readyz - Report the schema self-check and the database circuit
+/// breaker; `503` until every required table, index and function is in
+/// place, and while the breaker is open
+pub async fn ready(
+    State(pool): State<Pool>,
+    Extension(breaker): Extension<DbBreaker>,
+) -> impl IntoResponse {
+    let breaker = breaker.state();
+    // The schema is not checked against a database known to be failing
+    if breaker == BreakerState::Open {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ReadyResponse {
+                status: "not-ready".to_string(),
+                reason: Some("Database circuit breaker is open".to_string()),
+                breaker: breaker.as_str(),
+                checks: Vec::new(),
+            }),
+        );
+    }
+
+    match crate::db::readiness(&pool).await {
+        Ok(report) => {
+            let status = match report.ready {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            (
+                status,
+                Json(ReadyResponse {
+                    status: if report.ready { "ready" } else { "not-ready" }.to_string(),
+                    reason: None,
+                    breaker: breaker.as_str(),
+                    checks: report.checks,
+                }),
+            )
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Readiness check failed");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ReadyResponse {
+                    status: "not-ready".to_string(),
+                    reason: Some(format!("Schema check failed: {}", e)),
+                    breaker: breaker.as_str(),
+                    checks: Vec::new(),
+                }),
+            )
+        }
+    }
+}
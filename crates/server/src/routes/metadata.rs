@@ -1,9 +1,30 @@
 //! Metadata endpoint handler
 
-use axum::Json;
-use fhir_core::CapabilityStatement;
+use std::sync::Arc;
+
+use axum::{Extension, Json};
+use fhir_core::{CapabilityStatement, PackageRegistry};
+
+use crate::ai::AiOperations;
+
+/// AI operations and the resource type they run on (`None`: system level)
+const AI_OPERATIONS: [(Option<&str>, &str); 3] = [
+    (Some("Patient"), "nl-search"),
+    (Some("Patient"), "generate"),
+    (None, "chat"),
+];
 
 /// GET /metadata - Return server capability statement
-pub async fn get() -> Json<CapabilityStatement> {
-    Json(CapabilityStatement::new())
+pub async fn get(
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(ai_operations): Extension<AiOperations>,
+) -> Json<CapabilityStatement> {
+    let mut statement = CapabilityStatement::with_packages(&packages);
+    crate::db::restrict_capabilities(&mut statement);
+    for (resource_type, name) in AI_OPERATIONS {
+        if ai_operations.available(name) {
+            statement.add_operation(resource_type, name);
+        }
+    }
+    Json(statement)
 }
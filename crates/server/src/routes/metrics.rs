@@ -1,9 +1,37 @@
 //! Prometheus metrics endpoint
 
-use axum::{Extension, response::IntoResponse};
+use axum::{
+    Extension,
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
 use metrics_exporter_prometheus::PrometheusHandle;
 
-/// GET /metrics - Render collected metrics in Prometheus text format
-pub async fn get(Extension(handle): Extension<PrometheusHandle>) -> impl IntoResponse {
-    handle.render()
+use crate::middleware::metrics::Exemplars;
+
+/// Content type of the OpenMetrics text format
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// GET /metrics - Render collected metrics in Prometheus text format, or in
+/// OpenMetrics format with latency exemplars when the scraper asks for it
+pub async fn get(
+    Extension(handle): Extension<PrometheusHandle>,
+    Extension(exemplars): Extension<Exemplars>,
+    headers: HeaderMap,
+) -> Response {
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    if !openmetrics {
+        return handle.render().into_response();
+    }
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(OPENMETRICS_CONTENT_TYPE),
+        )],
+        exemplars.openmetrics(&handle.render()),
+    )
+        .into_response()
 }
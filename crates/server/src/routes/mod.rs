@@ -1,30 +1,176 @@
 //! HTTP route definitions
 
+mod admin;
+mod bundle;
+mod concept_map;
+mod export;
 pub mod health;
 pub mod metadata;
 pub mod metrics;
+mod naming_system;
 mod operations;
+mod params;
 mod patient;
+mod resource;
+mod structure_definition;
+mod versions;
 
 use axum::{
     Router,
-    routing::{get, post},
+    routing::{MethodRouter, get, post},
 };
 use deadpool_postgres::Pool;
 
 /// Build FHIR routes
 pub fn fhir_routes() -> Router<Pool> {
+    Router::new()
+        .route("/", requires("fhir_transaction", post(bundle::process)))
+        .merge(patient_routes())
+        .route(
+            "/ConceptMap",
+            get(concept_map::search).post(concept_map::create),
+        )
+        .route(
+            "/ConceptMap/$translate",
+            get(concept_map::translate).post(concept_map::translate_post),
+        )
+        .route("/ConceptMap/{id}", get(concept_map::read))
+        .route(
+            "/ConceptMap/{id}/$translate",
+            get(concept_map::translate_instance),
+        )
+        .route(
+            "/NamingSystem",
+            get(naming_system::search).post(naming_system::create),
+        )
+        .route(
+            "/NamingSystem/$preferred-id",
+            get(naming_system::preferred_id),
+        )
+        .route("/NamingSystem/{id}", get(naming_system::read))
+        .route("/StructureDefinition", get(structure_definition::search))
+        .route(
+            "/StructureDefinition/$snapshot",
+            get(structure_definition::snapshot_by_url).post(structure_definition::snapshot),
+        )
+        .route("/StructureDefinition/{id}", get(structure_definition::read))
+        .route(
+            "/StructureDefinition/{id}/$meta",
+            get(structure_definition::meta),
+        )
+        .route("/$versions", get(versions::get))
+        .route("/$export", get(export::system_export))
+        .route(
+            "/$export-status/{id}",
+            get(export::status).delete(export::cancel),
+        )
+        .route("/$export-file/{id}/{file}", get(export::download))
+        .route(
+            "/{resource_type}",
+            get(resource::search).post(resource::create),
+        )
+        .route(
+            "/{resource_type}/{id}",
+            get(resource::read)
+                .put(resource::update)
+                .delete(resource::delete),
+        )
+        .merge(ai_routes())
+}
+
+/// Patient routes, which also speak FHIR XML
+fn patient_routes() -> Router<Pool> {
     Router::new()
         .route("/Patient", get(patient::search).post(patient::create))
+        .route("/Patient/_search", post(patient::search_post))
         .route(
             "/Patient/{id}",
             get(patient::read)
                 .put(patient::update)
+                .patch(patient::patch)
                 .delete(patient::delete),
         )
-        .route("/Patient/{id}/_history", get(patient::history))
+        .route(
+            "/Patient/_history",
+            requires("fhir_history_since", get(patient::type_history)),
+        )
+        .route(
+            "/Patient/{id}/_history",
+            requires("fhir_history", get(patient::history)),
+        )
+        .route(
+            "/Patient/{id}/_history/{vid}",
+            requires("fhir_get_version", get(patient::vread)),
+        )
+        .route(
+            "/Patient/{id}/$lock",
+            requires("fhir_lock", post(patient::lock)),
+        )
+        .route(
+            "/Patient/{id}/$unlock",
+            requires("fhir_unlock", post(patient::unlock)),
+        )
         .route("/Patient/$validate", post(patient::validate))
-        .route("/Patient/$nl-search", post(operations::nl_search))
-        .route("/Patient/$generate", post(operations::generate))
-        .route("/$chat", post(operations::chat))
+        .route("/Patient/$export", get(export::patient_export))
+        .route("/Patient/$extract-cohort", post(export::extract_cohort))
+        .route_layer(axum::middleware::from_fn(crate::middleware::xml_middleware))
+}
+
+/// `handler`, answering `501` when the extension lacks `function`
+fn requires(function: &'static str, handler: MethodRouter<Pool>) -> MethodRouter<Pool> {
+    handler.route_layer(axum::middleware::from_fn_with_state(
+        function,
+        crate::middleware::require_function_middleware,
+    ))
+}
+
+/// `handler`, answering `403` to callers that are not admin clients
+fn admin_only(handler: MethodRouter<Pool>) -> MethodRouter<Pool> {
+    handler.route_layer(axum::middleware::from_fn(
+        crate::middleware::require_admin_middleware,
+    ))
+}
+
+/// `handler`, answering `501` when the AI operation `code` is switched off
+fn ai_operation(code: &'static str, handler: MethodRouter<Pool>) -> MethodRouter<Pool> {
+    handler.route_layer(axum::middleware::from_fn_with_state(
+        code,
+        crate::middleware::require_ai_operation_middleware,
+    ))
+}
+
+/// AI operations, behind their own rate, concurrency and payload limits
+fn ai_routes() -> Router<Pool> {
+    Router::new()
+        .route(
+            "/Patient/$nl-search",
+            ai_operation("nl-search", post(operations::nl_search)),
+        )
+        .route(
+            "/Patient/$generate",
+            ai_operation("generate", post(operations::generate)),
+        )
+        .route("/$chat", ai_operation("chat", post(operations::chat)))
+        .route_layer(axum::middleware::from_fn(
+            crate::middleware::ai_limit_middleware,
+        ))
+}
+
+/// Build administrative routes
+pub fn admin_routes() -> Router<Pool> {
+    Router::new()
+        .route("/stats", get(admin::stats))
+        .route(
+            "/export",
+            admin_only(requires("fhir_export_snapshot", get(admin::export))),
+        )
+        .route("/ai-audit", admin_only(get(admin::ai_audit)))
+        .route(
+            "/log-level",
+            admin_only(
+                get(admin::get_log_level)
+                    .put(admin::set_log_level)
+                    .delete(admin::reset_log_level),
+            ),
+        )
 }
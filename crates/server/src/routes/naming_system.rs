@@ -0,0 +1,162 @@
+//! NamingSystem handlers: registry and `$preferred-id`
+//!
+//! Stored NamingSystems map the OIDs and URIs an identifier or code system is
+//! known by; `$preferred-id` resolves one to the other.
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use deadpool_postgres::Pool;
+use fhir_core::naming_system;
+use fhir_core::{Bundle, BundleEntry, FhirVersion};
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+use uuid::Uuid;
+
+use crate::db::ConformanceRepository;
+use crate::error::AppError;
+use crate::middleware::fhir_version::base_path;
+use crate::urls::UrlBuilder;
+
+const RESOURCE_TYPE: &str = "NamingSystem";
+
+fn repository(pool: Pool) -> ConformanceRepository {
+    ConformanceRepository::new(pool, RESOURCE_TYPE)
+}
+
+/// Query parameters for NamingSystem search
+#[derive(Debug, Deserialize, Default)]
+pub struct SearchParams {
+    /// A unique id (OID or URI) of the system
+    pub value: Option<String>,
+}
+
+/// Input parameters of `$preferred-id`
+#[derive(Debug, Deserialize)]
+pub struct PreferredIdParams {
+    /// The known id
+    pub id: String,
+    /// Type of id wanted (`oid`, `uri`, `uuid`, `other`)
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+fn validate(body: &JsonValue) -> Result<(), AppError> {
+    if body.get("resourceType").and_then(|v| v.as_str()) != Some(RESOURCE_TYPE) {
+        return Err(AppError::BadRequest(
+            "Expected a NamingSystem resource".to_string(),
+        ));
+    }
+    if naming_system::unique_ids(body).is_empty() {
+        return Err(AppError::BadRequest(
+            "NamingSystem requires at least one uniqueId with type and value".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// POST /fhir/NamingSystem - Register a NamingSystem
+///
+/// Rejects a NamingSystem sharing a unique id with a registered one, since
+/// resolution would otherwise be ambiguous.
+pub async fn create(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Json(body): Json<JsonValue>,
+) -> Result<impl IntoResponse, AppError> {
+    validate(&body)?;
+    let repo = repository(pool);
+    let registered = repo.all().await?;
+    for unique_id in naming_system::unique_ids(&body) {
+        if let Some((id, _)) = registered
+            .iter()
+            .find(|(_, ns)| naming_system::identifies(ns, &unique_id.value))
+        {
+            return Err(AppError::Conflict(format!(
+                "'{}' is already registered by NamingSystem/{}",
+                unique_id.value, id
+            )));
+        }
+    }
+    let id = repo.create(body).await?;
+
+    tracing::info!(naming_system_id = %id, "NamingSystem created");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::LOCATION,
+        UrlBuilder::new(base_path(version))
+            .segment("NamingSystem")
+            .segment(id)
+            .header(),
+    );
+    headers.insert("ETag", "W/\"1\"".parse().unwrap());
+
+    Ok((StatusCode::CREATED, headers))
+}
+
+/// GET /fhir/NamingSystem/{id} - Read a NamingSystem
+pub async fn read(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut data = repository(pool)
+        .get(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("NamingSystem/{} not found", id)))?;
+    data["id"] = JsonValue::String(id.to_string());
+    Ok(Json(data))
+}
+
+/// GET /fhir/NamingSystem - Search registered NamingSystems by unique id
+pub async fn search(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Query(params): Query<SearchParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let systems = repository(pool).all().await?;
+    let entries: Vec<BundleEntry> = systems
+        .into_iter()
+        .filter(|(_, ns)| {
+            params
+                .value
+                .as_deref()
+                .is_none_or(|value| naming_system::identifies(ns, value))
+        })
+        .map(|(id, ns)| {
+            BundleEntry::new(
+                Some(format!("{}/NamingSystem/{}", base_path(version), id)),
+                ns,
+            )
+        })
+        .collect();
+    Ok(Json(Bundle::searchset(entries.len() as u32, entries)))
+}
+
+/// GET /fhir/NamingSystem/$preferred-id?id=&type= - Resolve an id of a
+/// system to its preferred id of another type
+pub async fn preferred_id(
+    State(pool): State<Pool>,
+    Query(params): Query<PreferredIdParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let systems: Vec<JsonValue> = repository(pool)
+        .all()
+        .await?
+        .into_iter()
+        .map(|(_, ns)| ns)
+        .collect();
+    let result = naming_system::resolve(&systems, &params.id, &params.kind).ok_or_else(|| {
+        AppError::NotFound(format!(
+            "No NamingSystem maps '{}' to an id of type '{}'",
+            params.id, params.kind
+        ))
+    })?;
+
+    Ok(Json(json!({
+        "resourceType": "Parameters",
+        "parameter": [{"name": "result", "valueString": result}],
+    })))
+}
@@ -1,14 +1,25 @@
 //! AI-powered operation endpoints ($nl-search, $generate, $chat)
 
-use axum::{Extension, Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum::{
+    Extension, Json,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
 use deadpool_postgres::Pool;
-use fhir_core::{Bundle, BundleEntry};
+use fhir_core::{Bundle, BundleEntry, NormalizedName, OperationOutcome};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use tracing::Instrument;
 
-use crate::ai::ClaudeClient;
+use crate::ai::chatbot::{ChatError, ChatLimits, ToolCall};
+use crate::ai::{AiAudit, AiInteraction, ClaudeClient, guard};
+use crate::config::AiOutputGuard;
 use crate::db::PatientRepository;
 use crate::error::AppError;
+use crate::middleware::request_id::RequestId;
+use crate::webhook::WebhookNotifier;
+use crate::write_policy::{WriteContext, WriteInteraction, WritePolicies};
 
 /// Request body for natural language search
 #[derive(Deserialize)]
@@ -20,25 +31,93 @@ pub struct NlSearchRequest {
 #[derive(Deserialize)]
 pub struct GenerateRequest {
     count: Option<u32>,
+    #[serde(default)]
+    duplicates: DuplicatePolicy,
 }
 
+/// What `$generate` does with a generated patient that matches an existing
+/// one (or one generated earlier in the same request)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Store it anyway
+    #[default]
+    Allow,
+    /// Leave it out
+    Skip,
+    /// Leave it out and ask for a replacement
+    Regenerate,
+}
+
+/// Extra generation rounds `DuplicatePolicy::Regenerate` spends on
+/// replacing duplicates before giving up
+const MAX_REGENERATE_ROUNDS: u32 = 2;
+
+/// Generated patients stored at the same time, each on its own connection
+const MAX_CONCURRENT_STORES: usize = 8;
+
 /// Response body for patient generation
 #[derive(Serialize)]
 pub struct GenerateResponse {
     created: u32,
+    /// Generated duplicates that were not stored
+    skipped: u32,
+    /// Generated patients that could not be stored
+    failed: u32,
+    /// The stored patients
     resources: Vec<JsonValue>,
+    /// What happened to each generated patient that was not skipped, in
+    /// generation order
+    outcomes: Vec<StoreOutcome>,
+}
+
+/// Result of storing one generated patient
+#[derive(Serialize)]
+pub struct StoreOutcome {
+    /// HTTP status a create of this patient alone would have returned
+    status: u16,
+    /// Id of the stored patient
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Why the patient was not stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<OperationOutcome>,
+}
+
+impl StoreOutcome {
+    fn created(id: uuid::Uuid) -> Self {
+        Self {
+            status: StatusCode::CREATED.as_u16(),
+            id: Some(id.to_string()),
+            outcome: None,
+        }
+    }
+
+    fn failed(error: AppError) -> Self {
+        let (status, outcome) = error.status_and_outcome();
+        Self {
+            status: status.as_u16(),
+            id: None,
+            outcome: Some(outcome),
+        }
+    }
 }
 
 /// Request body for chat
 #[derive(Deserialize)]
 pub struct ChatRequest {
     message: String,
+    /// Include the tool calls made to answer in the response
+    #[serde(default)]
+    trace: bool,
 }
 
 /// Response body for chat
 #[derive(Serialize)]
 pub struct ChatResponse {
     response: String,
+    #[serde(rename = "toolCalls", skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
 }
 
 /// POST /fhir/Patient/$nl-search — Natural language patient search
@@ -48,24 +127,79 @@ pub struct ChatResponse {
 pub async fn nl_search(
     State(pool): State<Pool>,
     Extension(client): Extension<Option<ClaudeClient>>,
+    Extension(audit): Extension<AiAudit>,
+    Extension(output_guard): Extension<AiOutputGuard>,
+    request_id: Option<Extension<RequestId>>,
     Json(body): Json<NlSearchRequest>,
 ) -> Result<impl IntoResponse, AppError> {
-    let client =
-        client.ok_or_else(|| AppError::Internal("ANTHROPIC_API_KEY not configured".to_string()))?;
-
     tracing::info!(query = &body.query, "Natural language search");
 
-    // Convert natural language to FHIR search params via Claude
-    let params = crate::ai::nl_search::convert_to_params(&client, &body.query)
-        .await
-        .map_err(|e| AppError::Internal(format!("AI search conversion failed: {}", e)))?;
+    // Convert natural language to FHIR search params via Claude, falling
+    // back to keyword rules when no key is configured or the call fails
+    let converted = match client {
+        Some(client) => {
+            let (client, usage) = client.metered();
+            match crate::ai::nl_search::convert_to_params(&client, &body.query).await {
+                Ok(params) => Some((params, client.model().to_string(), usage)),
+                Err(e) => {
+                    tracing::warn!(error = %e, "AI search conversion failed, using keyword rules");
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let (params, model) = match converted {
+        Some((params, model, usage)) => (params, Some((model, usage))),
+        None => (crate::ai::nl_search::parse_rules(&body.query), None),
+    };
 
     tracing::info!(params = %params, "Converted NL query to FHIR params");
 
+    // Parts of the query the conversion could not express are reported
+    // rather than silently broadening the search
+    let (params, dropped) = crate::ai::nl_search::split_unsupported(params);
+    let params = crate::search_values::normalize(params);
+
     // Execute the search
-    let repo = PatientRepository::new(pool);
+    let repo = PatientRepository::new(pool.clone());
     let results = repo.search(params.clone()).await?;
-    let total = repo.count(params).await? as u32;
+    let total = repo.count(params.clone()).await? as u32;
+
+    // Warnings echo model output, so they may only mention patients found
+    let found: Vec<uuid::Uuid> = results.iter().map(|(id, _)| *id).collect();
+    let mut issues = Vec::with_capacity(dropped.len());
+    for msg in &dropped {
+        let msg = guard::screen(output_guard, &repo, msg, &found).await;
+        issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::NotSupported,
+            &msg,
+        ));
+    }
+
+    // Only conversions made by the model are audited
+    match model {
+        Some((model, usage)) => audit.record(
+            pool,
+            AiInteraction {
+                request_id: request_id.map(|Extension(RequestId(id))| id),
+                operation: "nl-search",
+                model,
+                prompt: body.query.clone(),
+                response: params.to_string(),
+                tool_calls: JsonValue::Array(Vec::new()),
+                resources: results
+                    .iter()
+                    .map(|(id, _)| format!("Patient/{}", id))
+                    .collect(),
+                usage: usage.total(),
+            },
+        ),
+        None => issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::Informational,
+            "No language model was available; the query was interpreted with keyword rules",
+        )),
+    }
 
     // Build bundle response
     let entries: Vec<BundleEntry> = results
@@ -73,54 +207,215 @@ pub async fn nl_search(
         .map(|(id, data)| BundleEntry::new(Some(format!("/fhir/Patient/{}", id)), data))
         .collect();
 
-    let bundle = Bundle::searchset(total, entries);
+    let mut bundle = Bundle::searchset(total, entries);
+    bundle.add_outcome(fhir_core::OperationOutcome::from_issues(issues));
     Ok(Json(bundle))
 }
 
+/// `(system, value)` pairs of a resource's identifiers
+fn identifier_keys(resource: &JsonValue) -> Vec<(Option<&str>, &str)> {
+    resource
+        .get("identifier")
+        .and_then(JsonValue::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|i| {
+            let value = i.get("value").and_then(JsonValue::as_str)?;
+            Some((i.get("system").and_then(JsonValue::as_str), value))
+        })
+        .collect()
+}
+
+/// Whether two patients are the same person: they share an identifier, or
+/// have the same birth date and a name that is the same after normalization
+fn same_patient(a: &JsonValue, b: &JsonValue) -> bool {
+    let b_identifiers = identifier_keys(b);
+    if identifier_keys(a).iter().any(|k| b_identifiers.contains(k)) {
+        return true;
+    }
+
+    let birth_date = |p: &JsonValue| p.get("birthDate").and_then(JsonValue::as_str);
+    if birth_date(a).is_none() || birth_date(a) != birth_date(b) {
+        return false;
+    }
+    let b_names = NormalizedName::all_from_resource(b);
+    NormalizedName::all_from_resource(a)
+        .iter()
+        .any(|name| b_names.iter().any(|other| name.same_name(other)))
+}
+
+/// Whether `patient` duplicates a stored patient or one of `accepted`
+async fn is_duplicate(
+    repo: &PatientRepository,
+    patient: &JsonValue,
+    accepted: &[JsonValue],
+) -> Result<bool, AppError> {
+    if accepted.iter().any(|other| same_patient(patient, other)) {
+        return Ok(true);
+    }
+    let existing = repo
+        .duplicate_candidates(patient)
+        .await?
+        .into_iter()
+        .find(|(_, other)| same_patient(patient, other));
+    if let Some((id, _)) = existing {
+        tracing::info!(patient_id = %id, "Generated patient duplicates an existing one");
+    }
+    Ok(existing.is_some())
+}
+
 /// POST /fhir/Patient/$generate — Generate synthetic patient data
 ///
 /// Uses Claude to generate realistic FHIR R4 Patient resources, stores them
-/// in the database, and returns the created resources.
+/// in the database concurrently, and returns the created resources with an
+/// outcome for every patient: its id, or why it was rejected by a write
+/// policy or could not be stored. With `"duplicates": "skip"`
+/// patients matching an existing one by identifier or by name and birth date
+/// are left out; `"regenerate"` also asks for replacements.
 pub async fn generate(
     State(pool): State<Pool>,
     Extension(client): Extension<Option<ClaudeClient>>,
+    Extension(audit): Extension<AiAudit>,
+    Extension(policies): Extension<WritePolicies>,
+    request_id: Option<Extension<RequestId>>,
+    headers: HeaderMap,
     Json(body): Json<GenerateRequest>,
 ) -> Result<impl IntoResponse, AppError> {
     let client =
         client.ok_or_else(|| AppError::Internal("ANTHROPIC_API_KEY not configured".to_string()))?;
+    let (client, usage) = client.metered();
 
     let count = body.count.unwrap_or(5).min(50); // Cap at 50 to avoid abuse
     tracing::info!(count = count, "Generating synthetic patients");
 
     // Generate patients via Claude
-    let patients = crate::ai::generator::generate_patients(&client, count)
-        .await
-        .map_err(|e| AppError::Internal(format!("AI generation failed: {}", e)))?;
+    let generate = |count: u32| {
+        let client = &client;
+        async move {
+            crate::ai::generator::generate_patients(client, count)
+                .await
+                .map_err(|e| AppError::Internal(format!("AI generation failed: {}", e)))
+        }
+    };
+    let repo = PatientRepository::new(pool.clone());
+    let mut pending = generate(count).await?;
+    let mut patients = Vec::new();
+    let mut skipped = 0;
+    let mut round = 0;
+    loop {
+        for patient in pending {
+            if body.duplicates != DuplicatePolicy::Allow
+                && is_duplicate(&repo, &patient, &patients).await?
+            {
+                skipped += 1;
+            } else {
+                patients.push(patient);
+            }
+        }
+
+        let missing = count.saturating_sub(patients.len() as u32);
+        if body.duplicates != DuplicatePolicy::Regenerate
+            || missing == 0
+            || round == MAX_REGENERATE_ROUNDS
+        {
+            break;
+        }
+        round += 1;
+        pending = generate(missing).await?;
+    }
+
+    // Store the generated patients independently, a bounded number at a
+    // time; a patient that fails is reported without affecting the others
+    let context = WriteContext::new("Patient", WriteInteraction::Create, &headers);
+    let mut stored: Vec<Option<Result<uuid::Uuid, AppError>>> =
+        (0..patients.len()).map(|_| None).collect();
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, patient) in patients.iter().enumerate() {
+        if let Err(e) = policies.evaluate(None, patient, &context) {
+            stored[index] = Some(Err(e));
+            continue;
+        }
+        if tasks.len() == MAX_CONCURRENT_STORES {
+            let joined = tasks.join_next().await;
+            if let Some(Ok((index, result))) = joined {
+                stored[index] = Some(result);
+            }
+        }
+        let repo = repo.clone();
+        let patient = patient.clone();
+        tasks.spawn(
+            crate::db::inherit_request_scope(async move { (index, repo.create(patient).await) })
+                .instrument(tracing::Span::current()),
+        );
+    }
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok((index, result)) = joined {
+            stored[index] = Some(result);
+        }
+    }
 
-    // Store each generated patient in the database
-    let repo = PatientRepository::new(pool);
     let mut created = Vec::new();
-    for patient in patients {
-        match repo.create(patient.clone()).await {
+    let mut outcomes = Vec::new();
+    for (mut resource, result) in patients.into_iter().zip(stored) {
+        // A task that panicked left no result
+        let result = result.unwrap_or_else(|| {
+            Err(AppError::Internal(
+                "Storing the patient failed unexpectedly".to_string(),
+            ))
+        });
+        match result {
             Ok(id) => {
-                tracing::info!(patient_id = %id, "Generated patient stored");
-                let mut resource = patient;
                 if let Some(obj) = resource.as_object_mut() {
                     obj.insert("id".to_string(), JsonValue::String(id.to_string()));
                 }
                 created.push(resource);
+                outcomes.push(StoreOutcome::created(id));
             }
             Err(e) => {
-                tracing::warn!(error = ?e, "Failed to store generated patient");
+                tracing::warn!(error = %e, "Generated patient not stored");
+                outcomes.push(StoreOutcome::failed(e));
             }
         }
     }
+    let failed = (outcomes.len() - created.len()) as u32;
+    tracing::info!(
+        count = created.len(),
+        skipped = skipped,
+        failed = failed,
+        "Generated patients stored"
+    );
+
+    audit.record(
+        pool,
+        AiInteraction {
+            request_id: request_id.map(|Extension(RequestId(id))| id),
+            operation: "generate",
+            model: client.model().to_string(),
+            prompt: format!("count={}", count),
+            response: format!(
+                "created {} patients, skipped {} duplicates, {} failed",
+                created.len(),
+                skipped,
+                failed
+            ),
+            tool_calls: JsonValue::Array(Vec::new()),
+            resources: created
+                .iter()
+                .filter_map(|r| r.get("id").and_then(JsonValue::as_str))
+                .map(|id| format!("Patient/{}", id))
+                .collect(),
+            usage: usage.total(),
+        },
+    );
 
     Ok((
         StatusCode::CREATED,
         Json(GenerateResponse {
             created: created.len() as u32,
+            skipped,
+            failed,
             resources: created,
+            outcomes,
         }),
     ))
 }
@@ -132,17 +427,53 @@ pub async fn generate(
 pub async fn chat(
     State(pool): State<Pool>,
     Extension(client): Extension<Option<ClaudeClient>>,
+    Extension(audit): Extension<AiAudit>,
+    Extension(output_guard): Extension<AiOutputGuard>,
+    Extension(limits): Extension<ChatLimits>,
+    Extension(notifier): Extension<WebhookNotifier>,
+    request_id: Option<Extension<RequestId>>,
     Json(body): Json<ChatRequest>,
 ) -> Result<impl IntoResponse, AppError> {
     let client =
         client.ok_or_else(|| AppError::Internal("ANTHROPIC_API_KEY not configured".to_string()))?;
+    let (client, usage) = client.metered();
 
     tracing::info!(message = &body.message, "Chat request");
 
-    let repo = PatientRepository::new(pool);
-    let response = crate::ai::chatbot::chat(&client, &repo, &body.message)
+    let repo = PatientRepository::new(pool.clone());
+    let mut reply = crate::ai::chatbot::chat(&client, &repo, &body.message, &limits)
         .await
-        .map_err(|e| AppError::Internal(format!("Chat failed: {}", e)))?;
+        .map_err(|e| match e {
+            ChatError::TimedOut(budget) => {
+                notifier.record_budget_exhausted("ai_chat_time_ms", budget.as_millis() as u64);
+                AppError::Timeout(e.to_string())
+            }
+            ChatError::Failed(msg) => AppError::Internal(format!("Chat failed: {}", msg)),
+        })?;
+
+    // Only patients returned by the tools may appear in the reply
+    reply.text = guard::screen(output_guard, &repo, &reply.text, &reply.patients).await;
+
+    audit.record(
+        pool,
+        AiInteraction {
+            request_id: request_id.map(|Extension(RequestId(id))| id),
+            operation: "chat",
+            model: client.model().to_string(),
+            prompt: body.message.clone(),
+            response: reply.text.clone(),
+            tool_calls: serde_json::to_value(&reply.tool_calls).unwrap_or_default(),
+            resources: reply
+                .patients
+                .iter()
+                .map(|id| format!("Patient/{}", id))
+                .collect(),
+            usage: usage.total(),
+        },
+    );
 
-    Ok(Json(ChatResponse { response }))
+    Ok(Json(ChatResponse {
+        response: reply.text,
+        tool_calls: body.trace.then_some(reply.tool_calls),
+    }))
 }
@@ -0,0 +1,14 @@
+//! Shared query parameter parsing helpers
+
+use crate::error::AppError;
+
+/// Parse a FHIR instant (RFC 3339) and normalize it to UTC (`Z`) so the
+/// value can be passed to PostgreSQL and echoed safely in links
+pub fn parse_instant(name: &str, value: &str) -> Result<String, AppError> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|dt| {
+            dt.with_timezone(&chrono::Utc)
+                .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+        })
+        .map_err(|e| AppError::BadRequest(format!("Invalid {} instant: {}", name, e)))
+}
@@ -1,51 +1,188 @@
 //! Patient resource HTTP handlers
 
+use std::sync::Arc;
+
 use axum::{
-    Json,
-    extract::{Path, Query, State},
-    http::{HeaderMap, StatusCode, header},
-    response::IntoResponse,
+    Extension, Json,
+    body::Body,
+    extract::{Path, Query, RawForm, State},
+    http::{HeaderMap, HeaderValue, StatusCode, Uri, header},
+    response::{IntoResponse, Response},
 };
 use deadpool_postgres::Pool;
-use fhir_core::{Bundle, BundleEntry};
-use serde::Deserialize;
+use fhir_core::convert::patient_to;
+use fhir_core::json_patch;
+use fhir_core::narrative::check_div;
+use fhir_core::{Bundle, BundleEntry, FhirError, FhirVersion, HttpVerb, PackageRegistry};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use uuid::Uuid;
 
+use super::params::parse_instant;
+use crate::config::{NarrativePolicy, PhotoLimit, ReplacedRedirect};
 use crate::db::PatientRepository;
 use crate::error::AppError;
+use crate::geocode::Geocoding;
+use crate::middleware::Principal;
+use crate::middleware::fhir_version::base_path;
+use crate::urls::UrlBuilder;
+use crate::write_policy::{WriteContext, WriteInteraction, WritePolicies};
 
-/// Query parameters for patient search
+/// Query parameters for patient search, built by [`SearchParams::from_query`]
 #[derive(Debug, Deserialize, Default)]
 pub struct SearchParams {
-    pub name: Option<String>,
-    pub gender: Option<String>,
-    pub birthdate: Option<String>,
+    /// Built-in search parameters as `(code[:modifier], value)` in query
+    /// order; a repeated code must match every value and a value may list
+    /// comma-separated alternatives
+    #[serde(skip)]
+    pub filters: Vec<(String, String)>,
     #[serde(rename = "_count")]
     pub count: Option<i64>,
     #[serde(rename = "_offset")]
     pub offset: Option<i64>,
     #[serde(rename = "_sort")]
     pub sort: Option<String>,
+    #[serde(rename = "_asOf")]
+    pub as_of: Option<String>,
+    #[serde(rename = "_summary")]
+    pub summary: Option<String>,
+    #[serde(rename = "_total")]
+    pub total: Option<String>,
 }
 
-impl SearchParams {
-    /// Convert to JSON for the PGRX search function
-    fn to_json(&self) -> JsonValue {
-        let mut map = serde_json::Map::new();
+/// Query parameters for reading a single patient
+#[derive(Debug, Deserialize, Default)]
+pub struct ReadParams {
+    #[serde(rename = "_asOf")]
+    pub as_of: Option<String>,
+    #[serde(rename = "_summary")]
+    pub summary: Option<String>,
+}
 
-        if let Some(ref name) = self.name {
-            map.insert("name".to_string(), JsonValue::String(name.clone()));
-        }
-        if let Some(ref gender) = self.gender {
-            map.insert("gender".to_string(), JsonValue::String(gender.clone()));
+impl SearchParams {
+    /// Parse a search query. Search parameters may repeat and are kept in
+    /// [`Self::filters`]; of a repeated result parameter (`_count`, `_sort`,
+    /// ...) the first occurrence is used.
+    fn from_query(query: &[(String, String)]) -> Result<Self, AppError> {
+        let mut seen = std::collections::HashSet::new();
+        let first: Vec<&(String, String)> = query
+            .iter()
+            .filter(|(code, _)| seen.insert(code.as_str()))
+            .collect();
+        let first = serde_urlencoded::to_string(first)
+            .map_err(|e| AppError::BadRequest(format!("Invalid search parameters: {}", e)))?;
+        let mut params: SearchParams = serde_urlencoded::from_str(&first)
+            .map_err(|e| AppError::BadRequest(format!("Invalid search parameters: {}", e)))?;
+        params.filters = query
+            .iter()
+            .filter(|(code, _)| is_built_in(code))
+            .cloned()
+            .collect();
+        for (name, value) in [("_count", params.count), ("_offset", params.offset)] {
+            if value.is_some_and(|v| v < 0) {
+                return Err(AppError::BadRequest(format!(
+                    "{} must not be negative",
+                    name
+                )));
+            }
         }
-        if let Some(ref birthdate) = self.birthdate {
-            map.insert(
-                "birthdate".to_string(),
-                JsonValue::String(birthdate.clone()),
-            );
+        Ok(params)
+    }
+
+    /// The first value given for search parameter `code`
+    fn first(&self, code: &str) -> Option<&str> {
+        self.filters
+            .iter()
+            .find(|(c, _)| c == code)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// How resources are reduced (`_summary=true|text|data`), if at all
+    fn summary_mode(&self) -> Option<Summary> {
+        Summary::parse(self.summary.as_deref())
+    }
+
+    /// How the Bundle total is computed (`_total`, accurate by default)
+    fn total_mode(&self) -> TotalMode {
+        TotalMode::parse(self.total.as_deref()).unwrap_or(TotalMode::Accurate)
+    }
+
+    /// Whether only the match count was asked for (`_summary=count` or
+    /// `_count=0`), so no rows need to be fetched
+    fn count_only(&self) -> bool {
+        self.summary.as_deref() == Some("count") || self.count == Some(0)
+    }
+
+    /// The parameters a search with `json_params` (from [`Self::to_json`])
+    /// applies, for the Bundle links: normalized filter values including the
+    /// `active` default, IG filters, then the result parameters honoured.
+    /// Paging is left to the caller.
+    fn applied(&self, json_params: &JsonValue) -> Vec<(String, String)> {
+        let text = |value: &JsonValue| value.as_str().map(str::to_string);
+        let filters = json_params.as_object().into_iter().flatten();
+        // A repeated parameter is repeated in the link
+        let mut applied: Vec<(String, String)> = BUILT_IN_SEARCH_PARAMS
+            .iter()
+            .flat_map(|code| {
+                filters
+                    .clone()
+                    .filter(move |(key, _)| key.split(':').next() == Some(*code))
+            })
+            .flat_map(|(key, value)| {
+                let values = match value {
+                    JsonValue::Array(values) => values.iter().filter_map(text).collect(),
+                    value => text(value).into_iter().collect::<Vec<_>>(),
+                };
+                values.into_iter().map(|value| (key.clone(), value))
+            })
+            .collect();
+        for filter in json_params
+            .get("_paths")
+            .and_then(JsonValue::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if let (Some(code), Some(value)) = (
+                filter.get("code").and_then(text),
+                filter.get("value").and_then(text),
+            ) {
+                applied.push((code, value));
+            }
         }
+
+        let sorted = self.sort.as_deref().filter(|sort| {
+            let field = sort.strip_prefix('-').unwrap_or(sort);
+            SORT_FIELDS.contains(&field) && (field != "_score" || self.first("name").is_some())
+        });
+        let result = [
+            ("_sort", sorted.map(str::to_string)),
+            ("_asOf", self.as_of.clone()),
+            (
+                "_summary",
+                self.summary_mode().map(|s| s.code().to_string()),
+            ),
+            ("_total", self.total.clone()),
+        ];
+        applied.extend(
+            result
+                .into_iter()
+                .filter_map(|(code, value)| Some((code.to_string(), value?))),
+        );
+        applied
+    }
+
+    /// Convert to JSON for the PGRX search function
+    fn to_json(&self) -> JsonValue {
+        let mut map = crate::search_values::multimap(
+            self.filters
+                .iter()
+                .map(|(code, value)| (code.as_str(), value.as_str())),
+        );
+        // Patients that are inactive or replaced by another are only found
+        // when asked for with `active=false`
+        map.entry("active")
+            .or_insert_with(|| JsonValue::String("true".to_string()));
         if let Some(count) = self.count {
             map.insert("_count".to_string(), JsonValue::Number(count.into()));
         }
@@ -55,71 +192,799 @@ impl SearchParams {
         if let Some(ref sort) = self.sort {
             map.insert("_sort".to_string(), JsonValue::String(sort.clone()));
         }
+        if let Some(ref as_of) = self.as_of {
+            map.insert("_asOf".to_string(), JsonValue::String(as_of.clone()));
+        }
+
+        crate::search_values::normalize(JsonValue::Object(map))
+    }
+}
+
+/// How a search Bundle's `total` is computed (`_total`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TotalMode {
+    /// Count every match
+    Accurate,
+    /// Take the query planner's row estimate
+    Estimate,
+    /// Leave the total out; paging looks one row ahead instead
+    None,
+}
+
+impl TotalMode {
+    fn parse(value: Option<&str>) -> Option<Self> {
+        match value? {
+            "accurate" => Some(TotalMode::Accurate),
+            "estimate" => Some(TotalMode::Estimate),
+            "none" => Some(TotalMode::None),
+            _ => None,
+        }
+    }
+}
+
+/// Patient elements kept by `_summary=true` (those marked as summary
+/// elements in the specification); photos, contacts and the narrative are
+/// among those dropped
+const SUMMARY_ELEMENTS: &[&str] = &[
+    "resourceType",
+    "id",
+    "meta",
+    "implicitRules",
+    "modifierExtension",
+    "identifier",
+    "active",
+    "name",
+    "telecom",
+    "gender",
+    "birthDate",
+    "deceased[x]",
+    "address",
+    "managingOrganization",
+    "link",
+];
+
+/// Patient elements kept by `_summary=text`; Patient has no mandatory
+/// elements that would have to be kept as well
+const TEXT_ELEMENTS: &[&str] = &["resourceType", "id", "meta", "text"];
+
+/// Part of a resource returned for `_summary`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Summary {
+    /// The summary elements (`_summary=true`)
+    Elements,
+    /// The narrative, id and meta (`_summary=text`)
+    Text,
+    /// Everything but the narrative (`_summary=data`)
+    Data,
+}
+
+impl Summary {
+    /// The reduction a `_summary` value asks for; `false`, `count` and
+    /// unknown values keep resources whole
+    fn parse(value: Option<&str>) -> Option<Self> {
+        match value? {
+            "true" => Some(Summary::Elements),
+            "text" => Some(Summary::Text),
+            "data" => Some(Summary::Data),
+            _ => None,
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            Summary::Elements => "true",
+            Summary::Text => "text",
+            Summary::Data => "data",
+        }
+    }
+
+    fn keeps(self, element: &str) -> bool {
+        match self {
+            Summary::Elements => {
+                SUMMARY_ELEMENTS
+                    .iter()
+                    .any(|name| match name.strip_suffix("[x]") {
+                        Some(choice) => fhir_core::choice::variant_type(element, choice).is_some(),
+                        None => *name == element,
+                    })
+            }
+            Summary::Text => TEXT_ELEMENTS.contains(&element),
+            Summary::Data => element != "text",
+        }
+    }
+}
+
+/// Reduce a Patient to the elements of a summary mode, tagged `SUBSETTED`
+/// as the specification requires of partial resources
+fn summarize(mut data: JsonValue, summary: Summary) -> JsonValue {
+    let Some(patient) = data.as_object_mut() else {
+        return data;
+    };
+    patient.retain(|element, _| summary.keeps(element));
+    let meta = patient
+        .entry("meta")
+        .or_insert_with(|| serde_json::json!({}));
+    if let Some(meta) = meta.as_object_mut() {
+        let tags = meta.entry("tag").or_insert_with(|| serde_json::json!([]));
+        if let Some(tags) = tags.as_array_mut() {
+            tags.push(serde_json::json!({
+                "system": "http://terminology.hl7.org/CodeSystem/v3-ObservationValue",
+                "code": "SUBSETTED",
+                "display": "subsetted",
+            }));
+        }
+    }
+    data
+}
+
+/// Search parameter codes handled by [`SearchParams`]
+const BUILT_IN_SEARCH_PARAMS: &[&str] = &[
+    "name",
+    "family",
+    "given",
+    "gender",
+    "birthdate",
+    "identifier",
+    "telecom",
+    "phone",
+    "email",
+    "address",
+    "address-city",
+    "address-state",
+    "address-postalcode",
+    "address-country",
+    "active",
+];
+
+/// Built-in parameters that take the `:exact` and `:contains` modifiers
+const STRING_SEARCH_PARAMS: &[&str] = &[
+    "family",
+    "given",
+    "address",
+    "address-city",
+    "address-state",
+    "address-postalcode",
+    "address-country",
+];
+
+/// Whether `code` is a built-in search parameter, with an `:exact` or
+/// `:contains` modifier where the parameter takes one; other modifiers are
+/// not (and are reported as ignored)
+fn is_built_in(code: &str) -> bool {
+    match code.split_once(':') {
+        Some((base, modifier)) => {
+            STRING_SEARCH_PARAMS.contains(&base) && matches!(modifier, "exact" | "contains")
+        }
+        None => BUILT_IN_SEARCH_PARAMS.contains(&code),
+    }
+}
+
+/// Result parameters handled by [`SearchParams`]
+const RESULT_PARAMS: &[&str] = &["_count", "_offset", "_sort", "_asOf", "_summary", "_total"];
+
+/// Fields `_sort` can order by (anything else falls back to creation order)
+const SORT_FIELDS: &[&str] = &[
+    "name",
+    "gender",
+    "birthdate",
+    "birthDate",
+    "_lastUpdated",
+    "_score",
+];
+
+/// Largest page a search returns; larger `_count` values are reduced
+pub(super) const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Clamp the page size and report parameters the search will not apply, so
+/// the Bundle can say what was ignored instead of silently returning a
+/// broader or shorter result
+fn search_issues(
+    params: &mut SearchParams,
+    query: &[(String, String)],
+    ig_codes: &[&str],
+) -> Vec<fhir_core::OperationOutcomeIssue> {
+    let mut issues: Vec<fhir_core::OperationOutcomeIssue> = query
+        .iter()
+        .map(|(code, _)| code.as_str())
+        .filter(|code| {
+            !is_built_in(code) && !RESULT_PARAMS.contains(code) && !ig_codes.contains(code)
+        })
+        .map(|code| {
+            fhir_core::OperationOutcomeIssue::warning(
+                fhir_core::IssueType::NotSupported,
+                &format!(
+                    "Search parameter '{}' is not supported and was ignored",
+                    code
+                ),
+            )
+        })
+        .collect();
+
+    if let Some(ref sort) = params.sort {
+        let field = sort.strip_prefix('-').unwrap_or(sort);
+        if !SORT_FIELDS.contains(&field) {
+            issues.push(fhir_core::OperationOutcomeIssue::warning(
+                fhir_core::IssueType::NotSupported,
+                &format!(
+                    "Sort field '{}' is not supported; results are in creation order",
+                    field
+                ),
+            ));
+        }
+        if field == "_score" && params.first("name").is_none() {
+            issues.push(fhir_core::OperationOutcomeIssue::warning(
+                fhir_core::IssueType::NotSupported,
+                "Only name searches are scored; results are in creation order",
+            ));
+        }
+    }
+
+    let mut unsupported_active = Vec::new();
+    params.filters.retain(|(code, value)| {
+        let supported = code != "active"
+            || crate::search_values::alternatives(value)
+                .iter()
+                .all(|a| a == "true" || a == "false");
+        if !supported {
+            unsupported_active.push(value.clone());
+        }
+        supported
+    });
+    for active in unsupported_active {
+        issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::NotSupported,
+            &format!(
+                "active={} is not supported; only active patients are returned",
+                active
+            ),
+        ));
+    }
+
+    if let Some(summary) = params
+        .summary
+        .as_deref()
+        .filter(|s| !["count", "true", "text", "data", "false"].contains(s))
+    {
+        issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::NotSupported,
+            &format!(
+                "_summary={} is not supported; full resources are returned",
+                summary
+            ),
+        ));
+    }
+
+    if let Some(total) = params
+        .total
+        .take_if(|t| TotalMode::parse(Some(t.as_str())).is_none())
+    {
+        issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::NotSupported,
+            &format!(
+                "_total={} is not supported; the total is counted accurately",
+                total
+            ),
+        ));
+    }
+    if params.total_mode() == TotalMode::Estimate && !crate::db::supports("fhir_count_estimate") {
+        params.total = None;
+        issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::NotSupported,
+            "The extension cannot estimate totals; the total is counted accurately",
+        ));
+    }
+
+    if let Some(count) = params.count.filter(|c| *c > MAX_PAGE_SIZE) {
+        params.count = Some(MAX_PAGE_SIZE);
+        issues.push(fhir_core::OperationOutcomeIssue::warning(
+            fhir_core::IssueType::TooCostly,
+            &format!(
+                "_count={} exceeds the maximum page size; pages are limited to {} entries",
+                count, MAX_PAGE_SIZE
+            ),
+        ));
+    }
+
+    issues
+}
+
+/// Filters for Patient search parameters defined by loaded IG packages, as
+/// `(code, fhir_search path filter)`
+fn ig_search_filters(
+    packages: &PackageRegistry,
+    query: &[(String, String)],
+) -> Vec<(String, JsonValue)> {
+    query
+        .iter()
+        .filter(|(code, _)| !BUILT_IN_SEARCH_PARAMS.contains(&code.as_str()))
+        .filter_map(|(code, value)| {
+            let param = packages
+                .search_params()
+                .iter()
+                .find(|p| &p.code == code && p.base.iter().any(|b| b == "Patient"))?;
+            let path = param.element_path("Patient")?;
+            // Tokens naming neither a system nor a code, and values left
+            // empty by normalization, are dropped; a filter with none left
+            // is dropped as a whole (and reported as ignored)
+            let alternatives: Vec<String> = crate::search_values::alternatives(value)
+                .iter()
+                .filter_map(|value| match param.param_type.as_str() {
+                    "token" => fhir_core::TokenParam::parse(value).map(|t| t.to_string()),
+                    param_type => crate::search_values::normalize_typed(param_type, value),
+                })
+                .collect();
+            if alternatives.is_empty() {
+                return None;
+            }
+            let filter_value = crate::search_values::join_alternatives(&alternatives);
+            Some((
+                code.clone(),
+                serde_json::json!({
+                    "code": code,
+                    "path": path,
+                    "type": param.param_type,
+                    "value": filter_value,
+                }),
+            ))
+        })
+        .collect()
+}
+
+/// Validate R5 writes against the R5 model, enforce the narrative policy,
+/// check photos, choice elements and extensions, and tag the resource with
+/// the FHIR version it is written in
+pub(super) fn prepare_write(
+    version: FhirVersion,
+    narrative_policy: NarrativePolicy,
+    photo_limit: PhotoLimit,
+    packages: &PackageRegistry,
+    mut body: JsonValue,
+) -> Result<JsonValue, AppError> {
+    check_narrative(narrative_policy, &mut body)?;
+    check_photos(photo_limit, &body)?;
+    check_choices("Patient", &body)?;
+    check_extensions(packages, &body)?;
+    if version != FhirVersion::R4B {
+        version
+            .validate_patient(body.clone())
+            .map_err(|e| AppError::BadRequest(format!("Validation failed: {}", e)))?;
+    }
+    version.tag(&mut body);
+    Ok(body)
+}
+
+/// Reject photos that are not image Attachments carrying either inline data
+/// within the size limit or a Binary reference
+fn check_photos(limit: PhotoLimit, body: &JsonValue) -> Result<(), AppError> {
+    let problems: Vec<String> = match body.get("photo") {
+        None => Vec::new(),
+        Some(JsonValue::Array(photos)) => photos
+            .iter()
+            .enumerate()
+            .flat_map(|(i, photo)| fhir_core::attachment::check_photo(photo, i, limit.0))
+            .collect(),
+        Some(_) => vec!["Patient.photo must be an array".to_string()],
+    };
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::BadRequest(format!(
+        "Invalid photo: {}",
+        problems.join("; ")
+    )))
+}
+
+/// Reject choice elements (`deceased[x]`, ...) given in more than one form
+/// or in a type they cannot take
+pub(super) fn check_choices(resource_type: &str, body: &JsonValue) -> Result<(), AppError> {
+    let problems = fhir_core::choice::check(resource_type, body);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::BadRequest(format!(
+        "Invalid choice element: {}",
+        problems.join("; ")
+    )))
+}
+
+/// Reject malformed extensions and modifier extensions that no loaded
+/// package defines
+pub(super) fn check_extensions(
+    packages: &PackageRegistry,
+    body: &JsonValue,
+) -> Result<(), AppError> {
+    let problems = fhir_core::extension::check(body, |url| {
+        packages
+            .structure_definition(url, None)
+            .is_some_and(|sd| sd.get("type").and_then(|t| t.as_str()) == Some("Extension"))
+    });
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::BadRequest(format!(
+        "Invalid extension: {}",
+        problems.join("; ")
+    )))
+}
+
+/// Reject or clean a `text.div` outside the FHIR XHTML subset
+pub(super) fn check_narrative(
+    policy: NarrativePolicy,
+    body: &mut JsonValue,
+) -> Result<(), AppError> {
+    let Some(div) = body.pointer_mut("/text/div") else {
+        return Ok(());
+    };
+    let Some(xhtml) = div.as_str() else {
+        return Err(AppError::BadRequest(
+            "text.div must be a string".to_string(),
+        ));
+    };
+
+    let check = check_div(xhtml);
+    if check.is_clean() {
+        return Ok(());
+    }
+
+    match policy {
+        NarrativePolicy::Reject => Err(AppError::BadRequest(format!(
+            "Invalid narrative: {}",
+            check.violations.join("; ")
+        ))),
+        NarrativePolicy::Sanitize => {
+            tracing::warn!(violations = ?check.violations, "Sanitized narrative");
+            *div = JsonValue::String(check.sanitized);
+            Ok(())
+        }
+    }
+}
 
-        JsonValue::Object(map)
+/// What a write returns, from `Prefer: return=...` (minimal by default)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReturnPreference {
+    /// Headers only
+    Minimal,
+    /// The stored resource, with its id and meta
+    Representation,
+    /// An OperationOutcome describing the write
+    OperationOutcome,
+}
+
+impl ReturnPreference {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get("Prefer")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.split(',').find_map(|p| match p.trim() {
+                    "return=minimal" => Some(ReturnPreference::Minimal),
+                    "return=representation" => Some(ReturnPreference::Representation),
+                    "return=OperationOutcome" => Some(ReturnPreference::OperationOutcome),
+                    _ => None,
+                })
+            })
+            .unwrap_or(ReturnPreference::Minimal)
+    }
+}
+
+/// Response to a successful write of version `vid`, with the body the client
+/// asked for
+async fn write_response(
+    repo: &PatientRepository,
+    (id, vid): (Uuid, i32),
+    version: FhirVersion,
+    (status, headers): (StatusCode, HeaderMap),
+    preference: ReturnPreference,
+    message: &str,
+) -> Result<Response, AppError> {
+    match preference {
+        ReturnPreference::Minimal => Ok((status, headers).into_response()),
+        ReturnPreference::Representation => {
+            crate::db::require("fhir_get_version")?;
+            let (data, last_modified) = repo.get_version(id, vid).await?.ok_or_else(|| {
+                AppError::NotFound(format!("Patient/{}/_history/{} not found", id, vid))
+            })?;
+            let data = version_resource(data, id, vid, &last_modified, version);
+            Ok((status, headers, Json(data)).into_response())
+        }
+        ReturnPreference::OperationOutcome => Ok((
+            status,
+            headers,
+            Json(fhir_core::OperationOutcome::success(message)),
+        )
+            .into_response()),
     }
 }
 
 /// POST /fhir/Patient - Create a new patient
+///
+/// The response body follows `Prefer: return=minimal` (none, the default),
+/// `return=representation` or `return=OperationOutcome`.
 pub async fn create(
     State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(narrative_policy): Extension<NarrativePolicy>,
+    Extension(photo_limit): Extension<PhotoLimit>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(geocoding): Extension<Geocoding>,
+    Extension(policies): Extension<WritePolicies>,
+    request_headers: HeaderMap,
     Json(body): Json<JsonValue>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
+    let mut body = prepare_write(version, narrative_policy, photo_limit, &packages, body)?;
+    policies.evaluate(
+        None,
+        &body,
+        &WriteContext::new("Patient", WriteInteraction::Create, &request_headers),
+    )?;
+    geocoding.apply(&mut body).await;
     let repo = PatientRepository::new(pool);
     let id = repo.create(body).await?;
 
-    tracing::info!(patient_id = %id, "Patient created");
+    tracing::info!(patient_id = %id, fhir_version = version.code(), "Patient created");
 
     let mut headers = HeaderMap::new();
     headers.insert(
         header::LOCATION,
-        format!("/fhir/Patient/{}", id).parse().unwrap(),
+        UrlBuilder::new(base_path(version))
+            .segment("Patient")
+            .segment(id)
+            .header(),
     );
     headers.insert("ETag", "W/\"1\"".parse().unwrap());
 
-    Ok((StatusCode::CREATED, headers))
+    write_response(
+        &repo,
+        (id, 1),
+        version,
+        (StatusCode::CREATED, headers),
+        ReturnPreference::from_headers(&request_headers),
+        &format!("Created Patient/{}", id),
+    )
+    .await
+}
+
+/// The Patient a `replaced-by` link points to, e.g. after a merge
+fn replaced_by(data: &JsonValue) -> Option<Uuid> {
+    data.get("link")?
+        .as_array()?
+        .iter()
+        .filter(|link| link.get("type").and_then(|t| t.as_str()) == Some("replaced-by"))
+        .find_map(|link| {
+            let reference = link.pointer("/other/reference")?.as_str()?;
+            Uuid::parse_str(reference.strip_prefix("Patient/")?).ok()
+        })
 }
 
 /// GET /fhir/Patient/{id} - Read a patient
+///
+/// With `_asOf=<instant>`, returns the patient as it was at that time
+/// (reconstructed from history). A current read of a patient replaced by
+/// another answers `301` with the surviving patient's `Location`, unless
+/// `REDIRECT_REPLACED_PATIENTS` is off.
 pub async fn read(
     State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(ReplacedRedirect(redirect_replaced)): Extension<ReplacedRedirect>,
     Path(id): Path<Uuid>,
-) -> Result<impl IntoResponse, AppError> {
+    uri: Uri,
+    headers: HeaderMap,
+    Query(params): Query<ReadParams>,
+) -> Result<Response, AppError> {
     let repo = PatientRepository::new(pool);
 
-    match repo.get(id).await? {
+    let data = match params.as_of.as_deref() {
+        Some(as_of) => repo.get_as_of(id, &parse_instant("_asOf", as_of)?).await?,
+        None => repo.get(id).await?,
+    };
+
+    if let Some(target) = data
+        .as_ref()
+        .filter(|_| redirect_replaced && params.as_of.is_none())
+        .and_then(replaced_by)
+    {
+        tracing::info!(patient_id = %id, replaced_by = %target, "Replaced patient read");
+        let location = UrlBuilder::new(base_path(version))
+            .segment("Patient")
+            .segment(target)
+            .query(uri.query().unwrap_or(""));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::LOCATION, location.header());
+        return Ok((StatusCode::MOVED_PERMANENTLY, headers).into_response());
+    }
+
+    // Resources written in another FHIR version are converted on the way out
+    let data = data.map(|data| patient_to(data, version)).map(|data| {
+        match Summary::parse(params.summary.as_deref()) {
+            Some(summary) => summarize(data, summary),
+            None => data,
+        }
+    });
+
+    match data {
         Some(data) => {
             tracing::info!(patient_id = %id, "Patient read");
+            let wants_turtle = accepts(&headers, TURTLE_CONTENT_TYPES);
             let mut headers = HeaderMap::new();
             // Extract version from meta if available, default to 1
-            let version = data
+            let version_id = data
                 .get("meta")
                 .and_then(|m| m.get("versionId"))
                 .and_then(|v| v.as_str())
                 .unwrap_or("1");
-            headers.insert("ETag", format!("W/\"{}\"", version).parse().unwrap());
+            headers.insert("ETag", format!("W/\"{}\"", version_id).parse().unwrap());
+            headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+
+            if wants_turtle {
+                let iri = format!("{}/Patient/{}", base_path(version), id);
+                return Ok((StatusCode::OK, headers, turtle(&data, &iri, version)).into_response());
+            }
 
-            Ok((StatusCode::OK, headers, Json(data)))
+            Ok((StatusCode::OK, headers, Json(data)).into_response())
         }
         None => Err(AppError::NotFound(format!("Patient/{} not found", id))),
     }
 }
 
+/// The version an `If-Match` header requires (`W/"3"`, `"3"` or `3`)
+fn if_match_version(headers: &HeaderMap) -> Result<Option<i32>, AppError> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .map(|v| v.trim())
+        .map(|v| v.strip_prefix("W/").unwrap_or(v).trim_matches('"'))
+        .and_then(|v| v.parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            AppError::BadRequest("If-Match must be a version ETag such as W/\"3\"".to_string())
+        })
+}
+
 /// PUT /fhir/Patient/{id} - Update a patient
+///
+/// With `If-Match: W/"n"` the update only applies if the stored version is
+/// still `n`, and fails with 412 Precondition Failed otherwise. The response
+/// body follows `Prefer: return=...` as for create.
 pub async fn update(
     State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(narrative_policy): Extension<NarrativePolicy>,
+    Extension(photo_limit): Extension<PhotoLimit>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(geocoding): Extension<Geocoding>,
+    Extension(policies): Extension<WritePolicies>,
     Path(id): Path<Uuid>,
+    headers: HeaderMap,
     Json(body): Json<JsonValue>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
+    let mut body = prepare_write(version, narrative_policy, photo_limit, &packages, body)?;
     let repo = PatientRepository::new(pool);
+    let mut expected_version = if_match_version(&headers)?;
 
-    match repo.update(id, body).await? {
-        Some(version) => {
-            tracing::info!(patient_id = %id, version = version, "Patient updated");
-            let mut headers = HeaderMap::new();
-            headers.insert("ETag", format!("W/\"{}\"", version).parse().unwrap());
+    // Policies are evaluated against the current version, which the update
+    // is then made conditional on so it cannot change underneath them
+    if !policies.is_empty() {
+        let (current, current_version) = repo
+            .get_current(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Patient/{} not found", id)))?;
+        policies.evaluate(
+            Some(&current),
+            &body,
+            &WriteContext::new("Patient", WriteInteraction::Update, &headers),
+        )?;
+        expected_version = expected_version.or(Some(current_version));
+    }
+    geocoding.apply(&mut body).await;
+
+    let updated = match expected_version {
+        Some(expected) => repo.update_if(id, body, expected).await?,
+        None => repo.update(id, body).await?,
+    };
+    match updated {
+        Some(vid) => {
+            tracing::info!(patient_id = %id, version = vid, "Patient updated");
+            let mut response_headers = HeaderMap::new();
+            response_headers.insert("ETag", format!("W/\"{}\"", vid).parse().unwrap());
 
-            Ok((StatusCode::OK, headers))
+            write_response(
+                &repo,
+                (id, vid),
+                version,
+                (StatusCode::OK, response_headers),
+                ReturnPreference::from_headers(&headers),
+                &format!("Updated Patient/{} to version {}", id, vid),
+            )
+            .await
+        }
+        None => Err(AppError::NotFound(format!("Patient/{} not found", id))),
+    }
+}
+
+/// Media type of JSON Patch request bodies
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// PATCH /fhir/Patient/{id} - Apply a JSON Patch to a patient
+///
+/// The patch is applied to the current version, which is only replaced if it
+/// is still current when written back, so concurrent patches fail with 412
+/// instead of overwriting each other. `If-Match: W/"n"` additionally requires
+/// the current version to be `n`. The response body follows
+/// `Prefer: return=...` as for create.
+pub async fn patch(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(narrative_policy): Extension<NarrativePolicy>,
+    Extension(photo_limit): Extension<PhotoLimit>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(geocoding): Extension<Geocoding>,
+    Extension(policies): Extension<WritePolicies>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(patch): Json<JsonValue>,
+) -> Result<Response, AppError> {
+    let is_json_patch = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case(JSON_PATCH_CONTENT_TYPE));
+    if !is_json_patch {
+        return Err(AppError::BadRequest(format!(
+            "PATCH requires Content-Type: {}",
+            JSON_PATCH_CONTENT_TYPE
+        )));
+    }
+
+    let repo = PatientRepository::new(pool);
+    let (current, current_version) = repo
+        .get_current(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Patient/{} not found", id)))?;
+    if let Some(expected) = if_match_version(&headers)?.filter(|v| *v != current_version) {
+        return Err(AppError::PreconditionFailed(format!(
+            "Patient/{} is at version {}, not the expected version {}",
+            id, current_version, expected
+        )));
+    }
+
+    let current = patient_to(current, version);
+    let patched = json_patch::apply(&current, &patch).map_err(|e| match e {
+        FhirError::Conflict(msg) => AppError::Conflict(msg),
+        FhirError::Invalid(msg) => AppError::BadRequest(msg),
+        other => AppError::Internal(other.to_string()),
+    })?;
+    if patched.get("resourceType") != Some(&JsonValue::from("Patient")) {
+        return Err(AppError::BadRequest(
+            "A patch cannot change the resourceType".to_string(),
+        ));
+    }
+
+    let mut body = prepare_write(version, narrative_policy, photo_limit, &packages, patched)?;
+    policies.evaluate(
+        Some(&current),
+        &body,
+        &WriteContext::new("Patient", WriteInteraction::Patch, &headers),
+    )?;
+    geocoding.apply(&mut body).await;
+
+    match repo.update_if(id, body, current_version).await? {
+        Some(vid) => {
+            tracing::info!(patient_id = %id, version = vid, "Patient patched");
+            let mut response_headers = HeaderMap::new();
+            response_headers.insert("ETag", format!("W/\"{}\"", vid).parse().unwrap());
+
+            write_response(
+                &repo,
+                (id, vid),
+                version,
+                (StatusCode::OK, response_headers),
+                ReturnPreference::from_headers(&headers),
+                &format!("Patched Patient/{} to version {}", id, vid),
+            )
+            .await
         }
         None => Err(AppError::NotFound(format!("Patient/{} not found", id))),
     }
@@ -128,10 +993,24 @@ pub async fn update(
 /// DELETE /fhir/Patient/{id} - Delete a patient
 pub async fn delete(
     State(pool): State<Pool>,
+    Extension(policies): Extension<WritePolicies>,
     Path(id): Path<Uuid>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, AppError> {
     let repo = PatientRepository::new(pool);
 
+    if !policies.is_empty() {
+        let current = repo
+            .get(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Patient/{} not found", id)))?;
+        policies.evaluate(
+            Some(&current),
+            &JsonValue::Null,
+            &WriteContext::new("Patient", WriteInteraction::Delete, &headers),
+        )?;
+    }
+
     if repo.delete(id).await? {
         tracing::info!(patient_id = %id, "Patient deleted");
         Ok(StatusCode::NO_CONTENT)
@@ -141,149 +1020,691 @@ pub async fn delete(
 }
 
 /// GET /fhir/Patient - Search patients
+///
+/// With `Accept: application/fhir+ndjson`, every match is streamed as one
+/// resource per line instead of a paged Bundle (`_count` / `_offset` ignored).
 pub async fn search(
     State(pool): State<Pool>,
-    Query(params): Query<SearchParams>,
-) -> Result<impl IntoResponse, AppError> {
+    Extension(version): Extension<FhirVersion>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    headers: HeaderMap,
+    Query(raw_query): Query<Vec<(String, String)>>,
+) -> Result<Response, AppError> {
+    run_search(pool, version, &packages, &headers, raw_query).await
+}
+
+/// POST /fhir/Patient/_search - Search patients with form-encoded parameters
+///
+/// Lets clients keep search values out of URLs. Parameters in the query
+/// string are combined with those in the body; both are parsed exactly as
+/// for `GET /fhir/Patient`.
+pub async fn search_post(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    headers: HeaderMap,
+    uri: Uri,
+    RawForm(body): RawForm,
+) -> Result<Response, AppError> {
+    let form: Vec<u8> = match uri.query().filter(|q| !q.is_empty()) {
+        Some(query) if !body.is_empty() => [query.as_bytes(), b"&", &body].concat(),
+        Some(query) => query.as_bytes().to_vec(),
+        None => body.to_vec(),
+    };
+    let raw_query: Vec<(String, String)> = serde_urlencoded::from_bytes(&form)
+        .map_err(|e| AppError::BadRequest(format!("Invalid search parameters: {}", e)))?;
+    run_search(pool, version, &packages, &headers, raw_query).await
+}
+
+/// Search shared by the GET and POST forms
+async fn run_search(
+    pool: Pool,
+    version: FhirVersion,
+    packages: &PackageRegistry,
+    headers: &HeaderMap,
+    raw_query: Vec<(String, String)>,
+) -> Result<Response, AppError> {
+    let mut params = SearchParams::from_query(&raw_query)?;
+    params.as_of = params
+        .as_of
+        .as_deref()
+        .map(|s| parse_instant("_asOf", s))
+        .transpose()?;
+
     let repo = PatientRepository::new(pool);
-    let json_params = params.to_json();
+    let ig_filters = ig_search_filters(packages, &raw_query);
+    let ig_codes: Vec<&str> = ig_filters.iter().map(|(code, _)| code.as_str()).collect();
+    let issues = search_issues(&mut params, &raw_query, &ig_codes);
+    let mut json_params = params.to_json();
+    if !ig_filters.is_empty() {
+        json_params["_paths"] = ig_filters.iter().map(|(_, f)| f.clone()).collect();
+    }
+    let applied = params.applied(&json_params);
+
+    if params.count_only() {
+        let total = match params.total_mode() {
+            TotalMode::Estimate => repo.count_estimate(json_params).await? as u32,
+            _ => repo.count(json_params).await? as u32,
+        };
+        tracing::info!(total = total, "Patient search (count only)");
 
-    // Get search results
-    let results = repo.search(json_params.clone()).await?;
+        let mut bundle = Bundle::searchset(total, Vec::new());
+        bundle.add_outcome(fhir_core::OperationOutcome::from_issues(issues));
+        return Ok(Json(bundle).into_response());
+    }
+
+    // Resources written in another FHIR version are converted on the way out
+    let summary = params.summary_mode();
+    let to_output = move |data: JsonValue| {
+        let data = patient_to(data, version);
+        match summary {
+            Some(summary) => summarize(data, summary),
+            None => data,
+        }
+    };
+
+    if accepts(headers, NDJSON_CONTENT_TYPES) {
+        tracing::info!(
+            name = params.first("name").unwrap_or(""),
+            gender = params.first("gender").unwrap_or(""),
+            "Patient search (NDJSON stream)"
+        );
+        let lines = repo.search_stream(json_params).await?.map(move |row| {
+            row.map(|(_, data)| {
+                let mut line =
+                    serde_json::to_vec(&to_output(data)).expect("JSON values always serialize");
+                line.push(b'\n');
+                line
+            })
+        });
+
+        return Ok((
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(NDJSON_CONTENT_TYPES[0]),
+            )],
+            Body::from_stream(lines),
+        )
+            .into_response());
+    }
 
-    // Get total count for pagination
-    let total = repo.count(json_params).await? as u32;
+    // Pagination parameters; negative values were rejected and `_count=0`
+    // answered as count-only above, so a page always moves forward
+    let count = params.count.map_or(100, |c| c.clamp(1, MAX_PAGE_SIZE)) as u32;
+    let offset = u32::try_from(params.offset.unwrap_or(0)).unwrap_or(u32::MAX);
+
+    // Get search results and the total; without an accurate count one extra
+    // row tells whether there is a next page
+    let (results, total, has_next) = match params.total_mode() {
+        TotalMode::Accurate => {
+            let results = repo.search_scored(json_params.clone()).await?;
+            let total = repo.count(json_params).await? as u32;
+            (results, Some(total), offset.saturating_add(count) < total)
+        }
+        mode => {
+            let mut page_params = json_params.clone();
+            page_params["_count"] = JsonValue::from(count + 1);
+            let mut results = repo.search_scored(page_params).await?;
+            let has_next = results.len() > count as usize;
+            results.truncate(count as usize);
+            // An estimate is never below what this page has already shown
+            let total = match mode {
+                TotalMode::Estimate => Some(
+                    (repo.count_estimate(json_params).await? as u32).max(
+                        offset
+                            .saturating_add(results.len() as u32)
+                            .saturating_add(u32::from(has_next)),
+                    ),
+                ),
+                _ => None,
+            };
+            (results, total, has_next)
+        }
+    };
 
     tracing::info!(
-        total = total,
-        name = params.name.as_deref().unwrap_or(""),
-        gender = params.gender.as_deref().unwrap_or(""),
+        total = ?total,
+        name = params.first("name").unwrap_or(""),
+        gender = params.first("gender").unwrap_or(""),
         "Patient search"
     );
 
     // Build bundle entries
     let entries: Vec<BundleEntry> = results
         .into_iter()
-        .map(|(id, data)| BundleEntry::new(Some(format!("/fhir/Patient/{}", id)), data))
+        .map(|(id, data, score)| {
+            let full_url = Some(format!("{}/Patient/{}", base_path(version), id));
+            let data = to_output(data);
+            match score {
+                Some(score) => BundleEntry::scored(full_url, data, score),
+                None => BundleEntry::new(full_url, data),
+            }
+        })
         .collect();
 
-    // Pagination parameters
-    let count = params.count.unwrap_or(100) as u32;
-    let offset = params.offset.unwrap_or(0) as u32;
-
     // Create bundle response
-    let mut bundle = Bundle::searchset(total, entries);
+    let mut bundle = Bundle::searchset(0, entries);
+    bundle.total = total;
+    bundle.add_outcome(fhir_core::OperationOutcome::from_issues(issues));
 
-    // Build base query string (without pagination)
-    let mut base_query = Vec::new();
-    if let Some(ref name) = params.name {
-        base_query.push(format!("name={}", name));
-    }
-    if let Some(ref gender) = params.gender {
-        base_query.push(format!("gender={}", gender));
+    // Paging links repeat the search as applied
+    let link = |offset: u32| {
+        UrlBuilder::new(base_path(version))
+            .segment("Patient")
+            .params(applied.iter().map(|(name, value)| (name, value)))
+            .param("_count", count)
+            .param("_offset", offset)
+            .build()
+    };
+    bundle.add_link("self", &link(offset));
+    if has_next {
+        bundle.add_link("next", &link(offset.saturating_add(count)));
     }
-    if let Some(ref birthdate) = params.birthdate {
-        base_query.push(format!("birthdate={}", birthdate));
+    if offset > 0 {
+        bundle.add_link("previous", &link(offset.saturating_sub(count)));
     }
-    if let Some(ref sort) = params.sort {
-        base_query.push(format!("_sort={}", sort));
+
+    if accepts(headers, TURTLE_CONTENT_TYPES) {
+        let bundle = serde_json::to_value(&bundle)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize bundle: {}", e)))?;
+        let iri = format!("{}/Patient", base_path(version));
+        return Ok(turtle(&bundle, &iri, version).into_response());
     }
-    let base_query_str = if base_query.is_empty() {
-        String::new()
-    } else {
-        format!("{}&", base_query.join("&"))
-    };
 
-    // Add self link
-    bundle.add_link(
-        "self",
-        &format!(
-            "/fhir/Patient?{}_count={}&_offset={}",
-            base_query_str, count, offset
-        ),
-    );
+    Ok(Json(bundle).into_response())
+}
 
-    // Add next link if there are more results
-    if offset + count < total {
-        bundle.add_link(
-            "next",
-            &format!(
-                "/fhir/Patient?{}_count={}&_offset={}",
-                base_query_str,
-                count,
-                offset + count
-            ),
-        );
-    }
+/// MIME types of newline-delimited FHIR JSON (the first is sent back)
+const NDJSON_CONTENT_TYPES: &[&str] = &["application/fhir+ndjson", "application/ndjson"];
 
-    // Add previous link if not on first page
-    if offset > 0 {
-        let prev_offset = offset.saturating_sub(count);
-        bundle.add_link(
-            "previous",
-            &format!(
-                "/fhir/Patient?{}_count={}&_offset={}",
-                base_query_str, count, prev_offset
-            ),
-        );
-    }
+/// MIME types of FHIR RDF Turtle (the first is sent back)
+const TURTLE_CONTENT_TYPES: &[&str] = &["application/fhir+turtle", "text/turtle"];
 
-    Ok(Json(bundle))
+/// Whether the `Accept` header asks for one of the given MIME types
+fn accepts(headers: &HeaderMap, mime_types: &[&str]) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| {
+            accept.split(',').any(|m| {
+                let m = m.split(';').next().unwrap_or("").trim();
+                mime_types.iter().any(|t| m.eq_ignore_ascii_case(t))
+            })
+        })
+}
+
+/// Render a resource (or Bundle) as a FHIR RDF Turtle response body
+fn turtle(resource: &JsonValue, iri: &str, version: FhirVersion) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(TURTLE_CONTENT_TYPES[0]),
+        )],
+        fhir_core::rdf::to_turtle(resource, iri, base_path(version)),
+    )
+}
+
+/// A history Bundle entry for one stored version on the base of `fhir_version`;
+/// deletions carry no resource
+fn history_entry(
+    fhir_version: FhirVersion,
+    id: Uuid,
+    version: i32,
+    method: &str,
+    data: Option<JsonValue>,
+    last_modified: String,
+) -> BundleEntry {
+    BundleEntry::history(
+        base_path(fhir_version),
+        &format!("Patient/{}", id),
+        version,
+        HttpVerb::parse(method).unwrap_or(HttpVerb::Put),
+        data,
+        Some(last_modified),
+    )
+}
+
+/// Paging parameters for instance history
+#[derive(Debug, Deserialize, Default)]
+pub struct HistoryParams {
+    #[serde(rename = "_count")]
+    pub count: Option<i32>,
+    #[serde(rename = "_offset")]
+    pub offset: Option<i32>,
 }
 
 /// GET /fhir/Patient/{id}/_history - Get patient history
+///
+/// Versions are returned newest first, `_count` (default 100, at most 1000)
+/// at a time, with `next` / `previous` links between pages.
 pub async fn history(
     State(pool): State<Pool>,
+    Extension(fhir_version): Extension<FhirVersion>,
     Path(id): Path<Uuid>,
+    Query(params): Query<HistoryParams>,
 ) -> Result<impl IntoResponse, AppError> {
+    let count = params.count.unwrap_or(100).clamp(1, 1000);
+    let offset = params.offset.unwrap_or(0).max(0);
     let repo = PatientRepository::new(pool);
-    let versions = repo.history(id).await?;
 
-    tracing::info!(patient_id = %id, versions = versions.len(), "Patient history");
+    // One extra version tells whether there is a next page
+    let mut versions = repo.history(id, count + 1, offset).await?;
+    let has_next = versions.len() > count as usize;
+    versions.truncate(count as usize);
+
+    tracing::info!(patient_id = %id, versions = versions.len(), offset = offset, "Patient history");
 
     // If no history found, the resource doesn't exist
-    if versions.is_empty() {
+    if versions.is_empty() && offset == 0 {
         return Err(AppError::NotFound(format!("Patient/{} not found", id)));
     }
 
-    // Build bundle entries with versioned URLs
+    // Build bundle entries with versioned URLs and the request that wrote them
     let entries: Vec<BundleEntry> = versions
         .into_iter()
-        .map(|(version, data)| {
-            BundleEntry::new(
-                Some(format!("/fhir/Patient/{}/_history/{}", id, version)),
-                data,
-            )
+        .map(|(version, method, data, last_modified)| {
+            history_entry(fhir_version, id, version, &method, data, last_modified)
         })
         .collect();
 
     // Create history bundle
-    let bundle = Bundle::history(entries);
+    let mut bundle = Bundle::history(entries);
+    let link = |offset: i32| {
+        UrlBuilder::new(base_path(fhir_version))
+            .segment("Patient")
+            .segment(id)
+            .segment("_history")
+            .param("_count", count)
+            .param("_offset", offset)
+            .build()
+    };
+    bundle.add_link("self", &link(offset));
+    if has_next {
+        bundle.add_link("next", &link(offset + count));
+    }
+    if offset > 0 {
+        bundle.add_link("previous", &link((offset - count).max(0)));
+    }
 
     Ok(Json(bundle))
 }
 
+/// Cache-Control for version reads, which are immutable once written but
+/// only for callers allowed to read them, so shared caches must not keep them
+const VERSION_CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// Request headers a version read's representation depends on: the format
+/// and the caller
+const VERSION_VARY: &str = "Accept, X-API-Key";
+
+/// A stored version as returned to clients, with its id and meta filled in
+fn version_resource(
+    data: JsonValue,
+    id: Uuid,
+    vid: i32,
+    last_modified: &str,
+    version: FhirVersion,
+) -> JsonValue {
+    let mut data = patient_to(data, version);
+    data["id"] = JsonValue::String(id.to_string());
+    data["meta"]["versionId"] = JsonValue::String(vid.to_string());
+    data["meta"]["lastUpdated"] = JsonValue::String(last_modified.to_string());
+    data
+}
+
+/// GET /fhir/Patient/{id}/_history/{vid} - Read one version of a patient
+pub async fn vread(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Path((id, vid)): Path<(Uuid, i32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = PatientRepository::new(pool);
+    let (data, last_modified) = repo
+        .get_version(id, vid)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Patient/{}/_history/{} not found", id, vid)))?;
+
+    tracing::info!(patient_id = %id, version = vid, "Patient version read");
+
+    let data = version_resource(data, id, vid, &last_modified, version);
+
+    // Historical versions never change, so the ETag is strong and the
+    // caller's cache may keep the response indefinitely; the XML middleware
+    // gives the XML representation its own ETag
+    let mut headers = HeaderMap::new();
+    headers.insert("ETag", format!("\"{}\"", vid).parse().unwrap());
+    headers.insert(
+        header::CACHE_CONTROL,
+        VERSION_CACHE_CONTROL.parse().unwrap(),
+    );
+    headers.insert(header::VARY, VERSION_VARY.parse().unwrap());
+    if let Ok(modified) = chrono::DateTime::parse_from_rfc3339(&last_modified) {
+        headers.insert(
+            header::LAST_MODIFIED,
+            modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+                .parse()
+                .unwrap(),
+        );
+    }
+
+    Ok((headers, Json(data)))
+}
+
+/// Query parameters for the type-level history feed
+#[derive(Debug, Deserialize, Default)]
+pub struct HistoryFeedParams {
+    #[serde(rename = "_since")]
+    pub since: Option<String>,
+    #[serde(rename = "_at")]
+    pub at: Option<String>,
+    #[serde(rename = "_cursor")]
+    pub cursor: Option<String>,
+    #[serde(rename = "_count")]
+    pub count: Option<i32>,
+}
+
+/// Parse an opaque `<micros>.<history id>` feed cursor
+fn parse_cursor(cursor: &str) -> Option<(i64, Uuid)> {
+    let (micros, id) = cursor.split_once('.')?;
+    Some((micros.parse().ok()?, Uuid::parse_str(id).ok()?))
+}
+
+/// GET /fhir/Patient/_history - Type-level history feed for incremental sync
+///
+/// Returns every Patient version written at or after `_since`, oldest first.
+/// Follow the `next` link (an opaque `_cursor`) until it is absent; the feed
+/// holds back versions written in the last few seconds so that a consumer
+/// following the cursor never skips a late-committing write.
+///
+/// With `_at` it instead returns the version of each Patient that was
+/// current at that instant, ordered by id and paged the same way.
+pub async fn type_history(
+    State(pool): State<Pool>,
+    Extension(fhir_version): Extension<FhirVersion>,
+    Query(params): Query<HistoryFeedParams>,
+) -> Result<Json<Bundle>, AppError> {
+    let count = params.count.unwrap_or(100).clamp(1, 1000);
+    let repo = PatientRepository::new(pool);
+    match (params.since.as_deref(), params.at.as_deref()) {
+        (Some(_), Some(_)) => Err(AppError::BadRequest(
+            "_since and _at cannot be combined".to_string(),
+        )),
+        (None, Some(at)) => {
+            history_at(&repo, fhir_version, at, params.cursor.as_deref(), count).await
+        }
+        (since, None) => {
+            history_since(&repo, fhir_version, since, params.cursor.as_deref(), count).await
+        }
+    }
+}
+
+/// Type-level history page of the versions written at or after `since`
+async fn history_since(
+    repo: &PatientRepository,
+    fhir_version: FhirVersion,
+    since: Option<&str>,
+    cursor: Option<&str>,
+    count: i32,
+) -> Result<Json<Bundle>, AppError> {
+    let since = match since {
+        Some(s) => parse_instant("_since", s)?,
+        None => "1970-01-01T00:00:00Z".to_string(),
+    };
+    let cursor = match cursor {
+        Some(c) => Some(
+            parse_cursor(c).ok_or_else(|| AppError::BadRequest("Invalid _cursor".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let rows = repo.history_since(&since, cursor, count).await?;
+
+    tracing::info!(since = %since, entries = rows.len(), "Patient type history");
+
+    let last_cursor = rows
+        .last()
+        .map(|(_, _, _, _, _, micros, history_id)| format!("{}.{}", micros, history_id));
+    let full_page = rows.len() == count as usize;
+
+    let entries: Vec<BundleEntry> = rows
+        .into_iter()
+        .map(|(id, version, method, data, last_modified, _, _)| {
+            history_entry(fhir_version, id, version, &method, data, last_modified)
+        })
+        .collect();
+
+    let mut bundle = Bundle::history(entries);
+    let link = UrlBuilder::new(base_path(fhir_version))
+        .segment("Patient")
+        .segment("_history")
+        .param("_since", &since)
+        .param("_count", count);
+    bundle.add_link("self", &link.build());
+    if let (true, Some(cursor)) = (full_page, last_cursor) {
+        bundle.add_link("next", &link.param("_cursor", cursor).build());
+    }
+
+    Ok(Json(bundle))
+}
+
+/// Type-level history page of the versions current at `at`; the cursor is
+/// the id of the last Patient on the previous page
+async fn history_at(
+    repo: &PatientRepository,
+    fhir_version: FhirVersion,
+    at: &str,
+    cursor: Option<&str>,
+    count: i32,
+) -> Result<Json<Bundle>, AppError> {
+    crate::db::require("fhir_history_at")?;
+    let at = parse_instant("_at", at)?;
+    let after = match cursor {
+        Some(c) => Some(
+            Uuid::parse_str(c).map_err(|_| AppError::BadRequest("Invalid _cursor".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let rows = repo.history_at(&at, after, count).await?;
+
+    tracing::info!(at = %at, entries = rows.len(), "Patient type history at");
+
+    let last_id = rows.last().map(|(id, _, _, _, _)| *id);
+    let full_page = rows.len() == count as usize;
+
+    let entries: Vec<BundleEntry> = rows
+        .into_iter()
+        .map(|(id, version, method, data, last_modified)| {
+            history_entry(fhir_version, id, version, &method, data, last_modified)
+        })
+        .collect();
+
+    let mut bundle = Bundle::history(entries);
+    let link = UrlBuilder::new(base_path(fhir_version))
+        .segment("Patient")
+        .segment("_history")
+        .param("_at", &at)
+        .param("_count", count);
+    bundle.add_link("self", &link.build());
+    if let (true, Some(last_id)) = (full_page, last_id) {
+        bundle.add_link("next", &link.param("_cursor", last_id).build());
+    }
+
+    Ok(Json(bundle))
+}
+
+/// Request body for `$lock`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockRequest {
+    /// Required only when authentication is disabled
+    pub owner: Option<String>,
+    pub lease_seconds: Option<i32>,
+}
+
+/// Request body for `$unlock`
+#[derive(Debug, Deserialize)]
+pub struct UnlockRequest {
+    /// Required only when authentication is disabled
+    pub owner: Option<String>,
+}
+
+/// Response body for a granted lock
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockResponse {
+    pub owner: String,
+    pub expires_at: String,
+}
+
+/// The owner of a `$lock` / `$unlock` request: the authenticated principal,
+/// or the `owner` of the body if authentication is disabled
+fn lock_owner(
+    principal: Option<Extension<Principal>>,
+    claimed: Option<String>,
+) -> Result<String, AppError> {
+    match (principal, claimed) {
+        (Some(Extension(Principal(name))), Some(claimed)) if claimed != name => {
+            Err(AppError::BadRequest(format!(
+                "Locks are owned by the authenticated client '{}', not '{}'",
+                name, claimed
+            )))
+        }
+        (Some(Extension(Principal(name))), _) => Ok(name),
+        (None, Some(claimed)) => Ok(claimed),
+        (None, None) => Err(AppError::BadRequest(
+            "owner is required when authentication is disabled".to_string(),
+        )),
+    }
+}
+
+/// POST /fhir/Patient/{id}/$lock - Acquire or renew a checkout lock
+///
+/// The lock is owned by the authenticated client (or, without
+/// authentication, by the body's `owner`, which writes then name in
+/// `X-Lock-Owner`). Writes of other clients get 423 Locked until the lease
+/// expires or is released.
+pub async fn lock(
+    State(pool): State<Pool>,
+    principal: Option<Extension<Principal>>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<LockRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let owner = lock_owner(principal, body.owner)?;
+    let repo = PatientRepository::new(pool);
+
+    if repo.get(id).await?.is_none() {
+        return Err(AppError::NotFound(format!("Patient/{} not found", id)));
+    }
+
+    let lease_seconds = body.lease_seconds.unwrap_or(300).clamp(1, 3600);
+    match repo.lock(id, &owner, lease_seconds).await? {
+        Some(expires_at) => {
+            tracing::info!(patient_id = %id, owner = %owner, "Patient locked");
+            Ok(Json(LockResponse { owner, expires_at }))
+        }
+        None => Err(AppError::Locked(format!(
+            "Patient/{} is locked by another owner",
+            id
+        ))),
+    }
+}
+
+/// POST /fhir/Patient/{id}/$unlock - Release a checkout lock
+pub async fn unlock(
+    State(pool): State<Pool>,
+    principal: Option<Extension<Principal>>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<UnlockRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let owner = lock_owner(principal, body.owner)?;
+    let repo = PatientRepository::new(pool);
+
+    if repo.unlock(id, &owner).await? {
+        tracing::info!(patient_id = %id, owner = %owner, "Patient unlocked");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::Conflict(format!(
+            "Patient/{} is not locked by {}",
+            id, owner
+        )))
+    }
+}
+
+/// Query parameters for `$validate`
+#[derive(Debug, Deserialize)]
+pub struct ValidateParams {
+    /// Profile canonical URL or id (e.g. `us-core-patient`)
+    pub profile: Option<String>,
+}
+
 /// POST /fhir/Patient/$validate - Validate a patient without storing
-pub async fn validate(Json(body): Json<JsonValue>) -> impl IntoResponse {
+///
+/// Validates against `?profile=` if given, otherwise against the profiles
+/// the resource claims in `meta.profile`.
+pub async fn validate(
+    Extension(version): Extension<FhirVersion>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Query(params): Query<ValidateParams>,
+    Json(body): Json<JsonValue>,
+) -> impl IntoResponse {
+    let profiles = packages.profiles();
     // Check resourceType is present and correct
     let resource_type = body.get("resourceType").and_then(|v| v.as_str());
 
     match resource_type {
         Some("Patient") => {
-            // Try to deserialize into fhir-sdk Patient type for validation
-            match serde_json::from_value::<fhir_core::Patient>(body) {
-                Ok(_) => {
-                    tracing::info!("Patient validation succeeded");
-                    let outcome = fhir_core::OperationOutcome::success("Patient resource is valid");
-                    (StatusCode::OK, Json(outcome))
-                }
-                Err(e) => {
-                    tracing::warn!(error = %e, "Patient validation failed");
-                    let outcome =
-                        fhir_core::OperationOutcome::invalid(&format!("Validation failed: {}", e));
-                    (StatusCode::BAD_REQUEST, Json(outcome))
+            // Try to deserialize into the fhir-sdk Patient type of the requested version
+            if let Err(e) = version.validate_patient(body.clone()) {
+                tracing::warn!(error = %e, "Patient validation failed");
+                let outcome =
+                    fhir_core::OperationOutcome::invalid(&format!("Validation failed: {}", e));
+                return (StatusCode::BAD_REQUEST, Json(outcome));
+            }
+
+            let claimed: Vec<String> = match params.profile {
+                Some(ref profile) => vec![profile.clone()],
+                None => body
+                    .pointer("/meta/profile")
+                    .and_then(|p| p.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|p| p.as_str().map(str::to_string))
+                    .collect(),
+            };
+
+            let mut issues = Vec::new();
+            for reference in &claimed {
+                match profiles.get(reference) {
+                    Some(profile) => issues.extend(profiles.validate(profile, &body)),
+                    None if params.profile.is_some() => {
+                        let outcome = fhir_core::OperationOutcome::error(
+                            fhir_core::IssueType::NotSupported,
+                            &format!("Unknown profile '{}'", reference),
+                        );
+                        return (StatusCode::BAD_REQUEST, Json(outcome));
+                    }
+                    None => issues.push(fhir_core::OperationOutcomeIssue {
+                        severity: fhir_core::IssueSeverity::Warning,
+                        code: fhir_core::IssueType::NotSupported,
+                        diagnostics: Some(format!("Profile {} is not known; skipped", reference)),
+                        location: Vec::new(),
+                    }),
                 }
             }
+
+            let outcome = fhir_core::OperationOutcome::from_issues(issues);
+            if outcome.has_errors() {
+                tracing::warn!(profiles = ?claimed, "Patient profile validation failed");
+                return (StatusCode::BAD_REQUEST, Json(outcome));
+            }
+
+            tracing::info!("Patient validation succeeded");
+            let mut success = fhir_core::OperationOutcome::success("Patient resource is valid");
+            success.issue.extend(outcome.issue);
+            (StatusCode::OK, Json(success))
         }
         Some(other) => {
             let outcome = fhir_core::OperationOutcome::invalid(&format!(
@@ -0,0 +1,256 @@
+//! Generic handlers for resource types without a dedicated module
+//!
+//! Any R4B resource type (Observation, Encounter, Condition, ...) can be
+//! created, read, updated, deleted and listed through `/fhir/{resourceType}`.
+//! Routes registered for a specific type (Patient, ConceptMap, ...) take
+//! precedence over these.
+
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use deadpool_postgres::Pool;
+use fhir_core::resource_type::is_resource_type;
+use fhir_core::{Bundle, BundleEntry, FhirVersion, PackageRegistry};
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+use uuid::Uuid;
+
+use crate::config::NarrativePolicy;
+use crate::db::ResourceRepository;
+use crate::error::AppError;
+use crate::geocode::Geocoding;
+use crate::middleware::fhir_version::base_path;
+use crate::urls::UrlBuilder;
+use crate::write_policy::{WriteContext, WriteInteraction, WritePolicies};
+
+/// Paging parameters for listing a resource type
+#[derive(Debug, Deserialize, Default)]
+pub struct SearchParams {
+    #[serde(rename = "_count")]
+    pub count: Option<i64>,
+    #[serde(rename = "_offset")]
+    pub offset: Option<i64>,
+    #[serde(rename = "_summary")]
+    pub summary: Option<String>,
+}
+
+/// Repository for `resource_type`, or 404 if it is not a resource type
+fn repository(pool: Pool, resource_type: &str) -> Result<ResourceRepository, AppError> {
+    match is_resource_type(resource_type) {
+        true => Ok(ResourceRepository::new(pool, resource_type)),
+        false => Err(AppError::NotFound(format!(
+            "Unknown resource type '{}'",
+            resource_type
+        ))),
+    }
+}
+
+/// Check the body is a resource of `resource_type`, enforce the narrative
+/// policy, check choice elements and extensions, and tag its FHIR version
+fn prepare_write(
+    version: FhirVersion,
+    narrative_policy: NarrativePolicy,
+    packages: &PackageRegistry,
+    resource_type: &str,
+    mut body: JsonValue,
+) -> Result<JsonValue, AppError> {
+    let actual = body.get("resourceType").and_then(|v| v.as_str());
+    if actual != Some(resource_type) {
+        return Err(AppError::BadRequest(format!(
+            "Expected a {} resource, got {}",
+            resource_type,
+            actual.unwrap_or("no resourceType")
+        )));
+    }
+    super::patient::check_narrative(narrative_policy, &mut body)?;
+    super::patient::check_choices(resource_type, &body)?;
+    super::patient::check_extensions(packages, &body)?;
+    version.tag(&mut body);
+    Ok(body)
+}
+
+/// POST /fhir/{resourceType} - Create a resource
+pub async fn create(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(narrative_policy): Extension<NarrativePolicy>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(policies): Extension<WritePolicies>,
+    Extension(geocoding): Extension<Geocoding>,
+    Path(resource_type): Path<String>,
+    request_headers: HeaderMap,
+    Json(body): Json<JsonValue>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = repository(pool, &resource_type)?;
+    let mut body = prepare_write(version, narrative_policy, &packages, &resource_type, body)?;
+    policies.evaluate(
+        None,
+        &body,
+        &WriteContext::new(&resource_type, WriteInteraction::Create, &request_headers),
+    )?;
+    geocoding.apply(&mut body).await;
+    let id = repo.create(body).await?;
+
+    tracing::info!(resource_type = %resource_type, resource_id = %id, "Resource created");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::LOCATION,
+        UrlBuilder::new(base_path(version))
+            .segment(&resource_type)
+            .segment(id)
+            .header(),
+    );
+    headers.insert("ETag", "W/\"1\"".parse().unwrap());
+
+    Ok((StatusCode::CREATED, headers))
+}
+
+/// GET /fhir/{resourceType}/{id} - Read a resource
+pub async fn read(
+    State(pool): State<Pool>,
+    Path((resource_type, id)): Path<(String, Uuid)>,
+) -> Result<impl IntoResponse, AppError> {
+    let data = repository(pool, &resource_type)?
+        .get(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("{}/{} not found", resource_type, id)))?;
+
+    let version_id = data
+        .get("meta")
+        .and_then(|m| m.get("versionId"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("1");
+    let mut headers = HeaderMap::new();
+    headers.insert("ETag", format!("W/\"{}\"", version_id).parse().unwrap());
+
+    Ok((headers, Json(data)))
+}
+
+/// PUT /fhir/{resourceType}/{id} - Update a resource
+pub async fn update(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Extension(narrative_policy): Extension<NarrativePolicy>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Extension(policies): Extension<WritePolicies>,
+    Extension(geocoding): Extension<Geocoding>,
+    Path((resource_type, id)): Path<(String, Uuid)>,
+    request_headers: HeaderMap,
+    Json(body): Json<JsonValue>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = repository(pool, &resource_type)?;
+    let mut body = prepare_write(version, narrative_policy, &packages, &resource_type, body)?;
+    if !policies.is_empty() {
+        let current = repo
+            .get(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("{}/{} not found", resource_type, id)))?;
+        policies.evaluate(
+            Some(&current),
+            &body,
+            &WriteContext::new(&resource_type, WriteInteraction::Update, &request_headers),
+        )?;
+    }
+    geocoding.apply(&mut body).await;
+
+    match repo.update(id, body).await? {
+        Some(version) => {
+            tracing::info!(resource_type = %resource_type, resource_id = %id, version = version, "Resource updated");
+            let mut headers = HeaderMap::new();
+            headers.insert("ETag", format!("W/\"{}\"", version).parse().unwrap());
+            Ok((StatusCode::OK, headers))
+        }
+        None => Err(AppError::NotFound(format!(
+            "{}/{} not found",
+            resource_type, id
+        ))),
+    }
+}
+
+/// DELETE /fhir/{resourceType}/{id} - Delete a resource
+pub async fn delete(
+    State(pool): State<Pool>,
+    Extension(policies): Extension<WritePolicies>,
+    Path((resource_type, id)): Path<(String, Uuid)>,
+    request_headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = repository(pool, &resource_type)?;
+    if !policies.is_empty() {
+        let current = repo
+            .get(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("{}/{} not found", resource_type, id)))?;
+        policies.evaluate(
+            Some(&current),
+            &JsonValue::Null,
+            &WriteContext::new(&resource_type, WriteInteraction::Delete, &request_headers),
+        )?;
+    }
+
+    match repo.delete(id).await? {
+        true => {
+            tracing::info!(resource_type = %resource_type, resource_id = %id, "Resource deleted");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        false => Err(AppError::NotFound(format!(
+            "{}/{} not found",
+            resource_type, id
+        ))),
+    }
+}
+
+/// GET /fhir/{resourceType} - List resources of a type, paged
+pub async fn search(
+    State(pool): State<Pool>,
+    Extension(version): Extension<FhirVersion>,
+    Path(resource_type): Path<String>,
+    Query(params): Query<SearchParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = repository(pool, &resource_type)?;
+    let count = params
+        .count
+        .unwrap_or(100)
+        .clamp(0, super::patient::MAX_PAGE_SIZE);
+    let offset = params.offset.unwrap_or(0).max(0);
+    let json_params = json!({"_count": count, "_offset": offset});
+
+    // `_summary=count` and `_count=0` only need the total
+    if params.summary.as_deref() == Some("count") || count == 0 {
+        let total = repo.count(json_params).await? as u32;
+        return Ok(Json(Bundle::searchset(total, Vec::new())));
+    }
+
+    let results = repo.search(json_params.clone()).await?;
+    let total = repo.count(json_params).await? as u32;
+
+    let entries: Vec<BundleEntry> = results
+        .into_iter()
+        .map(|(id, data)| {
+            BundleEntry::new(
+                Some(format!("{}/{}/{}", base_path(version), resource_type, id)),
+                data,
+            )
+        })
+        .collect();
+
+    let mut bundle = Bundle::searchset(total, entries);
+    let link = |offset: i64| {
+        UrlBuilder::new(base_path(version))
+            .segment(&resource_type)
+            .param("_count", count)
+            .param("_offset", offset)
+            .build()
+    };
+    bundle.add_link("self", &link(offset));
+    if offset + count < i64::from(total) {
+        bundle.add_link("next", &link(offset + count));
+    }
+
+    Ok(Json(bundle))
+}
@@ -0,0 +1,177 @@
+//! StructureDefinition registry handlers
+//!
+//! Serves the StructureDefinitions of the loaded IG packages (read-only):
+//! lookup by canonical URL and version, `$snapshot` and `$meta`.
+
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    response::IntoResponse,
+};
+use fhir_core::{Bundle, BundleEntry, FhirError, FhirVersion, PackageRegistry};
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+
+use crate::error::AppError;
+use crate::middleware::fhir_version::base_path;
+
+/// Query parameters for StructureDefinition search
+#[derive(Debug, Deserialize, Default)]
+pub struct SearchParams {
+    /// Canonical URL, optionally `url|version`
+    pub url: Option<String>,
+    pub version: Option<String>,
+    #[serde(rename = "type")]
+    pub sd_type: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Query parameters for `$snapshot`
+#[derive(Debug, Deserialize)]
+pub struct SnapshotParams {
+    /// Canonical URL of a loaded StructureDefinition, optionally `url|version`
+    pub url: Option<String>,
+}
+
+fn to_app_error(err: FhirError) -> AppError {
+    match err {
+        FhirError::NotFound(msg) => AppError::NotFound(msg),
+        FhirError::Invalid(msg) | FhirError::Conflict(msg) => AppError::BadRequest(msg),
+        FhirError::Database(msg) | FhirError::Internal(msg) => AppError::Internal(msg),
+    }
+}
+
+fn field<'a>(sd: &'a JsonValue, name: &str) -> Option<&'a str> {
+    sd.get(name).and_then(|v| v.as_str())
+}
+
+fn find_by_id<'a>(packages: &'a PackageRegistry, id: &str) -> Result<&'a JsonValue, AppError> {
+    packages
+        .structure_definitions()
+        .iter()
+        .rev()
+        .find(|sd| field(sd, "id") == Some(id))
+        .ok_or_else(|| AppError::NotFound(format!("StructureDefinition/{} not found", id)))
+}
+
+fn find_by_url<'a>(packages: &'a PackageRegistry, url: &str) -> Result<&'a JsonValue, AppError> {
+    packages
+        .structure_definition(url, None)
+        .ok_or_else(|| AppError::NotFound(format!("StructureDefinition {} not found", url)))
+}
+
+/// GET /fhir/StructureDefinition - Search loaded StructureDefinitions
+///
+/// `url` accepts `url|version`; without a version every loaded version of the
+/// canonical matches.
+pub async fn search(
+    Extension(version): Extension<FhirVersion>,
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    let (url, sd_version) = match params.url.as_deref().map(|u| u.split_once('|')) {
+        Some(Some((url, v))) => (Some(url), Some(v)),
+        _ => (params.url.as_deref(), params.version.as_deref()),
+    };
+    let matches = |sd: &&JsonValue| {
+        let is =
+            |name: &str, wanted: Option<&str>| wanted.is_none_or(|w| field(sd, name) == Some(w));
+        is("url", url)
+            && is("version", sd_version)
+            && is("type", params.sd_type.as_deref())
+            && is("name", params.name.as_deref())
+    };
+
+    let entries: Vec<BundleEntry> = packages
+        .structure_definitions()
+        .iter()
+        .filter(matches)
+        .map(|sd| {
+            BundleEntry::new(
+                Some(format!(
+                    "{}/StructureDefinition/{}",
+                    base_path(version),
+                    field(sd, "id").unwrap_or_default()
+                )),
+                sd.clone(),
+            )
+        })
+        .collect();
+
+    Json(Bundle::searchset(entries.len() as u32, entries))
+}
+
+/// GET /fhir/StructureDefinition/{id} - Read a loaded StructureDefinition
+pub async fn read(
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(find_by_id(&packages, &id)?.clone()))
+}
+
+/// GET /fhir/StructureDefinition/$snapshot?url= - Snapshot of a loaded
+/// StructureDefinition
+pub async fn snapshot_by_url(
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Query(params): Query<SnapshotParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let url = params
+        .url
+        .ok_or_else(|| AppError::BadRequest("$snapshot requires a url".to_string()))?;
+    let sd = find_by_url(&packages, &url)?;
+    Ok(Json(packages.snapshot(sd).map_err(to_app_error)?))
+}
+
+/// POST /fhir/StructureDefinition/$snapshot - Generate a snapshot
+///
+/// The body is either a StructureDefinition or a `Parameters` resource with a
+/// `definition` (resource) or `url` parameter.
+pub async fn snapshot(
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Json(body): Json<JsonValue>,
+) -> Result<impl IntoResponse, AppError> {
+    let sd = match field(&body, "resourceType") {
+        Some("StructureDefinition") => body,
+        Some("Parameters") => {
+            let parameters = body["parameter"].as_array().cloned().unwrap_or_default();
+            let param = |name: &str| parameters.iter().find(|p| p["name"] == name);
+            if let Some(definition) = param("definition").and_then(|p| p.get("resource")) {
+                definition.clone()
+            } else if let Some(url) = param("url").and_then(|p| {
+                ["valueUri", "valueCanonical", "valueString"]
+                    .iter()
+                    .find_map(|key| p.get(*key).and_then(|v| v.as_str()))
+            }) {
+                find_by_url(&packages, url)?.clone()
+            } else {
+                return Err(AppError::BadRequest(
+                    "$snapshot requires a definition or url parameter".to_string(),
+                ));
+            }
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Expected a StructureDefinition or Parameters resource".to_string(),
+            ));
+        }
+    };
+
+    Ok(Json(packages.snapshot(&sd).map_err(to_app_error)?))
+}
+
+/// GET /fhir/StructureDefinition/{id}/$meta - Profiles, tags and security
+/// labels of a loaded StructureDefinition
+pub async fn meta(
+    Extension(packages): Extension<Arc<PackageRegistry>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let sd = find_by_id(&packages, &id)?;
+    let meta = sd.get("meta").cloned().unwrap_or_else(|| json!({}));
+
+    Ok(Json(json!({
+        "resourceType": "Parameters",
+        "parameter": [{"name": "return", "valueMeta": meta}],
+    })))
+}
@@ -0,0 +1,23 @@
+//! `$versions` operation handler
+
+use axum::Json;
+use fhir_core::FhirVersion;
+use serde_json::{Value as JsonValue, json};
+
+/// GET /fhir/$versions - List the FHIR versions this server supports
+///
+/// Returns a `Parameters` resource with one `version` per supported version
+/// and the `default` used when a request does not specify `fhirVersion`.
+pub async fn get() -> Json<JsonValue> {
+    let mut parameter: Vec<JsonValue> = [FhirVersion::R4B, FhirVersion::R5]
+        .into_iter()
+        .filter(FhirVersion::is_supported)
+        .map(|v| json!({"name": "version", "valueCode": v.code()}))
+        .collect();
+    parameter.push(json!({"name": "default", "valueCode": FhirVersion::default().code()}));
+
+    Json(json!({
+        "resourceType": "Parameters",
+        "parameter": parameter,
+    }))
+}
@@ -0,0 +1,259 @@
+//! Built-in maintenance jobs
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use deadpool_postgres::Pool;
+
+use super::{Job, JobFuture};
+use crate::export::ExportManager;
+
+/// Deletes superseded history versions older than the retention period.
+///
+/// A version is only deleted when a later full (non-delta) version exists, so
+/// the current version and every snapshot that delta versions replay from are
+/// always kept.
+pub struct HistoryPruneJob {
+    pub retention_days: i32,
+}
+
+impl Job for HistoryPruneJob {
+    fn name(&self) -> &'static str {
+        "history-prune"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(6 * 60 * 60)
+    }
+
+    fn run<'a>(&'a self, pool: &'a Pool) -> JobFuture<'a> {
+        Box::pin(async move {
+            let client = pool.get().await.map_err(|e| e.to_string())?;
+            let deleted = client
+                .execute(
+                    "DELETE FROM fhir_history h
+                      WHERE h.created_at < NOW() - make_interval(days => $1)
+                        AND EXISTS (
+                          SELECT 1 FROM fhir_history k
+                           WHERE k.resource_id = h.resource_id
+                             AND k.version > h.version
+                             AND NOT k.delta)",
+                    &[&self.retention_days],
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(format!("pruned {} history versions", deleted))
+        })
+    }
+}
+
+/// Reports groups of live patients with the same birth date whose names are
+/// the same after normalization (case, diacritics, nicknames), which are
+/// likely duplicates.
+pub struct DuplicateDetectionJob;
+
+impl Job for DuplicateDetectionJob {
+    fn name(&self) -> &'static str {
+        "duplicate-detection"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    fn run<'a>(&'a self, pool: &'a Pool) -> JobFuture<'a> {
+        Box::pin(async move {
+            let client = pool.get().await.map_err(|e| e.to_string())?;
+            let rows = client
+                .query(
+                    "SELECT data->'name'->0, data->>'birthDate'
+                       FROM fhir_resources
+                      WHERE resource_type = 'Patient' AND deleted_at IS NULL
+                        AND data->>'birthDate' IS NOT NULL
+                        AND jsonb_typeof(data->'name'->0) = 'object'",
+                    &[],
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let mut counts: HashMap<(String, String), usize> = HashMap::new();
+            for row in &rows {
+                let name: serde_json::Value = row.get(0);
+                let birth_date: String = row.get(1);
+                if let Some(name) = fhir_core::name::NormalizedName::from_json(&name) {
+                    *counts.entry((birth_date, name.blocking_key())).or_default() += 1;
+                }
+            }
+            let groups = counts.values().filter(|n| **n > 1).count();
+            if groups > 0 {
+                tracing::warn!(groups = groups, "Possible duplicate patients detected");
+            }
+            Ok(format!("{} possible duplicate groups", groups))
+        })
+    }
+}
+
+/// Re-extracts search parameters for resources whose index rows are stale,
+/// one short batch per transaction so writes are never blocked for long.
+pub struct SearchIndexRebuildJob {
+    pub resource_type: &'static str,
+    pub batch_size: i32,
+}
+
+impl Job for SearchIndexRebuildJob {
+    fn name(&self) -> &'static str {
+        "search-index-rebuild"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn run<'a>(&'a self, pool: &'a Pool) -> JobFuture<'a> {
+        Box::pin(async move {
+            let client = pool.get().await.map_err(|e| e.to_string())?;
+            let mut total = 0;
+            loop {
+                let row = client
+                    .query_one(
+                        "SELECT fhir_reindex($1, $2)",
+                        &[&self.resource_type, &self.batch_size],
+                    )
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let reindexed: i64 = row.get(0);
+                if reindexed == 0 {
+                    break;
+                }
+                total += reindexed;
+            }
+            Ok(format!(
+                "reindexed {} {} resources",
+                total, self.resource_type
+            ))
+        })
+    }
+}
+
+/// Re-encrypts values stored under the previous encryption key (see
+/// `fhir.encryption_previous_key`) in short batches, so a key can be rotated
+/// while the server keeps serving requests.
+pub struct EncryptionKeyRotationJob {
+    pub batch_size: i32,
+}
+
+impl Job for EncryptionKeyRotationJob {
+    fn name(&self) -> &'static str {
+        "encryption-key-rotation"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    fn run<'a>(&'a self, pool: &'a Pool) -> JobFuture<'a> {
+        Box::pin(async move {
+            let client = pool.get().await.map_err(|e| e.to_string())?;
+            let row = client
+                .query_one("SELECT fhir_encryption_pending()", &[])
+                .await
+                .map_err(|e| e.to_string())?;
+            let mut remaining: i64 = row.get(0);
+            let mut total = 0;
+            while remaining > 0 {
+                let row = client
+                    .query_one("SELECT fhir_rotate_encryption($1)", &[&self.batch_size])
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let rotated: i64 = row.get(0);
+                if rotated == 0 {
+                    break;
+                }
+                total += rotated;
+                remaining = (remaining - rotated).max(0);
+                metrics::gauge!("fhir_encryption_rotation_remaining").set(remaining as f64);
+                tracing::info!(
+                    rotated = total,
+                    remaining = remaining,
+                    "Re-encrypted batch under the current key"
+                );
+            }
+            metrics::gauge!("fhir_encryption_rotation_remaining").set(0.0);
+            Ok(format!("re-encrypted {} rows", total))
+        })
+    }
+}
+
+/// Drives the configured extension backfills (see `fhir_backfill`) batch by
+/// batch until each has finished. Progress is kept in the database, so a
+/// restarted server resumes where the previous one stopped.
+pub struct BackfillJob {
+    pub backfills: Vec<String>,
+    pub batch_size: i32,
+}
+
+impl Job for BackfillJob {
+    fn name(&self) -> &'static str {
+        "backfill"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    fn run<'a>(&'a self, pool: &'a Pool) -> JobFuture<'a> {
+        Box::pin(async move {
+            let client = pool.get().await.map_err(|e| e.to_string())?;
+            let mut summary = Vec::new();
+            for backfill in &self.backfills {
+                let mut total = 0i64;
+                loop {
+                    let row = client
+                        .query_one(
+                            "SELECT fhir_backfill($1, $2)",
+                            &[backfill, &self.batch_size],
+                        )
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    let processed: i64 = row.get(0);
+                    if processed == 0 {
+                        break;
+                    }
+                    total += processed;
+                    metrics::counter!("fhir_backfill_rows_total", "backfill" => backfill.clone())
+                        .increment(processed as u64);
+                }
+                if total > 0 {
+                    tracing::info!(backfill = %backfill, rows = total, "Backfill finished");
+                }
+                summary.push(format!("{}: {} rows", backfill, total));
+            }
+            Ok(summary.join(", "))
+        })
+    }
+}
+
+/// Deletes bulk export output once its retention period has passed.
+pub struct ExportCleanupJob {
+    pub manager: ExportManager,
+}
+
+impl Job for ExportCleanupJob {
+    fn name(&self) -> &'static str {
+        "export-cleanup"
+    }
+
+    fn interval(&self) -> Duration {
+        // Check often enough that files never outlive retention by much
+        self.manager
+            .retention()
+            .clamp(Duration::from_secs(60), Duration::from_secs(60 * 60))
+    }
+
+    fn run<'a>(&'a self, _pool: &'a Pool) -> JobFuture<'a> {
+        Box::pin(async move {
+            let removed = self.manager.remove_expired().await;
+            Ok(format!("removed {} expired exports", removed))
+        })
+    }
+}
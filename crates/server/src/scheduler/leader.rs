@@ -0,0 +1,64 @@
+//! Leader election among server replicas
+//!
+//! Every replica registers the same jobs, but only the one holding a
+//! session-level Postgres advisory lock runs them. The lock is held on a
+//! connection detached from the pool for as long as the replica lives; when
+//! it exits or its connection breaks, the lock is released and another
+//! replica takes over within [`CHECK_INTERVAL`].
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use deadpool_postgres::{ClientWrapper, Object, Pool};
+
+/// Advisory lock key of the scheduler leader ("fhirSchd")
+const LEADER_LOCK_KEY: i64 = 0x6668_6972_5363_6864;
+
+/// How often followers try to take over and the leader checks its connection
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spawn the election loop, keeping `leader` up to date
+pub(super) fn spawn_election(pool: Pool, leader: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        let mut held: Option<ClientWrapper> = None;
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            held = match held.take() {
+                Some(client) => match client.simple_query("SELECT 1").await {
+                    Ok(_) => Some(client),
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Lost scheduler leadership");
+                        leader.store(false, Ordering::Relaxed);
+                        None
+                    }
+                },
+                None => match try_acquire(&pool).await {
+                    Ok(Some(client)) => {
+                        tracing::info!("Became scheduler leader");
+                        leader.store(true, Ordering::Relaxed);
+                        Some(client)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Scheduler leader election failed");
+                        None
+                    }
+                },
+            };
+        }
+    });
+}
+
+/// Take the leader lock, returning the connection that holds it
+async fn try_acquire(pool: &Pool) -> Result<Option<ClientWrapper>, String> {
+    let client = pool.get().await.map_err(|e| e.to_string())?;
+    let acquired: bool = client
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&LEADER_LOCK_KEY])
+        .await
+        .map_err(|e| e.to_string())?
+        .get(0);
+    // Detach the connection so the lock lives as long as the replica
+    Ok(acquired.then(|| Object::take(client)))
+}
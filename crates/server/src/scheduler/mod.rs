@@ -0,0 +1,199 @@
+//! Background job scheduler
+//!
+//! Recurring maintenance jobs implement [`Job`] and are registered with a
+//! [`Scheduler`], which runs each one on its own tokio interval and records
+//! the outcome for the admin stats endpoint. When several replicas share a
+//! database, only the elected leader runs jobs; the others skip their ticks.
+
+pub mod jobs;
+mod leader;
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+
+use crate::webhook::{AdminEvent, WebhookNotifier};
+
+/// Boxed future returned by [`Job::run`]
+pub type JobFuture<'a> = Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>>;
+
+/// A recurring background job
+pub trait Job: Send + Sync + 'static {
+    /// Unique job name (used in logs and stats)
+    fn name(&self) -> &'static str;
+
+    /// How often the job runs
+    fn interval(&self) -> Duration;
+
+    /// Run the job once, returning a short human-readable summary
+    fn run<'a>(&'a self, pool: &'a Pool) -> JobFuture<'a>;
+}
+
+/// Last-known status of a registered job
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub name: String,
+    pub interval_secs: u64,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    /// Ticks skipped because another replica is the leader
+    pub skipped: u64,
+    /// Seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Shared, cloneable view of job statuses
+#[derive(Clone, Default)]
+pub struct SchedulerHandle {
+    statuses: Arc<Mutex<BTreeMap<&'static str, JobStatus>>>,
+    leader: Arc<AtomicBool>,
+}
+
+impl SchedulerHandle {
+    /// Whether this replica currently runs the jobs
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Join the leader election, keeping [`is_leader`](Self::is_leader) up to
+    /// date
+    pub fn spawn_election(&self, pool: Pool) {
+        leader::spawn_election(pool, self.leader.clone());
+    }
+
+    /// Snapshot of every registered job's status, ordered by name
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.statuses.lock().unwrap().values().cloned().collect()
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut JobStatus)) {
+        if let Some(status) = self.statuses.lock().unwrap().get_mut(name) {
+            f(status);
+        }
+    }
+}
+
+/// Registry of recurring jobs
+pub struct Scheduler {
+    jobs: Vec<Arc<dyn Job>>,
+    handle: SchedulerHandle,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            jobs: Vec::new(),
+            handle: SchedulerHandle::default(),
+        }
+    }
+
+    /// Register a job; it starts running when [`Scheduler::start`] is called
+    pub fn register(&mut self, job: impl Job) {
+        self.handle.statuses.lock().unwrap().insert(
+            job.name(),
+            JobStatus {
+                name: job.name().to_string(),
+                interval_secs: job.interval().as_secs(),
+                ..Default::default()
+            },
+        );
+        self.jobs.push(Arc::new(job));
+    }
+
+    /// Status handle for the admin stats endpoint
+    pub fn handle(&self) -> SchedulerHandle {
+        self.handle.clone()
+    }
+
+    /// Spawn one tokio task per registered job; the jobs run only while the
+    /// handle's election is won
+    pub fn start(self, pool: Pool, notifier: WebhookNotifier) {
+        for job in self.jobs {
+            let pool = pool.clone();
+            let handle = self.handle.clone();
+            let notifier = notifier.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(job.interval());
+                // The first tick completes immediately; wait a full period instead
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    if !handle.is_leader() {
+                        tracing::debug!(job = job.name(), "Job skipped, not the scheduler leader");
+                        handle.update(job.name(), |s| s.skipped += 1);
+                        continue;
+                    }
+                    run_once(job.as_ref(), &pool, &handle, &notifier).await;
+                }
+            });
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a job once and record the outcome
+async fn run_once(
+    job: &dyn Job,
+    pool: &Pool,
+    handle: &SchedulerHandle,
+    notifier: &WebhookNotifier,
+) {
+    let name = job.name();
+    handle.update(name, |s| s.running = true);
+
+    let started = Instant::now();
+    let result = job.run(pool).await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+    let finished_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    match &result {
+        Ok(summary) => tracing::info!(job = name, duration_ms, summary = %summary, "Job completed"),
+        Err(e) => tracing::error!(job = name, duration_ms, error = %e, "Job failed"),
+    }
+
+    handle.update(name, |s| {
+        s.running = false;
+        s.runs += 1;
+        s.last_run_at = Some(finished_at);
+        s.last_duration_ms = Some(duration_ms);
+        match &result {
+            Ok(summary) => {
+                s.last_result = Some(summary.clone());
+                s.last_error = None;
+            }
+            Err(e) => {
+                s.failures += 1;
+                s.last_error = Some(e.clone());
+            }
+        }
+    });
+
+    notifier.notify(AdminEvent::JobCompleted {
+        job: name.to_string(),
+        success: result.is_ok(),
+        detail: Some(result.unwrap_or_else(|e| e)),
+    });
+}
@@ -0,0 +1,188 @@
+//! Normalization of search parameter values
+//!
+//! Searches reach `fhir_search` from the HTTP layer, `$nl-search` and the
+//! chatbot tools. Every path passes its values through [`normalize`] so the
+//! same search produces the same pg-ext query whichever way it arrived.
+//!
+//! A parameter given once is a string in the params object; one given
+//! several times is an array of strings, all of which must match. Each
+//! string may list alternatives separated by commas (`\,` for a literal
+//! comma), any of which may match. pg-ext reads values the same way.
+
+use serde_json::Value as JsonValue;
+
+/// Date search prefixes understood by pg-ext
+const DATE_PREFIXES: &[&str] = &["eq", "ne", "gt", "lt", "ge", "le"];
+
+/// Group `(code, value)` pairs into a params object: a code given once maps
+/// to its value, a repeated one to an array of its values in order
+pub fn multimap<'a>(
+    pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> serde_json::Map<String, JsonValue> {
+    let mut map = serde_json::Map::new();
+    for (code, value) in pairs {
+        let value = JsonValue::String(value.to_string());
+        match map.get_mut(code) {
+            Some(JsonValue::Array(values)) => values.push(value),
+            Some(first) => *first = JsonValue::Array(vec![first.take(), value]),
+            None => {
+                map.insert(code.to_string(), value);
+            }
+        }
+    }
+    map
+}
+
+/// Normalize the built-in search values in a params object
+///
+/// Every alternative of every value is normalized on its own. Values that
+/// are empty after normalization are removed; result parameters (`_count`,
+/// `_sort`, ...) and unknown keys pass through unchanged.
+pub fn normalize(params: JsonValue) -> JsonValue {
+    let JsonValue::Object(map) = params else {
+        return params;
+    };
+    let map = map
+        .into_iter()
+        .filter_map(|(code, value)| {
+            // Modifiers (`address:exact`) do not change how a value is read
+            let base = code.split_once(':').map_or(code.as_str(), |(base, _)| base);
+            let Some(normalize_one) = value_normalizer(base) else {
+                return Some((code, value));
+            };
+            let normalize_text = |value: JsonValue| match value {
+                JsonValue::String(text) => {
+                    normalize_alternatives(&text, normalize_one).map(JsonValue::String)
+                }
+                other => Some(other),
+            };
+            let value = match value {
+                JsonValue::Array(values) => {
+                    let values: Vec<JsonValue> =
+                        values.into_iter().filter_map(normalize_text).collect();
+                    if values.is_empty() {
+                        return None;
+                    }
+                    JsonValue::Array(values)
+                }
+                value => normalize_text(value)?,
+            };
+            Some((code, value))
+        })
+        .collect();
+    JsonValue::Object(map)
+}
+
+/// How the values of a built-in search parameter are normalized
+fn value_normalizer(code: &str) -> Option<fn(&str) -> Option<String>> {
+    match code {
+        "name" | "family" | "given" | "address" | "address-city" | "address-state"
+        | "address-postalcode" | "address-country" => Some(normalize_string),
+        "gender" => Some(normalize_code),
+        "birthdate" => Some(normalize_date),
+        "identifier" | "telecom" | "phone" | "email" => Some(normalize_token),
+        _ => None,
+    }
+}
+
+/// Normalize each alternative of a value, dropping those left empty
+fn normalize_alternatives(value: &str, normalize: fn(&str) -> Option<String>) -> Option<String> {
+    let normalized: Vec<String> = alternatives(value)
+        .iter()
+        .filter_map(|alternative| normalize(alternative))
+        .collect();
+    Some(join_alternatives(&normalized)).filter(|v| !v.is_empty())
+}
+
+/// The comma-separated alternatives of a search value, with `\,` read as a
+/// literal comma; empty alternatives are dropped
+pub fn alternatives(value: &str) -> Vec<String> {
+    let mut alternatives = vec![String::new()];
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        let current = alternatives.last_mut().expect("never empty");
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                current.push(',');
+                chars.next();
+            }
+            ',' => alternatives.push(String::new()),
+            c => current.push(c),
+        }
+    }
+    alternatives.retain(|a| !a.is_empty());
+    alternatives
+}
+
+/// Join alternatives into one search value, escaping their commas
+pub fn join_alternatives(alternatives: &[String]) -> String {
+    alternatives
+        .iter()
+        .map(|alternative| alternative.replace(',', "\\,"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Normalize a value by search parameter type (`string`, `token`, `date`)
+pub fn normalize_typed(param_type: &str, value: &str) -> Option<String> {
+    match param_type {
+        "string" => normalize_string(value),
+        "date" => normalize_date(value),
+        _ => Some(value.trim().to_string()).filter(|v| !v.is_empty()),
+    }
+}
+
+/// Trim and collapse runs of whitespace; matching is case-insensitive in
+/// pg-ext, so case is kept
+fn normalize_string(value: &str) -> Option<String> {
+    let collapsed = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    Some(collapsed).filter(|v| !v.is_empty())
+}
+
+/// Reduce a token to its lowercase code; a `system|` part is dropped since
+/// built-in token parameters are plain codes
+fn normalize_code(value: &str) -> Option<String> {
+    let code = value.rsplit_once('|').map_or(value, |(_, code)| code);
+    Some(code.trim().to_lowercase()).filter(|v| !v.is_empty())
+}
+
+/// Trim the system and value of a `system|value` token; both are
+/// case-sensitive, so nothing else changes
+fn normalize_token(value: &str) -> Option<String> {
+    let token = match value.split_once('|') {
+        Some((system, value)) => format!("{}|{}", system.trim(), value.trim()),
+        None => value.trim().to_string(),
+    };
+    Some(token).filter(|t| !t.is_empty() && t != "|")
+}
+
+/// Lowercase the prefix and zero-pad the date to `YYYY`, `YYYY-MM` or
+/// `YYYY-MM-DD`; `/` separators are accepted and a time part is dropped.
+/// Anything else is passed on trimmed, for pg-ext to compare as given.
+fn normalize_date(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let (prefix, date) = match value.get(..2).map(str::to_lowercase) {
+        Some(prefix) if DATE_PREFIXES.contains(&prefix.as_str()) => (prefix, &value[2..]),
+        _ => (String::new(), value),
+    };
+    let date = date.trim();
+    let day = date.split_once('T').map_or(date, |(day, _)| day);
+    let parts: Vec<&str> = day.split(['-', '/']).collect();
+    let numeric = |part: &&str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let canonical = match parts.as_slice() {
+        [year, rest @ ..] if year.len() == 4 && rest.len() <= 2 && parts.iter().all(numeric) => {
+            std::iter::once(year.to_string())
+                .chain(rest.iter().map(|part| format!("{:0>2}", part)))
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+        _ => date.to_string(),
+    };
+    if canonical.is_empty() {
+        return None;
+    }
+    Some(format!("{}{}", prefix, canonical))
+}
@@ -0,0 +1,157 @@
+//! Seed data loading
+//!
+//! `SEED_DIR` points at a directory of FHIR fixtures (ValueSets, sample
+//! patients, SearchParameters, ...) loaded at startup so demo and test
+//! environments provision themselves. Files are read in name order: `.json`
+//! files hold one resource or a Bundle of them, `.ndjson` files one resource
+//! per line.
+//!
+//! Loading is idempotent: each resource is created only if no live resource
+//! of its type shares its `url` (conformance resources) or, lacking one, its
+//! first `identifier`. Resources with neither are skipped, since they could
+//! not be matched on the next start.
+
+use std::path::Path;
+
+use deadpool_postgres::Pool;
+use fhir_core::resource_type::is_resource_type;
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+use crate::db::ResourceRepository;
+use crate::error::AppError;
+
+/// Outcome of loading a seed directory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SeedReport {
+    /// Resources created
+    pub created: usize,
+    /// Resources already present
+    pub existing: usize,
+    /// Resources that could not be read or matched
+    pub skipped: usize,
+}
+
+/// Load every fixture in `dir`
+///
+/// Unreadable files and resources are logged and counted as skipped; only
+/// database errors abort the load.
+pub async fn load_dir(pool: &Pool, dir: &Path) -> Result<SeedReport, AppError> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .map_err(|e| AppError::Internal(format!("Cannot read {}: {}", dir.display(), e)))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+
+    let mut report = SeedReport::default();
+    for path in files {
+        let resources = match read_file(&path) {
+            Some(Ok(resources)) => resources,
+            Some(Err(e)) => {
+                tracing::warn!(file = %path.display(), error = %e, "Skipping unreadable seed file");
+                report.skipped += 1;
+                continue;
+            }
+            None => continue,
+        };
+        for resource in resources {
+            load_resource(pool, &path, resource, &mut report).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// The resources of a fixture file, or `None` if it is not one
+fn read_file(path: &Path) -> Option<Result<Vec<JsonValue>, String>> {
+    let extension = path.extension().and_then(|e| e.to_str())?;
+    let read = || std::fs::read_to_string(path).map_err(|e| e.to_string());
+    match extension {
+        "json" => Some(read().and_then(|text| {
+            let value: JsonValue = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+            Ok(unbundle(value))
+        })),
+        "ndjson" => Some(read().and_then(|text| {
+            text.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+                .collect()
+        })),
+        _ => None,
+    }
+}
+
+/// The entries of a Bundle, or the resource itself
+fn unbundle(value: JsonValue) -> Vec<JsonValue> {
+    if value.get("resourceType").and_then(|v| v.as_str()) != Some("Bundle") {
+        return vec![value];
+    }
+    value
+        .get("entry")
+        .and_then(|e| e.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.get("resource").cloned())
+        .collect()
+}
+
+async fn load_resource(
+    pool: &Pool,
+    path: &Path,
+    resource: JsonValue,
+    report: &mut SeedReport,
+) -> Result<(), AppError> {
+    let resource_type = resource
+        .get("resourceType")
+        .and_then(|v| v.as_str())
+        .filter(|t| is_resource_type(t))
+        .map(str::to_string);
+    let Some(resource_type) = resource_type else {
+        tracing::warn!(file = %path.display(), "Skipping seed resource without a known resourceType");
+        report.skipped += 1;
+        return Ok(());
+    };
+
+    let repo = ResourceRepository::new(pool.clone(), resource_type.as_str());
+    let existing = match match_key(&resource) {
+        Some(MatchKey::Url(url)) => repo.find_by_url(url).await?,
+        Some(MatchKey::Identifier(identifier)) => repo.find_by_identifier(identifier).await?,
+        None => {
+            tracing::warn!(
+                file = %path.display(),
+                resource_type = %resource_type,
+                "Skipping seed resource without url or identifier"
+            );
+            report.skipped += 1;
+            return Ok(());
+        }
+    };
+
+    match existing {
+        Some(_) => report.existing += 1,
+        None => {
+            let id: Uuid = repo.create(resource).await?;
+            tracing::debug!(resource_type = %resource_type, resource_id = %id, "Seed resource created");
+            report.created += 1;
+        }
+    }
+    Ok(())
+}
+
+/// What identifies a seed resource across loads
+enum MatchKey<'a> {
+    Url(&'a str),
+    Identifier(&'a JsonValue),
+}
+
+fn match_key(resource: &JsonValue) -> Option<MatchKey<'_>> {
+    if let Some(url) = resource.get("url").and_then(|v| v.as_str()) {
+        return Some(MatchKey::Url(url));
+    }
+    resource
+        .get("identifier")
+        .and_then(|v| v.as_array())
+        .and_then(|ids| ids.iter().find(|id| id.get("value").is_some()))
+        .map(MatchKey::Identifier)
+}
@@ -0,0 +1,82 @@
+//! Construction of the URLs the server hands out
+//!
+//! `Location` headers, Bundle links and `$export` file URLs are all built
+//! here, so every path segment and query value is percent-encoded the same
+//! way. A value may contain spaces, `+`, `&`, `|` or any Unicode text and
+//! still come back unchanged when the URL is followed.
+
+use axum::http::HeaderValue;
+
+/// A URL built from a base, path segments and query parameters
+#[derive(Debug, Clone)]
+pub struct UrlBuilder {
+    path: String,
+    query: Vec<(String, String)>,
+}
+
+impl UrlBuilder {
+    /// Start from a base taken as is, e.g. `/fhir` or `http://host/fhir`
+    pub fn new(base: &str) -> Self {
+        Self {
+            path: base.trim_end_matches('/').to_string(),
+            query: Vec::new(),
+        }
+    }
+
+    /// Append one path segment, percent-encoding anything outside the
+    /// unreserved characters and `$` (kept for operation names such as
+    /// `$export-file`)
+    pub fn segment(mut self, segment: impl ToString) -> Self {
+        self.path.push('/');
+        for byte in segment.to_string().bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~$".contains(&byte) {
+                self.path.push(byte as char);
+            } else {
+                self.path.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        self
+    }
+
+    /// Append a query parameter
+    pub fn param(mut self, name: &str, value: impl ToString) -> Self {
+        self.query.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Append query parameters in order
+    pub fn params<K, V>(mut self, params: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.query.extend(
+            params
+                .into_iter()
+                .map(|(name, value)| (name.as_ref().to_string(), value.as_ref().to_string())),
+        );
+        self
+    }
+
+    /// Append the parameters of an already encoded query string, re-encoded
+    /// so the result is canonical
+    pub fn query(self, query: &str) -> Self {
+        self.params(form_urlencoded::parse(query.as_bytes()))
+    }
+
+    /// The URL as a string; query values are form-encoded (space as `+`)
+    pub fn build(&self) -> String {
+        if self.query.is_empty() {
+            return self.path.clone();
+        }
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&self.query)
+            .finish();
+        format!("{}?{}", self.path, query)
+    }
+
+    /// The URL as a header value; always valid since it is fully encoded
+    pub fn header(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.build()).expect("encoded URLs are valid header values")
+    }
+}
@@ -0,0 +1,213 @@
+//! Webhook notifications for administrative events
+//!
+//! Events are serialized to JSON, signed with HMAC-SHA256 over the raw body
+//! (`X-Webhook-Signature: sha256=<hex>`), and delivered to every configured
+//! URL in the background with exponential-backoff retry.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+/// Header carrying the payload signature
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Delivery attempts per URL before giving up
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry (doubled on each subsequent attempt)
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Window over which authentication failures are counted
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Minimum time between two alerts for the same exhausted budget
+const BUDGET_ALERT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Operational events that ops teams can subscribe to
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AdminEvent {
+    JobCompleted {
+        job: String,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    SubscriptionDeliveryFailed {
+        subscription_id: String,
+        endpoint: String,
+        error: String,
+    },
+    BudgetExhausted {
+        budget: String,
+        limit: u64,
+    },
+    RepeatedAuthFailures {
+        count: u32,
+        window_secs: u64,
+    },
+}
+
+/// Envelope sent to webhook receivers
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    event: &'a AdminEvent,
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+}
+
+/// Sends signed administrative events to the configured webhook URLs
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    http: reqwest::Client,
+    urls: Arc<Vec<String>>,
+    secret: Option<Arc<String>>,
+    auth_failure_threshold: u32,
+    auth_failures: Arc<Mutex<(Instant, u32)>>,
+    /// When each budget was last reported exhausted
+    budget_alerts: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl WebhookNotifier {
+    pub fn new(urls: Vec<String>, secret: Option<String>, auth_failure_threshold: u32) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            urls: Arc::new(urls),
+            secret: secret.map(Arc::new),
+            auth_failure_threshold,
+            auth_failures: Arc::new(Mutex::new((Instant::now(), 0))),
+            budget_alerts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether any webhook URL is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.urls.is_empty()
+    }
+
+    /// Deliver an event to every configured URL in the background
+    pub fn notify(&self, event: AdminEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let body = match serde_json::to_vec(&WebhookPayload {
+            event: &event,
+            timestamp,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to serialize webhook payload");
+                return;
+            }
+        };
+        let signature = self.secret.as_deref().map(|secret| sign(secret, &body));
+
+        for url in self.urls.iter().cloned() {
+            let http = self.http.clone();
+            let body = body.clone();
+            let signature = signature.clone();
+            tokio::spawn(async move {
+                deliver(&http, &url, body, signature.as_deref()).await;
+            });
+        }
+    }
+
+    /// Record an authentication failure, alerting once the threshold is hit
+    /// within the counting window
+    pub fn record_auth_failure(&self) {
+        if self.auth_failure_threshold == 0 {
+            return;
+        }
+
+        let count = {
+            let mut state = self.auth_failures.lock().unwrap();
+            if state.0.elapsed() > AUTH_FAILURE_WINDOW {
+                *state = (Instant::now(), 0);
+            }
+            state.1 += 1;
+            state.1
+        };
+
+        // Alert exactly once per window when the threshold is crossed
+        if count == self.auth_failure_threshold {
+            tracing::warn!(count = count, "Repeated authentication failures");
+            self.notify(AdminEvent::RepeatedAuthFailures {
+                count,
+                window_secs: AUTH_FAILURE_WINDOW.as_secs(),
+            });
+        }
+    }
+
+    /// Record that `budget` ran out, alerting at most once per interval for
+    /// each budget however many requests it turns away
+    pub fn record_budget_exhausted(&self, budget: &str, limit: u64) {
+        {
+            let mut alerts = self.budget_alerts.lock().unwrap();
+            let now = Instant::now();
+            match alerts.get(budget) {
+                Some(last) if now.duration_since(*last) < BUDGET_ALERT_INTERVAL => return,
+                _ => {
+                    alerts.insert(budget.to_string(), now);
+                }
+            }
+        }
+
+        tracing::warn!(budget = budget, limit = limit, "Budget exhausted");
+        self.notify(AdminEvent::BudgetExhausted {
+            budget: budget.to_string(),
+            limit,
+        });
+    }
+}
+
+/// POST a payload, retrying with exponential backoff on failure
+async fn deliver(http: &reqwest::Client, url: &str, body: Vec<u8>, signature: Option<&str>) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+        if let Some(sig) = signature {
+            request = request.header(SIGNATURE_HEADER, sig);
+        }
+
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp) => {
+                tracing::warn!(url = url, attempt = attempt, status = %resp.status(), "Webhook rejected");
+            }
+            Err(e) => {
+                tracing::warn!(url = url, attempt = attempt, error = %e, "Webhook delivery failed");
+            }
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    tracing::error!(url = url, "Webhook delivery abandoned after retries");
+}
+
+/// Compute the `sha256=<hex>` HMAC signature of a payload
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
@@ -0,0 +1,343 @@
+//! Write policies: site-specific business rules checked before a write
+//!
+//! A [`WritePolicy`] sees the resource about to be written next to its
+//! current version (`None` for a create; `null` is written by a delete) and
+//! returns a `business-rule` issue for every rule the write breaks. Any issue
+//! rejects the write with `422`, listing the issues of all policies. Policies
+//! are evaluated on every write through the FHIR API: create, update, patch
+//! and delete of Patients and other resource types, the entries of
+//! transaction and batch Bundles, and `$generate`. The built-in policies
+//! check what is written, so they let deletes through.
+//!
+//! The built-in policies are configured from the environment
+//! ([`WritePolicies::from_config`]); deployments register their own with
+//! [`WritePolicies::with`] and hand them to
+//! [`build_app_with_policies`](crate::build_app_with_policies).
+
+use std::sync::Arc;
+
+use axum::http::HeaderMap;
+use fhir_core::{IssueType, OperationOutcomeIssue, ResourceIdentifier};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Header carrying the caller's space-separated scopes, as set by the
+/// gateway that authenticated the request
+pub const SCOPES_HEADER: &str = "x-scopes";
+
+/// Kind of write being evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteInteraction {
+    Create,
+    Update,
+    Patch,
+    Delete,
+}
+
+/// What is being written, and by whom
+#[derive(Debug, Clone)]
+pub struct WriteContext {
+    pub resource_type: String,
+    pub interaction: WriteInteraction,
+    /// Scopes granted to the caller
+    pub scopes: Vec<String>,
+}
+
+impl WriteContext {
+    /// Context of a write of `resource_type`, with the scopes in `headers`
+    pub fn new(resource_type: &str, interaction: WriteInteraction, headers: &HeaderMap) -> Self {
+        let scopes = headers
+            .get(SCOPES_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        Self {
+            resource_type: resource_type.to_string(),
+            interaction,
+            scopes,
+        }
+    }
+
+    /// Whether the caller was granted `scope`
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// A business rule evaluated before every write
+pub trait WritePolicy: Send + Sync + 'static {
+    /// Policy name (used in logs)
+    fn name(&self) -> &'static str;
+
+    /// Issues the write of `new` over `old` raises; empty if it may proceed.
+    /// A delete writes `null` over the current version.
+    fn evaluate(
+        &self,
+        old: Option<&Value>,
+        new: &Value,
+        context: &WriteContext,
+    ) -> Vec<OperationOutcomeIssue>;
+}
+
+/// A `business-rule` issue at `location`
+pub fn violation(location: &str, message: &str) -> OperationOutcomeIssue {
+    OperationOutcomeIssue {
+        location: vec![location.to_string()],
+        ..OperationOutcomeIssue::error(IssueType::BusinessRule, message)
+    }
+}
+
+/// The registered policies, shared through request extensions
+#[derive(Clone, Default)]
+pub struct WritePolicies(Arc<Vec<Arc<dyn WritePolicy>>>);
+
+impl WritePolicies {
+    /// The built-in policies enabled by the configuration
+    pub fn from_config(config: &Config) -> Self {
+        let mut policies = Self::default();
+        if !config.immutable_identifier_systems.is_empty() {
+            policies = policies.with(ImmutableIdentifiers {
+                systems: config.immutable_identifier_systems.clone(),
+            });
+        }
+        if let Some(scope) = &config.birthdate_change_scope {
+            policies = policies.with(BirthDateChangeScope {
+                scope: scope.clone(),
+            });
+        }
+        if !config.required_elements.is_empty() {
+            policies = policies.with(RequiredElements {
+                paths: config.required_elements.clone(),
+            });
+        }
+        for (path, values) in &config.allowed_values {
+            policies = policies.with(AllowedValues {
+                path: path.clone(),
+                values: values.clone(),
+            });
+        }
+        policies
+    }
+
+    /// These policies and `policy`
+    pub fn with(self, policy: impl WritePolicy) -> Self {
+        let mut policies = (*self.0).clone();
+        policies.push(Arc::new(policy));
+        Self(Arc::new(policies))
+    }
+
+    /// Whether no policy is registered, so writes need not read the current
+    /// version
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Evaluate every policy, failing with all their issues
+    pub fn evaluate(
+        &self,
+        old: Option<&Value>,
+        new: &Value,
+        context: &WriteContext,
+    ) -> Result<(), AppError> {
+        let mut issues = Vec::new();
+        for policy in self.0.iter() {
+            let raised = policy.evaluate(old, new, context);
+            if !raised.is_empty() {
+                tracing::info!(
+                    policy = policy.name(),
+                    resource_type = %context.resource_type,
+                    issues = raised.len(),
+                    "Write rejected by policy"
+                );
+            }
+            issues.extend(raised);
+        }
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(AppError::BusinessRule(issues)),
+        }
+    }
+}
+
+/// Identifiers of the listed systems keep their values once assigned
+/// (`IMMUTABLE_IDENTIFIER_SYSTEMS`)
+pub struct ImmutableIdentifiers {
+    pub systems: Vec<String>,
+}
+
+impl WritePolicy for ImmutableIdentifiers {
+    fn name(&self) -> &'static str {
+        "immutable-identifiers"
+    }
+
+    fn evaluate(
+        &self,
+        old: Option<&Value>,
+        new: &Value,
+        context: &WriteContext,
+    ) -> Vec<OperationOutcomeIssue> {
+        let Some(old) = old.filter(|_| context.interaction != WriteInteraction::Delete) else {
+            return Vec::new();
+        };
+        self.systems
+            .iter()
+            .filter_map(|system| {
+                let before = identifier_values(old, system);
+                let after = identifier_values(new, system);
+                (!before.is_empty() && before != after).then(|| {
+                    violation(
+                        &format!("{}.identifier", context.resource_type),
+                        &format!(
+                            "Identifier {} cannot change once assigned (currently {})",
+                            system,
+                            before.join(", ")
+                        ),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Sorted values of the identifiers with `system`
+fn identifier_values(resource: &Value, system: &str) -> Vec<String> {
+    let mut values: Vec<String> = ResourceIdentifier::all_from_resource(resource)
+        .into_iter()
+        .filter(|identifier| identifier.system.as_deref() == Some(system))
+        .filter_map(|identifier| identifier.value)
+        .collect();
+    values.sort_unstable();
+    values
+}
+
+/// Changing a Patient's `birthDate` requires a scope
+/// (`BIRTHDATE_CHANGE_SCOPE`)
+pub struct BirthDateChangeScope {
+    pub scope: String,
+}
+
+impl WritePolicy for BirthDateChangeScope {
+    fn name(&self) -> &'static str {
+        "birthdate-change-scope"
+    }
+
+    fn evaluate(
+        &self,
+        old: Option<&Value>,
+        new: &Value,
+        context: &WriteContext,
+    ) -> Vec<OperationOutcomeIssue> {
+        match old {
+            Some(old)
+                if context.resource_type == "Patient"
+                    && context.interaction != WriteInteraction::Delete
+                    && old.get("birthDate") != new.get("birthDate")
+                    && !context.has_scope(&self.scope) =>
+            {
+                vec![violation(
+                    "Patient.birthDate",
+                    &format!("Changing birthDate requires the {} scope", self.scope),
+                )]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Elements a resource must carry, as `Type.element` paths
+/// (`REQUIRED_ELEMENTS`)
+pub struct RequiredElements {
+    pub paths: Vec<String>,
+}
+
+impl WritePolicy for RequiredElements {
+    fn name(&self) -> &'static str {
+        "required-elements"
+    }
+
+    fn evaluate(
+        &self,
+        _old: Option<&Value>,
+        new: &Value,
+        context: &WriteContext,
+    ) -> Vec<OperationOutcomeIssue> {
+        if context.interaction == WriteInteraction::Delete {
+            return Vec::new();
+        }
+        self.paths
+            .iter()
+            .filter(|path| {
+                path.split_once('.')
+                    .is_some_and(|(resource_type, element)| {
+                        resource_type == context.resource_type && is_empty(new.get(element))
+                    })
+            })
+            .map(|path| violation(path, &format!("{} is required", path)))
+            .collect()
+    }
+}
+
+/// Whether an element is absent or carries no value
+fn is_empty(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => true,
+        Some(Value::String(s)) => s.trim().is_empty(),
+        Some(Value::Array(items)) => items.is_empty(),
+        Some(Value::Object(map)) => map.is_empty(),
+        Some(_) => false,
+    }
+}
+
+/// A primitive element takes only the listed values (`ALLOWED_VALUES`)
+pub struct AllowedValues {
+    /// `Type.element` path
+    pub path: String,
+    pub values: Vec<String>,
+}
+
+impl WritePolicy for AllowedValues {
+    fn name(&self) -> &'static str {
+        "allowed-values"
+    }
+
+    fn evaluate(
+        &self,
+        _old: Option<&Value>,
+        new: &Value,
+        context: &WriteContext,
+    ) -> Vec<OperationOutcomeIssue> {
+        let element = match self.path.split_once('.') {
+            Some((resource_type, element))
+                if resource_type == context.resource_type
+                    && context.interaction != WriteInteraction::Delete =>
+            {
+                element
+            }
+            _ => return Vec::new(),
+        };
+        let values: Vec<&str> = match new.get(element) {
+            Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+            Some(value) => value.as_str().into_iter().collect(),
+            None => Vec::new(),
+        };
+        values
+            .into_iter()
+            .filter(|value| !self.values.iter().any(|allowed| allowed == value))
+            .map(|value| {
+                violation(
+                    &self.path,
+                    &format!(
+                        "{} must be one of {}, got {}",
+                        self.path,
+                        self.values.join(", "),
+                        value
+                    ),
+                )
+            })
+            .collect()
+    }
+}
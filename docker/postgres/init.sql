@@ -1,2 +1,5 @@
 -- Initialize the FHIR PGRX extension on first startup
 CREATE EXTENSION IF NOT EXISTS fhir_pg_ext;
+
+-- pgcrypto backs the optional element-level encryption (fhir.encrypted_paths)
+CREATE EXTENSION IF NOT EXISTS pgcrypto;